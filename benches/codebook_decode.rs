@@ -0,0 +1,48 @@
+// Compare the codebook decoding with the first lookup table against the bisection only.
+// Run with `cargo bench --bench codebook_decode`, it reads `test.ogg` from the package root.
+
+use std::time::Instant;
+use revorbis::*;
+
+fn main() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let packets = ogg_stream_packets(&data, None).unwrap();
+	let ident = VorbisIdentificationHeader::load_from_slice(&packets[0].data).unwrap();
+	let setup = VorbisSetupHeader::load(&mut BitReader::new(&packets[2].data), &ident).unwrap();
+	for book in setup.static_codebooks.iter() {
+		let table = CodeBook::new_for_decode(book).unwrap();
+		if table.used_entries < 2 {
+			continue;
+		}
+		let treeless = CodeBook {dec_firsttable: Vec::new(), dec_firsttablen: 0, ..table.clone()};
+		let encoder = CodeBook::new_for_encode(book).unwrap();
+		// Pick the entries by the probabilities their codeword lengths imply
+		let weights: Vec<f64> = book.lengthlist.iter().map(|&length|if length > 0 {0.5f64.powi(length as i32)} else {0.0}).collect();
+		let total: f64 = weights.iter().sum();
+		let mut seed = 0x1234_5678_9abc_def0u64;
+		let count = 100000;
+		let entries: Vec<i32> = (0..count).map(|_| {
+			seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+			let mut r = (seed >> 11) as f64 / (1u64 << 53) as f64 * total;
+			weights.iter().position(|&w| {
+				r -= w;
+				w > 0.0 && r < 0.0
+			}).unwrap_or_else(||weights.iter().rposition(|&w|w > 0.0).unwrap()) as i32
+		}).collect();
+		let mut bitwriter = BitWriterCursor::default();
+		for &entry in entries.iter() {
+			encoder.encode(entry, &mut bitwriter).unwrap();
+		}
+		let bytes = bitwriter.into_bytes();
+		let mut timing = [0.0; 2];
+		for (t, codebook) in [&table, &treeless].into_iter().enumerate() {
+			let begin = Instant::now();
+			let mut bitreader = BitReader::new(&bytes);
+			for &entry in entries.iter() {
+				assert_eq!(codebook.decode_scalar(&mut bitreader).unwrap(), entry);
+			}
+			timing[t] = begin.elapsed().as_secs_f64() * 1e9 / count as f64;
+		}
+		println!("entries {:5} used {:5} maxlength {:2} firsttablen {}: table {:6.1} ns, bisect {:6.1} ns", book.entries, table.used_entries, table.dec_maxlength, table.dec_firsttablen, timing[0], timing[1]);
+	}
+}
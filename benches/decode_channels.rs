@@ -0,0 +1,61 @@
+// Decode a 6-channel stream with `decode_packet_into()` to measure the per-channel synthesis.
+// Run with `cargo bench --bench decode_channels --features rayon` to compare one thread against the whole thread pool,
+// without the `rayon` feature only the serial decoding is measured.
+
+use std::time::Instant;
+use revorbis::*;
+
+const CHANNELS: usize = 6;
+
+/// * Encode three seconds of a different tone on each channel
+fn six_channel_stream() -> Vec<u8> {
+	let sample_rate = 44100;
+	let frames = sample_rate * 3;
+	let samples: Vec<Vec<f32>> = (0..CHANNELS).map(|ch| {
+		let freq = 220.0 * (ch + 1) as f32;
+		(0..frames).map(|i|(i as f32 * freq * std::f32::consts::TAU / sample_rate as f32).sin() * 0.5).collect()
+	}).collect();
+	let wav = pcm_to_wav_i16(CHANNELS as u16, sample_rate as u32, &samples).unwrap();
+	encode_wav_to_ogg_vorbis(&wav, 0.4).unwrap()
+}
+
+/// * Decode all of the audio packets of the stream `rounds` times, returns the nanoseconds per packet.
+/// * The stream is parsed here, the `VorbisInfo` isn't `Send` so it's created on the thread pool that decodes.
+fn decode_all(ogg: &[u8], rounds: usize) -> f64 {
+	let packets = ogg_stream_packets(ogg, None).unwrap();
+	let ident = VorbisIdentificationHeader::load_from_slice(&packets[0].data).unwrap();
+	let setup = VorbisSetupHeader::load(&mut BitReader::new(&packets[2].data), &ident).unwrap();
+	let vi = VorbisInfo::new(&ident, &setup).unwrap();
+	let audio_packets = &packets[3..];
+	let mut out = vec![vec![0.0f32; vi.codec_setup.block_size[1] as usize / 2]; CHANNELS];
+	let mut elapsed = 0.0;
+	for _ in 0..rounds {
+		let mut vd = VorbisDspState::new(vi.clone(), false).unwrap();
+		let begin = Instant::now();
+		for packet in audio_packets.iter() {
+			vd.decode_packet_into(&packet.data, &mut out).unwrap();
+		}
+		elapsed += begin.elapsed().as_secs_f64();
+	}
+	elapsed * 1e9 / (rounds * audio_packets.len()) as f64
+}
+
+fn main() {
+	let ogg = six_channel_stream();
+	let num_packets = ogg_vorbis_audio_packets(&ogg).unwrap().len();
+	let rounds = 20;
+	decode_all(&ogg, 1);
+
+	#[cfg(feature = "rayon")]
+	{
+		let serial = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap().install(||decode_all(&ogg, rounds));
+		let threads = rayon::current_num_threads();
+		let parallel = decode_all(&ogg, rounds);
+		println!("{CHANNELS} channels, {num_packets} packets: 1 thread {serial:9.1} ns/packet, {threads} threads {parallel:9.1} ns/packet, speedup {:.2}x", serial / parallel);
+	}
+	#[cfg(not(feature = "rayon"))]
+	{
+		let serial = decode_all(&ogg, rounds);
+		println!("{CHANNELS} channels, {num_packets} packets: {serial:9.1} ns/packet, enable the rayon feature to compare with the thread pool");
+	}
+}
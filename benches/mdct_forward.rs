@@ -0,0 +1,59 @@
+// Compare the 2048-point forward MDCT that allocates its working space on every call against the one that reuses a scratch buffer.
+// Run with `cargo bench --bench mdct_forward`, the allocations are counted by a wrapper of the system allocator.
+
+use std::{
+	alloc::{GlobalAlloc, Layout, System},
+	sync::atomic::{AtomicUsize, Ordering},
+	time::Instant,
+};
+use revorbis::*;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+		unsafe {System.alloc(layout)}
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		unsafe {System.dealloc(ptr, layout)}
+	}
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn main() {
+	let n = 2048;
+	let count = 20000;
+	let lookup = MdctLookup::new(n);
+	let input: Vec<f32> = (0..n).map(|i|(i as f32 * 0.05).sin() + (i as f32 * 0.31).cos() * 0.25).collect();
+	let mut output = vec![0.0f32; n / 2];
+	let mut scratch = Vec::new();
+	let mut timing = [f64::MAX; 2];
+	let mut allocations = [0usize; 2];
+	let mut checksum = [0.0f32; 2];
+	// Alternate the two a few rounds and keep the best time of each, so the warm-up doesn't favor either
+	for _ in 0..5 {
+		for (t, with_scratch) in [false, true].into_iter().enumerate() {
+			let allocated = ALLOCATIONS.load(Ordering::Relaxed);
+			let begin = Instant::now();
+			for _ in 0..count {
+				if with_scratch {
+					lookup.forward_with_scratch(&input, &mut output, &mut scratch);
+				} else {
+					lookup.forward(&input, &mut output);
+				}
+				checksum[t] += output[1];
+			}
+			timing[t] = timing[t].min(begin.elapsed().as_secs_f64() * 1e9 / count as f64);
+			allocations[t] = ALLOCATIONS.load(Ordering::Relaxed) - allocated;
+		}
+	}
+	assert_eq!(checksum[0], checksum[1]);
+	println!("{n}-point forward MDCT x {count}: allocating {:8.1} ns, {} allocations", timing[0], allocations[0]);
+	println!("{n}-point forward MDCT x {count}: scratch    {:8.1} ns, {} allocations", timing[1], allocations[1]);
+}
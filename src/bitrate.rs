@@ -3,21 +3,21 @@ use std::{
     cmp::{min, max},
     fmt::Debug,
     io,
-    mem,
     rc::Rc,
     cell::RefCell,
 };
 
 use crate::*;
+use ogg::{OggPacket, OggPacketType};
 use codec::VorbisInfo;
-use blocks::VorbisBlock;
+use blocks::{VorbisBlock, PacketBlobs};
 
-#[derive(Debug, Clone)]
+#[derive(Default, Debug, Clone)]
 pub struct VorbisBitrateManagerState {
     pub managed: bool,
 
-    pub avg_reservoir: usize,
-    pub minmax_reservoir: usize,
+    pub avg_reservoir: i64,
+    pub minmax_reservoir: i64,
     pub avg_bitsper: i32,
     pub min_bitsper: i32,
     pub max_bitsper: i32,
@@ -25,6 +25,9 @@ pub struct VorbisBitrateManagerState {
     pub short_per_long: i32,
     pub avgfloat: f64,
 
+    /// The initial fill of the reservoirs, `flush()` puts them back to it
+    pub desired_fill: i64,
+
     pub vorbis_block: Option<Rc<RefCell<VorbisBlock>>>,
     pub choice: i32,
 }
@@ -37,16 +40,17 @@ impl VorbisBitrateManagerState {
         if manager_info.reservoir_bits > 0 {
             let ratesamples = vorbis_info.sample_rate as f32;
             let halfsamples = (codec_setup.block_size[0] >> 1) as f32;
-            let desired_fill = (manager_info.reservoir_bits as f64 * manager_info.reservoir_bias) as usize;
+            let desired_fill = (manager_info.reservoir_bits as f64 * manager_info.reservoir_bias) as i64;
             Self {
                 managed: true,
                 short_per_long: codec_setup.block_size[1] / codec_setup.block_size[0],
-                avg_bitsper: rint!(1.0 * manager_info.avg_rate as f32 * halfsamples / ratesamples),
-                min_bitsper: rint!(1.0 * manager_info.min_rate as f32 * halfsamples / ratesamples),
-                max_bitsper: rint!(1.0 * manager_info.max_rate as f32 * halfsamples / ratesamples),
-                avgfloat: (PACKETBLOBS / 2) as f64,
+                avg_bitsper: (manager_info.avg_rate as f32 * halfsamples / ratesamples).round_ties_even() as i32,
+                min_bitsper: (manager_info.min_rate as f32 * halfsamples / ratesamples).round_ties_even() as i32,
+                max_bitsper: (manager_info.max_rate as f32 * halfsamples / ratesamples).round_ties_even() as i32,
+                avgfloat: PacketBlobs::center() as f64,
                 minmax_reservoir: desired_fill,
                 avg_reservoir: desired_fill,
+                desired_fill,
                 vorbis_block: None,
                 ..Default::default()
             }
@@ -59,25 +63,26 @@ impl VorbisBitrateManagerState {
     pub fn add_block(&mut self, block: Rc<RefCell<VorbisBlock>>) -> io::Result<()> {
         let vb = block.borrow_mut();
         let vbi = &vb.internal.as_ref().expect("The block should be in encoding mode");
-        let vd = &vb.vorbis_dsp_state;
-        let vi = &vd.vorbis_info;
+        let vi = &vb.vorbis_info;
         let ci = &vi.codec_setup;
         let bi = &ci.bitrate_manager_info;
+        let blob_bits = |choice: i32| (vbi.packetblob.get_clamped(choice).borrow().get_total_bytes() * 8) as i64;
 
-        let mut choice = rint!(self.avgfloat);
-        let mut this_bits = vbi.packetblob[choice as usize].borrow().get_total_bytes() * 8;
+        let mut choice = self.avgfloat.round_ties_even() as i32;
+        let mut this_bits = blob_bits(choice);
         let min_target_bits = if vb.W != 0 {
             self.min_bitsper * self.short_per_long
         } else {
             self.min_bitsper
-        } as usize;
+        } as i64;
         let max_target_bits = if vb.W != 0 {
             self.max_bitsper * self.short_per_long
         } else {
             self.max_bitsper
-        } as usize;
+        } as i64;
         let samples = ci.block_size[vb.W as usize] >> 1;
-        let desired_fill = (bi.reservoir_bits as f64 * bi.reservoir_bias) as usize;
+        let desired_fill = (bi.reservoir_bits as f64 * bi.reservoir_bias) as i64;
+        let reservoir_bits = bi.reservoir_bits as i64;
         if !self.managed {
             /* not a bitrate managed stream, but for API simplicity, we'll
                buffer the packet to keep the code path clean */
@@ -98,7 +103,7 @@ impl VorbisBitrateManagerState {
                 self.avg_bitsper * self.short_per_long
             } else {
                 self.avg_bitsper
-            } as usize;
+            } as i64;
 
             /* choosing a new floater:
                if we're over target, we slew down
@@ -115,34 +120,34 @@ impl VorbisBitrateManagerState {
                 while choice > 0 && this_bits > avg_target_bits &&
                     self.avg_reservoir + (this_bits - avg_target_bits) > desired_fill {
                     choice -= 1;
-                    this_bits = vbi.packetblob[choice as usize].borrow().get_total_bytes() * 8;
+                    this_bits = blob_bits(choice);
                 }
             } else if self.avg_reservoir + (this_bits - avg_target_bits) < desired_fill {
-                while choice + 1 > PACKETBLOBS as i32 && this_bits < avg_target_bits &&
+                while choice + 1 < PACKETBLOBS as i32 && this_bits < avg_target_bits &&
                     self.avg_reservoir + (this_bits - avg_target_bits) < desired_fill {
                     choice += 1;
-                    this_bits = vbi.packetblob[choice as usize].borrow().get_total_bytes() * 8;
+                    this_bits = blob_bits(choice);
                 }
             }
 
             let slewlimit = 15.0 / bi.slew_damp;
-            let slew = rint!(choice as f64 - self.avgfloat) as f64 / samples as f64 * vi.sample_rate as f64;
+            let slew = (choice as f64 - self.avgfloat).round_ties_even() / samples as f64 * vi.sample_rate as f64;
             let slew = slew.clamp(-slewlimit, slewlimit);
             self.avgfloat += slew / vi.sample_rate as f64 * samples as f64;
-            choice = rint!(self.avgfloat);
-            this_bits = vbi.packetblob[choice as usize].borrow().get_total_bytes() * 8;
+            choice = self.avgfloat.round_ties_even() as i32;
+            this_bits = blob_bits(choice);
         }
 
         // enforce min(if used) on the current floater (if used)
         if self.min_bitsper > 0 {
             // do we need to force the bitrate up?
             if this_bits < min_target_bits {
-                while self.minmax_reservoir < min_target_bits - this_bits {
+                while self.minmax_reservoir - (min_target_bits - this_bits) < 0 {
                     choice += 1;
                     if choice >= PACKETBLOBS as i32 {
                         break;
                     }
-                    this_bits = vbi.packetblob[choice as usize].borrow().get_total_bytes() * 8;
+                    this_bits = blob_bits(choice);
                 }
             }
         }
@@ -150,13 +155,13 @@ impl VorbisBitrateManagerState {
         // enforce max (if used) on the current floater (if used)
         if self.max_bitsper > 0 {
             // do we need to force the bitrate down?
-            if this_bits > min_target_bits {
-                while self.minmax_reservoir + (this_bits - max_target_bits) > bi.reservoir_bits {
+            if this_bits > max_target_bits {
+                while self.minmax_reservoir + (this_bits - max_target_bits) > reservoir_bits {
                     choice -= 1;
                     if choice < 0 {
                         break;
                     }
-                    this_bits = vbi.packetblob[choice as usize].borrow().get_total_bytes() * 8;
+                    this_bits = blob_bits(choice);
                 }
             }
         }
@@ -167,26 +172,29 @@ impl VorbisBitrateManagerState {
         if choice < 0 {
             /* choosing a smaller packetblob is insufficient to trim bitrate.
                frame will need to be truncated */
-            let maxsize = (max_target_bits + (bi.reservoir_bits - self.minmax_reservoir)) / 8;
+            let maxsize = ((max_target_bits + (reservoir_bits - self.minmax_reservoir)) / 8).max(0) as usize;
             choice = 0;
             self.choice = 0;
 
-            let mut chosen_packetblob = vbi.packetblob[choice as usize].borrow_mut();
+            let mut chosen_packetblob = vbi.packetblob.get_clamped(choice).borrow_mut();
             if chosen_packetblob.get_total_bytes() > maxsize {
                 chosen_packetblob.write_trunc(maxsize * 8)?;
-                this_bits = chosen_packetblob.get_total_bytes() * 8;
+                this_bits = (chosen_packetblob.get_total_bytes() * 8) as i64;
             }
         } else {
             let mut minsize = (min_target_bits - self.minmax_reservoir + 7) / 8;
-            choice = max(choice, PACKETBLOBS as i32 - 1);
+            choice = min(choice, PACKETBLOBS as i32 - 1);
 
             self.choice = choice;
 
             // prop up bitrate according to demand. pad this frame out with zeroes
-            let mut chosen_packetblob = vbi.packetblob[choice as usize].borrow_mut();
-            minsize -= chosen_packetblob.get_total_bytes();
-            write_slice!(chosen_packetblob, &vec![0u8; minsize]);
-            this_bits = chosen_packetblob.get_total_bytes() * 8;
+            let mut chosen_packetblob = vbi.packetblob.get_clamped(choice).borrow_mut();
+            minsize -= chosen_packetblob.get_total_bytes() as i64;
+            while minsize > 0 {
+                chosen_packetblob.write(0, 8)?;
+                minsize -= 1;
+            }
+            this_bits = (chosen_packetblob.get_total_bytes() * 8) as i64;
         }
 
         /* now we have the final packet and the final packet size.  Update statistics */
@@ -222,23 +230,100 @@ impl VorbisBitrateManagerState {
                 self.avg_bitsper * self.short_per_long
             } else {
                 self.avg_bitsper
-            } as usize;
+            } as i64;
         }
 
         Ok(())
     }
-}
 
-impl Default for VorbisBitrateManagerState {
-    fn default() -> Self {
-        use std::ptr::{write, addr_of_mut};
-        let mut ret_z = mem::MaybeUninit::<Self>::zeroed();
-        unsafe {
-            let ptr = ret_z.as_mut_ptr();
-            write(addr_of_mut!((*ptr).vorbis_block), None);
-            ret_z.assume_init()
+    /// * The fill of the average bitrate reservoir, in bits
+    pub fn avg_reservoir(&self) -> i64 {
+        self.avg_reservoir
+    }
+
+    /// * The fill of the min/max bitrate reservoir, in bits
+    pub fn minmax_reservoir(&self) -> i64 {
+        self.minmax_reservoir
+    }
+
+    /// * The packet blob the next packet is taken from, the middle one if the bitrate isn't managed
+    pub fn current_choice(&self) -> i32 {
+        if self.managed {
+            self.choice
+        } else {
+            PacketBlobs::center() as i32
+        }
+    }
+
+    /// * Copy out the rate control state for monitoring, e.g. to log the reservoirs after each `add_block()`.
+    pub fn snapshot(&self) -> ReservoirSnapshot {
+        ReservoirSnapshot {
+            managed: self.managed,
+            avg_reservoir: self.avg_reservoir,
+            minmax_reservoir: self.minmax_reservoir,
+            desired_fill: self.desired_fill,
+            avgfloat: self.avgfloat,
+            choice: self.current_choice(),
         }
     }
+
+    /// * Take the buffered block out as an Ogg packet, `None` if no block is buffered.
+    /// * The packet blob chosen by `add_block()` is used for a managed stream, otherwise the middle one.
+    pub fn take_packet(&mut self) -> Option<OggPacket> {
+        let block = self.vorbis_block.take()?;
+        let vb = block.borrow();
+        let vbi = &vb.internal.as_ref().expect("The block should be in encoding mode");
+        let choice = self.current_choice();
+        let mut ret = OggPacket::new(vb.ogg_stream_id, if vb.eofflag {
+            OggPacketType::EndOfStream
+        } else {
+            OggPacketType::Continuation
+        }, vb.sequence);
+        ret.granule_position = vb.granulepos;
+        ret.write(&vbi.packetblob.get_clamped(choice).borrow_mut().to_bytes());
+        Some(ret)
+    }
+
+    /// * Force out the last buffered block at the end of the stream, the packet is marked as the end of the stream.
+    /// * The reservoirs and the floater are put back to where they began, the manager is drained and could take another stream.
+    pub fn flush(&mut self) -> io::Result<Option<OggPacket>> {
+        if let Some(block) = &self.vorbis_block {
+            match block.try_borrow_mut() {
+                Ok(mut vb) => vb.eofflag = true,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::ResourceBusy, "The buffered block is being borrowed while flushing the bitrate manager".to_string())),
+            }
+        }
+        let ret = self.take_packet();
+        if self.managed {
+            self.avg_reservoir = self.desired_fill;
+            self.minmax_reservoir = self.desired_fill;
+            self.avgfloat = PacketBlobs::center() as f64;
+            self.choice = 0;
+        }
+        Ok(ret)
+    }
+}
+
+/// * The rate control state of a `VorbisBitrateManagerState` at a moment, from `VorbisBitrateManagerState::snapshot()`
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct ReservoirSnapshot {
+    /// * Whether the bitrate is managed, the reservoirs aren't used otherwise
+    pub managed: bool,
+
+    /// * The fill of the average bitrate reservoir, in bits
+    pub avg_reservoir: i64,
+
+    /// * The fill of the min/max bitrate reservoir, in bits
+    pub minmax_reservoir: i64,
+
+    /// * The fill the reservoirs began with
+    pub desired_fill: i64,
+
+    /// * The floating packet blob choice the average bitrate control slews
+    pub avgfloat: f64,
+
+    /// * The packet blob the next packet is taken from
+    pub choice: i32,
 }
 
 #[derive(Default, Debug, Clone, PartialEq)]
@@ -1,4 +1,5 @@
 use std::{
+    borrow::Cow,
     io::{self, Write},
     fmt::{self, Debug, Formatter},
 };
@@ -20,6 +21,9 @@ const MASK: [u32; 33] = [
     0x1fffffff, 0x3fffffff, 0x7fffffff, 0xffffffff
 ];
 
+/// * The most bits `BitReader::read()` and `BitWriter::write()` could take in one call
+pub const MAX_BITS_PER_CALL: i32 = 32;
+
 macro_rules! define_worksize_consts {
     () => {
         const BITS: usize = Unit::BITS as usize;
@@ -46,40 +50,59 @@ macro_rules! define_worksize {
     };
 }
 
-define_worksize!(8);
+define_worksize!(64);
+
+/// * The number of bits needed to store `v`, `0` for `0`
+pub const fn ilog(v: u64) -> u32 {
+    u64::BITS - v.leading_zeros()
+}
+
+/// * The number of `1` bits in `v`
+pub const fn icount(v: u64) -> u32 {
+    v.count_ones()
+}
 
+/// * The integer types that `ilog!()` could give, the result of `ilog()` always fits in them.
+pub trait ILogResult {
+    /// * Store `ilog(v)` into `self`
+    fn set_ilog(&mut self, v: u64);
+}
+
+macro_rules! impl_ilog_result {
+    ($($t:ty),+) => {
+        $(impl ILogResult for $t {
+            fn set_ilog(&mut self, v: u64) {
+                *self = ilog(v) as $t;
+            }
+        })+
+    }
+}
+
+impl_ilog_result!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+/// * `ilog()` that takes any integer and gives the integer type the context wants, the argument is evaluated once.
+/// * The result starts from an integer literal, so the type is inferred from the call site, `i32` if nothing else is wanted.
 #[macro_export]
 macro_rules! ilog {
     ($v:expr) => {
         {
             let mut ret = 0;
-            let mut v = $v as u64;
-            while v != 0 {
-                v >>= 1;
-                ret += 1;
-            }
+            $crate::ILogResult::set_ilog(&mut ret, $v as u64);
             ret
         }
     }
 }
 
+/// * `icount()` that takes any integer and gives `usize`, the argument is evaluated once.
 #[macro_export]
 macro_rules! icount {
     ($v:expr) => {
-        {
-            let mut ret = 0usize;
-            let mut v = $v as u64;
-            while v != 0 {
-                ret += (v as usize) & 1;
-                v >>= 1;
-            }
-            ret
-        }
+        $crate::icount($v as u64) as usize
     }
 }
 
 /// * BitReader: read vorbis data bit by bit
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub struct BitReader<'a> {
     /// * Currently ends at which bit in the last byte
     pub endbit: i32,
@@ -109,10 +132,10 @@ impl<'a> BitReader<'a> {
     }
 
     /// * Read data bit by bit
-    /// * bits <= 32
+    /// * bits <= `MAX_BITS_PER_CALL`
     pub fn read(&mut self, mut bits: i32) -> io::Result<i32> {
-        if !(0..=32).contains(&bits) {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid bit number: {bits}")));
+        if !(0..=MAX_BITS_PER_CALL).contains(&bits) {
+            return_Err!(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid bit number: {bits}, should be 0 to {MAX_BITS_PER_CALL}")));
         }
         let mut ret: i32;
         let m = MASK[bits as usize];
@@ -125,7 +148,7 @@ impl<'a> BitReader<'a> {
             let eof_err = || -> io::Error {
                 io::Error::new(io::ErrorKind::UnexpectedEof, format!("UnexpectedEof when trying to read {origbits} bits from the input position 0x{:x}", index))
             };
-            self.data.get(index).ok_or(eof_err()).copied()
+            self.data.get(index).ok_or_else(eof_err).copied()
         };
 
         bits += self.endbit;
@@ -153,6 +176,13 @@ impl<'a> BitReader<'a> {
         Ok(ret)
     }
 
+    /// * Read data bit by bit without moving the read position
+    /// * bits <= `MAX_BITS_PER_CALL`
+    pub fn look(&self, bits: i32) -> io::Result<i32> {
+        let mut bitreader = *self;
+        bitreader.read(bits)
+    }
+
     /// * Skip the current unfinished byte, goto the next byte
     pub fn goto_next_byte(&mut self) {
         self.total_bits += 8 - self.endbit as usize;
@@ -164,6 +194,52 @@ impl<'a> BitReader<'a> {
     pub fn has_reached_end(&self) -> bool {
         self.cursor >= self.data.len()
     }
+
+    /// * How many bits are left to read
+    pub fn remaining_bits(&self) -> usize {
+        (self.data.len().saturating_sub(self.cursor) * 8).saturating_sub(self.endbit as usize)
+    }
+
+    /// * Read `n` bytes. If the read position is at a byte boundary, the bytes are borrowed from the data directly,
+    ///   otherwise they are read bit by bit.
+    /// * Nothing is read if there aren't enough bytes.
+    pub fn read_aligned_bytes(&mut self, n: usize) -> io::Result<Cow<'a, [u8]>> {
+        if n * 8 > self.remaining_bits() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("UnexpectedEof when trying to read {n} bytes from the input position 0x{:x}", self.cursor)));
+        }
+        if self.endbit == 0 {
+            let ret = &self.data[self.cursor..self.cursor + n];
+            self.cursor += n;
+            self.total_bits += n * 8;
+            Ok(Cow::Borrowed(ret))
+        } else {
+            let mut ret = Vec::<u8>::with_capacity(n);
+            for _ in 0..n {
+                ret.push(self.read(8)? as u8);
+            }
+            Ok(Cow::Owned(ret))
+        }
+    }
+
+    /// * Read `out.len()` bytes into `out`, so the caller could reuse the buffer. If the read position is at a byte boundary, the bytes are copied directly,
+    ///   otherwise they are read bit by bit.
+    /// * Nothing is read if there aren't enough bytes.
+    pub fn read_bytes_into(&mut self, out: &mut [u8]) -> io::Result<()> {
+        let n = out.len();
+        if n * 8 > self.remaining_bits() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("UnexpectedEof when trying to read {n} bytes from the input position 0x{:x}", self.cursor)));
+        }
+        if self.endbit == 0 {
+            out.copy_from_slice(&self.data[self.cursor..self.cursor + n]);
+            self.cursor += n;
+            self.total_bits += n * 8;
+        } else {
+            for byte in out.iter_mut() {
+                *byte = self.read(8)? as u8;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// * BitWriter: write vorbis data bit by bit
@@ -213,15 +289,28 @@ where
     fn write_byte(&mut self, byte: u8) -> io::Result<()> {
         self.cache.write_all(&[byte])?;
         if self.cache.len() >= Self::CACHE_SIZE {
-            self.flush()?;
+            self.flush_finished_bytes()?;
         }
         Ok(())
     }
 
-    /// * Write data in bits, max is 32 bit.
+    /// * Write the cached bytes to the sink except the last one, the last byte receives the next bits so it's kept in the cache.
+    /// * This is what the cache does when it's full, since `endbit` may not be updated yet in the middle of a write.
+    fn flush_finished_bytes(&mut self) -> io::Result<()> {
+        let len = self.cache.len();
+        if len > 1 {
+            let last_byte = self.cache[len - 1];
+            self.writer.write_all(&self.cache[..(len - 1)])?;
+            self.cache.clear();
+            self.cache.write_all(&[last_byte])?;
+        }
+        Ok(())
+    }
+
+    /// * Write data in bits, max is `MAX_BITS_PER_CALL` bits.
     pub fn write(&mut self, mut value: u32, mut bits: i32) -> io::Result<()> {
-        if !(0..=32).contains(&bits) {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid bits {bits}")));
+        if !(0..=MAX_BITS_PER_CALL).contains(&bits) {
+            return_Err!(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid bits {bits}, should be 0 to {MAX_BITS_PER_CALL}")));
         }
         value &= MASK[bits as usize];
         let origbits = bits;
@@ -251,6 +340,9 @@ where
         Ok(())
     }
 
+    /// * Write the cached bytes to the sink. If the write position is at a byte boundary, the whole cache is written,
+    ///   including the empty byte that would receive the next bits, otherwise the unfinished byte is kept in the cache.
+    /// * Use `force_flush()` to finish the bitstream, it doesn't write the empty byte.
     pub fn flush(&mut self) -> io::Result<()> {
         if self.cache.is_empty() {
             Ok(())
@@ -259,21 +351,43 @@ where
             self.cache.clear();
             Ok(())
         } else {
-            let len = self.cache.len();
-            let last_byte = self.cache[len - 1];
-            self.writer.write_all(&self.cache[..(len - 1)])?;
-            self.cache.clear();
-            self.cache.write_all(&[last_byte])?;
-            Ok(())
+            self.flush_finished_bytes()
         }
     }
 
+    /// * Write all of the cached bits to the sink, the unfinished byte is padded with zero bits.
     pub fn force_flush(&mut self) -> io::Result<()> {
-        self.writer.write_all(&self.cache[..])?;
-        self.cache.clear();
         if self.endbit != 0 {
+            self.writer.write_all(&self.cache[..])?;
             self.total_bits += 8 - self.endbit as usize;
             self.endbit = 0;
+        } else if !self.cache.is_empty() {
+            // No bits were written into the last byte yet
+            self.writer.write_all(&self.cache[..(self.cache.len() - 1)])?;
+        }
+        self.cache.clear();
+        Ok(())
+    }
+
+    /// * Write bytes. If the write position is at a byte boundary, the bytes go into the cache directly,
+    ///   otherwise they are written bit by bit.
+    pub fn write_aligned_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.endbit == 0 {
+            // Replace the empty last byte with the bytes, then leave an empty byte for the next bits
+            let len = self.cache.len();
+            if len > 0 {
+                self.cache.set_len(len - 1);
+            }
+            self.cache.write_all(bytes)?;
+            self.cache.write_all(&[0u8])?;
+            self.total_bits += bytes.len() * 8;
+            if self.cache.len() >= Self::CACHE_SIZE {
+                self.flush_finished_bytes()?;
+            }
+        } else {
+            for &byte in bytes.iter() {
+                self.write(byte as u32, 8)?;
+            }
         }
         Ok(())
     }
@@ -309,6 +423,22 @@ impl BitWriterCursor {
     }
 }
 
+/// * The specialized `BitWriter` that appends to a borrowed `Vec<u8>`, for assembling several parts into one buffer without moving the bytes around.
+pub type BitWriterVec<'a> = BitWriter<&'a mut Vec<u8>>;
+
+impl<'a> BitWriterVec<'a> {
+    /// * Write to the end of `buffer`, the bytes already in it are kept.
+    /// * The bits are cached, call `finish()` to write all of them to the buffer.
+    pub fn append_to(buffer: &'a mut Vec<u8>) -> Self {
+        Self::new(buffer)
+    }
+
+    /// * Write all of the cached bits to the buffer and release it, the unfinished byte is padded with zero bits.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.force_flush()
+    }
+}
+
 pub trait BitWriterSeekable {
     fn set_len(&mut self, len: u64) -> io::Result<()>;
     fn write_trunc(&mut self, bits: usize) -> io::Result<()>;
@@ -373,8 +503,9 @@ macro_rules! f32_pack_non_ieee {
                 sign = 0;
             }
             let exp = (val.log2() + 0.001).floor() as i32; /* +epsilon */
-            let mant = rint!(ldexpf(val, VQ_FMAN - 1 - exp));
-            let exp = (exp + VQ_FEXP_BIAS) << VQ_FMAN;
+            /* zero, infinity and the values out of the exponent range wrap like the C code instead of panicking */
+            let mant = rint!(ldexpf(val, (VQ_FMAN - 1).wrapping_sub(exp)));
+            let exp = exp.wrapping_add(VQ_FEXP_BIAS) << VQ_FMAN;
             sign | exp | mant
         }
     };
@@ -447,12 +578,10 @@ macro_rules! write_f32_non_ieee {
 #[macro_export]
 macro_rules! read_slice {
     ($bitreader:ident, $length:expr) => {
-        {
-            let mut ret = Vec::<u8>::with_capacity($length);
-            for _ in 0..$length {
-                ret.push(read_bits!($bitreader, 8) as u8);
-            }
-            ret
+        if DEBUG_ON_READ_BITS {
+            $bitreader.read_aligned_bytes($length).unwrap().into_owned()
+        } else {
+            $bitreader.read_aligned_bytes($length)?.into_owned()
         }
     };
 }
@@ -487,11 +616,23 @@ macro_rules! write_slice {
     };
 }
 
+/// * Write a byte array to the `BitWriter`, byte-aligned data goes into the cache directly
+#[macro_export]
+macro_rules! write_bytes {
+    ($bitwriter:ident, $data:expr) => {
+        if DEBUG_ON_WRITE_BITS {
+            $bitwriter.write_aligned_bytes($data).unwrap()
+        } else {
+            $bitwriter.write_aligned_bytes($data)?
+        }
+    };
+}
+
 /// * Write a sized string to the `BitWriter`
 #[macro_export]
 macro_rules! write_string {
     ($bitwriter:ident, $string:expr) => {
-        write_slice!($bitwriter, $string.as_bytes());
+        write_bytes!($bitwriter, $string.as_bytes());
     };
 }
 
@@ -505,26 +646,45 @@ pub fn align(size: usize, alignment: usize) -> usize {
 }
 
 /// * Transmute vector, change its type, but not by cloning it or changing its memory location or capacity.
-/// * Will panic or crash if you don't know what you are doing.
+/// * Will panic or crash if you don't know what you are doing, see `try_transmute_vector()` for the preconditions.
 pub fn transmute_vector<S, D>(vector: Vec<S>) -> Vec<D>
 where
     S: Sized,
     D: Sized {
 
-    use std::{any::type_name, mem::{size_of, ManuallyDrop}};
+    use std::{any::type_name, mem::size_of};
+    let size_in_bytes = size_of::<S>() * vector.len();
+    match try_transmute_vector(vector) {
+        Ok(ret) => ret,
+        Err(_) => panic!("Could not transmute from Vec<{}> to Vec<{}>: the number of bytes {size_in_bytes} is not divisible to {}, or the alignments differ.", type_name::<S>(), type_name::<D>(), size_of::<D>()),
+    }
+}
+
+/// * Transmute vector, change its type, but not by cloning it or changing its memory location or capacity.
+/// * The original vector is given back if it can't be transmuted. The preconditions are:
+///   1. `S` and `D` have the same alignment, the allocation is freed with the layout of `D` later.
+///   2. Both of the length and the capacity in bytes are divisible by the size of `D`.
+/// * The bytes are reinterpreted as they are in the memory, so the result depends on the endianness of the machine,
+///   and every bit pattern of the bytes must be a valid `D`.
+pub fn try_transmute_vector<S, D>(vector: Vec<S>) -> Result<Vec<D>, Vec<S>>
+where
+    S: Sized,
+    D: Sized {
+
+    use std::mem::{size_of, align_of, ManuallyDrop};
     let s_size = size_of::<S>();
     let d_size = size_of::<D>();
-    let s_name = type_name::<S>();
-    let d_name = type_name::<D>();
+    if s_size == 0 || d_size == 0 || align_of::<S>() != align_of::<D>() {
+        return Err(vector);
+    }
     let size_in_bytes = s_size * vector.len();
-    let remain_size = size_in_bytes % d_size;
-    if remain_size != 0 {
-        panic!("Could not transmute from Vec<{s_name}> to Vec<{d_name}>: the number of bytes {size_in_bytes} is not divisible to {d_size}.")
-    } else {
-        let mut s = ManuallyDrop::new(vector);
-        unsafe {
-            Vec::<D>::from_raw_parts(s.as_mut_ptr() as *mut D, size_in_bytes / d_size, s.capacity() * s_size / d_size)
-        }
+    let capacity_in_bytes = s_size * vector.capacity();
+    if !size_in_bytes.is_multiple_of(d_size) || !capacity_in_bytes.is_multiple_of(d_size) {
+        return Err(vector);
+    }
+    let mut s = ManuallyDrop::new(vector);
+    unsafe {
+        Ok(Vec::<D>::from_raw_parts(s.as_mut_ptr() as *mut D, size_in_bytes / d_size, capacity_in_bytes / d_size))
     }
 }
 
@@ -544,24 +704,17 @@ pub fn shift_data_to_front(data: &[u8], bits: usize, total_bits: usize) -> Vec<u
         if bits == 0 {
             data
         } else {
+            // Work on `Unit`s in little endian in place, the lower bits of the `Unit` are the front bits,
+            // the front bits of the next byte fill the back of the `Unit`. The next byte isn't shifted yet when it's read.
             data.resize(align(data.len(), ALIGN), 0);
-            let mut to_shift: Vec<Unit> = transmute_vector(data);
-
-            fn combine_bits(data1: Unit, data2: Unit, bits: usize) -> Unit {
-                let move_high = BITS - bits;
-                (data1 >> bits) | (data2 << move_high)
-            }
-
-            for i in 0..(to_shift.len() - 1) {
-                to_shift[i] = combine_bits(to_shift[i], to_shift[i + 1], bits);
+            for offset in (0..data.len()).step_by(ALIGN) {
+                let unit = Unit::from_le_bytes(data[offset..offset + ALIGN].try_into().unwrap());
+                let next = data.get(offset + ALIGN).copied().unwrap_or(0) as Unit;
+                let shifted = (unit >> bits) | (next << (BITS - bits));
+                data[offset..offset + ALIGN].copy_from_slice(&shifted.to_le_bytes());
             }
-
-            let last = to_shift.pop().unwrap() >> bits;
-            to_shift.push(last);
-
-            let mut ret = transmute_vector(to_shift);
-            ret.truncate(align(shifted_total_bits, 8) / 8);
-            ret
+            data.truncate(align(shifted_total_bits, 8) / 8);
+            data
         }
     }
 }
@@ -590,7 +743,8 @@ pub fn shift_data_to_back(data: &[u8], bits: usize, total_bits: usize) -> Vec<u8
 
 /// * A utility for you to manipulate data bitwise, mainly to concatenate data in bits or to split data from a specific bit position.
 /// * This is mainly used for Vorbis data parsing.
-#[derive(Default, Clone, PartialEq, Eq)]
+/// * The `data` could be longer than the bits need, e.g. after `fit_to_aligned_size()`. Two `BitwiseData` are equal if their bits are the same.
+#[derive(Default, Clone)]
 pub struct BitwiseData {
     /// * Store as bytes
     pub data: Vec<u8>,
@@ -701,6 +855,96 @@ impl BitwiseData {
         self.total_bits += rhs.total_bits;
     }
 
+    /// * Append a single bit to the bitstream, at the same LSb-first position as `BitWriter` writes it.
+    /// * The data isn't trimmed, the padding bytes from `fit_to_aligned_size()` receive the bits.
+    pub fn push_bit(&mut self, bit: bool) {
+        let index = self.total_bits >> 3;
+        if index >= self.data.len() {
+            self.data.push(0);
+        }
+        if bit {
+            self.data[index] |= 1 << (self.total_bits & 7);
+        }
+        self.total_bits += 1;
+    }
+
+    /// * Append the lowest `bits` bits of `value` to the bitstream, LSb first. At most `MAX_BITS_PER_CALL` bits, nothing is appended otherwise.
+    pub fn push_bits(&mut self, value: u32, bits: u32) -> io::Result<()> {
+        if bits > MAX_BITS_PER_CALL as u32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid bits {bits}, should be 0 to {MAX_BITS_PER_CALL}")));
+        }
+        for i in 0..bits {
+            self.push_bit((value >> i) & 1 != 0);
+        }
+        Ok(())
+    }
+
+    /// * Find the position of the first bit that differs, `None` if the two bitstreams are identical.
+    /// * If one of them is the prefix of the other, the position is the length of the shorter one.
+    pub fn first_difference(&self, other: &Self) -> Option<usize> {
+        let common_bits = self.total_bits.min(other.total_bits);
+        let byte_at = |data: &[u8], i: usize| data.get(i).copied().unwrap_or(0);
+        for i in 0..BitwiseData::calc_total_bytes(common_bits) {
+            let mut diff = byte_at(&self.data, i) ^ byte_at(&other.data, i);
+            let bits_left = common_bits - i * 8;
+            if bits_left < 8 {
+                diff &= MASK8[bits_left];
+            }
+            if diff != 0 {
+                return Some(i * 8 + diff.trailing_zeros() as usize);
+            }
+        }
+        if self.total_bits != other.total_bits {
+            Some(common_bits)
+        } else {
+            None
+        }
+    }
+
+    /// * Remove `bits` bits from the front in place, the allocation is reused.
+    pub fn shift_front(&mut self, bits: usize) {
+        if bits == 0 {
+            return;
+        }
+        if bits >= self.total_bits {
+            self.data.clear();
+            self.total_bits = 0;
+            return;
+        }
+        self.data.drain(..(bits >> 3));
+        let bits_in_byte = bits & 7;
+        if bits_in_byte != 0 {
+            let move_high = 8 - bits_in_byte;
+            for i in 0..self.data.len() - 1 {
+                self.data[i] = (self.data[i] >> bits_in_byte) | (self.data[i + 1] << move_high);
+            }
+            if let Some(last) = self.data.last_mut() {
+                *last >>= bits_in_byte;
+            }
+        }
+        self.total_bits -= bits;
+    }
+
+    /// * Insert `bits` zero bits to the front in place, the allocation is reused if the capacity is enough.
+    pub fn shift_back(&mut self, bits: usize) {
+        if bits == 0 {
+            return;
+        }
+        let bits_in_byte = bits & 7;
+        if bits_in_byte != 0 {
+            let move_low = 8 - bits_in_byte;
+            if self.data.len() < Self::calc_total_bytes(self.total_bits + bits_in_byte) {
+                self.data.push(0);
+            }
+            for i in (1..self.data.len()).rev() {
+                self.data[i] = (self.data[i] << bits_in_byte) | (self.data[i - 1] >> move_low);
+            }
+            self.data[0] <<= bits_in_byte;
+        }
+        self.data.splice(0..0, std::iter::repeat_n(0u8, bits >> 3));
+        self.total_bits += bits;
+    }
+
     /// * Turn to byte array
     pub fn into_bytes(mut self) -> Vec<u8> {
         self.shrink_to_fit();
@@ -708,6 +952,14 @@ impl BitwiseData {
     }
 }
 
+impl PartialEq for BitwiseData {
+    fn eq(&self, other: &Self) -> bool {
+        self.first_difference(other).is_none()
+    }
+}
+
+impl Eq for BitwiseData {}
+
 impl Debug for BitwiseData {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("BitwiseData")
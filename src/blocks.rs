@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 #![allow(private_interfaces)]
 use std::{
+    io,
     fmt::{self, Debug, Formatter},
     rc::Rc,
     cell::RefCell,
@@ -12,15 +13,58 @@ pub const BLOCKTYPE_TRANSITION : i32 = 0;
 pub const BLOCKTYPE_LONG       : i32 = 1;
 
 use crate::*;
-use codec::VorbisDspState;
+use codec::{VorbisDspState, VorbisDspStatePrivate};
 use bitwise::BitWriterCursor;
 
+/// * A packet blob is the same packet encoded at one of the bitrate levels
+pub type PacketBlob = Rc<RefCell<BitWriterCursor>>;
+
+/// * The packet blobs of a block, from the smallest to the biggest. The bitrate manager chooses one of them to output.
+#[derive(Default, Debug, Clone)]
+pub struct PacketBlobs([PacketBlob; PACKETBLOBS]);
+
+impl PacketBlobs {
+    /// * The index of the middle blob, it shares the buffer with `VorbisBlock::ogg_pack_buffer`.
+    /// * Without bitrate management, this is the one to output.
+    pub const fn center() -> usize {
+        PACKETBLOBS / 2
+    }
+
+    /// * Create the blobs, the middle one is `center_blob`
+    pub fn new(center_blob: PacketBlob) -> Self {
+        Self(std::array::from_fn(|i| {
+            if i == Self::center() {
+                center_blob.clone()
+            } else {
+                Rc::default()
+            }
+        }))
+    }
+
+    /// * Get the blob at `idx`, `None` if out of range.
+    pub fn get(&self, idx: usize) -> Option<&PacketBlob> {
+        self.0.get(idx)
+    }
+
+    /// * Get the blob of the bitrate manager's choice, the choice is clamped into the range of the blobs.
+    pub fn get_clamped(&self, choice: i32) -> &PacketBlob {
+        &self.0[choice.clamp(0, PACKETBLOBS as i32 - 1) as usize]
+    }
+
+    /// * Iterate through the blobs, from the smallest to the biggest.
+    pub fn iter(&self) -> std::slice::Iter<'_, PacketBlob> {
+        self.0.iter()
+    }
+}
+
+derive_index!(PacketBlobs, PacketBlob, 0);
+
 #[derive(Default, Debug, Clone)]
 pub struct VorbisBlockInternal {
     pub pcmdelay: Vec<Vec<f32>>,
     pub ampmax: f32,
     pub blocktype: i32,
-    pub packetblob: [Rc<RefCell<BitWriterCursor>>; PACKETBLOBS],
+    pub packetblob: PacketBlobs,
 }
 
 /// Necessary stream state for linking to the framing abstraction
@@ -43,7 +87,10 @@ pub struct VorbisBlock {
     pub ogg_stream_id: u32,
 
     /// For read-only access of configuration
-    pub vorbis_dsp_state: Rc<VorbisDspState>,
+    pub vorbis_info: Rc<VorbisInfo>,
+
+    /// * Shared with the `VorbisDspState` the block was carved out from, the global psychoacoustics state carries across the blocks through it
+    pub backend_state: Rc<RefCell<VorbisDspStatePrivate>>,
 
     pub glue_bits: i32,
     pub time_bits: i32,
@@ -54,11 +101,12 @@ pub struct VorbisBlock {
 }
 
 impl VorbisBlock {
-    pub fn new(vorbis_dsp_state: Rc<VorbisDspState>, ogg_stream_id: u32) -> Self {
+    pub fn new(vorbis_dsp_state: &VorbisDspState, ogg_stream_id: u32) -> Self {
         let mut ret = Self {
             ogg_pack_buffer: Rc::default(),
             ogg_stream_id,
-            vorbis_dsp_state: vorbis_dsp_state.clone(),
+            vorbis_info: vorbis_dsp_state.vorbis_info.clone(),
+            backend_state: vorbis_dsp_state.backend_state.clone(),
             internal: None,
             ..Default::default()
         };
@@ -67,18 +115,38 @@ impl VorbisBlock {
                 pcmdelay: Vec::new(),
                 ampmax: -9999.0,
                 blocktype: 0,
-                packetblob: std::array::from_fn(|i|{
-                    if i == PACKETBLOBS / 2 {
-                        ret.ogg_pack_buffer.clone()
-                    } else {
-                        Rc::default()
-                    }
-                }),
+                packetblob: PacketBlobs::new(ret.ogg_pack_buffer.clone()),
             })
         }
 
         ret
     }
+
+    /// * How many samples the granule position advances by this packet, the distance between the centers of the previous block and this block.
+    /// * The first audio packet of a stream only primes the overlap-add and finishes no samples, its granule doesn't advance.
+    ///   It can't be told from the block itself, so the muxer should skip the advance for the first packet.
+    pub fn granule_advance(&self) -> u64 {
+        let block_size = &self.vorbis_info.codec_setup.block_size;
+        (block_size[self.lW] / 4 + block_size[self.W] / 4) as u64
+    }
+
+    /// * Analyze the block carved out by `VorbisDspState::analysis_blockout()` and encode it into the packet blobs, this is `vorbis_analysis()` of `libvorbis`.
+    /// * Pass the block to `VorbisDspState::bitrate_addblock()` afterwards to get the packet out.
+    pub fn analysis(&mut self) -> io::Result<()> {
+        let Some(vbi) = self.internal.as_ref() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The block isn't in encoding mode".to_string()));
+        };
+        for blob in vbi.packetblob.iter() {
+            *blob.borrow_mut() = BitWriterCursor::default();
+        }
+        let vi = self.vorbis_info.clone();
+        let ci = &vi.codec_setup;
+        let Some(mode) = ci.modes.get(self.mode as usize) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid mode number {}, there are {} modes", self.mode, ci.modes.len())));
+        };
+        let mapping = mode.mapping_ref_in(&ci.maps)?.clone();
+        mapping.forward(self)
+    }
 }
 
 impl Debug for VorbisBlock {
@@ -95,7 +163,8 @@ impl Debug for VorbisBlock {
         .field("granulepos", &self.granulepos)
         .field("sequence", &self.sequence)
         .field("ogg_stream_id", &self.ogg_stream_id)
-        .field("vorbis_dsp_state", &self.vorbis_dsp_state)
+        .field("vorbis_info", &self.vorbis_info)
+        .field("backend_state", &self.backend_state)
         .field("glue_bits", &self.glue_bits)
         .field("time_bits", &self.time_bits)
         .field("floor_bits", &self.floor_bits)
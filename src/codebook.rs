@@ -36,7 +36,7 @@ fn make_words(lengthlist: &[i8], n: i32, sparsecount: i32) -> io::Result<Vec<u32
             /* update ourself */
             if length < 32 && (entry >> length) != 0 {
                 /* error condition; the lengths must specify an overpopulated tree */
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("The lengths must specify an overpopulated tree. Length: {length}")));
+                return Err(VorbisError::OverpopulatedTree.into());
             }
 
             ret[count] = entry;
@@ -77,11 +77,16 @@ fn make_words(lengthlist: &[i8], n: i32, sparsecount: i32) -> io::Result<Vec<u32
     /* any underpopulated tree must be rejected. */
     /* Single-entry codebooks are a retconned extension to the spec.
        They have a single codeword '0' of length 1 that results in an
-       underpopulated tree. Shield that case from the underformed tree check. */
-    if !(count == 1 && marker[2] == 2) {
+       underpopulated tree. Shield that case from the underformed tree check.
+       libvorbis tests `count == 1` here, but without `sparsecount` (the encoder) `count` also
+       counts the unused entries, so its encoder rejects a book like [0, 1, 0] that its decoder
+       accepts. Counting the used entries gives both the same answer. `count` is never less
+       than the used entries, so every book libvorbis accepts is still accepted. */
+    let used_entries = lengthlist[..n].iter().filter(|&&length|length > 0).count();
+    if !(used_entries == 1 && marker[2] == 2) {
         for i in 1..33 {
             if (marker[i] & (0xffffffff >> (32 - i))) != 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Underpopulated tree. `marker[i]`: {}", marker[i])));
+                return Err(VorbisError::UnderpopulatedTree.into());
             }
         }
     }
@@ -116,6 +121,7 @@ fn make_words(lengthlist: &[i8], n: i32, sparsecount: i32) -> io::Result<Vec<u32
 /// * Vorbis data are all stored in bitwise form, almost anything is not byte-aligned. Split data in byte arrays just won't work on Vorbis data.
 /// * We have to do it in a bitwise way.
 #[derive(Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StaticCodeBook {
     pub dim: i32,
     pub entries: i32,
@@ -128,6 +134,28 @@ pub struct StaticCodeBook {
     pub quantlist: Vec<i32>,
 }
 
+/// * The codeword statistics of a `StaticCodeBook`, for comparing the cost of different books.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CodeBookStats {
+    /// * The shortest codeword length of the used entries
+    pub min_length: i32,
+
+    /// * The longest codeword length of the used entries
+    pub max_length: i32,
+
+    /// * The average codeword length of the used entries
+    pub mean_length: f64,
+
+    /// * How many entries have a codeword
+    pub used_entries: usize,
+
+    /// * How many entries have no codeword
+    pub unused_entries: usize,
+
+    /// * How many bits `pack()` uses to store the codeword lengths, including the ordering and the sparseness flags
+    pub length_spec_bits: usize,
+}
+
 impl Debug for StaticCodeBook {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("StaticCodeBook")
@@ -145,22 +173,122 @@ impl Debug for StaticCodeBook {
 }
 
 impl StaticCodeBook {
+    /// * Create a codebook without value mapping from the codeword lengths, `0` means the entry is unused.
+    /// * The lengths are checked the same way `make_words()` does, so the codebook can be packed and loaded back.
+    pub fn from_lengths(dim: i32, lengthlist: Vec<i8>) -> io::Result<Self> {
+        if !(1..=0xFFFF).contains(&dim) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Bad codebook dimensions {dim}")));
+        }
+        if lengthlist.is_empty() || lengthlist.len() > 0xFFFFFF {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Bad number of codebook entries {}", lengthlist.len())));
+        }
+        if lengthlist.iter().all(|&length|length == 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "All of the codebook entries are unused.".to_string()));
+        }
+        let ret = Self {
+            dim,
+            entries: lengthlist.len() as i32,
+            lengthlist,
+            maptype: 0,
+            ..Default::default()
+        };
+        ret.validate()?;
+        Ok(ret)
+    }
+
+    /// * Check if the codebook is well-formed before packing it or creating a `CodeBook` from it.
+    /// * The codeword tree is walked the same way as `make_words()`, an overpopulated or underpopulated tree is an error.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.dim < 0 || self.entries < 0 || ilog!(self.dim) + ilog!(self.entries) > 24 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad dimensions {} or entries {} of the codebook: {} + {} > 24", self.dim, self.entries, ilog!(self.dim), ilog!(self.entries))));
+        }
+        if self.lengthlist.len() != self.entries as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The codebook has {} entries, but {} codeword lengths", self.entries, self.lengthlist.len())));
+        }
+        if let Some(length) = self.lengthlist.iter().find(|&&length|!(0..=32).contains(&length)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad codeword length {length}")));
+        }
+        match self.maptype {
+            0 => (),
+            1 | 2 => {
+                if !(1..=16).contains(&self.q_quant) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad bits of the quantized values {}", self.q_quant)));
+                }
+                let quantvals = match self.maptype {
+                    1 => if self.dim == 0 {0} else {self.book_maptype1_quantvals() as usize},
+                    2 => self.entries as usize * self.dim as usize,
+                    _ => unreachable!(),
+                };
+                if self.quantlist.len() != quantvals {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The codebook of maptype {} needs {quantvals} quantized values, got {}", self.maptype, self.quantlist.len())));
+                }
+            }
+            o => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected maptype {o}"))),
+        }
+        make_words(&self.lengthlist, self.entries, 0)?;
+        Ok(())
+    }
+
+    /// * Check if the codebook is the retconned single-entry form: one used entry with the single codeword '0' of length 1.
+    /// * Its tree is underpopulated, `make_words()` lets it pass and `CodeBook::new_for_decode()` gives it a trivial first table.
+    pub fn is_single_entry(&self) -> bool {
+        let mut used = self.lengthlist.iter().filter(|&&length|length > 0);
+        used.next() == Some(&1) && used.next().is_none()
+    }
+
+    /// * Summarize the codeword lengths without packing the book.
+    pub fn bit_stats(&self) -> CodeBookStats {
+        let entries = (self.entries.max(0) as usize).min(self.lengthlist.len());
+        let lengthlist = &self.lengthlist[..entries];
+        let used: Vec<i32> = lengthlist.iter().filter(|&&length|length > 0).map(|&length|length as i32).collect();
+        let mean_length = if used.is_empty() {0.0} else {used.iter().sum::<i32>() as f64 / used.len() as f64};
+
+        // The same decision between the length ordered and the length random packing as `pack()`
+        let ordered = !lengthlist.is_empty() && lengthlist.windows(2).all(|w|w[0] != 0 && w[1] >= w[0]);
+        let length_spec_bits = if ordered {
+            let mut bits = 1 + 5;
+            let mut count = 0usize;
+            for i in 1..entries {
+                let (this, last) = (lengthlist[i], lengthlist[i - 1]);
+                for _ in last..this {
+                    bits += ilog!(entries - count) as usize;
+                    count = i;
+                }
+            }
+            bits + ilog!(entries - count) as usize
+        } else if used.len() == entries {
+            1 + 1 + 5 * entries
+        } else {
+            1 + 1 + entries + 5 * used.len()
+        };
+
+        CodeBookStats {
+            min_length: used.iter().copied().min().unwrap_or(0),
+            max_length: used.iter().copied().max().unwrap_or(0),
+            mean_length,
+            used_entries: used.len(),
+            unused_entries: entries - used.len(),
+            length_spec_bits,
+        }
+    }
+
     /// unpacks a codebook from the packet buffer into the codebook struct,
     /// readies the codebook auxiliary structures for decode
-    pub fn load(bitreader: &mut BitReader) -> io::Result<Self> {
+    pub fn load(bitreader: &mut BitReader) -> Result<Self, VorbisError> {
         let mut ret = Self::default();
 
         /* make sure alignment is correct */
         if read_bits!(bitreader, 24) != 0x564342 {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, "Check the `BCV` flag failed.".to_string()));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Check the `BCV` flag failed.".to_string()).into());
         }
 
         /* first the basic parameters */
         ret.dim = read_bits!(bitreader, 16);
         ret.entries = read_bits!(bitreader, 24);
         if ilog!(ret.dim) + ilog!(ret.entries) > 24 {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("{} + {} > 24", ilog!(ret.dim), ilog!(ret.entries))));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} + {} > 24", ilog!(ret.dim), ilog!(ret.entries))).into());
         }
+        let entries = ret.entries as usize;
 
         /* codeword ordering.... length ordered or unordered? */
         match read_bits!(bitreader, 1) {
@@ -168,6 +296,12 @@ impl StaticCodeBook {
                 /* allocated but unused entries? */
                 let unused = read_bits!(bitreader, 1) != 0;
 
+                /* every entry takes at least 1 bit for the flag or 5 bits for the length, don't allocate for the data that isn't there */
+                let least_bits = if unused {entries} else {entries * 5};
+                if least_bits > bitreader.remaining_bits() {
+                    return Err(VorbisError::UnexpectedEof);
+                }
+
                 /* unordered */
                 ret.lengthlist.resize(ret.entries as usize, 0);
 
@@ -191,21 +325,23 @@ impl StaticCodeBook {
             }
             1 => { /* ordered */
                 let mut length = read_bits!(bitreader, 5).wrapping_add(1) as i8;
-                ret.lengthlist.resize(ret.entries as usize, 0);
+
+                /* the first count takes ilog(entries) bits, and the lengths are only allocated for the counts that were read */
+                if ilog!(ret.entries) as usize > bitreader.remaining_bits() {
+                    return Err(VorbisError::UnexpectedEof);
+                }
                 let mut i = 0;
                 while i < ret.entries {
                     let num = read_bits!(bitreader, ilog!(ret.entries - i));
                     if length > 32 || num > ret.entries - i || (num > 0 && (num - 1) >> (length - 1) > 1) {
-                        return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("length({length}) > 32 || num({num}) > entries({}) - i({i}) || (num({num}) > 0 && (num({num}) - 1) >> (length({length}) - 1) > 1)", ret.entries)));
-                    }
-                    for _ in 0..num {
-                        ret.lengthlist[i as usize] = length;
-                        i += 1;
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("length({length}) > 32 || num({num}) > entries({}) - i({i}) || (num({num}) > 0 && (num({num}) - 1) >> (length({length}) - 1) > 1)", ret.entries)).into());
                     }
+                    ret.lengthlist.resize((i + num) as usize, length);
+                    i += num;
                     length += 1;
                 }
             }
-            o => return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected codeword ordering {o}"))),
+            o => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected codeword ordering {o}")).into()),
         }
 
         /* Do we have a mapping to unpack? */
@@ -226,13 +362,16 @@ impl StaticCodeBook {
                     _ => unreachable!(),
                 };
 
-                /* quantized values */
+                /* quantized values, the bits of them must be there before allocating */
+                if quantvals * ret.q_quant as usize > bitreader.remaining_bits() {
+                    return Err(VorbisError::UnexpectedEof);
+                }
                 ret.quantlist.resize(quantvals, 0);
                 for i in 0..quantvals {
                     ret.quantlist[i] = read_bits!(bitreader, ret.q_quant);
                 }
             }
-            o => return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected maptype {o}"))),
+            o => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected maptype {o}")).into()),
         }
         Ok(ret)
     }
@@ -340,8 +479,33 @@ impl StaticCodeBook {
         }
     }
 
+    /// * Iterate through the unquantized value vector of each entry, unused entries are included.
+    /// * The values are the same as `book_unquantize()` gives, the codebook must have a value mapping.
+    pub fn value_vectors(&self) -> io::Result<impl Iterator<Item = Vec<f32>>> {
+        if self.dim < 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad codebook dimensions {}", self.dim)));
+        }
+        let Some(values) = self.book_unquantize(self.entries as usize, None)? else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The codebook of maptype {} has no value mapping.", self.maptype)));
+        };
+        let dim = self.dim as usize;
+        Ok((0..self.entries as usize).map(move |i|values[i * dim..(i + 1) * dim].to_vec()))
+    }
+
+    /// * The number of bits `pack()` writes, counted without packing
+    pub fn packed_bits(&self) -> usize {
+        let entries = self.entries as usize;
+        let bits = 24 + 16 + 24 + self.bit_stats().length_spec_bits + 4;
+        let quantvals = match self.maptype {
+            1 => self.book_maptype1_quantvals() as usize,
+            2 => entries * self.dim as usize,
+            _ => return bits,
+        };
+        bits + 32 + 32 + 4 + 1 + quantvals * self.q_quant as usize
+    }
+
     /// * Pack the book into the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
@@ -425,8 +589,13 @@ impl StaticCodeBook {
         match self.maptype {
             0 => (),
             1 | 2 => {
-                if self.quantlist.is_empty() {
-                    return_Err!(io::Error::new(io::ErrorKind::InvalidData, "Missing quantlist data".to_string()));
+                let quantvals = match self.maptype {
+                    1 => self.book_maptype1_quantvals() as usize,
+                    2 => self.entries as usize * self.dim as usize,
+                    _ => unreachable!(),
+                };
+                if self.quantlist.is_empty() || self.quantlist.len() < quantvals {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Missing quantlist data, {} of {quantvals} values", self.quantlist.len())).into());
                 }
 
                 write_f32_non_ieee!(bitwriter, self.q_min);
@@ -434,17 +603,11 @@ impl StaticCodeBook {
                 write_bits!(bitwriter, self.q_quant.wrapping_sub(1), 4);
                 write_bits!(bitwriter, self.q_sequencep, 1);
 
-                let quantvals = match self.maptype {
-                    1 => self.book_maptype1_quantvals() as usize,
-                    2 => self.entries as usize * self.dim as usize,
-                    _ => unreachable!(),
-                };
-
                 for i in 0..quantvals {
                     write_bits!(bitwriter, self.quantlist[i].unsigned_abs(), self.q_quant);
                 }
             }
-            o => return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected maptype {o}"))),
+            o => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected maptype {o}")).into()),
         }
 
         Ok(bitwriter.total_bits - begin_bits)
@@ -505,6 +668,7 @@ impl CodeBook {
             used_entries: src.entries,
             static_codebook: Some(src.clone()),
             code_list: make_words(&src.lengthlist, src.entries, 0)?,
+            value_list: src.book_unquantize(src.entries as usize, None)?,
             quantvals: src.book_maptype1_quantvals(),
             minval: src.q_min,
             delta: src.q_delta,
@@ -516,12 +680,12 @@ impl CodeBook {
     pub fn new_for_decode(src: &StaticCodeBook) -> io::Result<Self> {
         /* count actually used entries and find max length */
         let mut n = 0usize;
-        let used_entries = src.entries;
         for i in 0..src.entries as usize {
             if src.lengthlist[i] > 0 {
                 n += 1;
             }
         }
+        let used_entries = n as i32;
 
         if n == 0 {
             Ok(Self {
@@ -589,7 +753,7 @@ impl CodeBook {
 
             let mut dec_firsttablen;
             let mut dec_firsttable;
-            if n == 1 && dec_maxlength == 1 {
+            if src.is_single_entry() {
                 dec_firsttablen = 1;
                 dec_firsttable = vec![1, 1];
             } else {
@@ -611,10 +775,10 @@ impl CodeBook {
                 let mask = 0xFFFFFFFEu32 << (31 - dec_firsttablen);
                 let mut lo = 0;
                 let mut hi = 0;
-                for _ in 0..tabn {
-                    let word = (1 << (32 - dec_firsttablen)) as u32;
+                for i in 0..tabn {
+                    let word = (i as u32) << (32 - dec_firsttablen);
                     if dec_firsttable[bitreverse(word) as usize] == 0 {
-                        while lo + 1 < n && code_list[lo + 1] < word {
+                        while lo + 1 < n && code_list[lo + 1] <= word {
                             lo += 1;
                         }
                         while hi < n && word >= (code_list[hi] & mask) {
@@ -645,6 +809,225 @@ impl CodeBook {
     }
 }
 
+impl CodeBook {
+    /// * The ratio of the used entries to all of the entries, `1.0` means every entry has a codeword.
+    pub fn sparsity(&self) -> f32 {
+        if self.entries == 0 {
+            0.0
+        } else {
+            self.used_entries as f32 / self.entries as f32
+        }
+    }
+
+    /// * The codeword length of each entry, 0 for the unused entries, recovered from the decode tables if the book isn't created for encoding.
+    fn entry_lengths(&self) -> Vec<i8> {
+        let entries = self.entries.max(0) as usize;
+        if let Some(static_codebook) = &self.static_codebook {
+            return static_codebook.lengthlist[..entries].to_vec();
+        }
+        let mut ret = vec![0; entries];
+        for (&entry, &length) in self.dec_index.iter().zip(self.dec_codelengths.iter()) {
+            ret[entry as usize] = length;
+        }
+        ret
+    }
+
+    /// * The value vector of an used entry, `value_list` is entry-ordered for encoding and ordered as `dec_index` for decoding.
+    fn entry_value(&self, entry: usize) -> Option<&[f32]> {
+        let dim = self.dim as usize;
+        let value_list = self.value_list.as_ref()?;
+        let index = if self.static_codebook.is_some() {
+            entry
+        } else {
+            self.dec_index.iter().position(|&e|e as usize == entry)?
+        };
+        value_list.get(index * dim..(index + 1) * dim)
+    }
+
+    /// * Compare the defining data only: `dim`, `entries`, and the source book.
+    /// * The tables derived from the source differ between `new_for_encode()` and `new_for_decode()`, so `==` can't tell the books are equivalent.
+    ///   The decode books don't keep the source, the codeword lengths and the values of the used entries are compared for them instead.
+    pub fn eq_defining(&self, other: &CodeBook) -> bool {
+        if self.dim != other.dim || self.entries != other.entries {
+            return false;
+        }
+        if let (Some(a), Some(b)) = (&self.static_codebook, &other.static_codebook) {
+            return a == b;
+        }
+        let lengths = self.entry_lengths();
+        if lengths != other.entry_lengths() {
+            return false;
+        }
+        if self.value_list.is_some() != other.value_list.is_some() {
+            return false;
+        }
+        lengths.iter().enumerate().filter(|&(_, &length)|length > 0).all(|(entry, _)|self.entry_value(entry) == other.entry_value(entry))
+    }
+
+    /// * Are some of the entries unused, `new_for_decode()` only keeps the used entries in `value_list` and `code_list` in this case.
+    pub fn is_sparse(&self) -> bool {
+        self.used_entries != self.entries
+    }
+
+    /// * Find the packed entry number of the next codeword, the two-stage lookup of `libvorbis`
+    /// * The first stage is a direct hit in `dec_firsttable` for short codewords,
+    ///   otherwise the table gives a hint range, and the second stage bisects `code_list` in it.
+    /// * Without `dec_firsttable`, the whole `code_list` is bisected. `new_for_decode()` always builds the table,
+    ///   it's faster than bisecting for all of the book sizes in `bench_codebook_decode`, even for the books of 8 entries.
+    fn decode_packed_entry_number(&self, bitreader: &mut BitReader) -> io::Result<usize> {
+        let mut read = self.dec_maxlength as i32;
+        let mut lo;
+        let mut hi;
+
+        if self.dec_firsttable.is_empty() {
+            // No first stage table, bisect all of the codewords
+            lo = 0;
+            hi = self.used_entries as usize;
+        } else if let Ok(lok) = bitreader.look(self.dec_firsttablen as i32) {
+            let entry = self.dec_firsttable[lok as usize];
+            if entry & 0x80000000 != 0 {
+                lo = ((entry >> 15) & 0x7fff) as usize;
+                hi = self.used_entries as usize - (entry & 0x7fff) as usize;
+            } else {
+                bitreader.read(self.dec_codelengths[entry as usize - 1] as i32)?;
+                return Ok(entry as usize - 1);
+            }
+        } else {
+            lo = 0;
+            hi = self.used_entries as usize;
+        }
+
+        /* Single entry codebooks use a firsttablen of 1 and a
+           dec_maxlength of 1.  If a single-entry codebook gets here (due to
+           failure to read one bit above), the next look attempt will also
+           fail and we'll correctly kick out instead of trying to walk the
+           underformed tree */
+        let mut lok = bitreader.look(read);
+        while lok.is_err() && read > 1 {
+            read -= 1;
+            lok = bitreader.look(read);
+        }
+        let lok = lok?;
+
+        /* bisect search for the codeword in the ordered list */
+        let testword = bitreverse(lok as u32);
+        while hi - lo > 1 {
+            let p = (hi - lo) >> 1;
+            if self.code_list[lo + p] > testword {
+                hi -= p;
+            } else {
+                lo += p;
+            }
+        }
+
+        if self.dec_codelengths[lo] as i32 <= read {
+            bitreader.read(self.dec_codelengths[lo] as i32)?;
+            Ok(lo)
+        } else {
+            bitreader.read(read)?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid codeword 0x{:x} in {read} bits", lok)))
+        }
+    }
+
+    /// * Decode a codeword from the bitstream, returns the entry number.
+    /// * The codebook must be created by `new_for_decode()`
+    pub fn decode_scalar(&self, bitreader: &mut BitReader) -> io::Result<i32> {
+        if self.used_entries > 0 {
+            let packed_entry = self.decode_packed_entry_number(bitreader)?;
+            Ok(self.dec_index[packed_entry])
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "Decoding from a codebook that has no used entries.".to_string()))
+        }
+    }
+
+    /// * Decode codewords from the bitstream and fill `out` with the value vectors of the entries, `dim` values per codeword.
+    /// * The `q_sequencep` accumulation was already done by `book_unquantize()` when the codebook was created.
+    /// * A codebook that has no used entries decodes to zeros.
+    /// * The codebook must be created by `new_for_decode()`
+    pub fn decode_vector(&self, bitreader: &mut BitReader, out: &mut [f32]) -> io::Result<()> {
+        if self.used_entries == 0 {
+            out.fill(0.0);
+            return Ok(());
+        }
+        let Some(value_list) = &self.value_list else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Decoding vectors from a codebook that has no value mapping.".to_string()));
+        };
+        let dim = self.dim as usize;
+        if dim == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Decoding vectors from a codebook of zero dimensions.".to_string()));
+        }
+        for chunk in out.chunks_mut(dim) {
+            let packed_entry = self.decode_packed_entry_number(bitreader)?;
+            chunk.copy_from_slice(&value_list[packed_entry * dim..packed_entry * dim + chunk.len()]);
+        }
+        Ok(())
+    }
+
+    /// * Write the codeword of an entry into the bitstream, returns how many bits were written.
+    /// * The codebook must be created by `new_for_encode()`
+    pub fn encode<W>(&self, entry: i32, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    where
+        W: Write {
+        let Some(static_codebook) = &self.static_codebook else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Encoding with a codebook that isn't created for encoding.".to_string()));
+        };
+        if entry < 0 || entry >= self.entries {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Entry {entry} is out of the range of the codebook of {} entries", self.entries)));
+        }
+        let entry = entry as usize;
+        let length = static_codebook.lengthlist[entry];
+        if length <= 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Entry {entry} is unused in the codebook")));
+        }
+
+        /* Single-entry codebooks have a single codeword '0' of length 1,
+           the same as what `make_words()` shields from the underpopulated tree check. */
+        if static_codebook.is_single_entry() {
+            bitwriter.write(0, 1)?;
+            return Ok(1);
+        }
+
+        bitwriter.write(self.code_list[entry], length as i32)?;
+        Ok(length as usize)
+    }
+
+    /// * Find the entry whose values are the closest to `vec`, write its codeword and subtract its values from `vec`, returns how many bits were written.
+    /// * What's left in `vec` is the error to be coded by the next stage. `vec` could be shorter than `dim` at the end of a partition.
+    /// * The codebook must be created by `new_for_encode()`
+    pub fn encode_vector<W>(&self, vec: &mut [f32], bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    where
+        W: Write {
+        let (Some(static_codebook), Some(value_list)) = (&self.static_codebook, &self.value_list) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Encoding vectors with a codebook that has no values or isn't created for encoding.".to_string()));
+        };
+        let dim = self.dim as usize;
+        if dim == 0 || vec.len() > dim {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Encoding a vector of {} values with a codebook of {dim} dimensions.", vec.len())));
+        }
+
+        let mut best = None;
+        let mut best_error = 0.0f32;
+        for (entry, values) in value_list.chunks(dim).enumerate() {
+            if static_codebook.lengthlist[entry] <= 0 {
+                continue;
+            }
+            let error: f32 = vec.iter().zip(values.iter()).map(|(a, b)|(a - b) * (a - b)).sum();
+            if best.is_none() || error < best_error {
+                best = Some(entry);
+                best_error = error;
+            }
+        }
+        let Some(entry) = best else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Encoding vectors with a codebook that has no used entries.".to_string()));
+        };
+
+        for (v, value) in vec.iter_mut().zip(value_list[entry * dim..].iter()) {
+            *v -= value;
+        }
+        self.encode(entry as i32, bitwriter)
+    }
+}
+
 impl Debug for CodeBook {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let mut d = f.debug_struct("CodeBook");
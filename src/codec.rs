@@ -5,24 +5,42 @@ use std::{
     fmt::{self, Debug, Formatter},
     rc::Rc,
     cell::RefCell,
+    sync::Arc,
 };
 
 use crate::*;
 
-use ogg::{OggPacket, OggPacketType};
-use headers::{VorbisIdentificationHeader, VorbisMode, VorbisSetupHeader};
+use ogg::OggPacket;
+use headers::{VorbisIdentificationHeader, VorbisCommentHeader, VorbisMode, VorbisSetupHeader};
 use bitrate::{VorbisBitrateManagerInfo, VorbisBitrateManagerState};
 use codebook::{StaticCodeBook, CodeBook};
 use floor::{VorbisFloor, VorbisLookFloor};
 use mapping::VorbisMapping;
-use residue::{VorbisResidue, VorbisLookResidue};
+use residue::{VorbisResidue, VorbisLookResidue, VorbisResidueScratch};
 use psy::{VorbisInfoPsyGlobal, VorbisLookPsyGlobal, VorbisInfoPsy, VorbisLookPsy};
 use envelope::VorbisEnvelopeLookup;
+use blocks::{VorbisBlock, BLOCKTYPE_IMPULSE, BLOCKTYPE_PADDING, BLOCKTYPE_TRANSITION, BLOCKTYPE_LONG};
 use mdct::MdctLookup;
 use drft::DrftLookup;
 use highlevel::HighlevelEncodeSetup;
+use io_utils::CursorVecU8;
+use savagestr::prelude::*;
+
+/// * Apply the floor curve to the spectrum of a channel, then back to the time domain and window it.
+/// * Every channel only touches its own buffers, so the channels can be synthesized in parallel with the `rayon` feature.
+fn synthesize_channel(transform: &MdctLookup, window: &[f32], spectrum: &mut [f32], curve: Option<&[f32]>, block: &mut [f32]) {
+    match curve {
+        Some(curve) => spectrum.iter_mut().zip(curve.iter()).for_each(|(s, c)|*s *= c),
+        None => spectrum.fill(0.0),
+    }
+    transform.backward(spectrum, block);
+    block.iter_mut().zip(window.iter()).for_each(|(s, w)|*s *= w);
+}
 
 /// * VorbisCodecSetup
+/// * It's neither `Send` nor `Sync` because the floors, residues, maps and the codebooks are shared through `Rc`.
+///   To decode on multiple threads, give each thread its own `VorbisInfo` and `VorbisDspState`,
+///   the `MdctLookup`, `DrftLookup`, `StaticCodeBook` and `CodeBook` can be shared.
 #[derive(Debug, Default, Clone, PartialEq)]
 pub struct VorbisCodecSetup {
     /// Vorbis supports only short and long blocks, but allows the encoder to choose the sizes
@@ -105,8 +123,7 @@ impl VorbisCodecSetup {
         psy_noise_normal_thresh: &[f64],
     ) {
         let hi = &self.highlevel_encode_setup;
-        let psy = self.psys[n].clone();
-        let mut psy = *psy;
+        let mut psy = VorbisInfoPsy::info_template();
         psy.block_flag = n as i32 >> 1;
 
         if hi.noise_normalize_p != 0 {
@@ -120,7 +137,53 @@ impl VorbisCodecSetup {
     }
 }
 
+/// * The speaker positions of the channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    SideLeft,
+    SideRight,
+    RearLeft,
+    RearRight,
+    RearCenter,
+}
+
+/// * The channel orderings of the Vorbis I specification for 1 to 8 channels, section 4.3.9
+const CHANNEL_LAYOUTS: [&[Channel]; 8] = {
+    use Channel::*;
+    [
+        &[FrontCenter],
+        &[FrontLeft, FrontRight],
+        &[FrontLeft, FrontCenter, FrontRight],
+        &[FrontLeft, FrontRight, RearLeft, RearRight],
+        &[FrontLeft, FrontCenter, FrontRight, RearLeft, RearRight],
+        &[FrontLeft, FrontCenter, FrontRight, RearLeft, RearRight, LowFrequency],
+        &[FrontLeft, FrontCenter, FrontRight, SideLeft, SideRight, RearCenter, LowFrequency],
+        &[FrontLeft, FrontCenter, FrontRight, SideLeft, SideRight, RearLeft, RearRight, LowFrequency],
+    ]
+};
+
+/// * The rate control strategy implied by the bitrate hints of the identification header, a hint is set if it's positive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BitrateMode {
+    /// * Only the nominal bitrate is set, the bitrate varies around it without limits
+    Vbr,
+
+    /// * The upper or the lower limit is set, the bitrate varies within the limits, the nominal bitrate may also be set
+    Abr,
+
+    /// * All of the three are set to the same value, a fixed rate bitstream
+    Cbr,
+
+    /// * None is set, the coder doesn't speculate
+    Unspecified,
+}
+
 /// * The `VorbisInfo` structure
+/// * Not `Send` or `Sync` for the same reason as `VorbisCodecSetup`.
 #[derive(Debug, Default, Clone, PartialEq)]
 pub struct VorbisInfo {
     pub version: i32,
@@ -153,6 +216,7 @@ pub struct VorbisInfo {
 impl VorbisInfo {
     pub fn new(identification_header: &VorbisIdentificationHeader, setup_header: &VorbisSetupHeader) -> io::Result<Self> {
         let id = identification_header;
+        Self::check_setup_references(id, setup_header)?;
         Ok(Self {
             version: id.version,
             channels: id.channels,
@@ -165,18 +229,207 @@ impl VorbisInfo {
         })
     }
 
+    /// * The speaker position of each channel index, the ordering is defined by the Vorbis I specification for 1 to 8 channels.
+    /// * More than 8 channels have an application-defined ordering, the slice is empty then.
+    pub fn channel_layout(&self) -> &'static [Channel] {
+        match self.channels {
+            1..=8 => CHANNEL_LAYOUTS[self.channels as usize - 1],
+            _ => &[],
+        }
+    }
+
+    /// * The rate control strategy by the combination of `bitrate_upper`, `bitrate_nominal` and `bitrate_lower`
+    pub fn bitrate_mode(&self) -> BitrateMode {
+        let (upper, nominal, lower) = (self.bitrate_upper, self.bitrate_nominal, self.bitrate_lower);
+        if upper > 0 && upper == nominal && nominal == lower {
+            BitrateMode::Cbr
+        } else if upper > 0 || lower > 0 {
+            BitrateMode::Abr
+        } else if nominal > 0 {
+            BitrateMode::Vbr
+        } else {
+            BitrateMode::Unspecified
+        }
+    }
+
+    /// * The floor of the index in the codec setup, borrowed without touching the `Rc`
+    pub fn floor(&self, index: usize) -> Option<&VorbisFloor> {
+        self.codec_setup.floors.get(index).map(|floor|&**floor)
+    }
+
+    /// * The residue of the index in the codec setup, borrowed without touching the `Rc`
+    pub fn residue(&self, index: usize) -> Option<&VorbisResidue> {
+        self.codec_setup.residues.get(index).map(|residue|&**residue)
+    }
+
+    /// * The mapping of the index in the codec setup, borrowed without touching the `Rc`
+    pub fn mapping(&self, index: usize) -> Option<&VorbisMapping> {
+        self.codec_setup.maps.get(index).map(|mapping|&**mapping)
+    }
+
+    /// * The mode of the index in the codec setup
+    pub fn mode(&self, index: usize) -> Option<&VorbisMode> {
+        self.codec_setup.modes.get(index)
+    }
+
+    /// * The headers may come from different streams, or be modified after they were loaded.
+    /// * Make sure every mapping and mode only references floors, residues and mappings that exist in the setup header.
+    pub(crate) fn check_setup_references(identification_header: &VorbisIdentificationHeader, setup_header: &VorbisSetupHeader) -> io::Result<()> {
+        let channels = identification_header.channels as usize;
+        let floors = setup_header.floors.len() as i32;
+        let residues = setup_header.residues.len() as i32;
+        for (i, map) in setup_header.maps.iter().enumerate() {
+            let submaps = map.submaps as usize;
+            if submaps > map.floorsubmap.len() || submaps > map.residuesubmap.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mapping {i} has {submaps} submaps, but only {} floor submaps and {} residue submaps", map.floorsubmap.len(), map.residuesubmap.len())));
+            }
+            for j in 0..submaps {
+                let floorsubmap = map.floorsubmap[j];
+                if !(0..floors).contains(&floorsubmap) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mapping {i} submap {j} references floor {floorsubmap}, but there are only {floors} floors")));
+                }
+                let residuesubmap = map.residuesubmap[j];
+                if !(0..residues).contains(&residuesubmap) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mapping {i} submap {j} references residue {residuesubmap}, but there are only {residues} residues")));
+                }
+            }
+            for (j, &chmux) in map.chmuxlist.iter().take(channels).enumerate() {
+                if chmux as usize >= submaps {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mapping {i} channel {j} references submap {chmux}, but there are only {submaps} submaps")));
+                }
+            }
+        }
+        for (i, mode) in setup_header.modes.iter().enumerate() {
+            if mode.mapping as usize >= setup_header.maps.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mode {i} references mapping {}, but there are only {} mappings", mode.mapping, setup_header.maps.len())));
+            }
+        }
+        Ok(())
+    }
+
+    /// * Pack the three header packets for the beginning of the stream: the identification header, the comment header, and the setup header.
+    /// * The packets take the sequence numbers before `HEADER_PACKETS`, an encoding `VorbisDspState` numbers its first audio packet after them.
+    /// * The setup header is built from the codebooks, floors, residues, mappings and modes of the codec setup.
+    pub fn build_headers(&self, comments: &VorbisCommentHeader) -> io::Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+        let ci = &self.codec_setup;
+        let identification_header = VorbisIdentificationHeader {
+            version: self.version,
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bitrate_upper: self.bitrate_upper,
+            bitrate_nominal: self.bitrate_nominal,
+            bitrate_lower: self.bitrate_lower,
+            block_size: ci.block_size,
+        };
+        let setup_header = VorbisSetupHeader {
+            static_codebooks: ci.static_codebooks.clone(),
+            floors: ci.floors.iter().map(|floor|(**floor).clone()).collect(),
+            residues: ci.residues.iter().map(|residue|**residue).collect(),
+            maps: ci.maps.iter().map(|map|**map).collect(),
+            modes: ci.modes.clone(),
+        };
+        Self::check_setup_references(&identification_header, &setup_header)?;
+
+        let mut bitwriter = BitWriter::new(CursorVecU8::default());
+        identification_header.pack(&mut bitwriter)?;
+        let ident = bitwriter.into_bytes();
+
+        let mut bitwriter = BitWriter::new(CursorVecU8::default());
+        comments.pack(&mut bitwriter, &StringCodecMaps::new())?;
+        let comment = bitwriter.into_bytes();
+
+        let mut bitwriter = BitWriter::new(CursorVecU8::default());
+        setup_header.pack(&mut bitwriter, &identification_header)?;
+        let setup = bitwriter.into_bytes();
+
+        Ok((ident, comment, setup))
+    }
+
     pub fn psy_global_look(&self) -> VorbisLookPsyGlobal {
         let codec_setup = &self.codec_setup;
         VorbisLookPsyGlobal::new(-9999.0, self.channels, codec_setup.psy_g.clone())
     }
 }
 
+/// * The working buffers of `VorbisDspState::decode_packet_into()`, created by the first decoded packet and reused by the later ones
+#[derive(Default, Clone)]
+pub struct VorbisDecodeScratch {
+    /// * The floor curve of each channel, the half of the long block size
+    pub curves: Vec<Vec<f32>>,
+
+    /// * Whether the channel has the floor in the current packet
+    pub has_floor: Vec<bool>,
+
+    /// * Whether the channel has the residue in the current packet, the coupled channels share it
+    pub nonzero: Vec<bool>,
+
+    /// * Whether the residue of the channel is skipped, the opposite of `nonzero`
+    pub do_not_decode: Vec<bool>,
+
+    /// * The channels of the submap that is being decoded
+    pub submap_channels: Vec<usize>,
+
+    /// * The buffers for decoding the residues
+    pub residue: VorbisResidueScratch,
+
+    /// * The spectrum of each channel, the half of the long block size
+    pub spectra: Vec<Vec<f32>>,
+
+    /// * The windowed block of each channel, the long block size
+    pub blocks: Vec<Vec<f32>>,
+
+    /// * The windows by `W * 4 + lW * 2 + nW`
+    pub windows: Vec<Vec<f32>>,
+}
+
+impl VorbisDecodeScratch {
+    #[allow(non_snake_case)]
+    pub fn new(channels: usize, block_size: [usize; 2]) -> io::Result<Self> {
+        let mut windows = Vec::<Vec<f32>>::with_capacity(8);
+        for W in 0..2 {
+            for lW in 0..2 {
+                for nW in 0..2 {
+                    windows.push(try_vorbis_window(block_size[lW], block_size[W], block_size[nW])?);
+                }
+            }
+        }
+        Ok(Self {
+            curves: vec![vec![0.0; block_size[1] / 2]; channels],
+            has_floor: vec![false; channels],
+            nonzero: vec![false; channels],
+            do_not_decode: vec![false; channels],
+            submap_channels: Vec::with_capacity(channels),
+            residue: VorbisResidueScratch::default(),
+            spectra: vec![vec![0.0; block_size[1] / 2]; channels],
+            blocks: vec![vec![0.0; block_size[1]]; channels],
+            windows,
+        })
+    }
+}
+
+impl Debug for VorbisDecodeScratch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("VorbisDecodeScratch")
+        .field("curves", &NestVecFormatter::new_level1(&self.curves))
+        .field("has_floor", &self.has_floor)
+        .field("nonzero", &self.nonzero)
+        .field("do_not_decode", &self.do_not_decode)
+        .field("submap_channels", &self.submap_channels)
+        .field("residue", &self.residue)
+        .field("spectra", &NestVecFormatter::new_level1(&self.spectra))
+        .field("blocks", &NestVecFormatter::new_level1(&self.blocks))
+        .field("windows", &NestVecFormatter::new_level1(&self.windows))
+        .finish()
+    }
+}
+
 /// * The private part of the `VorbisDspState` for `libvorbis-1.3.7`
 #[derive(Default, Debug, Clone)]
 pub struct VorbisDspStatePrivate {
     pub envelope: Option<VorbisEnvelopeLookup>,
     pub window: [i32; 2],
-    pub transform: [[MdctLookup; 2]; 1],
+    /// * Shared by the states of the same block sizes if the `mdct-cache` feature is enabled
+    pub transform: [[Arc<MdctLookup>; 2]; 1],
     pub fft_look: Vec<DrftLookup>,
     pub modebits: i32,
 
@@ -186,6 +439,12 @@ pub struct VorbisDspStatePrivate {
     pub psy_g_look: VorbisLookPsyGlobal,
 
     pub bitrate_manager_state: Option<VorbisBitrateManagerState>,
+
+    /// * Encode only, the working space of the MDCT of the blocks, reused between the blocks
+    pub mdct_scratch: Vec<f32>,
+
+    /// * Decode only
+    pub decode_scratch: VorbisDecodeScratch,
 }
 
 impl VorbisDspStatePrivate {
@@ -212,13 +471,13 @@ impl VorbisDspStatePrivate {
         let modebits = ilog!(ci.modes.len() - 1);
         let transform = [
             [
-                MdctLookup::new(block_size[0] >> hs),
-                MdctLookup::new(block_size[1] >> hs),
+                MdctLookup::shared(block_size[0] >> hs)?,
+                MdctLookup::shared(block_size[1] >> hs)?,
             ],
         ];
         let window = [
-            ilog!(block_size[0]) - 7,
-            ilog!(block_size[1]) - 7
+            window_index(block_size[0] as i32),
+            window_index(block_size[1] as i32),
         ];
         let fft_look;
         if for_encode {
@@ -238,13 +497,15 @@ impl VorbisDspStatePrivate {
         let psy_g_look = vi.psy_global_look();
 
         for floor in ci.floors.iter() {
-            flr_look.push(VorbisLookFloor::look(floor.clone()));
+            flr_look.push(VorbisLookFloor::look(floor.clone(), ci.block_size));
         }
         for residue in ci.residues.iter() {
             residue_look.push(VorbisLookResidue::look(residue.clone(), vd));
         }
-        for psy in ci.psys.iter() {
-            psy_look.push(VorbisLookPsy::new(psy.clone(), &*ci.psy_g, block_size[psy.block_flag as usize] / 2, vi.sample_rate as u32));
+        if for_encode {
+            for psy in ci.psys.iter() {
+                psy_look.push(VorbisLookPsy::new(psy.clone(), &ci.psy_g, block_size[psy.block_flag as usize] / 2, vi.sample_rate as u32));
+            }
         }
 
         let bitrate_manager_state = if for_encode {
@@ -277,12 +538,16 @@ impl VorbisDspStatePrivate {
     }
 }
 
+/// * The number of the header packets at the beginning of a Vorbis stream, the audio packets are numbered after them.
+pub const HEADER_PACKETS: u32 = 3;
+
 /// * Am I going to reinvent the `libvorbis` wheel myself?
-#[derive(Default, Clone)]
+#[derive(Default)]
 #[allow(non_snake_case)]
 pub struct VorbisDspState {
     pub for_encode: bool,
-    pub vorbis_info: VorbisInfo,
+    /// * Shared with the blocks carved out for encoding
+    pub vorbis_info: Rc<VorbisInfo>,
 
     pub pcm: Vec<Vec<f32>>,
     pub pcm_ret: Vec<Vec<f32>>,
@@ -293,6 +558,9 @@ pub struct VorbisDspState {
     pub preextrapolate: i32,
     pub eofflag: bool,
 
+    /// * Where the input ended in `pcm` when encoding, valid if `eofflag` is set
+    pub eofpos: usize,
+
     /// previous window size
     pub lW: usize,
 
@@ -302,20 +570,32 @@ pub struct VorbisDspState {
     pub centerW: usize,
 
     pub granulepos: u64,
+
+    /// * The packet sequence number of the next block carved out for encoding, it begins after the header packets of `VorbisInfo::build_headers()`.
+    /// * The decoder doesn't use it, it stays 0 when decoding.
     pub sequence: u32,
 
+    /// * Encode only, the vendor string packed into the comment header by `headerout()`, `VENDOR_STRING` by default.
+    pub vendor: String,
+
     pub glue_bits: i64,
     pub time_bits: i64,
     pub floor_bits: i64,
     pub res_bits: i64,
 
-    pub backend_state: VorbisDspStatePrivate,
+    /// * Shared with the blocks carved out for encoding, so the global psychoacoustics state such as the tracked peak carries across the blocks
+    pub backend_state: Rc<RefCell<VorbisDspStatePrivate>>,
 }
 
 impl VorbisDspState {
     #[allow(non_snake_case)]
-    pub fn new(vi: VorbisInfo, for_encode: bool) -> io::Result<Self> {
-        let ci = &vi.codec_setup;
+    pub fn new(mut vi: VorbisInfo, for_encode: bool) -> io::Result<Self> {
+        let ci = &mut vi.codec_setup;
+        if for_encode {
+            ci.set_encoder_mode()?;
+        } else {
+            ci.set_decoder_mode()?;
+        }
         let pcm_storage = ci.block_size[1] as usize;
         let pcm = vecvec![[0.0; pcm_storage]; vi.channels as usize];
         let pcm_ret = vecvec![[0.0; pcm_storage]; vi.channels as usize];
@@ -324,50 +604,450 @@ impl VorbisDspState {
 
         let mut ret = Self{
             for_encode,
-            vorbis_info: vi,
+            vorbis_info: Rc::new(vi),
             pcm,
             pcm_ret,
             pcm_storage,
             pcm_current,
             centerW,
-            sequence: 3,
+            sequence: if for_encode {HEADER_PACKETS} else {0},
+            vendor: VENDOR_STRING.to_string(),
             ..Default::default()
         };
-        let vi = &mut ret.vorbis_info;
-        let ci = &mut vi.codec_setup;
-        if for_encode {
-            ci.set_encoder_mode()?;
-        } else {
-            ci.set_decoder_mode()?;
+        ret.backend_state = Rc::new(RefCell::new(VorbisDspStatePrivate::new(&ret)?));
+        if !for_encode {
+            ret.synthesis_restart();
         }
-        ret.backend_state = VorbisDspStatePrivate::new(&ret)?;
         Ok(ret)
     }
 
-    /// Consumes the inner `vorbis_block`, excretes an Ogg packet
-    pub fn packet_out(&mut self) -> Option<OggPacket> {
-        let bm = self.backend_state.bitrate_manager_state.as_mut().expect("The block should be in encoding mode");
-        let ret = if let Some(ref mut vb) = bm.vorbis_block {
-            let vb = vb.borrow();
-            let vbi = &vb.internal.as_ref().expect("The block should be in encoding mode");
-            let choice = if bm.managed {
-                bm.choice as usize
+    /// * Set the vendor string that identifies the encoder, it's packed into the comment header by `headerout()`.
+    pub fn set_vendor(&mut self, vendor: &str) {
+        self.vendor = vendor.to_string();
+    }
+
+    /// * Pack the three header packets for the stream, the same as `vorbis_analysis_headerout()` of `libvorbis`.
+    /// * The comment header takes the vendor string of the encoder rather than the one of `comments`, the rest is `VorbisInfo::build_headers()`.
+    pub fn headerout(&self, comments: &VorbisCommentHeader) -> io::Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+        if !self.for_encode {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The `VorbisDspState` is not for encoding".to_string()));
+        }
+        let comments = VorbisCommentHeader {
+            comments: comments.comments.clone(),
+            vendor: self.vendor.clone(),
+        };
+        self.vorbis_info.build_headers(&comments)
+    }
+
+    /// * Whether the encoder manages the bitrate, the bitrate manager is only set up for a managed encoding.
+    pub fn is_bitrate_managed(&self) -> bool {
+        self.backend_state.borrow().is_bitrate_managed()
+    }
+
+    /// * Get the buffers to write `samples` samples of each channel into, the buffers grow if needed.
+    /// * Call `analysis_wrote()` after the samples were written to submit them.
+    pub fn analysis_buffer(&mut self, samples: usize) -> Vec<&mut [f32]> {
+        if self.pcm_current + samples >= self.pcm_storage {
+            self.pcm_storage = self.pcm_current + samples * 2;
+            for pcm in self.pcm.iter_mut() {
+                pcm.resize(self.pcm_storage, 0.0);
+            }
+        }
+        let begin = self.pcm_current;
+        self.pcm.iter_mut().map(|pcm|&mut pcm[begin..begin + samples]).collect()
+    }
+
+    /// * Submit the samples written to the buffers from `analysis_buffer()`.
+    /// * Writing zero samples marks the end of the input. The input is extrapolated by LPC for the last blocks rather than padded with silence,
+    ///   so a loud ending isn't dropped off a cliff that creates spread spectrum noise. The beginning is extrapolated backwards the same way.
+    pub fn analysis_wrote(&mut self, samples: usize) -> io::Result<()> {
+        if !self.for_encode {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The `VorbisDspState` is not for encoding".to_string()));
+        }
+        if self.eofflag {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The end of the input was already marked".to_string()));
+        }
+        let long_block = self.vorbis_info.codec_setup.block_size[1] as usize;
+        if samples == 0 {
+            const ORDER: usize = 32;
+
+            // if it wasn't done earlier (very short sample)
+            if self.preextrapolate == 0 {
+                self.preextrapolate_helper();
+            }
+
+            let padding = long_block * 3;
+            self.analysis_buffer(padding);
+            self.eofflag = true;
+            self.eofpos = self.pcm_current;
+            self.pcm_current += padding;
+            let eofpos = self.eofpos;
+            let mut lpc = [0.0f32; ORDER];
+            for pcm in self.pcm.iter_mut() {
+                let (data, extrapolated) = pcm[..self.pcm_current].split_at_mut(eofpos);
+                if eofpos > ORDER * 2 {
+                    // make a predictor filter, then run it
+                    let n = eofpos.min(long_block);
+                    vorbis_lpc_from_data(&data[eofpos - n..], &mut lpc);
+                    vorbis_lpc_predict(&lpc, Some(&data[eofpos - ORDER..]), extrapolated);
+                } else {
+                    // not enough data to extrapolate, zeroes will do
+                    extrapolated.fill(0.0);
+                }
+            }
+        } else {
+            if self.pcm_current + samples > self.pcm_storage {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Wrote {samples} samples but the buffer only has {} samples left", self.pcm_storage - self.pcm_current)));
+            }
+            self.pcm_current += samples;
+
+            // we may want to reverse extrapolate the beginning of a stream too, in case we're beginning on a cliff
+            if self.preextrapolate == 0 && self.pcm_current - self.centerW > long_block {
+                self.preextrapolate_helper();
+            }
+        }
+        Ok(())
+    }
+
+    /// * Fill the samples before the first center, which the first block overlaps, by running the LPC extrapolation backwards from the input.
+    /// * It only runs once.
+    fn preextrapolate_helper(&mut self) {
+        const ORDER: usize = 16;
+        self.preextrapolate = 1;
+        let pcm_current = self.pcm_current;
+        let center = self.centerW;
+        if pcm_current - center <= ORDER * 2 {
+            // safety
+            return;
+        }
+        let mut lpc = [0.0f32; ORDER];
+        let mut work = vec![0.0f32; pcm_current];
+        for pcm in self.pcm.iter_mut() {
+            // need to run the extrapolation in reverse!
+            for (w, &p) in work.iter_mut().zip(pcm[..pcm_current].iter().rev()) {
+                *w = p;
+            }
+
+            // prime as above
+            let n = pcm_current - center;
+            vorbis_lpc_from_data(&work[..n], &mut lpc);
+
+            // run the predictor filter
+            let (data, extrapolated) = work.split_at_mut(n);
+            vorbis_lpc_predict(&lpc, Some(&data[n - ORDER..]), extrapolated);
+
+            for (p, &w) in pcm[..pcm_current].iter_mut().rev().zip(work.iter()) {
+                *p = w;
+            }
+        }
+    }
+
+    /// * Carve the submitted samples into the next overlapping block, returns `None` if there are not enough samples buffered yet,
+    ///   or all of the blocks were carved out after the end of the input.
+    /// * The block gets the raw samples, the window is applied when the block is analyzed.
+    #[allow(non_snake_case)]
+    pub fn analysis_blockout(&mut self) -> io::Result<Option<VorbisBlock>> {
+        if !self.for_encode {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The `VorbisDspState` is not for encoding".to_string()));
+        }
+        // check to see if we're started, the beginning is extrapolated first
+        if self.preextrapolate == 0 {
+            return Ok(None);
+        }
+        if self.eofflag && self.eofpos == 0 {
+            // All done
+            return Ok(None);
+        }
+        let vi = &self.vorbis_info;
+        let ci = &vi.codec_setup;
+        let block_size = [ci.block_size[0] as usize, ci.block_size[1] as usize];
+
+        // Search for the next boundary so we can determine `nW`, which lets us compute the shape of the current block's window
+        let mut backend_state = self.backend_state.borrow_mut();
+        let envelope = backend_state.envelope.as_mut().expect("The envelope lookup should be created for encoding");
+        let bp = envelope.mark_envelope(&ci.psy_g, &self.pcm, self.pcm_current, self.centerW, self.W, block_size);
+        if bp == -1 {
+            if !self.eofflag {
+                return Ok(None);
+            }
+            self.nW = 0;
+        } else {
+            self.nW = if block_size[0] == block_size[1] {0} else {bp as usize};
+        }
+
+        let centerNext = self.centerW + block_size[self.W] / 4 + block_size[self.nW] / 4;
+        let blockbound = centerNext + block_size[self.nW] / 2;
+        if self.pcm_current < blockbound {
+            // Not enough data yet
+            return Ok(None);
+        }
+
+        let mut vb = VorbisBlock::new(self, 0);
+        vb.lW = self.lW;
+        vb.W = self.W;
+        vb.nW = self.nW;
+        vb.mode = self.W as i32;
+        vb.sequence = self.sequence;
+        vb.granulepos = self.granulepos;
+        vb.pcmend = block_size[self.W];
+        self.sequence += 1;
+
+        let rate = vi.sample_rate;
+        let vbi = vb.internal.as_mut().unwrap();
+        vbi.blocktype = if self.W != 0 {
+            if self.lW == 0 || self.nW == 0 {
+                BLOCKTYPE_TRANSITION
             } else {
-                PACKETBLOBS / 2
-            };
-            let mut ret = OggPacket::new(vb.ogg_stream_id, if vb.eofflag {
-                OggPacketType::BeginOfStream
+                BLOCKTYPE_LONG
+            }
+        } else if backend_state.envelope.as_ref().unwrap().is_impulse(self.centerW, self.lW, self.W, self.nW, block_size) {
+            BLOCKTYPE_IMPULSE
+        } else {
+            BLOCKTYPE_PADDING
+        };
+
+        // This tracks the strongest peak for later psychoacoustics
+        vbi.ampmax = backend_state.psy_g_look.ampmax_decay(vbi.ampmax, block_size[self.W] / 2, rate);
+
+        // Copy the vectors
+        let beginW = self.centerW - block_size[self.W] / 2;
+        vbi.pcmdelay = self.pcm.iter().map(|pcm|pcm[..beginW + vb.pcmend].to_vec()).collect();
+        vb.pcm = vbi.pcmdelay.iter().map(|pcm|pcm[beginW..].to_vec()).collect();
+
+        // Handle the end of the input, this is the last block if the center reached it
+        if self.eofflag && self.centerW >= self.eofpos {
+            self.eofpos = 0;
+            vb.eofflag = true;
+            return Ok(Some(vb));
+        }
+
+        // Advance storage vectors and clean up
+        let new_centerNext = block_size[1] / 2;
+        if centerNext > new_centerNext {
+            let movementW = centerNext - new_centerNext;
+            backend_state.envelope.as_mut().unwrap().shift(movementW);
+            self.pcm_current -= movementW;
+            for pcm in self.pcm.iter_mut() {
+                pcm.copy_within(movementW..movementW + self.pcm_current, 0);
+            }
+            self.lW = self.W;
+            self.W = self.nW;
+            self.centerW = new_centerNext;
+            if self.eofflag {
+                // Do not add the padding to the end of the stream
+                self.eofpos = self.eofpos.saturating_sub(movementW);
+                if self.centerW >= self.eofpos {
+                    self.granulepos += (movementW + self.eofpos).saturating_sub(self.centerW) as u64;
+                } else {
+                    self.granulepos += movementW as u64;
+                }
             } else {
-                OggPacketType::Continuation
-            }, vb.sequence);
-            ret.granule_position = vb.granulepos;
-            ret.write(&vbi.packetblob[choice as usize].borrow_mut().to_bytes());
-            Some(ret)
+                self.granulepos += movementW as u64;
+            }
+        }
+        Ok(Some(vb))
+    }
+
+    /// * Forget the previous block, the next decoded packet only primes the overlap-add and returns no samples.
+    /// * `pcm[..][..pcm_current]` holds the windowed previous block when decoding, `centerW` is the center of it.
+    pub fn synthesis_restart(&mut self) {
+        self.lW = 0;
+        self.W = 0;
+        self.nW = 0;
+        self.centerW = 0;
+        self.pcm_current = 0;
+        self.pcm_returned = 0;
+        self.granulepos = 0;
+    }
+
+    /// * Decode an audio packet, returns the samples of each channel that are finished by this packet.
+    /// * The finished samples are from the center of the previous block to the center of this block, so the first packet returns no samples.
+    pub fn decode_packet(&mut self, packet: &[u8]) -> io::Result<Vec<Vec<f32>>> {
+        let channels = self.vorbis_info.channels as usize;
+        let mut ret = vec![vec![0.0f32; self.vorbis_info.codec_setup.block_size[1] as usize / 2]; channels];
+        let samples = self.decode_packet_into(packet, &mut ret)?;
+        ret.iter_mut().for_each(|channel|channel.truncate(samples));
+        Ok(ret)
+    }
+
+    /// * Decode an audio packet into the buffers of each channel, returns the number of the samples written to the beginning of each buffer.
+    /// * Every buffer must hold at least the half of the long block size, which is the most samples a packet could finish.
+    /// * The floors, the residues, the blocks and the windows are kept in the state and reused, so a playback loop could decode without allocating the sample buffers for every packet.
+    #[allow(non_snake_case)]
+    pub fn decode_packet_into(&mut self, packet: &[u8], out: &mut [Vec<f32>]) -> io::Result<usize> {
+        let vi = &self.vorbis_info;
+        let ci = &vi.codec_setup;
+        let channels = vi.channels as usize;
+        let block_size = [ci.block_size[0] as usize, ci.block_size[1] as usize];
+        if out.len() < channels {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Expected {channels} channel buffers, got {}", out.len())));
+        }
+        if let Some(short) = out[..channels].iter().find(|buffer|buffer.len() < block_size[1] / 2) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The channel buffers should hold at least {} samples, got {}", block_size[1] / 2, short.len())));
+        }
+        let mut bitreader = BitReader::new(packet);
+
+        if bitreader.read(1)? != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an audio packet".to_string()));
+        }
+        let mode_index = bitreader.read(self.backend_state.borrow().modebits)? as usize;
+        let Some(mode) = ci.modes.get(mode_index) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid mode number {mode_index}, there are {} modes", ci.modes.len())));
+        };
+        let W = mode.block_flag as usize;
+        let (lW, nW) = if W != 0 {
+            (bitreader.read(1)? as usize, bitreader.read(1)? as usize)
         } else {
-            None
+            (0, 0)
         };
-        bm.vorbis_block = None;
-        ret
+        let n = mode.block_size(vi) as usize;
+        let mapping = mode.mapping_ref_in(&ci.maps)?;
+        let submap_of = |ch: usize|mapping.chmuxlist.iter().nth(ch).copied().unwrap_or(0) as usize;
+        let books = ci.fullbooks.borrow();
+        let backend_state = &mut *self.backend_state.borrow_mut();
+        let scratch = &mut backend_state.decode_scratch;
+        if scratch.blocks.len() != channels || scratch.blocks.first().is_some_and(|block|block.len() != block_size[1]) {
+            *scratch = VorbisDecodeScratch::new(channels, block_size)?;
+        }
+
+        // Floors, a channel without the floor is silent
+        for ch in 0..channels {
+            let floor_index = mapping.floorsubmap[submap_of(ch)] as usize;
+            scratch.has_floor[ch] = backend_state.flr_look[floor_index].decode_curve_into(&mut bitreader, &books, &mut scratch.curves[ch][..n / 2])?;
+        }
+
+        // The coupled channels are decoded if any of them is not silent
+        scratch.nonzero.copy_from_slice(&scratch.has_floor);
+        let nonzero = &mut scratch.nonzero;
+        for i in 0..mapping.coupling_steps as usize {
+            let (m, a) = (mapping.coupling_mag[i] as usize, mapping.coupling_ang[i] as usize);
+            if nonzero[m] || nonzero[a] {
+                nonzero[m] = true;
+                nonzero[a] = true;
+            }
+        }
+
+        // Residues of each submap
+        for (skip, &nonzero) in scratch.do_not_decode.iter_mut().zip(scratch.nonzero.iter()) {
+            *skip = !nonzero;
+        }
+        for submap in 0..mapping.submaps as usize {
+            scratch.submap_channels.clear();
+            scratch.submap_channels.extend((0..channels).filter(|&ch|submap_of(ch) == submap));
+            let look = &backend_state.residue_look[mapping.residuesubmap[submap] as usize];
+            look.decode_into(&mut bitreader, &scratch.do_not_decode, &mut scratch.spectra, &scratch.submap_channels, n / 2, &mut scratch.residue)?;
+        }
+        mapping.inverse_coupling(&mut scratch.spectra);
+
+        // Apply the floors, then back to the time domain and window it
+        let window = &scratch.windows[W * 4 + lW * 2 + nW];
+        let transform = &backend_state.transform[0][W];
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            scratch.spectra.par_iter_mut().zip(scratch.curves.par_iter().zip(scratch.has_floor.par_iter())).zip(scratch.blocks.par_iter_mut()).for_each(|((spectrum, (curve, &has_floor)), block)| {
+                synthesize_channel(transform, window, &mut spectrum[..n / 2], has_floor.then_some(&curve[..n / 2]), &mut block[..n]);
+            });
+        }
+        #[cfg(not(feature = "rayon"))]
+        for ((spectrum, (curve, &has_floor)), block) in scratch.spectra.iter_mut().zip(scratch.curves.iter().zip(scratch.has_floor.iter())).zip(scratch.blocks.iter_mut()) {
+            synthesize_channel(transform, window, &mut spectrum[..n / 2], has_floor.then_some(&curve[..n / 2]), &mut block[..n]);
+        }
+
+        // Overlap-add the right half of the previous block with the left half of this block
+        let prev_n = self.pcm_current;
+        let mut samples = 0;
+        if prev_n != 0 {
+            let prev_center = self.centerW;
+            let len = prev_n / 4 + n / 4;
+            let cur_begin = n / 4;
+            for ((out, prev), block) in out.iter_mut().zip(self.pcm.iter()).zip(scratch.blocks.iter()) {
+                for (i, sample) in out[..len].iter_mut().enumerate() {
+                    let p = prev_center + i;
+                    let prev = if p < prev_n {prev[p]} else {0.0};
+                    let c = (cur_begin + i).wrapping_sub(prev_n / 4);
+                    let cur = if c < n {block[c]} else {0.0};
+                    *sample = prev + cur;
+                }
+            }
+            self.granulepos += len as u64;
+            samples = len;
+        }
+
+        for (pcm, block) in self.pcm.iter_mut().zip(scratch.blocks.iter()) {
+            pcm[..n].copy_from_slice(&block[..n]);
+        }
+        self.lW = lW;
+        self.W = W;
+        self.nW = nW;
+        self.pcm_current = n;
+        self.centerW = n / 2;
+        Ok(samples)
+    }
+
+    /// * Decode an audio packet into interleaved 16-bit samples, the channels are in the Vorbis channel order.
+    /// * The conversion is the same as `ov_read()`: scale by 32768, round to the nearest and clamp to the range of `i16`.
+    pub fn decode_packet_i16(&mut self, packet: &[u8]) -> io::Result<Vec<i16>> {
+        let pcm = self.decode_packet(packet)?;
+        let samples = pcm.first().map_or(0, |c|c.len());
+        let mut ret = Vec::<i16>::with_capacity(samples * pcm.len());
+        for i in 0..samples {
+            for channel in pcm.iter() {
+                ret.push((channel[i] * 32768.0).round_ties_even().clamp(-32768.0, 32767.0) as i16);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// * Hand the block analyzed by `VorbisBlock::analysis()` to the bitrate manager, then take the packet out with `packet_out()`.
+    pub fn bitrate_addblock(&mut self, vb: VorbisBlock) -> io::Result<()> {
+        let mut backend_state = self.backend_state.borrow_mut();
+        let bm = backend_state.bitrate_manager_state.as_mut().expect("The block should be in encoding mode");
+        bm.add_block(Rc::new(RefCell::new(vb)))
+    }
+
+    /// Consumes the inner `vorbis_block`, excretes an Ogg packet
+    pub fn packet_out(&mut self) -> Option<OggPacket> {
+        let mut backend_state = self.backend_state.borrow_mut();
+        let bm = backend_state.bitrate_manager_state.as_mut().expect("The block should be in encoding mode");
+        bm.take_packet()
+    }
+
+    /// * Force out the last buffered block at the end of the stream, encoders must call this to finish the stream.
+    pub fn flush_packet(&mut self) -> io::Result<Option<OggPacket>> {
+        let mut backend_state = self.backend_state.borrow_mut();
+        let bm = backend_state.bitrate_manager_state.as_mut().expect("The block should be in encoding mode");
+        bm.flush()
+    }
+}
+
+/// * The clone gets its own backend state, it's not shared with the blocks of the original state.
+impl Clone for VorbisDspState {
+    fn clone(&self) -> Self {
+        Self {
+            for_encode: self.for_encode,
+            vorbis_info: self.vorbis_info.clone(),
+            pcm: self.pcm.clone(),
+            pcm_ret: self.pcm_ret.clone(),
+            pcm_storage: self.pcm_storage,
+            pcm_current: self.pcm_current,
+            pcm_returned: self.pcm_returned,
+            preextrapolate: self.preextrapolate,
+            eofflag: self.eofflag,
+            eofpos: self.eofpos,
+            lW: self.lW,
+            W: self.W,
+            nW: self.nW,
+            centerW: self.centerW,
+            granulepos: self.granulepos,
+            sequence: self.sequence,
+            vendor: self.vendor.clone(),
+            glue_bits: self.glue_bits,
+            time_bits: self.time_bits,
+            floor_bits: self.floor_bits,
+            res_bits: self.res_bits,
+            backend_state: Rc::new(RefCell::new(self.backend_state.borrow().clone())),
+        }
     }
 }
 
@@ -383,12 +1063,14 @@ impl Debug for VorbisDspState {
         .field("pcm_returned", &self.pcm_returned)
         .field("preextrapolate", &self.preextrapolate)
         .field("eofflag", &self.eofflag)
+        .field("eofpos", &self.eofpos)
         .field("lW", &self.lW)
         .field("W", &self.W)
         .field("nW", &self.nW)
         .field("centerW", &self.centerW)
         .field("granulepos", &self.granulepos)
         .field("sequence", &self.sequence)
+        .field("vendor", &self.vendor)
         .field("glue_bits", &self.glue_bits)
         .field("time_bits", &self.time_bits)
         .field("floor_bits", &self.floor_bits)
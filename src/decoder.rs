@@ -0,0 +1,277 @@
+#![allow(dead_code)]
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{self, Read, Seek},
+};
+
+use crate::*;
+use ogg::{OggPacket, OggStreamReader};
+use headers::{VorbisIdentificationHeader, VorbisCommentHeader, VorbisSetupHeader};
+use codec::{VorbisInfo, VorbisDspState};
+use pages::{OggStreamPacket, NO_GRANULE};
+use savagestr::prelude::*;
+
+/// * Counts the decoded samples that are outside of `[-1.0, 1.0]` for each channel.
+/// * Lossy reconstruction may overshoot the original signal, this is for checking how hot the encoded audio is.
+/// * The decoder feeds every chunk of PCM samples it outputs into this counter.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClipCounter {
+    counts: Vec<u64>,
+}
+
+impl ClipCounter {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            counts: vec![0; channels],
+        }
+    }
+
+    /// * Count the clipped samples in the output PCM, one slice per channel
+    pub fn accumulate<S>(&mut self, pcm: &[S])
+    where
+        S: AsRef<[f32]> {
+        if self.counts.len() < pcm.len() {
+            self.counts.resize(pcm.len(), 0);
+        }
+        for (count, channel) in self.counts.iter_mut().zip(pcm.iter()) {
+            *count += channel.as_ref().iter().filter(|&&s|!(-1.0..=1.0).contains(&s)).count() as u64;
+        }
+    }
+
+    /// * The clipped samples of each channel so far
+    pub fn clip_count(&self) -> Vec<u64> {
+        self.counts.clone()
+    }
+
+    /// * Reset the counts to zero
+    pub fn reset(&mut self) {
+        self.counts.fill(0);
+    }
+}
+
+/// * A streaming Vorbis decoder, it reads the Ogg pages on demand and decodes one audio packet at a time.
+/// * The header packets are read on construction.
+pub struct VorbisDecoder<R>
+where
+    R: Read + Seek + Debug {
+    /// * Only taken out while rewinding
+    reader: Option<OggStreamReader<R>>,
+
+    /// * The offset of the next page to read
+    page_offset: usize,
+
+    /// * The packets finished on the pages read so far
+    packets: VecDeque<OggStreamPacket>,
+
+    /// * The unfinished packet at the end of the last page
+    partial_packet: Option<OggStreamPacket>,
+
+    /// * The samples decoded by seeking but not yet returned
+    pending_block: Option<Vec<Vec<f32>>>,
+
+    pub identification_header: VorbisIdentificationHeader,
+    pub comment_header: VorbisCommentHeader,
+    pub setup_header: VorbisSetupHeader,
+
+    vd: VorbisDspState,
+    clip_counter: ClipCounter,
+}
+
+impl<R> VorbisDecoder<R>
+where
+    R: Read + Seek + Debug {
+    /// * Read the three header packets and prepare for decoding the audio packets
+    pub fn new(reader: R) -> io::Result<Self> {
+        let mut ret = Self {
+            reader: Some(OggStreamReader::new(reader)),
+            page_offset: 0,
+            packets: VecDeque::new(),
+            partial_packet: None,
+            pending_block: None,
+            identification_header: VorbisIdentificationHeader::default(),
+            comment_header: VorbisCommentHeader::default(),
+            setup_header: VorbisSetupHeader::default(),
+            vd: VorbisDspState::default(),
+            clip_counter: ClipCounter::default(),
+        };
+        let text_codecs = StringCodecMaps::new();
+        let [ident, comment, setup] = ret.header_packets()?;
+        ret.identification_header = VorbisIdentificationHeader::load_from_slice(&ident.data)?;
+        ret.comment_header = VorbisCommentHeader::load(&mut BitReader::new(&comment.data), &text_codecs)?;
+        ret.setup_header = VorbisSetupHeader::load(&mut BitReader::new(&setup.data), &ret.identification_header)?;
+        ret.vd = VorbisDspState::new(VorbisInfo::new(&ret.identification_header, &ret.setup_header)?, false)?;
+        ret.clip_counter = ClipCounter::new(ret.channels() as usize);
+        Ok(ret)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.identification_header.sample_rate as u32
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.identification_header.channels as u16
+    }
+
+    /// * The clipped samples of each channel decoded so far
+    pub fn clip_count(&self) -> Vec<u64> {
+        self.clip_counter.clip_count()
+    }
+
+    /// * Read the three header packets
+    fn header_packets(&mut self) -> io::Result<[OggStreamPacket; 3]> {
+        let mut get_packet = || -> io::Result<OggStreamPacket> {
+            self.next_packet()?.ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "The Ogg stream ended before the Vorbis headers."))
+        };
+        Ok([get_packet()?, get_packet()?, get_packet()?])
+    }
+
+    /// * Go back to the beginning of the stream, the header packets are skipped.
+    fn rewind(&mut self) -> io::Result<()> {
+        let mut reader = self.reader.take().expect("The reader should only be taken out while rewinding").reader;
+        let result = reader.seek(io::SeekFrom::Start(0));
+        self.reader = Some(OggStreamReader::new(reader));
+        result?;
+        self.page_offset = 0;
+        self.packets.clear();
+        self.partial_packet = None;
+        self.pending_block = None;
+        self.header_packets()?;
+        self.vd.synthesis_restart();
+        Ok(())
+    }
+
+    /// * Read the next Ogg page and split it into packets, returns false at the end of the stream.
+    fn read_page(&mut self) -> io::Result<bool> {
+        let reader = self.reader.as_mut().expect("The reader should only be taken out while rewinding");
+        let Some(page) = reader.get_packet()? else {
+            return Ok(false);
+        };
+        let is_last_page = reader.is_eos();
+        let page_offset = self.page_offset;
+        let OggPacket {
+            granule_position,
+            segment_table,
+            data,
+            ..
+        } = page;
+        self.page_offset += 27 + segment_table.len() + data.len();
+        let mut pos = 0usize;
+        let mut last_finished = None;
+        for &lacing in segment_table.iter() {
+            let segment = &data[pos..pos + lacing as usize];
+            pos += lacing as usize;
+            let packet = self.partial_packet.get_or_insert_with(|| OggStreamPacket {
+                page_offset,
+                ..Default::default()
+            });
+            packet.data.extend(segment);
+            if lacing < 255 {
+                let mut packet = self.partial_packet.take().unwrap();
+                packet.is_last_page = is_last_page;
+                self.packets.push_back(packet);
+                last_finished = Some(self.packets.len() - 1);
+            }
+        }
+        if let Some(last_finished) = last_finished && granule_position != NO_GRANULE {
+            self.packets[last_finished].granule_position = Some(granule_position);
+        }
+        Ok(true)
+    }
+
+    /// * Get the next packet, read more pages if needed
+    fn next_packet(&mut self) -> io::Result<Option<OggStreamPacket>> {
+        while self.packets.is_empty() {
+            if !self.read_page()? {
+                return Ok(None);
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+
+    /// * Decode the next audio packet, returns the samples of each channel, or `None` at the end of the stream.
+    /// * The packets that don't output any samples are skipped, such as the first audio packet.
+    /// * The samples after the granule position of the last page are trimmed.
+    pub fn next_block(&mut self) -> io::Result<Option<Vec<Vec<f32>>>> {
+        let pcm = match self.pending_block.take() {
+            Some(pcm) => Some(pcm),
+            None => self.decode_next_block()?,
+        };
+        if let Some(ref pcm) = pcm {
+            self.clip_counter.accumulate(pcm);
+        }
+        Ok(pcm)
+    }
+
+    /// * Decode the next audio packet that outputs any samples
+    fn decode_next_block(&mut self) -> io::Result<Option<Vec<Vec<f32>>>> {
+        loop {
+            let Some(packet) = self.next_packet()? else {
+                return Ok(None);
+            };
+            if packet.data.is_empty() {
+                continue;
+            }
+            let mut pcm = self.vd.decode_packet(&packet.data)?;
+            if let Some(granule_position) = packet.granule_position && packet.is_last_page && self.vd.granulepos > granule_position {
+                let extra = (self.vd.granulepos - granule_position) as usize;
+                for channel in pcm.iter_mut() {
+                    channel.truncate(channel.len().saturating_sub(extra));
+                }
+                self.vd.granulepos = granule_position;
+            }
+            if pcm.iter().all(|c|c.is_empty()) {
+                continue;
+            }
+            return Ok(Some(pcm));
+        }
+    }
+
+    /// * Seek to the sample, the next call to `next_block()` returns the samples from it.
+    /// * The stream is scanned from the beginning for the last page whose granule position is not after the sample.
+    ///   The last packet finished on that page is decoded to prime the overlap-add and its output is dropped,
+    ///   then the packets are decoded forward until the block that contains the sample.
+    pub fn seek_to_sample(&mut self, sample: u64) -> io::Result<()> {
+        self.rewind()?;
+
+        let mut prime_granule = None;
+        loop {
+            let num_packets = self.packets.len();
+            if !self.read_page()? {
+                break;
+            }
+            if self.packets.len() == num_packets {
+                // No packet finished on this page
+                continue;
+            }
+            let Some(granule_position) = self.packets.back().unwrap().granule_position else {
+                continue;
+            };
+            if granule_position > sample {
+                break;
+            }
+            self.packets.drain(..self.packets.len() - 1);
+            prime_granule = Some(granule_position);
+        }
+        if let Some(granule_position) = prime_granule {
+            let packet = self.packets.pop_front().unwrap();
+            self.vd.decode_packet(&packet.data)?;
+            self.vd.granulepos = granule_position;
+        }
+
+        loop {
+            let begin = self.vd.granulepos;
+            let Some(mut pcm) = self.decode_next_block()? else {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Can't seek to sample {sample}, the stream ended at sample {begin}")));
+            };
+            if self.vd.granulepos > sample {
+                let skip = sample.saturating_sub(begin) as usize;
+                for channel in pcm.iter_mut() {
+                    channel.drain(..skip);
+                }
+                self.pending_block = Some(pcm);
+                return Ok(());
+            }
+        }
+    }
+}
@@ -1,15 +1,22 @@
 #![allow(dead_code)]
 use std::{
-    fmt::{self, Debug, Formatter},
+    fmt::{self, Debug, Display, Formatter},
+    io,
 };
 
 use crate::*;
 
 /// * DRFT transformer
+/// * `Send` and `Sync`, it only holds the twiddle factors.
 #[derive(Clone, PartialEq)]
 pub struct DrftLookup {
     n: usize,
+
+    /// * `3 * n` floats: the first `n` are the scratch buffer of the transform, the twiddle factors from `drfti1()` follow them.
+    /// * Empty if `n <= 1`, nothing to transform.
     trigcache: Vec<f32>,
+
+    /// * The factorization of `n`: `[n, number of factors, factors...]`
     splitcache: [i32; 32],
 }
 
@@ -33,6 +40,32 @@ impl Default for DrftLookup {
     }
 }
 
+/// * The error of running a `DrftLookup` on a buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrftError {
+    /// * The buffer length doesn't match the size of the transform
+    LengthMismatch {
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl Display for DrftError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::LengthMismatch{expected, got} => write!(f, "The DRFT size is {expected}, but the buffer has {got} samples"),
+        }
+    }
+}
+
+impl std::error::Error for DrftError {}
+
+impl From<DrftError> for io::Error {
+    fn from(e: DrftError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
 macro_rules! deref {
     ($ptr:ident [$index:expr]) => {
         *$ptr.add($index as usize)
@@ -54,7 +87,7 @@ impl DrftLookup {
         let mut ntry = 0;
         let mut j = -1i32;
         let mut update_ntry = true;
-        let mut nl = 0;
+        let mut nl = n as i32;
         let mut nf = 0;
         let mut nq;
 
@@ -110,10 +143,10 @@ impl DrftLookup {
         let mut l1 = 1;
         let mut is = 0;
         for k1 in 0..nfm1 {
-            let ip = ifac[k1 + 2];
+            let ip = ifac[k1 + 2] as usize;
             let mut ld = 0;
             let l2 = l1 * ip;
-            let ido = n / 12;
+            let ido = n / l2;
             let ipm = ip - 1;
             for _ in 0..ipm {
                 ld += l1;
@@ -245,8 +278,8 @@ impl DrftLookup {
                         let cr3 = wa2[i - 2] * deref!(cc[t3 - 1]) + wa2[i - 1] * deref!(cc[t3 + 0]);
                         let ci3 = wa2[i - 2] * deref!(cc[t3 + 0]) - wa2[i - 1] * deref!(cc[t3 - 1]);
                         t3 += t0;
-                        let cr4 = wa2[i - 2] * deref!(cc[t3 - 1]) + wa3[i - 1] * deref!(cc[t3 + 0]);
-                        let ci4 = wa2[i - 2] * deref!(cc[t3 + 0]) - wa3[i - 1] * deref!(cc[t3 - 1]);
+                        let cr4 = wa3[i - 2] * deref!(cc[t3 - 1]) + wa3[i - 1] * deref!(cc[t3]);
+                        let ci4 = wa3[i - 2] * deref!(cc[t3]) - wa3[i - 1] * deref!(cc[t3 - 1]);
 
                         let tr1 = cr2 + cr4;
                         let tr4 = cr4 - cr2;
@@ -323,35 +356,33 @@ impl DrftLookup {
                 }
             }
 
-            let mut is = 0usize.wrapping_sub(ido);
+            let mut is = 0;
             let mut t1 = 0;
             if nbd > l1 {
-                for _ in 0..ip {
+                for _ in 1..ip {
                     t1 += t0;
-                    is += ido;
                     let mut t2 = t1 - ido;
                     for _ in 0..l1 {
-                        let mut idij = is - 1;
+                        let mut idij = is + 1;
                         t2 += ido;
                         let mut t3 = t2;
                         for _ in (2..ido).step_by(2) {
-                            idij += 2;
                             t3 += 2;
                             unsafe {
                                 deref!(ch[t3 - 1]) = wa[idij - 1] * deref!(c1[t3 - 1]) + wa[idij] * deref!(c1[t3 + 0]);
                                 deref!(ch[t3 + 0]) = wa[idij - 1] * deref!(c1[t3 + 0]) - wa[idij] * deref!(c1[t3 - 1]);
                             }
+                            idij += 2;
                         }
                     }
+                    is += ido;
                 }
             } else {
-                for _ in 0..ip {
-                    is += ido;
-                    let mut idij = is - 1;
+                for _ in 1..ip {
+                    let mut idij = is + 1;
                     t1 += t0;
                     let mut t2 = t1;
                     for _ in (2..ido).step_by(2) {
-                        idij += 2;
                         t2 += 2;
                         let mut t3 = t2;
                         for _ in 0..l1 {
@@ -361,7 +392,9 @@ impl DrftLookup {
                             }
                             t3 += ido;
                         }
+                        idij += 2;
                     }
+                    is += ido;
                 }
             }
 
@@ -568,12 +601,12 @@ impl DrftLookup {
         if ido == 1 {
             return;
         } else if nbd >= l1 {
-            let mut t1 = 0 - ido;
+            let mut t1 = 0usize.wrapping_sub(ido);
             let mut t3 = 0;
             let mut t4 = 0;
             let mut t5 = ipp2 * t0;
             for _ in 1..ipph {
-                t1 += t2;
+                t1 = t1.wrapping_add(t2);
                 t3 += t2;
                 t4 += t0;
                 t5 -= t0;
@@ -605,7 +638,7 @@ impl DrftLookup {
         let mut t4 = 0;
         let mut t5 = ipp2 * t0;
         for _ in 1..ipph {
-            t1 += t2;
+            t1 = t1.wrapping_add(t2);
             t3 += t2;
             t4 += t0;
             t5 -= t0;
@@ -640,7 +673,7 @@ impl DrftLookup {
             let kh = nf - k1;
             let ip = ifac[kh + 1] as usize;
             let l1 = l2 / ip;
-            let ido = n / 12;
+            let ido = n / l2;
             let idl1 = ido * l1;
             iw -= (ip - 1) * ido;
             na = 1 - na;
@@ -1221,49 +1254,48 @@ impl DrftLookup {
             }
         }
 
-        if nbd <= 11 {
-            let mut is = 0usize.wrapping_sub(ido) - 1;
+        if nbd <= l1 {
+            let mut is = 0;
             let mut t1 = 0;
             for _ in 1..ip {
-                is = is.wrapping_add(ido);
                 t1 += t0;
-                let mut idij = is;
+                let mut idij = is + 1;
                 let mut t2 = t1;
                 for _ in (2..ido).step_by(2) {
                     t2 += 2;
-                    idij += 2;
                     let mut t3 = t2;
                     for _ in 0..l1 {
                         unsafe {
-                            deref!(c1[t3 - 1]) = wa[idij - 1] * deref!(ch[t3 - 1]) - wa[idij] * deref!(ch[t3 + 0]);
-                            deref!(c1[t3 + 0]) = wa[idij - 1] * deref!(ch[t3 + 0]) + wa[idij] * deref!(ch[t3 - 1]);
+                            deref!(c1[t3 - 1]) = wa[idij - 1] * deref!(ch[t3 - 1]) - wa[idij] * deref!(ch[t3]);
+                            deref!(c1[t3]) = wa[idij - 1] * deref!(ch[t3]) + wa[idij] * deref!(ch[t3 - 1]);
                         }
                         t3 += ido;
                     }
+                    idij += 2;
                 }
+                is += ido;
             }
-            return;
         } else {
 // L139
-            let mut is = 0usize.wrapping_sub(ido) - 1;
+            let mut is = 0;
             let mut t1 = 0;
             for _ in 1..ip {
-                is = is.wrapping_add(ido);
                 t1 += t0;
                 let mut t2 = t1;
                 for _ in 0..l1 {
-                    let mut idij = is;
+                    let mut idij = is + 1;
                     let mut t3 = t2;
                     for _ in (2..ido).step_by(2) {
-                        idij += 2;
                         t3 += 2;
                         unsafe {
-                            deref!(c1[t3 - 1]) = wa[idij - 1] * deref!(ch[t3 - 1]) - wa[idij] * deref!(ch[t3 + 1]);
-                            deref!(c1[t3 + 1]) = wa[idij - 1] * deref!(ch[t3 + 1]) + wa[idij] * deref!(ch[t3 - 1]);
+                            deref!(c1[t3 - 1]) = wa[idij - 1] * deref!(ch[t3 - 1]) - wa[idij] * deref!(ch[t3]);
+                            deref!(c1[t3]) = wa[idij - 1] * deref!(ch[t3]) + wa[idij] * deref!(ch[t3 - 1]);
                         }
+                        idij += 2;
                     }
                     t2 += ido;
                 }
+                is += ido;
             }
         }
     }
@@ -1339,8 +1371,15 @@ impl DrftLookup {
         }
     }
 
+    /// * Create the lookup of the size `n`, the transform of size 0 or 1 is inert: there's nothing to factorize, and the buffer is left untouched.
     pub fn new(n: usize) -> Self {
-        let mut ret =Self {
+        if n <= 1 {
+            return Self {
+                n,
+                ..Default::default()
+            };
+        }
+        let mut ret = Self {
             n,
             trigcache: vec![0.0; n * 3],
             splitcache: [0; 32],
@@ -1349,15 +1388,57 @@ impl DrftLookup {
         ret
     }
 
+    /// * The size of the transform
+    pub fn len(&self) -> usize {
+        self.n
+    }
+
+    /// * Is this an empty transform
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /// * Check if the buffer fits the transform
+    fn check_len(&self, data: &[f32]) -> Result<(), DrftError> {
+        if data.len() != self.n {
+            Err(DrftError::LengthMismatch {
+                expected: self.n,
+                got: data.len(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// * Forward real FFT in place, `data.len()` must be `n`.
+    pub fn forward_checked(&mut self, data: &mut [f32]) -> Result<(), DrftError> {
+        self.check_len(data)?;
+        self.forward(data);
+        Ok(())
+    }
+
+    /// * Backward real FFT in place, `data.len()` must be `n`. The output is not normalized, it's scaled by `n`.
+    pub fn backward_checked(&mut self, data: &mut [f32]) -> Result<(), DrftError> {
+        self.check_len(data)?;
+        self.backward(data);
+        Ok(())
+    }
+
+    /// * Forward real FFT in place, `data.len()` must be `n`, use `forward_checked()` to get the mismatch as an error.
+    /// * The transforms walk the buffer by raw pointers, so a buffer of the wrong length is left untouched in the release build.
     pub fn forward(&mut self, data: &mut [f32]) {
-        if self.n == 1 {
+        debug_assert_eq!(data.len(), self.n, "The DRFT size is {}, but the buffer has {} samples", self.n, data.len());
+        if self.n <= 1 || data.len() != self.n {
             return;
         }
         unsafe {Self::drftf1(self.n, data.as_mut_ptr(), self.trigcache.as_mut_ptr(), &self.trigcache[self.n..], &self.splitcache)};
     }
 
+    /// * Backward real FFT in place, `data.len()` must be `n`, use `backward_checked()` to get the mismatch as an error.
+    /// * The output is not normalized, it's scaled by `n`. The length is handled the same way as `forward()`.
     pub fn backward(&mut self, data: &mut [f32]) {
-        if self.n == 1 {
+        debug_assert_eq!(data.len(), self.n, "The DRFT size is {}, but the buffer has {} samples", self.n, data.len());
+        if self.n <= 1 || data.len() != self.n {
             return;
         }
         unsafe {Self::drftb1(self.n, data.as_mut_ptr(), self.trigcache.as_mut_ptr(), &self.trigcache[self.n..], &self.splitcache)};
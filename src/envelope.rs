@@ -1,7 +1,9 @@
 #![allow(dead_code)]
 use crate::*;
+use scales::*;
 use mdct::MdctLookup;
 use codec::VorbisInfo;
+use psy::VorbisInfoPsyGlobal;
 use copiablebuf::CopiableBuffer;
 
 pub const VE_PRE: usize = 16;
@@ -20,6 +22,9 @@ pub const VE_MAXSTRETCH: usize = 12;
 
 const INIT_STORAGE: usize = 128;
 
+/// * The window length of the detector
+const WIN_LENGTH: usize = 128;
+
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
 #[allow(non_snake_case)]
 pub struct VorbisEnvelopeFilterState {
@@ -46,6 +51,8 @@ pub struct VorbisEnvelopeLookup {
     pub minenergy: f32,
     pub mdct: MdctLookup,
     pub mdct_win: Vec<f32>,
+    /// * The working space of `mdct`, reused between the windows
+    pub mdct_scratch: Vec<f32>,
     pub band: [VorbisEnvelopeBand; VE_BANDS],
     pub filter: Vec<VorbisEnvelopeFilterState>,
     pub stretch: i32,
@@ -73,7 +80,7 @@ impl VorbisEnvelopeLookup {
             let n = b.end as usize;
             b.window.resize(n, 0.0);
             for i in 0..n {
-                let window = (i as f32 + 0.5) / n as f32 * PI;
+                let window = ((i as f32 + 0.5) / n as f32 * PI).sin();
                 b.window[i] = window;
                 b.total += window;
             }
@@ -83,13 +90,207 @@ impl VorbisEnvelopeLookup {
             ch,
             searchstep: 64,
             minenergy: psy_g.preecho_minenergy,
-            mdct_win: (0..INIT_STORAGE).map(|i|{let s = i as f32 / (INIT_STORAGE - 1) as f32; let s = s.sin(); s * s}).collect(),
+            mdct: MdctLookup::new(WIN_LENGTH),
+            mdct_win: (0..WIN_LENGTH).map(|i|{let s = i as f32 / (WIN_LENGTH - 1) as f32 * PI; let s = s.sin(); s * s}).collect(),
             band,
             filter: vec![VorbisEnvelopeFilterState::default(); VE_BANDS * ch as usize],
             mark: vec![0; INIT_STORAGE],
+            cursor: codec_setup.block_size[1] / 2,
             ..Default::default()
         }
     }
+
+    /// * Fairly straight threshold-by-band based pre-echo and post-echo detection of a window of one channel.
+    /// * Returns bit 1 for pre-echo, bit 2 for post-echo, bit 4 for resetting the stretch.
+    fn amp(&mut self, gi: &VorbisInfoPsyGlobal, data: &[f32], channel: usize) -> i32 {
+        let n = WIN_LENGTH;
+        let mut ret = 0;
+
+        // We want to have a 'minimum bar' for energy, else we're just basing blocks on quantization noise that outweighs the signal itself (for low power signals)
+        let min_v = self.minenergy;
+
+        // Stretch is used to gradually lengthen the number of windows considered previous-to-potential-trigger
+        let stretch = VE_MINSTRETCH.max((self.stretch / 2).max(0) as usize);
+        let penalty = (gi.stretch_penalty - (self.stretch / 2 - VE_MINSTRETCH as i32) as f32).clamp(0.0, gi.stretch_penalty.max(0.0));
+
+        // Window and transform
+        let windowed: Vec<f32> = data[..n].iter().zip(self.mdct_win.iter()).map(|(d, w)|d * w).collect();
+        let mut vec = vec![0.0f32; n / 2];
+        self.mdct.forward_with_scratch(&windowed, &mut vec, &mut self.mdct_scratch);
+
+        let filters = &mut self.filter[channel * VE_BANDS..(channel + 1) * VE_BANDS];
+
+        // Near-DC spreading function; this has nothing to do with psychoacoustics, just sidelobe leakage and window size
+        let mut decay = {
+            let filter = &mut filters[0];
+            let temp = vec[0] * vec[0] + 0.7 * vec[1] * vec[1] + 0.2 * vec[2] * vec[2];
+            let ptr = filter.nearptr;
+
+            // The accumulation is regularly refreshed from scratch to avoid floating point creep
+            let decay = if ptr == 0 {
+                filter.nearDC_acc = filter.nearDC_partialacc + temp;
+                filter.nearDC_partialacc = temp;
+                filter.nearDC_acc
+            } else {
+                filter.nearDC_acc += temp;
+                filter.nearDC_partialacc += temp;
+                filter.nearDC_acc
+            };
+            filter.nearDC_acc -= filter.nearDC[ptr];
+            filter.nearDC[ptr] = temp;
+
+            filter.nearptr += 1;
+            if filter.nearptr >= VE_NEARDC {
+                filter.nearptr = 0;
+            }
+            todB!(decay * (1.0 / (VE_NEARDC + 1) as f32)) * 0.5 - 15.0
+        };
+
+        // Perform spreading and limiting, also smooth the spectrum.
+        // Yes, the MDCT results in all real coefficients, but it still *behaves* like real/imaginary pairs
+        for i in (0..n / 2).step_by(2) {
+            let val = todB!(vec[i] * vec[i] + vec[i + 1] * vec[i + 1]) * 0.5;
+            vec[i >> 1] = val.max(decay).max(min_v);
+            decay -= 8.0;
+        }
+
+        // Perform pre-echo/post-echo triggering by band
+        for (j, (band, filter)) in self.band.iter().zip(filters.iter_mut()).enumerate() {
+            // Accumulate amplitude
+            let begin = band.begin as usize;
+            let acc = band.window.iter().enumerate().map(|(i, w)|vec[i + begin] * w).sum::<f32>() * band.total;
+
+            // Convert amplitude to delta
+            let this = filter.ampptr;
+            let mut p = (this + VE_AMP - 1) % VE_AMP;
+            let postmax = acc.max(filter.ampbuf[p]);
+            let postmin = acc.min(filter.ampbuf[p]);
+            let mut premax = -99999.0f32;
+            let mut premin = 99999.0f32;
+            for _ in 0..stretch {
+                p = (p + VE_AMP - 1) % VE_AMP;
+                premax = premax.max(filter.ampbuf[p]);
+                premin = premin.min(filter.ampbuf[p]);
+            }
+            let valmin = postmin - premin;
+            let valmax = postmax - premax;
+
+            filter.ampbuf[this] = acc;
+            filter.ampptr += 1;
+            if filter.ampptr >= VE_AMP {
+                filter.ampptr = 0;
+            }
+
+            // Look at min/max, decide trigger
+            if valmax > gi.preecho_thresh[j] + penalty {
+                ret |= 1;
+                ret |= 4;
+            }
+            if valmin < gi.postecho_thresh[j] - penalty {
+                ret |= 2;
+            }
+        }
+
+        ret
+    }
+
+    /// * Run the pre-echo detector over the newly submitted samples in `pcm[..][..pcm_current]` and mark the transients.
+    /// * `center_w` and `w` are the center and the block flag of the current block.
+    /// * Returns 1 if the next block can be long, 0 if the next block needs to be short for a transient, -1 if more samples are needed to decide.
+    pub fn mark_envelope(&mut self, gi: &VorbisInfoPsyGlobal, pcm: &[Vec<f32>], pcm_current: usize, center_w: usize, w: usize, block_size: [usize; 2]) -> i32 {
+        let searchstep = self.searchstep;
+        let first = (self.current / searchstep).max(0);
+        let last = pcm_current as i32 / searchstep - VE_WIN as i32;
+
+        // Make sure we have enough storage to match the PCM
+        let storage = (last + (VE_WIN + VE_POST) as i32).max(0) as usize;
+        if storage > self.mark.len() {
+            self.mark.resize(storage, 0);
+        }
+
+        for j in first..last {
+            let mut ret = 0;
+
+            self.stretch = (self.stretch + 1).min(VE_MAXSTRETCH as i32 * 2);
+
+            for (i, pcm) in pcm.iter().enumerate() {
+                let begin = (searchstep * j) as usize;
+                ret |= self.amp(gi, &pcm[begin..begin + WIN_LENGTH], i);
+            }
+
+            let j = j as usize;
+            self.mark[j + VE_POST] = 0;
+            if ret & 1 != 0 {
+                self.mark[j] = 1;
+                self.mark[j + 1] = 1;
+            }
+            if ret & 2 != 0 {
+                self.mark[j] = 1;
+                if j > 0 {
+                    self.mark[j - 1] = 1;
+                }
+            }
+            if ret & 4 != 0 {
+                self.stretch = -1;
+            }
+        }
+
+        self.current = last * searchstep;
+
+        let center_w = center_w as i32;
+        let test_w = center_w + (block_size[w] / 4 + block_size[1] / 2 + block_size[0] / 4) as i32;
+        let mut j = self.cursor;
+
+        // Account for post-echo working back one window
+        while j < self.current - searchstep {
+            if j >= test_w {
+                return 1;
+            }
+            self.cursor = j;
+            if self.mark[(j / searchstep) as usize] != 0 && j > center_w {
+                self.curmark = j;
+                return if j >= test_w {1} else {0};
+            }
+            j += searchstep;
+        }
+        -1
+    }
+
+    /// * Is there any transient marked inside of the current short block, then it's an impulse block.
+    pub fn is_impulse(&self, center_w: usize, lw: usize, w: usize, nw: usize, block_size: [usize; 2]) -> bool {
+        let center_w = center_w as i32;
+        let (mut begin_w, mut end_w) = (center_w - (block_size[w] / 4) as i32, center_w + (block_size[w] / 4) as i32);
+        if w != 0 {
+            begin_w -= (block_size[lw] / 4) as i32;
+            end_w += (block_size[nw] / 4) as i32;
+        } else {
+            begin_w -= (block_size[0] / 4) as i32;
+            end_w += (block_size[0] / 4) as i32;
+        }
+        if self.curmark >= begin_w && self.curmark < end_w {
+            return true;
+        }
+        let first = (begin_w / self.searchstep).max(0) as usize;
+        let last = ((end_w / self.searchstep).max(0) as usize).min(self.mark.len());
+        self.mark[first.min(last)..last].iter().any(|&m|m != 0)
+    }
+
+    /// * The PCM buffer was shifted by `shift` samples, shift the marks too.
+    pub fn shift(&mut self, shift: usize) {
+        // Adjust for placing marks ahead of `current`
+        let smallsize = ((self.current / self.searchstep) as usize + VE_POST).min(self.mark.len());
+        let smallshift = shift / self.searchstep as usize;
+        if smallshift < smallsize {
+            self.mark.copy_within(smallshift..smallsize, 0);
+        }
+
+        let shift = shift as i32;
+        self.current -= shift;
+        if self.curmark >= 0 {
+            self.curmark -= shift;
+        }
+        self.cursor -= shift;
+    }
 }
 
 impl Default for VorbisEnvelopeLookup {
@@ -100,6 +301,7 @@ impl Default for VorbisEnvelopeLookup {
             minenergy: 0.0,
             mdct: MdctLookup::default(),
             mdct_win: Vec::default(),
+            mdct_scratch: Vec::default(),
             band: [VorbisEnvelopeBand::default(); VE_BANDS],
             filter: Vec::default(),
             stretch: 0,
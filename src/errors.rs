@@ -0,0 +1,101 @@
+#![allow(dead_code)]
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+/// * The error of loading or packing the Vorbis headers and their components
+/// * Anything that isn't one of the specific failures is kept as `Io`.
+#[derive(Debug)]
+pub enum VorbisError {
+    /// * The header doesn't begin with the expected header type and the `vorbis` string
+    BadMagic {
+        expected: u8,
+        got: Vec<u8>,
+    },
+
+    /// * The codeword lengths specify an overpopulated Huffman tree
+    OverpopulatedTree,
+
+    /// * The codeword lengths specify an underpopulated Huffman tree
+    UnderpopulatedTree,
+
+    /// * The bitstream ended before the data was complete
+    UnexpectedEof,
+
+    /// * The floor type isn't 0 or 1
+    InvalidFloorType(i32),
+
+    /// * The residue type isn't 0, 1 or 2
+    InvalidResidueType(i32),
+
+    /// * The mapping type is reserved by the specification and can't be decoded, only the types of `VorbisMapping::supported_mapping_types()` are
+    UnsupportedMappingType(i32),
+
+    /// * The number of bits that was packed or loaded differs from the number it's accounted for
+    BitCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+
+    /// * Any other failure
+    Io(io::Error),
+}
+
+impl VorbisError {
+    /// * The `io::ErrorKind` this error would have as an `io::Error`
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::UnexpectedEof => io::ErrorKind::UnexpectedEof,
+            Self::UnsupportedMappingType(_) => io::ErrorKind::Unsupported,
+            Self::Io(e) => e.kind(),
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl Display for VorbisError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic{expected, got} => write!(f, "Expected a Vorbis header of type {expected}, got `{}`", String::from_utf8_lossy(got).escape_debug()),
+            Self::OverpopulatedTree => write!(f, "The lengths specify an overpopulated tree"),
+            Self::UnderpopulatedTree => write!(f, "The lengths specify an underpopulated tree"),
+            Self::UnexpectedEof => write!(f, "Unexpected end of the bitstream"),
+            Self::InvalidFloorType(t) => write!(f, "Invalid floor type {t}"),
+            Self::InvalidResidueType(t) => write!(f, "Invalid residue type {t}"),
+            Self::UnsupportedMappingType(t) => write!(f, "Unsupported mapping type {t}, the supported mapping types are {:?}", crate::mapping::VorbisMapping::supported_mapping_types()),
+            Self::BitCountMismatch{expected, actual} => write!(f, "Bit count mismatch: expected {expected} bits, got {actual} bits"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for VorbisError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VorbisError {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner|inner.is::<VorbisError>()) {
+            *e.into_inner().unwrap().downcast::<VorbisError>().unwrap()
+        } else if e.kind() == io::ErrorKind::UnexpectedEof {
+            Self::UnexpectedEof
+        } else {
+            Self::Io(e)
+        }
+    }
+}
+
+impl From<VorbisError> for io::Error {
+    fn from(e: VorbisError) -> Self {
+        match e {
+            VorbisError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use std::{
+    borrow::Borrow,
     fmt::{self, Debug, Formatter},
     io::{self, Write},
     rc::Rc,
@@ -7,13 +8,87 @@ use std::{
 
 use crate::*;
 use utils::*;
+use scales::*;
 use headers::VorbisSetupHeader;
+use codebook::CodeBook;
 use copiablebuf::CopiableBuffer;
 
 const VIF_POSIT: usize = 63;
 const VIF_CLASS: usize = 16;
 const VIF_PARTS: usize = 31;
 
+/// * The post value with this bit set is not used to render the floor1 curve
+pub const FLOOR1_UNUSED_POST: i32 = 0x8000;
+
+/// * Maps the floor1 curve values `0..=255` to the linear amplitudes, about 140 dB in range.
+#[allow(clippy::excessive_precision)]
+pub const FLOOR1_FROMDB_LOOKUP: [f32; 256] = [
+    1.0649863e-07, 1.1341951e-07, 1.2079015e-07, 1.2863977e-07,
+    1.3699951e-07, 1.4590251e-07, 1.5538408e-07, 1.6548181e-07,
+    1.7623575e-07, 1.8768854e-07, 1.9988560e-07, 2.1287529e-07,
+    2.2670913e-07, 2.4144197e-07, 2.5713222e-07, 2.7384212e-07,
+    2.9163793e-07, 3.1059020e-07, 3.3077410e-07, 3.5226967e-07,
+    3.7516214e-07, 3.9954228e-07, 4.2550679e-07, 4.5315862e-07,
+    4.8260742e-07, 5.1396997e-07, 5.4737063e-07, 5.8294186e-07,
+    6.2082471e-07, 6.6116939e-07, 7.0413590e-07, 7.4989462e-07,
+    7.9862700e-07, 8.5052628e-07, 9.0579827e-07, 9.6466214e-07,
+    1.0273513e-06, 1.0941144e-06, 1.1652161e-06, 1.2409384e-06,
+    1.3215816e-06, 1.4074654e-06, 1.4989304e-06, 1.5963394e-06,
+    1.7000785e-06, 1.8105592e-06, 1.9282195e-06, 2.0535261e-06,
+    2.1869758e-06, 2.3290978e-06, 2.4804557e-06, 2.6416496e-06,
+    2.8133189e-06, 2.9961442e-06, 3.1908506e-06, 3.3982100e-06,
+    3.6190448e-06, 3.8542307e-06, 4.1047004e-06, 4.3714469e-06,
+    4.6555282e-06, 4.9580706e-06, 5.2802740e-06, 5.6234159e-06,
+    5.9888571e-06, 6.3780468e-06, 6.7925282e-06, 7.2339450e-06,
+    7.7040474e-06, 8.2046998e-06, 8.7378875e-06, 9.3057246e-06,
+    9.9104630e-06, 1.0554501e-05, 1.1240392e-05, 1.1970856e-05,
+    1.2748789e-05, 1.3577277e-05, 1.4459605e-05, 1.5399272e-05,
+    1.6400003e-05, 1.7465768e-05, 1.8600792e-05, 1.9809576e-05,
+    2.1096914e-05, 2.2467910e-05, 2.3928002e-05, 2.5482978e-05,
+    2.7139005e-05, 2.8902651e-05, 3.0780908e-05, 3.2781225e-05,
+    3.4911533e-05, 3.7180281e-05, 3.9596465e-05, 4.2169667e-05,
+    4.4910089e-05, 4.7828600e-05, 5.0936772e-05, 5.4246930e-05,
+    5.7772201e-05, 6.1526564e-05, 6.5524907e-05, 6.9783084e-05,
+    7.4317982e-05, 7.9147583e-05, 8.4291039e-05, 8.9768746e-05,
+    9.5602425e-05, 1.0181521e-04, 1.0843173e-04, 1.1547824e-04,
+    1.2298267e-04, 1.3097477e-04, 1.3948625e-04, 1.4855085e-04,
+    1.5820452e-04, 1.6848554e-04, 1.7943468e-04, 1.9109536e-04,
+    2.0351381e-04, 2.1673929e-04, 2.3082423e-04, 2.4582449e-04,
+    2.6179955e-04, 2.7881276e-04, 2.9693158e-04, 3.1622787e-04,
+    3.3677813e-04, 3.5866387e-04, 3.8197187e-04, 4.0679456e-04,
+    4.3323036e-04, 4.6138411e-04, 4.9136744e-04, 5.2329927e-04,
+    5.5730620e-04, 5.9352310e-04, 6.3209357e-04, 6.7317057e-04,
+    7.1691699e-04, 7.6350629e-04, 8.1312323e-04, 8.6596456e-04,
+    9.2223982e-04, 9.8217215e-04, 1.0459992e-03, 1.1139742e-03,
+    1.1863665e-03, 1.2634632e-03, 1.3455702e-03, 1.4330129e-03,
+    1.5261382e-03, 1.6253152e-03, 1.7309374e-03, 1.8434234e-03,
+    1.9632195e-03, 2.0908005e-03, 2.2266725e-03, 2.3713743e-03,
+    2.5254795e-03, 2.6895994e-03, 2.8643847e-03, 3.0505286e-03,
+    3.2487691e-03, 3.4598924e-03, 3.6847357e-03, 3.9241906e-03,
+    4.1792066e-03, 4.4507950e-03, 4.7400327e-03, 5.0480668e-03,
+    5.3761186e-03, 5.7254890e-03, 6.0975636e-03, 6.4938176e-03,
+    6.9158224e-03, 7.3652515e-03, 7.8438871e-03, 8.3536270e-03,
+    8.8964928e-03, 9.4746370e-03, 1.0090352e-02, 1.0746080e-02,
+    1.1444421e-02, 1.2188144e-02, 1.2980198e-02, 1.3823725e-02,
+    1.4722068e-02, 1.5678791e-02, 1.6697687e-02, 1.7782797e-02,
+    1.8938423e-02, 2.0169149e-02, 2.1479853e-02, 2.2875735e-02,
+    2.4362329e-02, 2.5945531e-02, 2.7631618e-02, 2.9427276e-02,
+    3.1339626e-02, 3.3376251e-02, 3.5545228e-02, 3.7855157e-02,
+    4.0315199e-02, 4.2935107e-02, 4.5725272e-02, 4.8696758e-02,
+    5.1861348e-02, 5.5231590e-02, 5.8820850e-02, 6.2643360e-02,
+    6.6714279e-02, 7.1049748e-02, 7.5666961e-02, 8.0584227e-02,
+    8.5821044e-02, 9.1398178e-02, 9.7337747e-02, 1.0366330e-01,
+    1.1039993e-01, 1.1757434e-01, 1.2521498e-01, 1.3335215e-01,
+    1.4201813e-01, 1.5124727e-01, 1.6107616e-01, 1.7154380e-01,
+    1.8269168e-01, 1.9456402e-01, 2.0720788e-01, 2.2067342e-01,
+    2.3501402e-01, 2.5028656e-01, 2.6655159e-01, 2.8387361e-01,
+    3.0232132e-01, 3.2196786e-01, 3.4289114e-01, 3.6517414e-01,
+    3.8890521e-01, 4.1417847e-01, 4.4109412e-01, 4.6975890e-01,
+    5.0028647e-01, 5.3279791e-01, 5.6742212e-01, 6.0429640e-01,
+    6.4356699e-01, 6.8538959e-01, 7.2993007e-01, 7.7736504e-01,
+    8.2788260e-01, 8.8168307e-01, 9.3897980e-01, 1.0,
+];
+
 /// * The `VorbisFloor` for floor types
 #[derive(Debug, Clone, PartialEq)]
 #[allow(clippy::large_enum_variant)]
@@ -30,12 +105,12 @@ pub enum VorbisLookFloor {
 }
 
 impl VorbisFloor {
-    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> io::Result<VorbisFloor> {
+    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> Result<VorbisFloor, VorbisError> {
         let floor_type = read_bits!(bitreader, 16);
         match floor_type {
             0 => Ok(VorbisFloor0::load(bitreader, vorbis_info)?),
             1 => Ok(VorbisFloor1::load(bitreader, vorbis_info)?),
-            o => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid floor type {o}"))),
+            o => Err(VorbisError::InvalidFloorType(o)),
         }
     }
 
@@ -46,7 +121,7 @@ impl VorbisFloor {
         }
     }
 
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
         match self {
@@ -54,6 +129,14 @@ impl VorbisFloor {
             Self::Floor1(floor1) => floor1.pack(bitwriter),
         }
     }
+
+    /// * The number of bits `pack()` writes, counted without packing
+    pub fn packed_bits(&self) -> usize {
+        match self {
+            Self::Floor0(floor0) => floor0.packed_bits(),
+            Self::Floor1(floor1) => floor1.packed_bits(),
+        }
+    }
 }
 
 impl Default for VorbisFloor {
@@ -63,9 +146,10 @@ impl Default for VorbisFloor {
 }
 
 impl VorbisLookFloor {
-    pub fn look(floor: Rc<VorbisFloor>) -> VorbisLookFloor {
+    /// * `block_size` is the short and the long block size, floor 0 needs it to map the linear bins to the bark scale.
+    pub fn look(floor: Rc<VorbisFloor>, block_size: [i32; 2]) -> VorbisLookFloor {
         match *floor {
-            VorbisFloor::Floor0(ref floor0) => Self::Floor0(VorbisLookFloor0::look(floor0.clone())),
+            VorbisFloor::Floor0(ref floor0) => Self::Floor0(VorbisLookFloor0::look(floor0.clone(), block_size)),
             VorbisFloor::Floor1(ref floor1) => Self::Floor1(VorbisLookFloor1::look(floor1.clone())),
         }
     }
@@ -78,7 +162,40 @@ impl Default for VorbisLookFloor {
 }
 
 impl VorbisLookFloor {
-    // TODO
+    /// * Decode the floor of a channel from an audio packet and synthesize the curve of `n` bins, `n` is the half of the block size.
+    /// * Returns `None` if the floor is unused in this packet, the channel is silent then.
+    pub fn decode_curve<B>(&self, reader: &mut BitReader, books: &[B], n: usize) -> io::Result<Option<Vec<f32>>>
+    where
+        B: Borrow<CodeBook> {
+        let mut curve = vec![0.0f32; n];
+        if self.decode_curve_into(reader, books, &mut curve)? {
+            Ok(Some(curve))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// * Decode the floor of a channel from an audio packet and synthesize the curve into `curve`, the length of it is the half of the block size.
+    /// * Returns `false` if the floor is unused in this packet, the channel is silent then and `curve` is left untouched.
+    pub fn decode_curve_into<B>(&self, reader: &mut BitReader, books: &[B], curve: &mut [f32]) -> io::Result<bool>
+    where
+        B: Borrow<CodeBook> {
+        match self {
+            Self::Floor0(look) => {
+                let Some(lsp) = look.info.decode_lsp(look, reader, books)? else {
+                    return Ok(false);
+                };
+                look.info.inverse(look, &lsp, curve)?;
+            }
+            Self::Floor1(look) => {
+                let Some(posts) = look.info.decode_posts(look, reader, books)? else {
+                    return Ok(false);
+                };
+                look.info.inverse(look, &posts, curve);
+            }
+        }
+        Ok(true)
+    }
 }
 
 #[derive(Default, Clone, Copy, PartialEq)]
@@ -113,13 +230,13 @@ pub struct VorbisLookFloor0 {
 }
 
 impl VorbisFloor0 {
-    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> io::Result<VorbisFloor> {
+    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> Result<VorbisFloor, VorbisError> {
         let static_codebooks = &vorbis_info.static_codebooks;
         let mut ret = Self {
             order: read_bits!(bitreader, 8),
             rate: read_bits!(bitreader, 16),
             barkmap: read_bits!(bitreader, 16),
-            ampbits: read_bits!(bitreader, 8),
+            ampbits: read_bits!(bitreader, 6),
             ampdB: read_bits!(bitreader, 8),
             ..Default::default()
         };
@@ -129,23 +246,23 @@ impl VorbisFloor0 {
         || ret.rate < 1
         || ret.barkmap < 1
         || num_books < 1 {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid floor 0 data: \norder = {}\nrate = {}\nbarkmap = {}\nnum_books = {num_books}",
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid floor 0 data: \norder = {}\nrate = {}\nbarkmap = {}\nnum_books = {num_books}",
                 ret.order,
                 ret.rate,
                 ret.barkmap
-            )));
+            )).into());
         }
 
         for _ in 0..num_books {
             let book = read_bits!(bitreader, 8);
             if book < 0 || book as usize >= static_codebooks.len() {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book number: {book}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book number: {book}")).into());
             }
             if static_codebooks[book as usize].maptype == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "Invalid book maptype: 0".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid book maptype: 0".to_string()).into());
             }
             if static_codebooks[book as usize].dim < 1 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "Invalid book dimension: 0".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid book dimension: 0".to_string()).into());
             }
             ret.books.push(book);
         }
@@ -154,20 +271,166 @@ impl VorbisFloor0 {
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, _: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
-        // Floor0 never pack.
-        Ok(0)
+        let begin_bits = bitwriter.total_bits;
+        // floor type
+        write_bits!(bitwriter, 0, 16);
+        write_bits!(bitwriter, self.order, 8);
+        write_bits!(bitwriter, self.rate, 16);
+        write_bits!(bitwriter, self.barkmap, 16);
+        write_bits!(bitwriter, self.ampbits, 6);
+        write_bits!(bitwriter, self.ampdB, 8);
+        write_bits!(bitwriter, self.books.len().wrapping_sub(1), 4);
+        for book in self.books.iter() {
+            write_bits!(bitwriter, *book, 8);
+        }
+        Ok(bitwriter.total_bits - begin_bits)
+    }
+
+    /// * The number of bits `pack()` writes, counted without packing
+    pub fn packed_bits(&self) -> usize {
+        16 + 8 + 16 + 16 + 6 + 8 + 4 + self.books.len() * 8
+    }
+}
+
+impl VorbisFloor0 {
+    /// * Read the amplitude and the LSP coefficients of the floor from an audio packet.
+    /// * Returns `None` if the amplitude is zero, or the packet ends or has an invalid codeword before the floor is complete, the channel is silent then.
+    /// * The returned vector has `order` LSP coefficients, followed by the amplitude.
+    pub fn decode_lsp<B>(&self, look: &VorbisLookFloor0, reader: &mut BitReader, books: &[B]) -> io::Result<Option<Vec<f32>>>
+    where
+        B: Borrow<CodeBook> {
+        let ampraw = match reader.read(self.ampbits) {
+            Ok(ampraw) => ampraw,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if ampraw <= 0 {
+            return Ok(None);
+        }
+        let maxval = (1 << self.ampbits) - 1;
+        let amp = ampraw as f32 / maxval as f32 * self.ampdB as f32;
+        let booknum = match reader.read(ilog!(self.books.len())) {
+            Ok(booknum) => booknum as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if booknum >= self.books.len() {
+            return Ok(None);
+        }
+        let book_index = self.books[booknum];
+        let Some(book) = books.get(book_index as usize) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book index {book_index}, max books is {}", books.len())));
+        };
+        let book: &CodeBook = book.borrow();
+
+        let m = look.m as usize;
+        let mut lsp = vec![0.0f32; m + 1];
+        match book.decode_vector(reader, &mut lsp[..m]) {
+            Ok(()) => (),
+            // An invalid codeword, e.g. one cut off by the end of the packet, is `goto eop` as well
+            Err(e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut last = 0.0;
+        for chunk in lsp[..m].chunks_mut(book.dim as usize) {
+            for v in chunk.iter_mut() {
+                *v += last;
+            }
+            last = *chunk.last().unwrap();
+        }
+        lsp[m] = amp;
+        Ok(Some(lsp))
+    }
+
+    /// * Evaluate the LSP polynomial across the bark-mapped bins to get the floor curve, `out.len()` is the half of the block size.
+    /// * `lsp` is from `decode_lsp()`. The curve is written as linear amplitudes, multiply the residue with it to get the spectrum.
+    pub fn inverse(&self, look: &VorbisLookFloor0, lsp: &[f32], out: &mut [f32]) -> io::Result<()> {
+        let n = out.len();
+        let Some(map) = look.linearmap.iter().find(|map|map.len() == n + 1) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The floor 0 has no linear map for {n} bins, available: [{}]", format_array!(look.n))));
+        };
+        let m = look.m as usize;
+        if lsp.len() <= m {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Expected {} LSP coefficients and the amplitude, got {}", m, lsp.len())));
+        }
+        let amp = lsp[m];
+        let ampoffset = self.ampdB as f32;
+        let wdel = std::f32::consts::PI / look.ln as f32;
+        let lsp: Vec<f32> = lsp[..m].iter().map(|&v|2.0 * v.cos()).collect();
+
+        let mut i = 0;
+        while i < n {
+            let k = map[i];
+            let mut p = 0.5f32;
+            let mut q = 0.5f32;
+            let w = 2.0 * (wdel * k as f32).cos();
+            let mut j = 1;
+            while j < m {
+                q *= w - lsp[j - 1];
+                p *= w - lsp[j];
+                j += 2;
+            }
+            if j == m {
+                // odd order filter; slightly assymetric
+                // the last coefficient
+                q *= w - lsp[j - 1];
+                p = p * p * (4.0 - w * w);
+                q = q * q;
+            } else {
+                // even order filter; still symmetric
+                p = p * p * (2.0 - w);
+                q = q * q * (2.0 + w);
+            }
+
+            let q = fromdB!(amp / (p + q).sqrt() - ampoffset);
+            out[i] = q;
+            i += 1;
+            while map[i] == k {
+                out[i] = q;
+                i += 1;
+            }
+        }
+        Ok(())
     }
 }
 
 impl VorbisLookFloor0 {
-    pub fn look(floor0: Rc<VorbisFloor0>) -> VorbisLookFloor0 {
+    /// * `block_size` is the short and the long block size, the linear bins of both are mapped to the bark scale.
+    pub fn look(floor0: Rc<VorbisFloor0>, block_size: [i32; 2]) -> VorbisLookFloor0 {
+        let ln = floor0.barkmap;
+        let half_rate = floor0.rate as f32 / 2.0;
+
+        /* we choose a scaling constant so that:
+           floor(bark(rate/2-1)*C)=mapped-1
+           floor(bark(rate/2)*C)=mapped */
+        let scale = ln as f32 / toBARK!(half_rate);
+
+        /* the mapping from a linear scale to a smaller bark scale is
+           straightforward.  We do *not* make sure that the linear mapping
+           does not skip bark-scale bins; the decoder simply skips them and
+           the encoder may do what it wishes in filling them.  They're
+           necessary in some mapping combinations to keep the scale spacing
+           accurate */
+        let n = [block_size[0] / 2, block_size[1] / 2];
+        let linearmap = n.iter().map(|&n| {
+            let mut map: Vec<i32> = (0..n).map(|j| {
+                // bark numbers represent band edges
+                let val = (toBARK!(half_rate / n as f32 * j as f32) * scale).floor() as i32;
+                // guard against the approximation
+                val.min(ln - 1)
+            }).collect();
+            map.push(-1);
+            map
+        }).collect();
+
         VorbisLookFloor0 {
-            ln: floor0.barkmap,
+            ln,
             m: floor0.order,
-            linearmap: Vec::new(),
+            linearmap,
+            n,
             info: floor0.clone(),
             ..Default::default()
         }
@@ -247,6 +510,27 @@ pub struct VorbisFloor1 {
     pub n: i32,
 }
 
+/// * The least squares accumulators of the points between two neighboring posts, used by `VorbisFloor1::fit()`
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct LsfitAcc {
+    x0: i32,
+    x1: i32,
+
+    xa: i64,
+    ya: i64,
+    x2a: i64,
+    y2a: i64,
+    xya: i64,
+    an: i64,
+
+    xb: i64,
+    yb: i64,
+    x2b: i64,
+    y2b: i64,
+    xyb: i64,
+    bn: i64,
+}
+
 #[derive(Default, Clone, PartialEq)]
 pub struct VorbisLookFloor1 {
     sorted_index:  CopiableBuffer<i32, {VIF_POSIT + 2}>,
@@ -267,20 +551,29 @@ pub struct VorbisLookFloor1 {
 }
 
 impl VorbisFloor1 {
-    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> io::Result<VorbisFloor> {
+    /// * Load the floor 1 from the bitstream, the bad fields are errors rather than panics since the headers are untrusted input.
+    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> Result<VorbisFloor, VorbisError> {
         let static_codebooks = &vorbis_info.static_codebooks;
-        let mut ret = Self::default();
-
-        ret.partitions = read_bits!(bitreader, 5);
-        ret.partitions_class.resize(ret.partitions as usize, 0);
+        let partitions = read_bits!(bitreader, 5);
+        if partitions as usize > VIF_PARTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Too many partitions {partitions}, max is {VIF_PARTS}")).into());
+        }
+        let mut ret = Self {
+            partitions,
+            ..Default::default()
+        };
+        ret.partitions_class.try_resize(ret.partitions as usize, 0)?;
         for i in 0..ret.partitions_class.len() {
             ret.partitions_class[i] = read_bits!(bitreader, 4);
         }
-        let maxclass = ret.partitions_class.iter().copied().max().unwrap() as usize + 1;
-        ret.class_dim.resize(maxclass, 0);
-        ret.class_subs.resize(maxclass, 0);
-        ret.class_book.resize(maxclass, 0);
-        ret.class_subbook.resize(maxclass, CopiableBuffer::default());
+        let maxclass = ret.partitions_class.iter().map(|&class|class as usize + 1).max().unwrap_or(0);
+        if maxclass > VIF_CLASS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid partition class {}, max is {}", maxclass - 1, VIF_CLASS - 1)).into());
+        }
+        ret.class_dim.try_resize(maxclass, 0)?;
+        ret.class_subs.try_resize(maxclass, 0)?;
+        ret.class_book.try_resize(maxclass, 0)?;
+        ret.class_subbook.try_resize(maxclass, CopiableBuffer::default())?;
 
         for i in 0..maxclass {
             ret.class_dim[i] = read_bits!(bitreader, 3).wrapping_add(1);
@@ -289,14 +582,14 @@ impl VorbisFloor1 {
                 ret.class_book[i] = read_bits!(bitreader, 8);
             }
             if ret.class_book[i] as usize >= static_codebooks.len() {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid class book index {}, max books is {}", ret.class_book[i], static_codebooks.len())));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid class book index {}, max books is {}", ret.class_book[i], static_codebooks.len())).into());
             }
             let sublen = 1usize << ret.class_subs[i];
-            ret.class_subbook[i].resize(sublen, 0);
+            ret.class_subbook[i].try_resize(sublen, 0)?;
             for k in 0..sublen {
                 let subbook_index = read_bits!(bitreader, 8).wrapping_sub(1);
                 if subbook_index < -1 || subbook_index >= static_codebooks.len() as i32 {
-                    return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid class subbook index {subbook_index}, max books is {}", static_codebooks.len())));
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid class subbook index {subbook_index}, max books is {}", static_codebooks.len())).into());
                 }
                 ret.class_subbook[i][k] = subbook_index;
             }
@@ -309,36 +602,66 @@ impl VorbisFloor1 {
         let mut k = 0usize;
         let mut count = 0usize;
         for i in 0..ret.partitions_class.len() {
-            count += ret.class_dim[ret.partitions_class[i] as usize] as usize;
+            let class = ret.partitions_class[i] as usize;
+            let Some(&class_dim) = ret.class_dim.get(class) else {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid partition class {class}, there are {maxclass} classes")).into());
+            };
+            count += class_dim as usize;
             if count > VIF_POSIT {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid class dim sum {count}, max is {VIF_POSIT}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid class dim sum {count}, max is {VIF_POSIT}")).into());
             }
-            ret.postlist.resize(count + 2, 0);
+            ret.postlist.try_resize(count + 2, 0)?;
             while k < count {
                 let t = read_bits!(bitreader, rangebits);
                 if t < 0 || t >= maxrange {
-                    return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid value for postlist {t}")));
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid value for postlist {t}")).into());
                 }
                 ret.postlist[k + 2] = t;
                 k += 1;
             }
         }
+        ret.postlist.try_resize(count + 2, 0)?;
         ret.postlist[0] = 0;
         ret.postlist[1] = maxrange;
 
-        let mut checker = ret.postlist[..].to_vec();
+        ret.check_postlist()?;
+
+        Ok(VorbisFloor::Floor1(Rc::new(ret)))
+    }
+
+    /// * Check the posts of a floor that's built or modified by hand before packing it.
+    /// * The first two posts must be 0 and the range, the range must be a power of two, the other posts must be in the range without duplicates,
+    ///   and the number of the other posts must match the class dimensions of the partitions.
+    pub fn check_postlist(&self) -> io::Result<()> {
+        if self.postlist.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The postlist should have at least 2 posts, got {}", self.postlist.len())));
+        }
+        let maxrange = self.postlist[1];
+        if self.postlist[0] != 0 || !(1..=1 << 15).contains(&maxrange) || maxrange & (maxrange - 1) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The postlist should begin with 0 and the range of a power of two, got [{}]", format_array!(self.postlist))));
+        }
+        for &t in self.postlist.iter().skip(2) {
+            if t < 0 || t >= maxrange {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid value for postlist {t}")));
+            }
+        }
+        let count: usize = self.partitions_class.iter().map(|&class|self.class_dim.get(class as usize).copied().unwrap_or(0) as usize).sum();
+        if count + 2 != self.postlist.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The partitions have {count} posts, the postlist has {}", self.postlist.len() - 2)));
+        }
+
+        let mut checker = self.postlist[..].to_vec();
         checker.sort();
         for i in 1..checker.len() {
             if checker[i - 1] == checker[i] {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Bad postlist: [{}]", format_array!(ret.postlist))));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad postlist: [{}]", format_array!(self.postlist))));
             }
         }
-
-        Ok(VorbisFloor::Floor1(Rc::new(ret)))
+        Ok(())
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
@@ -350,7 +673,7 @@ impl VorbisFloor1 {
         for i in 0..self.partitions_class.len() {
             write_bits!(bitwriter, self.partitions_class[i], 4);
         }
-        let maxclass = self.partitions_class.iter().copied().max().unwrap() as usize + 1;
+        let maxclass = self.partitions_class.iter().map(|&class|class as usize + 1).max().unwrap_or(0);
         for i in 0..maxclass {
             write_bits!(bitwriter, self.class_dim[i].wrapping_sub(1), 3);
             write_bits!(bitwriter, self.class_subs[i], 2);
@@ -374,6 +697,600 @@ impl VorbisFloor1 {
         }
         Ok(bitwriter.total_bits - begin_bits)
     }
+
+    /// * The number of bits `pack()` writes, counted without packing
+    pub fn packed_bits(&self) -> usize {
+        let rangebits = ilog!(self.postlist[1] - 1) as usize;
+        let maxclass = self.partitions_class.iter().map(|&class|class as usize + 1).max().unwrap_or(0);
+        let classes: usize = (0..maxclass).map(|i| {
+            3 + 2 + if self.class_subs[i] != 0 {8} else {0} + self.class_subbook[i].len() * 8
+        }).sum();
+        let posts: usize = self.partitions_class.iter().map(|&class|self.class_dim[class as usize] as usize).sum();
+        16 + 5 + self.partitions_class.len() * 4 + classes + 2 + 4 + posts * rangebits
+    }
+
+    /// * Render a line segment of the floor1 curve from `(x0, y0)` to `(x1, y1)`, clipped to `d.len()`.
+    /// * Uses the integer Bresenham-like stepping from the spec, so the curve is the same as any other decoder.
+    fn render_line(x0: i32, x1: i32, y0: i32, y1: i32, d: &mut [f32]) {
+        let dy = y1 - y0;
+        let adx = x1 - x0;
+        let base = dy / adx;
+        let sy = if dy < 0 {base - 1} else {base + 1};
+        let ady = dy.abs() - (base * adx).abs();
+        let n = (d.len() as i32).min(x1);
+        let mut x = x0;
+        let mut y = y0;
+        let mut err = 0;
+
+        if x < n {
+            d[x as usize] = FLOOR1_FROMDB_LOOKUP[y as usize];
+        }
+        x += 1;
+        while x < n {
+            err += ady;
+            if err >= adx {
+                err -= adx;
+                y += sy;
+            } else {
+                y += base;
+            }
+            d[x as usize] = FLOOR1_FROMDB_LOOKUP[y as usize];
+            x += 1;
+        }
+    }
+
+    /// * Synthesize the floor curve from the decoded posts, `out.len()` is the half of the block size.
+    /// * `posts` are in the order of `postlist`, the ones with `FLOOR1_UNUSED_POST` set are skipped.
+    /// * The curve is written as linear amplitudes, multiply the residue with it to get the spectrum.
+    pub fn inverse(&self, look: &VorbisLookFloor1, posts: &[i32], out: &mut [f32]) {
+        let mult = self.mult;
+        let mut hx = 0;
+        let mut lx = 0;
+        let mut ly = (posts[0] * mult).clamp(0, 255);
+
+        for j in 1..look.posts {
+            let current = look.forward_index[j] as usize;
+            let hy = posts[current];
+            if hy & FLOOR1_UNUSED_POST == 0 {
+                hx = self.postlist[current];
+                let hy = (hy * mult).clamp(0, 255);
+                Self::render_line(lx, hx, ly, hy, out);
+                lx = hx;
+                ly = hy;
+            }
+        }
+        for v in out.iter_mut().skip(hx as usize) {
+            *v = FLOOR1_FROMDB_LOOKUP[ly as usize];
+        }
+    }
+
+    /// * Predict the value of a post on the line between its two neighbors
+    fn render_point(x0: i32, x1: i32, y0: i32, y1: i32, x: i32) -> i32 {
+        let y0 = y0 & 0x7fff;
+        let y1 = y1 & 0x7fff;
+        let dy = y1 - y0;
+        let adx = x1 - x0;
+        let err = dy.abs() * (x - x0);
+        let off = err / adx;
+        if dy < 0 {
+            y0 - off
+        } else {
+            y0 + off
+        }
+    }
+
+    /// * Read the posts of the floor from an audio packet, the result could be passed to `inverse()`.
+    /// * Returns `None` if the floor is unused in this packet, or the packet ends or has an invalid codeword before the floor is complete, the channel is silent then.
+    /// * The posts that aren't coded are predicted from their neighbors and marked with `FLOOR1_UNUSED_POST`.
+    pub fn decode_posts<B>(&self, look: &VorbisLookFloor1, reader: &mut BitReader, books: &[B]) -> io::Result<Option<Vec<i32>>>
+    where
+        B: Borrow<CodeBook> {
+        let get_book = |index: i32| -> io::Result<&CodeBook> {
+            match books.get(index as usize) {
+                Some(book) => Ok(book.borrow()),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book index {index}, max books is {}", books.len()))),
+            }
+        };
+        // Like `goto eop` in libvorbis, a codeword cut off by the end of the packet is invalid, and makes the channel silent
+        let eop_to_none = |e: io::Error| -> io::Result<Option<Vec<i32>>> {
+            if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) {
+                Ok(None)
+            } else {
+                Err(e)
+            }
+        };
+
+        match reader.read(1) {
+            Ok(1) => (),
+            Ok(_) => return Ok(None),
+            Err(e) => return eop_to_none(e),
+        }
+
+        let quant_q = look.quant_q;
+        let mut fit_value = vec![0i32; look.posts];
+        let postbits = ilog!(quant_q - 1);
+        for value in fit_value.iter_mut().take(2) {
+            match reader.read(postbits) {
+                Ok(v) => *value = v,
+                Err(e) => return eop_to_none(e),
+            }
+        }
+
+        // partition by partition
+        let mut j = 2usize;
+        for i in 0..self.partitions as usize {
+            let class = self.partitions_class[i] as usize;
+            let cdim = self.class_dim[class] as usize;
+            let csubbits = self.class_subs[class];
+            let csub = 1 << csubbits;
+            let mut cval = 0;
+
+            // decode the partition's first stage cascade value
+            if csubbits != 0 {
+                match get_book(self.class_book[class])?.decode_scalar(reader) {
+                    Ok(v) => cval = v,
+                    Err(e) => return eop_to_none(e),
+                }
+            }
+
+            for k in 0..cdim {
+                let book = self.class_subbook[class][(cval & (csub - 1)) as usize];
+                cval >>= csubbits;
+                fit_value[j + k] = if book >= 0 {
+                    match get_book(book)?.decode_scalar(reader) {
+                        Ok(v) => v,
+                        Err(e) => return eop_to_none(e),
+                    }
+                } else {
+                    0
+                };
+            }
+            j += cdim;
+        }
+
+        // unwrap positive values and reconsitute via linear interpolation
+        for i in 2..look.posts {
+            let lo = look.loneighbor[i - 2] as usize;
+            let hi = look.hineighbor[i - 2] as usize;
+            let predicted = Self::render_point(self.postlist[lo], self.postlist[hi], fit_value[lo], fit_value[hi], self.postlist[i]);
+            let hiroom = quant_q - predicted;
+            let loroom = predicted;
+            let room = hiroom.min(loroom) << 1;
+            let mut val = fit_value[i];
+
+            if val != 0 {
+                if val >= room {
+                    if hiroom > loroom {
+                        val -= loroom;
+                    } else {
+                        val = -1 - (val - hiroom);
+                    }
+                } else if val & 1 != 0 {
+                    val = -((val + 1) >> 1);
+                } else {
+                    val >>= 1;
+                }
+
+                fit_value[i] = (val + predicted) & 0x7fff;
+                fit_value[lo] &= 0x7fff;
+                fit_value[hi] &= 0x7fff;
+            } else {
+                fit_value[i] = predicted | FLOOR1_UNUSED_POST;
+            }
+        }
+
+        Ok(Some(fit_value))
+    }
+
+    /// * Quantize a dB value of the masking curve to the `0..=1023` floor1 fitting scale
+    fn db_quant(x: f32) -> i32 {
+        ((x * 7.3142857 + 1023.5) as i32).clamp(0, 1023)
+    }
+
+    /// * Collect the points between two posts for the least squares line fit.
+    /// * The points where the spectrum is above the mask (minus `twofitatten`) go to the `a` set, others to the `b` set.
+    fn accumulate_fit(&self, logmask: &[f32], logmdct: &[f32], x0: i32, x1: i32, n: i32) -> (LsfitAcc, i32) {
+        let mut acc = LsfitAcc {
+            x0,
+            x1,
+            ..Default::default()
+        };
+        let x1 = x1.min(n - 1);
+        for i in x0..=x1 {
+            let quantized = Self::db_quant(logmask[i as usize]) as i64;
+            if quantized != 0 {
+                let x = i as i64;
+                if logmdct[i as usize] + self.twofitatten >= logmask[i as usize] {
+                    acc.xa += x;
+                    acc.ya += quantized;
+                    acc.x2a += x * x;
+                    acc.y2a += quantized * quantized;
+                    acc.xya += x * quantized;
+                    acc.an += 1;
+                } else {
+                    acc.xb += x;
+                    acc.yb += quantized;
+                    acc.x2b += x * x;
+                    acc.y2b += quantized * quantized;
+                    acc.xyb += x * quantized;
+                    acc.bn += 1;
+                }
+            }
+        }
+        let na = acc.an as i32;
+        (acc, na)
+    }
+
+    /// * Fit a line over the accumulated ranges, `y0` and `y1` are the fixed ends if they are not negative.
+    /// * Returns `false` if the line couldn't be fitted, `y0` and `y1` are zeroed then.
+    fn fit_line(&self, fits: &[LsfitAcc], y0: &mut i32, y1: &mut i32) -> bool {
+        let mut xb = 0.0f64;
+        let mut yb = 0.0f64;
+        let mut x2b = 0.0f64;
+        let mut xyb = 0.0f64;
+        let mut bn = 0.0f64;
+        let x0 = fits[0].x0 as f64;
+        let x1 = fits[fits.len() - 1].x1 as f64;
+
+        for fit in fits.iter() {
+            let weight = (fit.bn + fit.an) as f64 * self.twofitweight as f64 / (fit.an + 1) as f64 + 1.0;
+            xb += fit.xb as f64 + fit.xa as f64 * weight;
+            yb += fit.yb as f64 + fit.ya as f64 * weight;
+            x2b += fit.x2b as f64 + fit.x2a as f64 * weight;
+            xyb += fit.xyb as f64 + fit.xya as f64 * weight;
+            bn += fit.bn as f64 + fit.an as f64 * weight;
+        }
+
+        for (x, y) in [(x0, *y0), (x1, *y1)] {
+            if y >= 0 {
+                let y = y as f64;
+                xb += x;
+                yb += y;
+                x2b += x * x;
+                xyb += y * x;
+                bn += 1.0;
+            }
+        }
+
+        let denom = bn * x2b - xb * xb;
+        if denom > 0.0 {
+            let a = (yb * x2b - xyb * xb) / denom;
+            let b = (bn * xyb - xb * yb) / denom;
+            *y0 = ((a + b * x0).round_ties_even() as i32).clamp(0, 1023);
+            *y1 = ((a + b * x1).round_ties_even() as i32).clamp(0, 1023);
+            true
+        } else {
+            *y0 = 0;
+            *y1 = 0;
+            false
+        }
+    }
+
+    /// * Walk the line from `(x0, y0)` to `(x1, y1)` and check whether it stays close enough to the mask.
+    /// * Returns `true` if the error is out of bounds and the range should be split.
+    fn inspect_error(&self, x0: i32, x1: i32, y0: i32, y1: i32, logmask: &[f32], logmdct: &[f32]) -> bool {
+        let dy = y1 - y0;
+        let adx = x1 - x0;
+        let base = dy / adx;
+        let sy = if dy < 0 {base - 1} else {base + 1};
+        let ady = dy.abs() - (base * adx).abs();
+        let mut x = x0;
+        let mut y = y0;
+        let mut err = 0;
+        let mut val = Self::db_quant(logmask[x as usize]);
+        let mut mse = ((y - val) * (y - val)) as f32;
+        let mut n = 1;
+
+        if logmdct[x as usize] + self.twofitatten >= logmask[x as usize] {
+            if y as f32 + self.maxover < val as f32 {return true;}
+            if y as f32 - self.maxunder > val as f32 {return true;}
+        }
+
+        x += 1;
+        while x < x1 {
+            err += ady;
+            if err >= adx {
+                err -= adx;
+                y += sy;
+            } else {
+                y += base;
+            }
+
+            val = Self::db_quant(logmask[x as usize]);
+            mse += ((y - val) * (y - val)) as f32;
+            n += 1;
+            if logmdct[x as usize] + self.twofitatten >= logmask[x as usize] && val != 0 {
+                if y as f32 + self.maxover < val as f32 {return true;}
+                if y as f32 - self.maxunder > val as f32 {return true;}
+            }
+            x += 1;
+        }
+
+        let n = n as f32;
+        if self.maxover * self.maxover / n > self.maxerr {return false;}
+        if self.maxunder * self.maxunder / n > self.maxerr {return false;}
+        mse / n > self.maxerr
+    }
+
+    /// * Fit the floor curve to the spectrum for encoding, `logmdct` and `logmask` are the dB spectrum and the masking curve of the half block.
+    /// * This is the greedy line splitting of `libvorbis`, the posts are in the order of `postlist` on the `0..=1023` fitting scale.
+    /// * The posts that could be predicted from their neighbors are marked with `FLOOR1_UNUSED_POST`.
+    /// * Returns `None` if nothing in the spectrum is above the mask, the channel is silent then.
+    pub fn fit(&self, look: &VorbisLookFloor1, logmdct: &[f32], logmask: &[f32]) -> Option<Vec<i32>> {
+        const UNUSED: i32 = -200;
+        let n = look.n;
+        let posts = look.posts;
+        let mut fit_value_a = vec![UNUSED; posts];
+        let mut fit_value_b = vec![UNUSED; posts];
+        let mut loneighbor = vec![0usize; posts];
+        let mut hineighbor = vec![1usize; posts];
+        let mut memo = vec![usize::MAX; posts];
+
+        let post_y = |a: &[i32], b: &[i32], pos: usize| -> i32 {
+            if a[pos] < 0 {
+                b[pos]
+            } else if b[pos] < 0 {
+                a[pos]
+            } else {
+                (a[pos] + b[pos]) >> 1
+            }
+        };
+
+        // quantize the relevant floor points and collect them into line fit structures (one per minimal division)
+        let mut nonzero = 0;
+        let mut fits = Vec::<LsfitAcc>::with_capacity(posts - 1);
+        for i in 0..(posts - 1) {
+            let (acc, na) = self.accumulate_fit(logmask, logmdct, look.sorted_index[i], look.sorted_index[i + 1], n);
+            fits.push(acc);
+            nonzero += na;
+        }
+        if nonzero == 0 {
+            return None;
+        }
+
+        // start by fitting the implicit base case
+        let mut y0 = UNUSED;
+        let mut y1 = UNUSED;
+        self.fit_line(&fits, &mut y0, &mut y1);
+        fit_value_a[0] = y0;
+        fit_value_b[0] = y0;
+        fit_value_a[1] = y1;
+        fit_value_b[1] = y1;
+
+        // progressive splitting, greedy and not optimal, but simple and close enough to the best answer
+        for i in 2..posts {
+            let sortpos = look.reverse_index[i] as usize;
+            let ln = loneighbor[sortpos];
+            let hn = hineighbor[sortpos];
+
+            // eliminate repeat searches of a particular range with a memo
+            if memo[ln] == hn {
+                continue;
+            }
+            memo[ln] = hn;
+
+            let lsortpos = look.reverse_index[ln] as usize;
+            let hsortpos = look.reverse_index[hn] as usize;
+            let lx = self.postlist[ln];
+            let hx = self.postlist[hn];
+            let ly = post_y(&fit_value_a, &fit_value_b, ln);
+            let hy = post_y(&fit_value_a, &fit_value_b, hn);
+
+            if !self.inspect_error(lx, hx, ly, hy, logmask, logmdct) {
+                fit_value_a[i] = UNUSED;
+                fit_value_b[i] = UNUSED;
+                continue;
+            }
+
+            // outside error bounds, split it
+            let mut ly0 = UNUSED;
+            let mut ly1 = UNUSED;
+            let mut hy0 = UNUSED;
+            let mut hy1 = UNUSED;
+            let ok0 = self.fit_line(&fits[lsortpos..sortpos], &mut ly0, &mut ly1);
+            let ok1 = self.fit_line(&fits[sortpos..hsortpos], &mut hy0, &mut hy1);
+
+            if !ok0 {
+                ly0 = ly;
+                ly1 = hy0;
+            }
+            if !ok1 {
+                hy0 = ly1;
+                hy1 = hy;
+            }
+
+            if !ok0 && !ok1 {
+                fit_value_a[i] = UNUSED;
+                fit_value_b[i] = UNUSED;
+            } else {
+                // store new edge values
+                fit_value_b[ln] = ly0;
+                if ln == 0 {
+                    fit_value_a[ln] = ly0;
+                }
+                fit_value_a[i] = ly1;
+                fit_value_b[i] = hy0;
+                fit_value_a[hn] = hy1;
+                if hn == 1 {
+                    fit_value_b[hn] = hy1;
+                }
+
+                if ly1 >= 0 || hy0 >= 0 {
+                    // store new neighbor values
+                    for j in (0..sortpos).rev() {
+                        if hineighbor[j] != hn {
+                            break;
+                        }
+                        hineighbor[j] = i;
+                    }
+                    for lo in loneighbor.iter_mut().skip(sortpos + 1) {
+                        if *lo != ln {
+                            break;
+                        }
+                        *lo = i;
+                    }
+                }
+            }
+        }
+
+        let mut output = vec![0i32; posts];
+        output[0] = post_y(&fit_value_a, &fit_value_b, 0);
+        output[1] = post_y(&fit_value_a, &fit_value_b, 1);
+
+        // fill in posts marked as not using a fit, they are unused when encoding unless the curve interpolation forces them into use
+        for i in 2..posts {
+            let ln = look.loneighbor[i - 2] as usize;
+            let hn = look.hineighbor[i - 2] as usize;
+            let x0 = self.postlist[ln];
+            let x1 = self.postlist[hn];
+            let predicted = Self::render_point(x0, x1, output[ln], output[hn], self.postlist[i]);
+            let vx = post_y(&fit_value_a, &fit_value_b, i);
+
+            output[i] = if vx >= 0 && predicted != vx {
+                vx
+            } else {
+                predicted | FLOOR1_UNUSED_POST
+            };
+        }
+
+        Some(output)
+    }
+
+    /// * Interpolate between two fits of the same floor, `del` is the weight of `b` out of `65536`.
+    /// * The bitrate managed streams use it to fill the packet blobs between the fits of the different noise offsets.
+    pub fn interpolate_fit(a: &[i32], b: &[i32], del: i32) -> Vec<i32> {
+        a.iter().zip(b.iter()).map(|(&a, &b)| {
+            let value = ((65536 - del) * (a & 0x7fff) + del * (b & 0x7fff) + 32768) >> 16;
+            if a & FLOOR1_UNUSED_POST != 0 && b & FLOOR1_UNUSED_POST != 0 {
+                value | FLOOR1_UNUSED_POST
+            } else {
+                value
+            }
+        }).collect()
+    }
+
+    /// * Write the posts from `fit()` into an audio packet, `None` marks the floor as unused in this packet.
+    /// * The posts are quantized to the scale of `mult`, then each one is coded as the difference from the prediction of its neighbors.
+    /// * Returns the quantized posts the same as `decode_posts()` reads them back, pass them to `inverse()` to get the curve the decoder gets.
+    pub fn encode<W, B>(&self, look: &VorbisLookFloor1, writer: &mut BitWriter<W>, books: &[B], posts: Option<&[i32]>) -> io::Result<Option<Vec<i32>>>
+    where
+        W: Write,
+        B: Borrow<CodeBook> {
+        let get_book = |index: i32| -> io::Result<&CodeBook> {
+            match books.get(index as usize) {
+                Some(book) => Ok(book.borrow()),
+                None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid book index {index}, max books is {}", books.len()))),
+            }
+        };
+
+        let Some(posts) = posts else {
+            writer.write(0, 1)?;
+            return Ok(None);
+        };
+        if posts.len() != look.posts {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The floor has {} posts, got {}", look.posts, posts.len())));
+        }
+
+        // quantize values to multiplier spec
+        let quant_q = look.quant_q;
+        let mut post: Vec<i32> = posts.iter().map(|&value| {
+            let quantized = match self.mult {
+                1 => (value & 0x7fff) >> 2,
+                2 => (value & 0x7fff) >> 3,
+                3 => (value & 0x7fff) / 12,
+                _ => (value & 0x7fff) >> 4,
+            };
+            quantized | (value & FLOOR1_UNUSED_POST)
+        }).collect();
+
+        // find prediction values for each post and subtract them
+        let mut out = vec![0i32; look.posts];
+        out[0] = post[0];
+        out[1] = post[1];
+        for i in 2..look.posts {
+            let lo = look.loneighbor[i - 2] as usize;
+            let hi = look.hineighbor[i - 2] as usize;
+            let predicted = Self::render_point(self.postlist[lo], self.postlist[hi], post[lo], post[hi], self.postlist[i]);
+
+            if post[i] & FLOOR1_UNUSED_POST != 0 || predicted == post[i] {
+                // in case there was roundoff jitter in interpolation
+                post[i] = predicted | FLOOR1_UNUSED_POST;
+                out[i] = 0;
+            } else {
+                let headroom = (quant_q - predicted).min(predicted);
+                let val = post[i] - predicted;
+
+                /* at this point the 'deviation' value is in the range +/- max
+                   range, but the real, unique range can always be mapped to
+                   only [0-maxrange).  So we want to wrap the deviation into
+                   this limited range, but do it in the way that least screws
+                   an essentially gaussian probability distribution. */
+                out[i] = if val < 0 {
+                    if val < -headroom {
+                        headroom - val - 1
+                    } else {
+                        -1 - (val << 1)
+                    }
+                } else if val >= headroom {
+                    val + headroom
+                } else {
+                    val << 1
+                };
+                post[lo] &= 0x7fff;
+                post[hi] &= 0x7fff;
+            }
+        }
+
+        // we have everything we need. pack it out
+        writer.write(1, 1)?;
+        let postbits = ilog!(quant_q - 1);
+        writer.write(out[0] as u32, postbits)?;
+        writer.write(out[1] as u32, postbits)?;
+
+        // partition by partition
+        let mut j = 2usize;
+        for i in 0..self.partitions as usize {
+            let class = self.partitions_class[i] as usize;
+            let cdim = self.class_dim[class] as usize;
+            let csubbits = self.class_subs[class];
+            let csub = 1usize << csubbits;
+            let mut bookas = [0usize; 8];
+
+            // generate the partition's first stage cascade value
+            if csubbits != 0 {
+                let mut maxval = [0i32; 8];
+                for (k, maxval) in maxval.iter_mut().enumerate().take(csub) {
+                    let booknum = self.class_subbook[class][k];
+                    *maxval = if booknum < 0 {
+                        1
+                    } else {
+                        get_book(booknum)?.entries
+                    };
+                }
+                let mut cval = 0;
+                let mut cshift = 0;
+                for k in 0..cdim {
+                    if let Some(l) = maxval[..csub].iter().position(|&maxval| out[j + k] < maxval) {
+                        bookas[k] = l;
+                    }
+                    cval |= (bookas[k] as i32) << cshift;
+                    cshift += csubbits;
+                }
+                get_book(self.class_book[class])?.encode(cval, writer)?;
+            }
+
+            // write post values
+            for k in 0..cdim {
+                let book = self.class_subbook[class][bookas[k]];
+                if book >= 0 {
+                    get_book(book)?.encode(out[j + k], writer)?;
+                }
+            }
+            j += cdim;
+        }
+
+        Ok(Some(post))
+    }
 }
 
 impl VorbisLookFloor1 {
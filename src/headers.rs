@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use std::{
-    fmt::Debug,
+    fmt::{self, Debug, Display, Formatter},
     io::{self, Read, Write, Seek},
 };
 
@@ -29,10 +29,10 @@ pub struct VorbisIdentificationHeader {
 
 impl VorbisIdentificationHeader {
     /// * Unpack from a bitstream
-    pub fn load(bitreader: &mut BitReader) -> io::Result<Self> {
+    pub fn load(bitreader: &mut BitReader) -> Result<Self, VorbisError> {
         let ident = read_slice!(bitreader, 7);
         if ident != b"\x01vorbis" {
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Not a Vorbis identification header, the header type is {}, the string is {}", ident[0], String::from_utf8_lossy(&ident[1..]))))
+            Err(VorbisError::BadMagic{expected: 0x01, got: ident})
         } else {
             let version = read_bits!(bitreader, 32);
             let channels = read_bits!(bitreader, 8);
@@ -42,43 +42,69 @@ impl VorbisIdentificationHeader {
             let bitrate_lower = read_bits!(bitreader, 32);
             let bs_1 = read_bits!(bitreader, 4);
             let bs_2 = read_bits!(bitreader, 4);
+            if !(6..=13).contains(&bs_1) || !(6..=13).contains(&bs_2) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad block size exponents {bs_1} and {bs_2}, they must be in 6..=13")).into());
+            }
             let block_size = [1 << bs_1, 1 << bs_2];
             let end_of_packet = read_bits!(bitreader, 1) & 1 == 1;
-            if sample_rate < 1
-            || channels < 1
-            || block_size[0] < 64
-            || block_size[1] < block_size[0]
-            || block_size[1] > 8192
-            || !end_of_packet {
-                Err(io::Error::new(io::ErrorKind::InvalidData, "Bad Vorbis identification header.".to_string()))
-            } else {
-                Ok(Self {
-                    version,
-                    channels,
-                    sample_rate,
-                    bitrate_upper,
-                    bitrate_nominal,
-                    bitrate_lower,
-                    block_size,
-                })
+            if !end_of_packet {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad Vorbis identification header.".to_string()).into());
             }
+            let ret = Self {
+                version,
+                channels,
+                sample_rate,
+                bitrate_upper,
+                bitrate_nominal,
+                bitrate_lower,
+                block_size,
+            };
+            ret.validate()?;
+            Ok(ret)
+        }
+    }
+
+    /// * Create an identification header for encoding, the bitrates are left unset.
+    /// * The block sizes must be powers of two and `64 <= block_size_short <= block_size_long <= 8192`.
+    pub fn new(channels: i32, sample_rate: i32, block_size_short: i32, block_size_long: i32) -> io::Result<Self> {
+        let ret = Self {
+            channels,
+            sample_rate,
+            block_size: [block_size_short, block_size_long],
+            ..Default::default()
+        };
+        ret.validate()?;
+        Ok(ret)
+    }
+
+    /// * Check the fields the same way `load()` does
+    fn validate(&self) -> io::Result<()> {
+        if self.sample_rate < 1
+        || !(1..=255).contains(&self.channels)
+        || self.block_size[0] < 64
+        || self.block_size[1] < self.block_size[0]
+        || self.block_size[1] > 8192
+        || self.block_size.iter().any(|&size|size.count_ones() != 1) {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "Bad Vorbis identification header.".to_string()))
+        } else {
+            Ok(())
         }
     }
 
     /// * Unpack from a slice
-    pub fn load_from_slice(data: &[u8]) -> io::Result<Self> {
+    pub fn load_from_slice(data: &[u8]) -> Result<Self, VorbisError> {
         let mut bitreader = BitReader::new(data);
         Self::load(&mut bitreader)
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
         let bs_1: u8 = ilog!(self.block_size[0] - 1);
         let bs_2: u8 = ilog!(self.block_size[1] - 1);
         let begin_bits = bitwriter.total_bits;
-        write_slice!(bitwriter, b"\x01vorbis");
+        write_bytes!(bitwriter, b"\x01vorbis");
         write_bits!(bitwriter, self.version, 32);
         write_bits!(bitwriter, self.channels, 8);
         write_bits!(bitwriter, self.sample_rate, 32);
@@ -92,6 +118,19 @@ impl VorbisIdentificationHeader {
     }
 }
 
+impl Display for VorbisIdentificationHeader {
+    /// * One line of the stream format, the bitrates that aren't set are shown as `-`
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let bitrate = |b: i32| if b > 0 {b.to_string()} else {"-".to_string()};
+        write!(f, "Vorbis {}: {} Hz, {} channels, block sizes {}/{}, bitrate upper/nominal/lower {}/{}/{}",
+            self.version, self.sample_rate, self.channels, self.block_size[0], self.block_size[1],
+            bitrate(self.bitrate_upper), bitrate(self.bitrate_nominal), bitrate(self.bitrate_lower))
+    }
+}
+
+/// * The vendor string that identifies this crate as the encoder
+pub const VENDOR_STRING: &str = concat!("revorbis-rs ", env!("CARGO_PKG_VERSION"));
+
 /// * The `VorbisCommentHeader` is the Vorbis comment header, the second header
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct VorbisCommentHeader {
@@ -100,32 +139,65 @@ pub struct VorbisCommentHeader {
 }
 
 impl VorbisCommentHeader {
-    /// * Unpack from a bitstream
-    pub fn load(bitreader: &mut BitReader, text_codecs: &StringCodecMaps) -> io::Result<Self> {
+    /// * Create an empty comment header for encoding, the vendor string identifies this crate.
+    pub fn new() -> Self {
+        Self {
+            comments: Vec::new(),
+            vendor: VENDOR_STRING.to_string(),
+        }
+    }
+
+    /// * Set the vendor string that will be packed into the comment header
+    pub fn set_vendor(&mut self, vendor: &str) {
+        self.vendor = vendor.to_string();
+    }
+
+    /// * Remove all of the comments of the field name, the field name is case-insensitive. Returns how many were removed.
+    pub fn remove_tag(&mut self, field_name: &str) -> usize {
+        let count = self.comments.len();
+        self.comments.retain(|comment| match comment.split_once('=') {
+            Some((name, _)) => !name.eq_ignore_ascii_case(field_name),
+            None => true,
+        });
+        count - self.comments.len()
+    }
+
+    /// * The default limit of the size of the vendor string or each comment for `load()`, in bytes
+    pub const DEFAULT_MAX_COMMENT_SIZE: usize = 8 << 20;
+
+    /// * Unpack from a bitstream, the vendor string and each comment are limited to `DEFAULT_MAX_COMMENT_SIZE` bytes.
+    pub fn load(bitreader: &mut BitReader, text_codecs: &StringCodecMaps) -> Result<Self, VorbisError> {
+        Self::load_with_limit(bitreader, text_codecs, Self::DEFAULT_MAX_COMMENT_SIZE)
+    }
+
+    /// * Unpack from a bitstream, the vendor string and each comment are limited to `max_comment_size` bytes.
+    /// * Raise the limit for the big embedded pictures like `METADATA_BLOCK_PICTURE`, the lengths are checked before reading the strings.
+    pub fn load_with_limit(bitreader: &mut BitReader, text_codecs: &StringCodecMaps, max_comment_size: usize) -> Result<Self, VorbisError> {
         let ident = read_slice!(bitreader, 7);
         if ident != b"\x03vorbis" {
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Not a Vorbis comment header, the header type is {}, the string is {}", ident[0], String::from_utf8_lossy(&ident[1..]))))
+            Err(VorbisError::BadMagic{expected: 0x03, got: ident})
         } else {
             let vendor_len = read_bits!(bitreader, 32);
-            if vendor_len < 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Bad vendor string length {vendor_len}")));
+            if vendor_len < 0 || vendor_len as usize > max_comment_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad vendor string length {vendor_len}, the limit is {max_comment_size}")).into());
             }
             let vendor = read_string!(bitreader, vendor_len as usize, text_codecs);
             let num_comments = read_bits!(bitreader, 32);
             if num_comments < 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Bad number of comments {num_comments}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad number of comments {num_comments}")).into());
             }
-            let mut comments = Vec::<String>::with_capacity(num_comments as usize);
+            // Each comment takes 32 bits for the length at least, don't reserve for the comments that can't be there
+            let mut comments = Vec::<String>::with_capacity((num_comments as usize).min(bitreader.remaining_bits() / 32));
             for _ in 0..num_comments {
                 let comment_len = read_bits!(bitreader, 32);
-                if comment_len < 0 {
-                    return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Bad comment string length {vendor_len}")));
+                if comment_len < 0 || comment_len as usize > max_comment_size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad comment string length {comment_len}, the limit is {max_comment_size}")).into());
                 }
                 comments.push(read_string!(bitreader, comment_len as usize, text_codecs));
             }
             let end_of_packet = read_bits!(bitreader, 1) & 1 == 1;
             if !end_of_packet {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("End of packet flag == {end_of_packet}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("End of packet flag == {end_of_packet}")).into());
             }
             Ok(Self{
                 comments,
@@ -135,7 +207,7 @@ impl VorbisCommentHeader {
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>, text_codecs: &StringCodecMaps) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>, text_codecs: &StringCodecMaps) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
@@ -144,10 +216,10 @@ impl VorbisCommentHeader {
             W: Write {
             let encoded = text_codecs.encode(s);
             write_bits!(bitwriter, encoded.len(), 32);
-            write_slice!(bitwriter, encoded);
+            write_bytes!(bitwriter, &encoded);
             Ok(())
         }
-        write_slice!(bitwriter, b"\x03vorbis");
+        write_bytes!(bitwriter, b"\x03vorbis");
         write_encoded(bitwriter, &self.vendor, text_codecs)?;
         write_bits!(bitwriter, self.comments.len(), 32);
         for comment in self.comments.iter() {
@@ -160,6 +232,20 @@ impl VorbisCommentHeader {
 
 derive_index!(VorbisCommentHeader, String, comments);
 
+impl Display for VorbisCommentHeader {
+    /// * The vendor string, then a `key: value` line for each comment
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "vendor: {}", self.vendor)?;
+        for comment in self.comments.iter() {
+            match comment.split_once('=') {
+                Some((key, value)) => write!(f, "\n{key}: {value}")?,
+                None => write!(f, "\n{comment}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct VorbisMode {
     pub block_flag: bool,
@@ -170,7 +256,7 @@ pub struct VorbisMode {
 
 impl VorbisMode {
     /// * Unpack from the bitstream
-    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> io::Result<Self> {
+    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> Result<Self, VorbisError> {
         let ret = Self {
             block_flag: read_bits!(bitreader, 1) != 0,
             window_type: read_bits!(bitreader, 16),
@@ -179,18 +265,18 @@ impl VorbisMode {
         };
 
         if ret.window_type != 0 {
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad window type: {}", ret.window_type)))
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad window type: {}", ret.window_type)).into())
         } else if ret.transform_type != 0 {
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad transfrom type: {}", ret.transform_type)))
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad transfrom type: {}", ret.transform_type)).into())
         } else if ret.mapping as usize >= vorbis_info.maps.len() {
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mapping exceeded boundary: {} >= {}", ret.mapping, vorbis_info.maps.len())))
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mapping exceeded boundary: {} >= {}", ret.mapping, vorbis_info.maps.len())).into())
         } else {
             Ok(ret)
         }
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
@@ -202,6 +288,29 @@ impl VorbisMode {
 
         Ok(bitwriter.total_bits - begin_bits)
     }
+
+    /// * The number of bits `pack()` writes
+    pub fn packed_bits(&self) -> usize {
+        1 + 16 + 16 + 8
+    }
+
+    /// * The block size of the packets in this mode, short or long by `block_flag`
+    pub fn block_size(&self, info: &VorbisInfo) -> i32 {
+        info.codec_setup.block_size[self.block_flag as usize]
+    }
+
+    /// * The mapping that this mode uses
+    pub fn mapping_ref<'a>(&self, setup: &'a VorbisSetupHeader) -> io::Result<&'a VorbisMapping> {
+        self.mapping_ref_in(&setup.maps)
+    }
+
+    /// * The mapping that this mode uses out of the mappings, such as the shared ones of `VorbisCodecSetup`
+    pub fn mapping_ref_in<'a, M>(&self, maps: &'a [M]) -> io::Result<&'a M> {
+        match maps.get(self.mapping as usize) {
+            Some(mapping) => Ok(mapping),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mapping exceeded boundary: {} >= {}", self.mapping, maps.len()))),
+        }
+    }
 }
 
 /// * The `VorbisSetupHeader` is the Vorbis setup header, the third header
@@ -225,16 +334,16 @@ pub struct VorbisSetupHeader {
 
 impl VorbisSetupHeader {
     /// * Unpack from a bitstream
-    pub fn load(bitreader: &mut BitReader, ident_header: &VorbisIdentificationHeader) -> io::Result<Self> {
+    pub fn load(bitreader: &mut BitReader, ident_header: &VorbisIdentificationHeader) -> Result<Self, VorbisError> {
         let ident = read_slice!(bitreader, 7);
         if ident != b"\x05vorbis" {
-            Err(io::Error::new(io::ErrorKind::InvalidData, format!("Not a Vorbis setup header, the header type is {}, the string is {}", ident[0], String::from_utf8_lossy(&ident[1..]))))
+            Err(VorbisError::BadMagic{expected: 0x05, got: ident})
         } else {
             let mut ret = Self::default();
 
             let books = read_bits!(bitreader, 8).wrapping_add(1);
             if books == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No codebook backend settings.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No codebook backend settings.".to_string()).into());
             }
             for _ in 0..books {
                 ret.static_codebooks.push(StaticCodeBook::load(bitreader)?);
@@ -243,19 +352,19 @@ impl VorbisSetupHeader {
             // time backend settings; hooks are unused
             let times = read_bits!(bitreader, 6).wrapping_add(1);
             if times == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No time backend settings.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No time backend settings.".to_string()).into());
             }
             for _ in 0..times {
                 let time_type = read_bits!(bitreader, 16);
                 if time_type != 0 {
-                    return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid time type {time_type}")));
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid time type {time_type}")).into());
                 }
             }
 
             // floor backend settings
             let floors = read_bits!(bitreader, 6).wrapping_add(1);
             if floors == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No floor backend settings.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No floor backend settings.".to_string()).into());
             }
             for _ in 0..floors {
                 ret.floors.push(VorbisFloor::load(bitreader, &ret)?);
@@ -264,7 +373,7 @@ impl VorbisSetupHeader {
             // residue backend settings
             let residues = read_bits!(bitreader, 6).wrapping_add(1);
             if residues == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No residues backend settings.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No residues backend settings.".to_string()).into());
             }
             for _ in 0..residues {
                 ret.residues.push(VorbisResidue::load(bitreader, &ret)?);
@@ -273,7 +382,7 @@ impl VorbisSetupHeader {
             // map backend settings
             let maps = read_bits!(bitreader, 6).wrapping_add(1);
             if maps == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No map backend settings.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No map backend settings.".to_string()).into());
             }
             for _ in 0..maps {
                 ret.maps.push(VorbisMapping::load(bitreader, &ret, ident_header)?);
@@ -282,7 +391,7 @@ impl VorbisSetupHeader {
             // mode settings
             let modes = read_bits!(bitreader, 6).wrapping_add(1);
             if modes == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No mode settings.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No mode settings.".to_string()).into());
             }
             for _ in 0..modes {
                 ret.modes.push(VorbisMode::load(bitreader, &ret)?);
@@ -291,7 +400,7 @@ impl VorbisSetupHeader {
             // EOP
             let end_of_packet = read_bits!(bitreader, 1) & 1 == 1;
             if !end_of_packet {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("End of packet flag == {end_of_packet}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("End of packet flag == {end_of_packet}")).into());
             }
 
             Ok(ret)
@@ -299,12 +408,12 @@ impl VorbisSetupHeader {
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>, ident_header: &VorbisIdentificationHeader) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>, ident_header: &VorbisIdentificationHeader) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
 
-        write_slice!(bitwriter, b"\x05vorbis");
+        write_bytes!(bitwriter, b"\x05vorbis");
 
         // books
         write_bits!(bitwriter, self.static_codebooks.len().wrapping_sub(1), 8);
@@ -343,7 +452,34 @@ impl VorbisSetupHeader {
         // EOP
         write_bits!(bitwriter, 1, 1);
 
-        Ok(bitwriter.total_bits - begin_bits)
+        let total_bits = bitwriter.total_bits - begin_bits;
+        let expected = self.packed_bit_size(ident_header);
+        if total_bits != expected {
+            return Err(VorbisError::BitCountMismatch{expected, actual: total_bits});
+        }
+        Ok(total_bits)
+    }
+
+    /// * The number of bits `pack()` writes, summed up from each part without packing.
+    /// * The size of the packet is `packed_bit_size().div_ceil(8)` bytes, useful to plan the Ogg pages before muxing.
+    /// * The mappings need the number of channels from the identification header.
+    pub fn packed_bit_size(&self, ident_header: &VorbisIdentificationHeader) -> usize {
+        7 * 8
+        + 8 + self.static_codebooks.iter().map(|book|book.packed_bits()).sum::<usize>()
+        + 6 + 16
+        + 6 + self.floors.iter().map(|floor|floor.packed_bits()).sum::<usize>()
+        + 6 + self.residues.iter().map(|residue|residue.packed_bits()).sum::<usize>()
+        + 6 + self.maps.iter().map(|map|map.packed_bits(ident_header.channels)).sum::<usize>()
+        + 6 + self.modes.iter().map(|mode|mode.packed_bits()).sum::<usize>()
+        + 1
+    }
+}
+
+impl Display for VorbisSetupHeader {
+    /// * The counts of the parts instead of dumping every codebook
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} codebooks, {} floors, {} residues, {} mappings, {} modes",
+            self.static_codebooks.len(), self.floors.len(), self.residues.len(), self.maps.len(), self.modes.len())
     }
 }
 
@@ -372,7 +508,7 @@ pub fn get_vorbis_headers_from_ogg_packet_bytes(data: &[u8], stream_id: &mut u32
                 1 => ident_header.extend(segment),
                 3 => metadata_header.extend(segment),
                 5 => setup_header.extend(segment),
-                o => return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Vorbis header type {o}"))),
+                o => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Vorbis header type {o}"))),
             }
         }
     }
@@ -381,6 +517,54 @@ pub fn get_vorbis_headers_from_ogg_packet_bytes(data: &[u8], stream_id: &mut u32
     Ok((ident_header, metadata_header, setup_header))
 }
 
+/// * Extract the three Vorbis header packets from the Ogg stream bytes, like `get_vorbis_headers_from_ogg_packet_bytes()`.
+/// * The CRC of every page is verified before its segments are used, a corrupted page is an error instead of garbage headers.
+/// * Only the pages of the first logical stream are read.
+pub fn get_vorbis_headers_checked(data: &[u8], stream_id: &mut u32) -> io::Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let mut headers = Vec::<Vec<u8>>::with_capacity(3);
+    let mut cur_packet = Vec::<u8>::new();
+    let mut first_stream_id = None;
+    for page in pages::ogg_pages(data) {
+        let page = page?;
+        match first_stream_id {
+            None => first_stream_id = Some(page.stream_id),
+            Some(id) => if id != page.stream_id {
+                continue;
+            }
+        }
+        if !page.verify_checksum() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The checksum of the Ogg page {} at 0x{:x} mismatches", page.page_index, page.offset)));
+        }
+        let mut pos = 0usize;
+        for &lacing in page.segment_table.iter() {
+            cur_packet.extend(&page.data[pos..pos + lacing as usize]);
+            pos += lacing as usize;
+            if lacing < 255 {
+                headers.push(std::mem::take(&mut cur_packet));
+                if headers.len() == 3 {
+                    break;
+                }
+            }
+        }
+        if headers.len() == 3 {
+            break;
+        }
+    }
+    if headers.len() < 3 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Expected 3 Vorbis header packets, got {}", headers.len())));
+    }
+    for (header, header_type) in headers.iter().zip([1u8, 3, 5]) {
+        if header.len() < 7 || header[0] != header_type || header[1..7] != *b"vorbis" {
+            return Err(VorbisError::BadMagic{expected: header_type, got: header[..header.len().min(7)].to_vec()}.into());
+        }
+    }
+    *stream_id = first_stream_id.unwrap();
+    let setup_header = headers.pop().unwrap();
+    let metadata_header = headers.pop().unwrap();
+    let ident_header = headers.pop().unwrap();
+    Ok((ident_header, metadata_header, setup_header))
+}
+
 /// * This function extracts data from Ogg packets, the packets contains the Vorbis header.
 /// * The packets were all decoded.
 pub fn read_vorbis_headers<R>(reader: &mut OggStreamReader<R>, text_codecs: &StringCodecMaps) -> io::Result<(VorbisIdentificationHeader, VorbisCommentHeader, VorbisSetupHeader)>
@@ -1,5 +1,4 @@
 #![allow(dead_code)]
-#![allow(private_interfaces)]
 use std::fmt::{self, Debug, Formatter};
 use crate::*;
 use utils::*;
@@ -10,40 +9,47 @@ use codebook::StaticCodeBook;
 use residue::VorbisResidue;
 use mapping::VorbisMapping;
 
+/// * The second stage books of the partition classes of a residue, up to 4 stages for each of the 12 classes, `None` for the unused stages
 #[derive(Default, Debug, Clone, PartialEq)]
-struct StaticBookBlock {
-    static_codebook: [[StaticCodeBook; 4]; 12],
+pub struct StaticBookBlock {
+    pub books: [[Option<StaticCodeBook>; 4]; 12],
 }
 
+/// * The residue of a mapping template, the books are added to the codec setup by `VorbisInfo::encode_setup_init()`
 #[derive(Default, Debug, Clone, PartialEq)]
-struct VorbisResidueTemplate {
-    res_type: i32,
-    limit_type: i32,
-    grouping: i32,
-    res: VorbisResidue,
-    book_aux: StaticCodeBook,
-    book_aux_managed: StaticCodeBook,
-    books_base: StaticBookBlock,
-    books_base_managed: StaticBookBlock,
+pub struct VorbisResidueTemplate {
+    pub res_type: i32,
+
+    /// 0 lowpass limited, 1 point stereo limited, 2 LFE
+    pub limit_type: i32,
+    pub grouping: i32,
+
+    /// The partitions and the class metrics, `end` is set from the lowpass
+    pub res: VorbisResidue,
+    pub book_aux: StaticCodeBook,
+    pub book_aux_managed: StaticCodeBook,
+    pub books_base: StaticBookBlock,
+    pub books_base_managed: StaticBookBlock,
 }
 
+/// * The mappings of the short and the long blocks, and the residues they refer to
 #[derive(Default, Debug, Clone, PartialEq)]
-struct VorbisMappingTemplate {
-    map: VorbisMapping,
-    res: VorbisResidueTemplate,
+pub struct VorbisMappingTemplate {
+    pub map: [VorbisMapping; 2],
+    pub res: Vec<VorbisResidueTemplate>,
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
-struct VpAdjBlock {
-    block: [i32; P_BANDS],
+pub struct VpAdjBlock {
+    pub block: [i32; P_BANDS],
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-struct CommandBlock {
-    data: [i32; NOISE_COMPAND_LEVELS],
+pub struct CompandBlock {
+    pub data: [i32; NOISE_COMPAND_LEVELS],
 }
 
-impl Default for CommandBlock {
+impl Default for CompandBlock {
     fn default() -> Self {
         Self {
             data: [0; NOISE_COMPAND_LEVELS],
@@ -52,36 +58,36 @@ impl Default for CommandBlock {
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
-struct Att3 {
-    att: [i32; P_NOISECURVES],
-    boost: f32,
-    decay: f32,
+pub struct Att3 {
+    pub att: [i32; P_NOISECURVES],
+    pub boost: f32,
+    pub decay: f32,
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
-struct Adj3 {
-    data: [i32; P_NOISECURVES],
+pub struct Adj3 {
+    pub data: [i32; P_NOISECURVES],
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
 #[allow(non_snake_case)]
-struct AdjStereo {
-    pre: [i32; PACKETBLOBS],
-    post: [i32; PACKETBLOBS],
-    kHz: [f32; PACKETBLOBS],
-    lowpasskHz: [f32; PACKETBLOBS],
+pub struct AdjStereo {
+    pub pre: [i32; PACKETBLOBS],
+    pub post: [i32; PACKETBLOBS],
+    pub kHz: [f32; PACKETBLOBS],
+    pub lowpasskHz: [f32; PACKETBLOBS],
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
-struct NoiseGuard {
-    lo: i32,
-    hi: i32,
-    fixed: i32,
+pub struct NoiseGuard {
+    pub lo: i32,
+    pub hi: i32,
+    pub fixed: i32,
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
-struct Noise3 {
-    data: [[i32; 17]; P_NOISECURVES]
+pub struct Noise3 {
+    pub data: [[i32; P_BANDS]; P_NOISECURVES]
 }
 
 #[derive(Default, Clone, PartialEq)]
@@ -112,7 +118,7 @@ pub struct VorbisEncodeSetupDataTemplate {
     pub psy_noise_bias_long: Vec<Noise3>,
     pub psy_noise_dBsuppress: Vec<i32>,
 
-    pub psy_noise_compand: Vec<CommandBlock>,
+    pub psy_noise_compand: Vec<CompandBlock>,
     pub psy_noise_compand_short_mapping: Vec<f64>,
     pub psy_noise_compand_long_mapping: Vec<f64>,
 
@@ -129,7 +135,7 @@ pub struct VorbisEncodeSetupDataTemplate {
     pub global_mapping: Vec<f64>,
     pub stereo_modes: Vec<AdjStereo>,
 
-    pub floor_books: Vec<Vec<Vec<StaticCodeBook>>>,
+    pub floor_books: Vec<Vec<StaticCodeBook>>,
     pub floor_params: Vec<VorbisFloor1>,
     pub floor_mappings: i32,
     pub floor_mapping_list: Vec<Vec<i32>>,
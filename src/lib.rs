@@ -1,8 +1,11 @@
 mod utils;
+mod errors;
 mod bitwise;
 mod scales;
 mod mdct;
+mod window;
 mod drft;
+mod lpc;
 
 mod headers;
 mod codec;
@@ -16,11 +19,20 @@ mod psy_masking;
 mod bitrate;
 mod envelope;
 mod highlevel;
+mod setup_44;
 
 mod vorbisenc;
 
+mod pages;
+mod oggvorbis;
+mod decoder;
+mod wav;
+
 pub use utils::*;
 pub use bitwise::*;
+pub use errors::VorbisError;
+pub use scales::{to_oc, from_oc, to_db, from_db};
+pub use psy_masking::{ath_curve, tone_mask, MAX_ATH, EHMER_MAX, EHMER_OFFSET, P_BANDS};
 
 pub const PACKETBLOBS: usize = 15;
 
@@ -31,9 +43,57 @@ pub const PANIC_ON_ERROR: bool = true;
 
 mod no_usage;
 
-pub use headers::get_vorbis_headers_from_ogg_packet_bytes;
+pub use mdct::{MdctLookup, MdctError};
+pub use drft::{DrftLookup, DrftError};
+pub use lpc::{vorbis_lpc_from_data, vorbis_lpc_predict};
+pub use window::{vorbis_window, try_vorbis_window, vorbis_window_slope, window_index};
+pub use codebook::{StaticCodeBook, CodeBook, CodeBookStats};
+pub use no_usage::{StaticCodeBooks, StaticCodeBooksPacked, verify_setup_roundtrip, replace_codebooks_in_setup_header};
+
+pub use headers::{VorbisIdentificationHeader, VorbisCommentHeader, VorbisSetupHeader, VENDOR_STRING, get_vorbis_headers_from_ogg_packet_bytes, get_vorbis_headers_checked};
+
+pub use codec::{VorbisInfo, VorbisDspState, Channel, BitrateMode, HEADER_PACKETS};
+pub use bitrate::ReservoirSnapshot;
+
+pub use pages::{OggPageView, OggPageIter, OggStreamPacket, ogg_pages, ogg_stream_packets, build_ogg_page, packets_to_ogg_pages};
+pub use oggvorbis::{decode_ogg_vorbis, ogg_vorbis_audio_packets, ogg_vorbis_duration, ogg_vorbis_bitrate_windows, ogg_vorbis_peak_bitrate, vorbis_packet_block_size, OggVorbisIndex, OggVorbisIndexEntry, retag_ogg_vorbis, strip_pictures_from_ogg, strip_comments_from_ogg_stream, validate_ogg_vorbis, ValidationReport, ValidationIssue};
+pub use decoder::{ClipCounter, VorbisDecoder};
+pub use wav::{WavPcm, parse_wav, encode_wav_to_ogg_vorbis, encode_wav_to_ogg_vorbis_with_vendor, pcm_to_wav_i16, decode_ogg_vorbis_to_wav};
+
+/// * The Ogg packets of `test.ogg`
+#[cfg(test)]
+fn load_test_packets() -> Vec<OggStreamPacket> {
+	let data = std::fs::read("test.ogg").unwrap();
+	ogg_stream_packets(&data, None).unwrap()
+}
+
+/// * The identification header and the setup header of `test.ogg`
+#[cfg(test)]
+fn load_test_headers() -> (VorbisIdentificationHeader, VorbisSetupHeader) {
+	let packets = load_test_packets();
+	let ident = VorbisIdentificationHeader::load_from_slice(&packets[0].data).unwrap();
+	let setup = VorbisSetupHeader::load(&mut BitReader::new(&packets[2].data), &ident).unwrap();
+	(ident, setup)
+}
+
+/// * The `VorbisInfo` of `test.ogg`
+#[cfg(test)]
+fn load_test_info() -> VorbisInfo {
+	let (ident, setup) = load_test_headers();
+	VorbisInfo::new(&ident, &setup).unwrap()
+}
 
-pub use codec::{VorbisInfo, VorbisDspState};
+/// * The `VorbisInfo` of `test.ogg` for encoding. The setup header has no psychoacoustic settings,
+///   the default global one has no `eighth_octave_lines`, so give it the value of the libvorbis templates.
+#[cfg(test)]
+fn load_test_info_for_encode() -> VorbisInfo {
+	let mut vi = load_test_info();
+	vi.codec_setup.psy_g = std::rc::Rc::new(psy::VorbisInfoPsyGlobal {
+		eighth_octave_lines: 8,
+		..Default::default()
+	});
+	vi
+}
 
 #[test]
 fn test_ogg_vorbis() {
@@ -53,3 +113,2515 @@ fn test_ogg_vorbis() {
 }
 
 
+
+#[test]
+fn test_psy_look() {
+	use std::rc::Rc;
+	use psy::{VorbisInfoPsy, VorbisInfoPsyGlobal, VorbisLookPsy};
+	let vi = Rc::new(VorbisInfoPsy {
+		noisewindowlo: 1.0,
+		noisewindowhi: 1.0,
+		noisewindowlomin: 2,
+		noisewindowhimin: 2,
+		..Default::default()
+	});
+	let gi = VorbisInfoPsyGlobal {
+		eighth_octave_lines: 8,
+		..Default::default()
+	};
+	let look = VorbisLookPsy::new(vi, &gi, 1024, 44100);
+	assert_eq!(look.shiftoc, 5);
+	assert_eq!(look.ath.len(), 1024);
+	assert_eq!(look.octave.len(), 1024);
+	assert_eq!(look.bark.len(), 1024);
+	assert!(look.total_octave_lines > 0);
+	assert!(look.octave.windows(2).all(|w|w[0] <= w[1]));
+}
+
+#[test]
+fn test_comment_header_vendor() {
+	use io_utils::CursorVecU8;
+	use savagestr::prelude::*;
+	let text_codecs = StringCodecMaps::new();
+	let mut comment_header = VorbisCommentHeader::new();
+	assert_eq!(comment_header.vendor, VENDOR_STRING);
+	comment_header.set_vendor("revorbis-rs test vendor");
+	comment_header.comments.push("TITLE=test".to_string());
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	comment_header.pack(&mut bitwriter, &text_codecs).unwrap();
+	let packed = bitwriter.into_bytes();
+	let loaded = VorbisCommentHeader::load(&mut BitReader::new(&packed), &text_codecs).unwrap();
+	assert_eq!(loaded.vendor, "revorbis-rs test vendor");
+	assert_eq!(loaded, comment_header);
+}
+
+#[test]
+fn test_encoder_vendor() {
+	use savagestr::prelude::*;
+	let text_codecs = StringCodecMaps::new();
+	let rate = 44100;
+	let tone: Vec<f32> = (0..rate as usize / 4).map(|i|0.3 * (i as f32 * std::f32::consts::TAU * 440.0 / rate as f32).sin()).collect();
+	let wav = pcm_to_wav_i16(1, rate, &[tone]).unwrap();
+	let decoded_vendor = |ogg: &[u8]| {
+		let packets = ogg_stream_packets(ogg, None).unwrap();
+		VorbisCommentHeader::load(&mut BitReader::new(&packets[1].data), &text_codecs).unwrap().vendor
+	};
+	assert_eq!(decoded_vendor(&encode_wav_to_ogg_vorbis(&wav, 0.4).unwrap()), VENDOR_STRING);
+	let ogg = encode_wav_to_ogg_vorbis_with_vendor(&wav, 0.4, "revorbis-rs test vendor").unwrap();
+	assert_eq!(decoded_vendor(&ogg), "revorbis-rs test vendor");
+	assert!(validate_ogg_vorbis(&ogg).is_ok());
+
+	// The encoder's vendor wins over the one of the comments
+	let mut vd = VorbisDspState::new(VorbisInfo::new_vbr(1, rate as i32, 0.4).unwrap(), true).unwrap();
+	assert_eq!(vd.vendor, VENDOR_STRING);
+	vd.set_vendor("another vendor");
+	let mut comments = VorbisCommentHeader::new();
+	comments.set_vendor("ignored vendor");
+	comments.comments.push("TITLE=test".to_string());
+	let (_, comment, _) = vd.headerout(&comments).unwrap();
+	let loaded = VorbisCommentHeader::load(&mut BitReader::new(&comment), &text_codecs).unwrap();
+	assert_eq!(loaded.vendor, "another vendor");
+	assert_eq!(loaded.comments, comments.comments);
+	let decoding = VorbisDspState::new(load_test_info(), false).unwrap();
+	assert!(decoding.headerout(&comments).is_err());
+}
+
+#[test]
+fn test_scales() {
+	assert!(to_oc(62.5).abs() < 1e-3);
+	assert!((to_oc(125.0) - 1.0).abs() < 1e-3);
+	assert!((from_oc(to_oc(1000.0)) - 1000.0).abs() < 0.1);
+	assert!(to_db(1.0).abs() < 1e-2);
+	assert!((to_db(0.1) + 20.0).abs() < 0.6);
+	assert!((from_db(to_db(0.5)) - 0.5).abs() < 1e-3);
+}
+
+#[test]
+fn test_setup_references() {
+	use std::rc::Rc;
+	use floor::{VorbisFloor, VorbisFloor1};
+	use residue::VorbisResidue;
+	use mapping::VorbisMapping;
+	let identification_header = VorbisIdentificationHeader {
+		channels: 2,
+		sample_rate: 44100,
+		block_size: [256, 2048],
+		..Default::default()
+	};
+	let mut map = VorbisMapping {
+		submaps: 1,
+		..Default::default()
+	};
+	map.floorsubmap.push(0);
+	map.residuesubmap.push(2);
+	let mut setup_header = VorbisSetupHeader {
+		floors: vec![VorbisFloor::Floor1(Rc::new(VorbisFloor1::default()))],
+		residues: vec![VorbisResidue::default(); 2],
+		maps: vec![map],
+		..Default::default()
+	};
+	assert!(VorbisInfo::new(&identification_header, &setup_header).is_err());
+	setup_header.maps[0].residuesubmap[0] = 1;
+	assert!(VorbisInfo::new(&identification_header, &setup_header).is_ok());
+}
+
+#[test]
+fn test_codebook_decode_scalar() {
+	use io_utils::CursorVecU8;
+	use codebook::{StaticCodeBook, CodeBook};
+	for lengthlist in [vec![2i8, 2, 3, 3, 3, 3], vec![1, 0, 2, 0, 4, 4, 4, 0, 4], vec![1], vec![0, 1, 0]] {
+		let static_codebook = StaticCodeBook {
+			dim: 1,
+			entries: lengthlist.len() as i32,
+			lengthlist,
+			..Default::default()
+		};
+		let encode_book = CodeBook::new_for_encode(&static_codebook).unwrap();
+		let decode_book = CodeBook::new_for_decode(&static_codebook).unwrap();
+		let entries: Vec<i32> = (0..static_codebook.entries).filter(|&i|static_codebook.lengthlist[i as usize] > 0).cycle().take(50).collect();
+		let mut bitwriter = BitWriter::new(CursorVecU8::default());
+		for &entry in entries.iter() {
+			assert_eq!(encode_book.encode(entry, &mut bitwriter).unwrap(), static_codebook.lengthlist[entry as usize] as usize);
+		}
+		assert!(encode_book.encode(static_codebook.entries, &mut bitwriter).is_err());
+		let packed = bitwriter.into_bytes();
+		let mut bitreader = BitReader::new(&packed);
+		for &entry in entries.iter() {
+			assert_eq!(decode_book.decode_scalar(&mut bitreader).unwrap(), entry);
+		}
+	}
+}
+
+#[test]
+fn test_codebook_sparse_single_entry() {
+	use codebook::{StaticCodeBook, CodeBook};
+	let book_of = |lengthlist: Vec<i8>|StaticCodeBook {
+		dim: 1,
+		entries: lengthlist.len() as i32,
+		lengthlist,
+		..Default::default()
+	};
+
+	// The single used entry among the unused ones is accepted by both the encoder and the decoder, libvorbis only decodes it
+	let sparse = book_of(vec![0, 0, 1, 0]);
+	let encode_book = CodeBook::new_for_encode(&sparse).unwrap();
+	let decode_book = CodeBook::new_for_decode(&sparse).unwrap();
+	let mut bitwriter = BitWriterCursor::default();
+	assert_eq!(encode_book.encode(2, &mut bitwriter).unwrap(), 1);
+	assert!(encode_book.encode(0, &mut bitwriter).is_err());
+	let packed = bitwriter.into_bytes();
+	assert_eq!(decode_book.decode_scalar(&mut BitReader::new(&packed)).unwrap(), 2);
+
+	// Only a codeword of length 1 is shielded, the other underpopulated trees are still rejected
+	for lengthlist in [vec![0, 2, 0], vec![0, 1, 0, 2], vec![2, 0, 2]] {
+		let book = book_of(lengthlist);
+		assert!(matches!(VorbisError::from(CodeBook::new_for_encode(&book).unwrap_err()), VorbisError::UnderpopulatedTree));
+		assert!(matches!(VorbisError::from(CodeBook::new_for_decode(&book).unwrap_err()), VorbisError::UnderpopulatedTree));
+	}
+}
+
+#[test]
+fn test_peak_bitrate() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let average = ogg_vorbis_bitrate_windows(&data, u32::MAX).unwrap();
+	assert_eq!(average.len(), 1);
+	let peak = ogg_vorbis_peak_bitrate(&data, 100).unwrap();
+	assert!(peak >= average[0], "peak = {peak}, average = {}", average[0]);
+}
+
+#[test]
+fn test_ogg_vorbis_index() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let index = OggVorbisIndex::build(&data).unwrap();
+	assert_eq!(index.total_samples, 106886);
+	let i = index.packet_for_sample(index.total_samples / 2).unwrap();
+	let entry = index.entries[i];
+	assert!(entry.start_granule <= index.total_samples / 2);
+	assert!(entry.start_granule + entry.num_samples > index.total_samples / 2);
+	assert!(entry.page_offset > 0 && entry.page_offset < data.len());
+	assert!(index.packet_for_sample(index.total_samples).is_none());
+}
+
+#[test]
+fn test_codebook_decode_vector() {
+	use io_utils::CursorVecU8;
+	use codebook::{StaticCodeBook, CodeBook};
+	let static_codebook = StaticCodeBook {
+		dim: 2,
+		entries: 9,
+		lengthlist: vec![3, 3, 3, 3, 3, 3, 3, 4, 4],
+		maptype: 1,
+		q_min: -1.0,
+		q_delta: 1.0,
+		q_quant: 2,
+		quantlist: vec![0, 1, 2],
+		..Default::default()
+	};
+	let encode_book = CodeBook::new_for_encode(&static_codebook).unwrap();
+	let decode_book = CodeBook::new_for_decode(&static_codebook).unwrap();
+	let entries = [8, 0, 4, 7, 1, 5];
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	for &entry in entries.iter() {
+		encode_book.encode(entry, &mut bitwriter).unwrap();
+	}
+	let packed = bitwriter.into_bytes();
+	let mut bitreader = BitReader::new(&packed);
+	let mut out = [0.0f32; 12];
+	decode_book.decode_vector(&mut bitreader, &mut out).unwrap();
+	for (i, &entry) in entries.iter().enumerate() {
+		assert_eq!(out[i * 2], (entry % 3 - 1) as f32);
+		assert_eq!(out[i * 2 + 1], (entry / 3 - 1) as f32);
+	}
+}
+
+#[test]
+fn test_clip_count() {
+	let mut hot = ClipCounter::new(2);
+	hot.accumulate(&[vec![0.5, 1.25, -1.5, 1.0], vec![-1.0, 0.0, 0.25, 0.75]]);
+	hot.accumulate(&[vec![1.01], vec![-2.0]]);
+	assert_eq!(hot.clip_count(), vec![3, 1]);
+	let mut quiet = ClipCounter::new(2);
+	quiet.accumulate(&[vec![0.5, -0.5, 1.0], vec![-1.0, 0.0, 0.25]]);
+	assert_eq!(quiet.clip_count(), vec![0, 0]);
+}
+
+#[test]
+fn test_codebook_new_for_decode() {
+	use io_utils::CursorVecU8;
+	use codebook::CodeBook;
+	let (_, setup_header) = load_test_headers();
+	for static_codebook in setup_header.static_codebooks.iter() {
+		let decode_book = CodeBook::new_for_decode(static_codebook).unwrap();
+		let used: Vec<i32> = (0..static_codebook.entries).filter(|&i|static_codebook.lengthlist[i as usize] > 0).collect();
+		assert_eq!(decode_book.used_entries as usize, used.len());
+		if used.is_empty() {
+			continue;
+		}
+		let encode_book = CodeBook::new_for_encode(static_codebook).unwrap();
+		let mut bitwriter = BitWriter::new(CursorVecU8::default());
+		for &entry in used.iter() {
+			encode_book.encode(entry, &mut bitwriter).unwrap();
+		}
+		let packed = bitwriter.into_bytes();
+		let mut bitreader = BitReader::new(&packed);
+		for &entry in used.iter() {
+			assert_eq!(decode_book.decode_scalar(&mut bitreader).unwrap(), entry);
+		}
+	}
+}
+
+#[test]
+fn test_strip_pictures() {
+	use savagestr::prelude::*;
+	let data = std::fs::read("test.ogg").unwrap();
+	let picture = format!("METADATA_BLOCK_PICTURE={}", "A".repeat(100000));
+	let tagged = retag_ogg_vorbis(&data, |comment_header| comment_header.comments.push(picture)).unwrap();
+	let stripped = strip_pictures_from_ogg(&tagged).unwrap();
+	assert!(stripped.len() < tagged.len());
+	assert!(ogg_pages(&stripped).all(|page|page.unwrap().verify_checksum()));
+	let original_packets = ogg_stream_packets(&data, None).unwrap();
+	let stripped_packets = ogg_stream_packets(&stripped, None).unwrap();
+	let comment_header = VorbisCommentHeader::load(&mut BitReader::new(&stripped_packets[1].data), &StringCodecMaps::new()).unwrap();
+	assert!(comment_header.comments.iter().all(|comment|!comment.starts_with("METADATA_BLOCK_PICTURE=")));
+	assert_eq!(original_packets.len(), stripped_packets.len());
+	for (a, b) in original_packets[2..].iter().zip(stripped_packets[2..].iter()) {
+		assert_eq!(a.data, b.data);
+		assert_eq!(a.granule_position, b.granule_position);
+	}
+}
+
+#[test]
+fn test_codebook_from_lengths() {
+	use io_utils::CursorVecU8;
+	use codebook::StaticCodeBook;
+	let static_codebook = StaticCodeBook::from_lengths(1, vec![2, 0, 2, 3, 3, 2]).unwrap();
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	static_codebook.pack(&mut bitwriter).unwrap();
+	let packed = bitwriter.into_bytes();
+	assert_eq!(StaticCodeBook::load(&mut BitReader::new(&packed)).unwrap(), static_codebook);
+	assert!(StaticCodeBook::from_lengths(1, vec![1]).is_ok());
+	assert!(StaticCodeBook::from_lengths(1, vec![1, 1, 1]).is_err());
+	assert!(StaticCodeBook::from_lengths(1, vec![2, 2, 2]).is_err());
+	assert!(StaticCodeBook::from_lengths(1, vec![0, 0]).is_err());
+	assert!(StaticCodeBook::from_lengths(0, vec![1, 1]).is_err());
+	assert!(StaticCodeBook {entries: 7, ..static_codebook.clone()}.validate().is_err());
+	assert!(StaticCodeBook {maptype: 3, ..static_codebook.clone()}.validate().is_err());
+	let stats = static_codebook.bit_stats();
+	assert_eq!((stats.min_length, stats.max_length, stats.used_entries, stats.unused_entries), (2, 3, 5, 1));
+	assert_eq!(stats.mean_length, 2.4);
+	assert_eq!(stats.length_spec_bits, 1 + 1 + 6 + 5 * 5);
+	let ordered = StaticCodeBook::from_lengths(1, vec![1, 2, 3, 3]).unwrap();
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	let packed_bits = ordered.pack(&mut bitwriter).unwrap();
+	assert_eq!(ordered.bit_stats().length_spec_bits, packed_bits - 24 - 16 - 24 - 4);
+	assert!(StaticCodeBook {lengthlist: vec![2, 0, 2, 3, 3, 3], ..static_codebook}.validate().is_err());
+}
+
+#[test]
+fn test_validate_ogg_vorbis() {
+	let mut data = std::fs::read("test.ogg").unwrap();
+	let report = validate_ogg_vorbis(&data);
+	assert!(report.is_ok(), "{report:?}");
+	let last_page = ogg_pages(&data).last().unwrap().unwrap().offset;
+	let len = data.len();
+	data[len - 1] ^= 0x55;
+	let report = validate_ogg_vorbis(&data);
+	assert!(report.issues.contains(&ValidationIssue::CrcMismatch {page_offset: last_page}), "{report:?}");
+
+	// Flip the bits of the setup packet and repage the headers, the corrupted setup header must be reported, not panic
+	let packets = load_test_packets();
+	let (ident_pages, page_index) = packets_to_ogg_pages(&[&packets[0].data], 1, 0, true, 0).unwrap();
+	let setup_bits = packets[2].data.len() * 8;
+	let mut num_reported = 0;
+	for bit in (7..setup_bits).step_by(setup_bits / 1000) {
+		let mut setup = packets[2].data.clone();
+		setup[bit / 8] ^= 1 << (bit % 8);
+		let (header_pages, _) = packets_to_ogg_pages(&[&packets[1].data, &setup], 1, page_index, false, 0).unwrap();
+		let ogg: Vec<u8> = ident_pages.iter().chain(header_pages.iter()).flatten().copied().collect();
+		if !validate_ogg_vorbis(&ogg).is_ok() {
+			num_reported += 1;
+		}
+	}
+	assert!(num_reported > 0);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_codebooks_serde() {
+	let (_, setup_header) = load_test_headers();
+	let books = StaticCodeBooks {
+		books: setup_header.static_codebooks.clone(),
+		..Default::default()
+	}.to_packed_codebooks().unwrap().unpack().unwrap();
+	let json = serde_json::to_string(&books).unwrap();
+	let deserialized: StaticCodeBooks = serde_json::from_str(&json).unwrap();
+	assert_eq!(deserialized, books);
+	assert_eq!(deserialized.to_packed_codebooks().unwrap().unpack().unwrap(), books);
+}
+
+#[test]
+fn test_mdct_forward_with_scratch() {
+	let n = 2048;
+	let lookup = MdctLookup::new(n);
+	let mut scratch = Vec::new();
+	for k in 0..4 {
+		let input: Vec<f32> = (0..n).map(|i|((i * (k + 1)) as f32 * 0.01).sin()).collect();
+		let mut expected = vec![0.0f32; n / 2];
+		let mut output = vec![0.0f32; n / 2];
+		lookup.forward(&input, &mut expected);
+		lookup.forward_with_scratch(&input, &mut output, &mut scratch);
+		assert_eq!(output, expected);
+	}
+	assert_eq!(scratch.len(), n);
+}
+
+#[test]
+fn test_mdct_try_new() {
+	assert_eq!(MdctLookup::try_new(1000), Err(MdctError::NotPowerOfTwo(1000)));
+	assert_eq!(MdctLookup::try_new(32), Err(MdctError::TooSmall(32)));
+	assert_eq!(MdctLookup::try_new(64).unwrap().log2n, 6);
+	assert_eq!(MdctLookup::try_new(8192).unwrap().log2n, 13);
+}
+
+#[test]
+fn test_mdct_roundtrip() {
+	for n in [64, 256, 2048, 8192] {
+		let lookup = MdctLookup::new(n);
+		let input: Vec<f32> = (0..n * 3).map(|i|(i as f32 * 0.05).sin() * 0.5 + (i as f32 * 0.31).cos() * 0.25).collect();
+		let error = lookup.roundtrip_error(&input);
+		assert!(error < 1e-4, "n = {n}, error = {error}");
+	}
+}
+
+#[test]
+fn test_vorbis_window() {
+	let long = vorbis_window(2048, 2048, 2048);
+	for i in 0..1024 {
+		assert!((long[i] * long[i] + long[i + 1024] * long[i + 1024] - 1.0).abs() < 1e-5);
+	}
+	let transition = vorbis_window(256, 2048, 2048);
+	assert!(transition[..448].iter().all(|&w|w == 0.0));
+	assert!(transition[576..1024].iter().all(|&w|w == 1.0));
+	assert_eq!(&transition[448..576], &vorbis_window_slope(128)[..]);
+	let short = vorbis_window(256, 256, 2048);
+	assert_eq!(short, vorbis_window(256, 256, 256));
+	assert_eq!(try_vorbis_window(256, 2048, 2048).unwrap(), transition);
+	for (left_n, n, right_n) in [(6, 6, 6), (2048, 2000, 2048), (256, 2048, 100), (0, 256, 256), (2, 2, 2)] {
+		assert_eq!(try_vorbis_window(left_n, n, right_n).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+	}
+	assert!(std::panic::catch_unwind(||vorbis_window(6, 6, 6)).is_err());
+}
+
+#[test]
+fn test_drft_roundtrip() {
+	let mut seed = 0x12345678u32;
+	for n in [8, 12, 30, 64, 98, 121, 256, 784, 2048] {
+		let mut lookup = DrftLookup::new(n);
+		let input: Vec<f32> = (0..n).map(|_|{
+			seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+			(seed >> 8) as f32 / (1 << 23) as f32 - 1.0
+		}).collect();
+		let mut data = input.clone();
+		lookup.forward(&mut data);
+		let dc: f32 = input.iter().sum();
+		assert!((data[0] - dc).abs() < 1e-3, "n = {n}, {} != {dc}", data[0]);
+		lookup.backward(&mut data);
+		for (i, (&x, &y)) in input.iter().zip(data.iter()).enumerate() {
+			assert!((x - y / n as f32).abs() < 1e-5, "n = {n}, i = {i}, {x} != {}", y / n as f32);
+		}
+	}
+}
+
+#[test]
+fn test_drft_length_check() {
+	let mut lookup = DrftLookup::new(64);
+	let mut data = vec![0.0f32; 63];
+	assert_eq!(lookup.forward_checked(&mut data), Err(DrftError::LengthMismatch{expected: 64, got: 63}));
+	assert!(lookup.backward_checked(&mut data).is_err());
+	data.push(1.0);
+	assert!(lookup.forward_checked(&mut data).is_ok());
+	assert!(lookup.backward_checked(&mut data).is_ok());
+	assert!((data[63] / 64.0 - 1.0).abs() < 1e-5);
+}
+
+#[test]
+fn test_floor1_inverse() {
+	use std::rc::Rc;
+	use copiablebuf::CopiableBuffer;
+	use floor::{VorbisFloor1, VorbisLookFloor1, FLOOR1_FROMDB_LOOKUP, FLOOR1_UNUSED_POST};
+	let mut floor1 = VorbisFloor1 {
+		partitions: 1,
+		mult: 1,
+		..Default::default()
+	};
+	floor1.partitions_class.push(0);
+	floor1.class_dim.push(1);
+	floor1.class_subs.push(0);
+	floor1.class_book.push(0);
+	floor1.class_subbook.push(CopiableBuffer::default());
+	for x in [0, 128, 64] {
+		floor1.postlist.push(x);
+	}
+	let floor1 = Rc::new(floor1);
+	let look = VorbisLookFloor1::look(floor1.clone());
+	let mut out = vec![0.0; 128];
+	floor1.inverse(&look, &[100, 200, 180], &mut out);
+	assert_eq!(out[0], FLOOR1_FROMDB_LOOKUP[100]);
+	assert_eq!(out[64], FLOOR1_FROMDB_LOOKUP[180]);
+	assert_eq!(out[32], FLOOR1_FROMDB_LOOKUP[140]);
+	assert!(out.windows(2).take(64).all(|w|w[0] <= w[1]));
+	floor1.inverse(&look, &[100, 200, 180 | FLOOR1_UNUSED_POST], &mut out);
+	assert_eq!(out[64], FLOOR1_FROMDB_LOOKUP[150]);
+	assert_eq!(out[127], FLOOR1_FROMDB_LOOKUP[199]);
+}
+
+#[test]
+fn test_floor1_fit() {
+	use std::rc::Rc;
+	use copiablebuf::CopiableBuffer;
+	use floor::{VorbisFloor1, VorbisLookFloor1, FLOOR1_UNUSED_POST};
+	let mut floor1 = VorbisFloor1 {
+		partitions: 1,
+		mult: 2,
+		maxover: 60.0,
+		maxunder: 30.0,
+		maxerr: 500.0,
+		twofitweight: 1.0,
+		twofitatten: 18.0,
+		..Default::default()
+	};
+	floor1.partitions_class.push(0);
+	floor1.class_dim.push(3);
+	floor1.class_subs.push(0);
+	floor1.class_book.push(0);
+	floor1.class_subbook.push(CopiableBuffer::default());
+	for x in [0, 128, 64, 32, 96] {
+		floor1.postlist.push(x);
+	}
+	let floor1 = Rc::new(floor1);
+	let look = VorbisLookFloor1::look(floor1.clone());
+	let quant = |db: f32| (db * 7.3142857 + 1023.5) as i32;
+
+	// A V shaped mask can't be a single line, the middle post must be used
+	let logmask: Vec<f32> = (0..128).map(|i|-40.0 - 60.0 * (1.0 - (i as f32 - 64.0).abs() / 64.0)).collect();
+	let logmdct: Vec<f32> = logmask.iter().map(|m|m + 10.0).collect();
+	let posts = floor1.fit(&look, &logmdct, &logmask).unwrap();
+	assert_eq!(posts.len(), 5);
+	assert!((posts[0] - quant(logmask[0])).abs() < 16);
+	assert!((posts[1] - quant(logmask[127])).abs() < 16);
+	assert_eq!(posts[2] & FLOOR1_UNUSED_POST, 0);
+	assert!((posts[2] - quant(logmask[64])).abs() < 16);
+
+	// A straight line only needs the two implicit posts
+	let logmask: Vec<f32> = (0..128).map(|i|-100.0 + i as f32 * 0.25).collect();
+	let posts = floor1.fit(&look, &logmask, &logmask).unwrap();
+	assert!(posts[2..].iter().all(|&p|p & FLOOR1_UNUSED_POST != 0));
+
+	// Nothing above the floor of the scale, the channel is silent
+	let logmask = vec![-200.0; 128];
+	assert!(floor1.fit(&look, &logmask, &logmask).is_none());
+}
+
+#[test]
+fn test_floor1_decode_posts() {
+	use codebook::CodeBook;
+	use floor::{VorbisFloor, VorbisLookFloor1, FLOOR1_UNUSED_POST};
+	let packets = load_test_packets();
+	let (ident, setup) = load_test_headers();
+	let books: Vec<CodeBook> = setup.static_codebooks.iter().map(|book|CodeBook::new_for_decode(book).unwrap()).collect();
+	let mut decoded = 0;
+	for packet in packets[3..].iter().take(20) {
+		let mut bitreader = BitReader::new(&packet.data);
+		assert_eq!(bitreader.read(1).unwrap(), 0);
+		let mode = &setup.modes[bitreader.read(ilog!(setup.modes.len() - 1)).unwrap() as usize];
+		if mode.block_flag {
+			bitreader.read(2).unwrap();
+		}
+		let mapping = mode.mapping_ref(&setup).unwrap();
+		for ch in 0..ident.channels as usize {
+			let submap = mapping.chmuxlist.iter().nth(ch).copied().unwrap_or(0) as usize;
+			let VorbisFloor::Floor1(floor1) = &setup.floors[mapping.floorsubmap[submap] as usize] else {
+				panic!("test.ogg should only use floor 1");
+			};
+			let look = VorbisLookFloor1::look(floor1.clone());
+			if let Some(posts) = floor1.decode_posts(&look, &mut bitreader, &books).unwrap() {
+				assert_eq!(posts.len(), floor1.postlist.len());
+				assert!(posts.iter().all(|&p|(0..256).contains(&(p & !FLOOR1_UNUSED_POST))));
+				let mut curve = vec![0.0; ident.block_size[mode.block_flag as usize] as usize / 2];
+				floor1.inverse(&look, &posts, &mut curve);
+				assert!(curve.iter().all(|&v|v > 0.0 && v <= 1.0));
+				decoded += 1;
+			}
+		}
+	}
+	assert!(decoded > 0);
+}
+
+#[test]
+fn test_floor0_decode() {
+	use std::rc::Rc;
+	use io_utils::CursorVecU8;
+	use codebook::{StaticCodeBook, CodeBook};
+	use floor::{VorbisFloor0, VorbisLookFloor0};
+	let static_codebook = StaticCodeBook {
+		dim: 2,
+		entries: 9,
+		lengthlist: vec![3, 3, 3, 3, 3, 3, 3, 4, 4],
+		maptype: 1,
+		q_min: 0.5,
+		q_delta: 0.5,
+		q_quant: 2,
+		quantlist: vec![0, 1, 2],
+		..Default::default()
+	};
+	let mut floor0 = VorbisFloor0 {
+		order: 2,
+		rate: 44100,
+		barkmap: 256,
+		ampbits: 6,
+		ampdB: 80,
+		..Default::default()
+	};
+	floor0.books.push(0);
+	let floor0 = Rc::new(floor0);
+	let look = VorbisLookFloor0::look(floor0.clone(), [256, 2048]);
+
+	// The amplitude, the book number and the codeword of (0.5, 1.0)
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	bitwriter.write(63, 6).unwrap();
+	bitwriter.write(0, 1).unwrap();
+	CodeBook::new_for_encode(&static_codebook).unwrap().encode(3, &mut bitwriter).unwrap();
+	let packed = bitwriter.into_bytes();
+	let books = [CodeBook::new_for_decode(&static_codebook).unwrap()];
+	let lsp = floor0.decode_lsp(&look, &mut BitReader::new(&packed), &books).unwrap().unwrap();
+	assert_eq!(lsp, vec![0.5, 1.0, 80.0]);
+	assert!(floor0.decode_lsp(&look, &mut BitReader::new(&[0]), &books).unwrap().is_none());
+	assert!(floor0.decode_lsp(&look, &mut BitReader::new(&packed[..1]), &books).unwrap().is_none());
+
+	let mut curve = vec![0.0; 1024];
+	floor0.inverse(&look, &lsp, &mut curve).unwrap();
+	let k = scales::toBARK(22050.0 / 1024.0 * 100.0) * 256.0 / scales::toBARK(22050.0);
+	let w = 2.0 * (std::f32::consts::PI / 256.0 * k.floor()).cos();
+	let p = 0.5 * (w - 2.0 * 1.0f32.cos());
+	let q = 0.5 * (w - 2.0 * 0.5f32.cos());
+	let expected = scales::fromdB(80.0 / (p * p * (2.0 - w) + q * q * (2.0 + w)).sqrt() - 80.0);
+	assert!((curve[100] - expected).abs() <= expected * 1e-4, "{} != {expected}", curve[100]);
+	assert!(curve.iter().all(|&v|v.is_finite() && v > 0.0));
+	assert!(floor0.inverse(&look, &lsp, &mut [0.0; 100]).is_err());
+}
+
+#[test]
+fn test_residue_decode() {
+	use floor::{VorbisFloor, VorbisLookFloor};
+	let packets = load_test_packets();
+	let (ident, setup) = load_test_headers();
+	let vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	let books = vd.vorbis_info.codec_setup.fullbooks.borrow().clone();
+	let channels = ident.channels as usize;
+	for packet in packets[3..].iter().take(20) {
+		let mut bitreader = BitReader::new(&packet.data);
+		assert_eq!(bitreader.read(1).unwrap(), 0);
+		let mode = &setup.modes[bitreader.read(ilog!(setup.modes.len() - 1)).unwrap() as usize];
+		if mode.block_flag {
+			bitreader.read(2).unwrap();
+		}
+		let n = ident.block_size[mode.block_flag as usize] as usize / 2;
+		let mapping = mode.mapping_ref(&setup).unwrap();
+		let submap_of = |ch: usize|mapping.chmuxlist.iter().nth(ch).copied().unwrap_or(0) as usize;
+		let mut do_not_decode = vec![true; channels];
+		for (ch, skip) in do_not_decode.iter_mut().enumerate() {
+			let floor_index = mapping.floorsubmap[submap_of(ch)] as usize;
+			let (VorbisFloor::Floor1(floor1), VorbisLookFloor::Floor1(look)) = (&setup.floors[floor_index], &vd.backend_state.borrow().flr_look[floor_index]) else {
+				panic!("test.ogg should only use floor 1");
+			};
+			*skip = floor1.decode_posts(look, &mut bitreader, &books).unwrap().is_none();
+		}
+		for i in 0..mapping.coupling_steps as usize {
+			let (m, a) = (mapping.coupling_mag[i] as usize, mapping.coupling_ang[i] as usize);
+			if !do_not_decode[m] || !do_not_decode[a] {
+				do_not_decode[m] = false;
+				do_not_decode[a] = false;
+			}
+		}
+		for submap in 0..mapping.submaps as usize {
+			let chs: Vec<usize> = (0..channels).filter(|&ch|submap_of(ch) == submap).collect();
+			let skip: Vec<bool> = chs.iter().map(|&ch|do_not_decode[ch]).collect();
+			let look = &vd.backend_state.borrow().residue_look[mapping.residuesubmap[submap] as usize];
+			let residues = look.decode(&mut bitreader, &skip, chs.len(), n).unwrap();
+			assert_eq!(residues.len(), chs.len());
+			assert!(residues.iter().all(|r|r.len() == n && r.iter().all(|v|v.is_finite())));
+		}
+		assert!(packet.data.len() * 8 - bitreader.total_bits < 8, "{} bits left", packet.data.len() * 8 - bitreader.total_bits);
+	}
+}
+
+#[test]
+fn test_inverse_coupling() {
+	use mapping::VorbisMapping;
+	let mut mapping = VorbisMapping {
+		coupling_steps: 1,
+		..Default::default()
+	};
+	mapping.coupling_mag.push(0);
+	mapping.coupling_ang.push(1);
+	let mut channels = vec![vec![3.0, 3.0, -3.0, -3.0, 2.0], vec![1.0, -1.0, 1.0, -1.0, 0.0]];
+	mapping.inverse_coupling(&mut channels);
+	assert_eq!(channels, vec![vec![3.0, 2.0, -3.0, -2.0, 2.0], vec![2.0, 3.0, -2.0, -3.0, 2.0]]);
+}
+
+#[test]
+fn test_decode_packet() {
+	let packets = load_test_packets();
+	let (ident, _) = load_test_headers();
+	let mut vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	let mut total = 0usize;
+	let mut energy = 0.0f64;
+	for (i, packet) in packets[3..].iter().enumerate() {
+		let pcm = vd.decode_packet(&packet.data).unwrap();
+		assert_eq!(pcm.len(), ident.channels as usize);
+		if i == 0 {
+			assert!(pcm.iter().all(|c|c.is_empty()));
+		}
+		assert!(pcm.iter().all(|c|c.len() == pcm[0].len()));
+		for s in pcm.iter().flatten() {
+			assert!(s.is_finite() && s.abs() < 2.0, "{s}");
+			energy += (*s as f64) * (*s as f64);
+		}
+		total += pcm[0].len();
+	}
+	assert_eq!(total as u64, vd.granulepos);
+	let last_granule = packets.last().unwrap().granule_position.unwrap();
+	assert!(total as u64 >= last_granule && total as u64 - last_granule < ident.block_size[1] as u64, "{total} vs {last_granule}");
+	assert!(energy / total as f64 > 1e-6, "The decoded audio is silent");
+}
+
+#[test]
+fn test_decode_packet_i16() {
+	let packets = load_test_packets();
+	let (ident, _) = load_test_headers();
+	let vi = load_test_info();
+	let mut vd_f32 = VorbisDspState::new(vi.clone(), false).unwrap();
+	let mut vd_i16 = VorbisDspState::new(vi, false).unwrap();
+	let channels = ident.channels as usize;
+	for packet in packets[3..].iter().take(20) {
+		let pcm = vd_f32.decode_packet(&packet.data).unwrap();
+		let interleaved = vd_i16.decode_packet_i16(&packet.data).unwrap();
+		assert_eq!(interleaved.len(), pcm[0].len() * channels);
+		for (i, frame) in interleaved.chunks(channels).enumerate() {
+			for (ch, &s) in frame.iter().enumerate() {
+				let expected = (pcm[ch][i] * 32768.0).round_ties_even().clamp(-32768.0, 32767.0) as i16;
+				assert_eq!(s, expected);
+			}
+		}
+	}
+}
+
+#[test]
+fn test_decode_ogg_vorbis() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let (ident, pcm) = decode_ogg_vorbis(&data).unwrap();
+	assert_eq!(pcm.len(), ident.channels as usize);
+	let total = OggVorbisIndex::build(&data).unwrap().total_samples as usize;
+	assert_eq!(total, 106886);
+	assert!(pcm.iter().all(|c|c.len() == total));
+	assert!(decode_ogg_vorbis(&data[..1000]).is_err());
+}
+
+#[test]
+fn test_vorbis_decoder() {
+	use std::{fs::File, io::BufReader};
+	let mut decoder = VorbisDecoder::new(BufReader::new(File::open("test.ogg").unwrap())).unwrap();
+	assert_eq!(decoder.channels(), 2);
+	let (ident, pcm) = decode_ogg_vorbis(&std::fs::read("test.ogg").unwrap()).unwrap();
+	assert_eq!(decoder.sample_rate(), ident.sample_rate as u32);
+	let mut pos = 0usize;
+	while let Some(block) = decoder.next_block().unwrap() {
+		assert_eq!(block.len(), 2);
+		for (channel, expected) in block.iter().zip(pcm.iter()) {
+			assert_eq!(channel[..], expected[pos..pos + channel.len()]);
+		}
+		pos += block[0].len();
+	}
+	assert_eq!(pos, pcm[0].len());
+	assert_eq!(decoder.clip_count().len(), 2);
+}
+
+#[test]
+fn test_vorbis_decoder_seek() {
+	use std::{fs::File, io::BufReader};
+	let (_, pcm) = decode_ogg_vorbis(&std::fs::read("test.ogg").unwrap()).unwrap();
+	let total = pcm[0].len() as u64;
+	let mut decoder = VorbisDecoder::new(BufReader::new(File::open("test.ogg").unwrap())).unwrap();
+	for sample in [50000, 0, 1, 1000, 70000, total - 1] {
+		decoder.seek_to_sample(sample).unwrap();
+		let mut pos = sample as usize;
+		for _ in 0..3 {
+			let Some(block) = decoder.next_block().unwrap() else {
+				break;
+			};
+			for (channel, expected) in block.iter().zip(pcm.iter()) {
+				assert_eq!(channel[..], expected[pos..pos + channel.len()], "seek to {sample}");
+			}
+			pos += block[0].len();
+		}
+	}
+	assert!(decoder.seek_to_sample(total).is_err());
+}
+
+#[test]
+fn test_ogg_vorbis_duration() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let (samples, seconds) = ogg_vorbis_duration(&data).unwrap();
+	let (ident, _) = load_test_headers();
+	assert_eq!(samples, 106886);
+	assert_eq!(seconds, 106886.0 / ident.sample_rate as f64);
+	assert!(ogg_vorbis_duration(&[]).is_err());
+}
+
+#[test]
+fn test_analysis_buffer() {
+	let (ident, _) = load_test_headers();
+	let vi = load_test_info_for_encode();
+	let mut vd = VorbisDspState::new(vi, true).unwrap();
+	let begin = vd.pcm_current;
+	for i in 0..10 {
+		let samples = 1000 * (i + 1);
+		let buffers = vd.analysis_buffer(samples);
+		assert_eq!(buffers.len(), ident.channels as usize);
+		for buffer in buffers {
+			assert_eq!(buffer.len(), samples);
+			buffer.fill(0.5);
+		}
+		vd.analysis_wrote(samples).unwrap();
+	}
+	assert_eq!(vd.pcm_current, begin + 55000);
+	assert!(vd.pcm.iter().all(|pcm|pcm[begin..vd.pcm_current].iter().all(|&s|s == 0.5)));
+	assert!(vd.analysis_wrote(vd.pcm_storage).is_err());
+	vd.analysis_wrote(0).unwrap();
+	assert!(vd.eofflag);
+	assert_eq!(vd.eofpos, begin + 55000);
+	// The end is extrapolated from the input rather than dropped to silence
+	assert!(vd.pcm.iter().all(|pcm|pcm[vd.eofpos..vd.pcm_current].iter().all(|s|s.is_finite() && s.abs() <= 0.5)));
+	assert!(vd.pcm.iter().all(|pcm|(pcm[vd.eofpos] - 0.5).abs() < 0.05));
+	assert!(vd.analysis_wrote(0).is_err());
+}
+
+#[test]
+fn test_lpc_extrapolate() {
+	let sine = |i: usize|(i as f32 * 0.05).sin() * 0.5;
+	let data: Vec<f32> = (0..2048).map(sine).collect();
+	let mut lpc = [0.0f32; 32];
+	let energy: f32 = data.iter().map(|s|s * s).sum();
+	let error = vorbis_lpc_from_data(&data, &mut lpc);
+	assert!(error >= 0.0 && error < energy * 1e-2, "{error} of {energy}");
+	// The filter is damped, so it follows the input closely only for a while
+	let mut predicted = vec![0.0f32; 16];
+	vorbis_lpc_predict(&lpc, Some(&data[data.len() - lpc.len()..]), &mut predicted);
+	for (i, &p) in predicted.iter().enumerate() {
+		assert!((p - sine(data.len() + i)).abs() < 0.02, "{i}: {p} vs {}", sine(data.len() + i));
+	}
+
+	// Silence gives a silent filter, so does no priming
+	let error = vorbis_lpc_from_data(&[0.0; 256], &mut lpc);
+	assert_eq!(error, 0.0);
+	assert!(lpc.iter().all(|&c|c == 0.0));
+	vorbis_lpc_predict(&lpc, None, &mut predicted);
+	assert!(predicted.iter().all(|&p|p == 0.0));
+}
+
+#[test]
+fn test_residue_encode() {
+	let (ident, setup) = load_test_headers();
+	let vi = load_test_info_for_encode();
+	let encoder = VorbisDspState::new(vi.clone(), true).unwrap();
+	let decoder = VorbisDspState::new(vi, false).unwrap();
+	let n = ident.block_size[1] as usize / 2;
+	let channels = ident.channels as usize;
+	let residuals: Vec<Vec<f32>> = (0..channels).map(|ch|(0..n).map(|i|((i * 7 + ch * 3) % 11) as f32 - 5.0).collect()).collect();
+	for (index, look) in encoder.backend_state.borrow().residue_look.iter().enumerate() {
+		let mut bitwriter = BitWriter::new(io_utils::CursorVecU8::default());
+		look.encode(&mut bitwriter, &residuals, &vec![false; channels]).unwrap();
+		let bytes = bitwriter.into_bytes();
+		let decoded = decoder.backend_state.borrow().residue_look[index].decode(&mut BitReader::new(&bytes), &vec![false; channels], channels, n).unwrap();
+		// Residue type 2 interleaves the channels, `end` covers the values of all of the channels.
+		let residue = &setup.residues[index];
+		assert_eq!(residue.residue_type, 2);
+		let end = residue.end as usize / channels;
+		for (original, decoded) in residuals.iter().zip(decoded.iter()) {
+			assert_eq!(original[..end], decoded[..end]);
+			assert!(decoded[end..].iter().all(|&v|v == 0.0));
+		}
+
+		// Nothing is written for the channels that are not encoded
+		let mut bitwriter = BitWriter::new(io_utils::CursorVecU8::default());
+		look.encode(&mut bitwriter, &residuals, &vec![true; channels]).unwrap();
+		assert_eq!(bitwriter.total_bits, 0);
+	}
+}
+
+#[test]
+fn test_analysis_blockout() {
+	let (ident, _) = load_test_headers();
+	let vi = load_test_info_for_encode();
+	let mut vd = VorbisDspState::new(vi, true).unwrap();
+	let long = ident.block_size[1] as usize;
+	let mut blocks = Vec::new();
+	let total = 20000;
+	for chunk in 0..total / 1000 {
+		for buffer in vd.analysis_buffer(1000) {
+			for (i, s) in buffer.iter_mut().enumerate() {
+				*s = ((chunk * 1000 + i) as f32 * 0.05).sin() * 0.5;
+			}
+		}
+		vd.analysis_wrote(1000).unwrap();
+		while let Some(vb) = vd.analysis_blockout().unwrap() {
+			blocks.push(vb);
+		}
+	}
+	vd.analysis_wrote(0).unwrap();
+	while let Some(vb) = vd.analysis_blockout().unwrap() {
+		blocks.push(vb);
+	}
+	assert!(vd.analysis_blockout().unwrap().is_none());
+	let short = ident.block_size[0] as usize;
+	assert_eq!(blocks[0].W, 0);
+	assert!(blocks[1..].iter().all(|vb|vb.W == 1 && vb.pcm.iter().all(|pcm|pcm.len() == long)));
+	assert!(blocks.iter().enumerate().all(|(i, vb)|vb.sequence == i as u32 + HEADER_PACKETS));
+	assert!(blocks[..blocks.len() - 1].iter().all(|vb|!vb.eofflag));
+	assert!(blocks.last().unwrap().eofflag);
+	assert_eq!(vd.granulepos, total as u64);
+
+	// The first block is a short one, the input begins at the center of it
+	let begin = long / 4 - short / 4;
+	let expected: Vec<f32> = (0..long - begin).map(|i|(i as f32 * 0.05).sin() * 0.5).collect();
+	assert_eq!(blocks[0].pcm[0][short / 2..], expected[..short / 2]);
+	assert_eq!(blocks[1].pcm[0][begin..], expected[..]);
+}
+
+#[test]
+fn test_mark_envelope() {
+	use std::rc::Rc;
+	use envelope::VorbisEnvelopeLookup;
+	use psy::VorbisInfoPsyGlobal;
+	let psy_g = VorbisInfoPsyGlobal {
+		eighth_octave_lines: 8,
+		preecho_thresh: [20.0, 14.0, 12.0, 12.0, 12.0, 12.0, 12.0],
+		postecho_thresh: [-60.0, -30.0, -40.0, -40.0, -40.0, -40.0, -40.0],
+		stretch_penalty: 2.0,
+		preecho_minenergy: -75.0,
+		ampmax_att_per_sec: -6.0,
+		..Default::default()
+	};
+	let mut vi = VorbisInfo {
+		channels: 1,
+		sample_rate: 44100,
+		..Default::default()
+	};
+	vi.codec_setup.block_size = [256, 2048];
+	vi.codec_setup.psy_g = Rc::new(psy_g);
+	let block_size = [256, 2048];
+	let len = 8192;
+
+	let tone = vec![(0..len).map(|i|(i as f32 * 0.05).sin() * 0.5).collect::<Vec<f32>>()];
+	let mut envelope = VorbisEnvelopeLookup::new(&vi);
+	assert_eq!(envelope.mark_envelope(&psy_g, &tone, len, 1024, 0, block_size), 1);
+
+	let mut click = vec![vec![0.0f32; len]];
+	click[0][1500] = 1.0;
+	let mut envelope = VorbisEnvelopeLookup::new(&vi);
+	assert_eq!(envelope.mark_envelope(&psy_g, &click, len, 1024, 0, block_size), 0);
+	assert!(envelope.is_impulse(1536, 1, 0, 0, block_size));
+}
+
+#[test]
+fn test_new_vbr() {
+	assert_eq!(VorbisInfo::new_vbr(0, 44100, 0.4).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+	assert_eq!(VorbisInfo::new_vbr(2, 0, 0.4).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+	assert_eq!(VorbisInfo::new_vbr(2, 44100, 1.5).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+	// Only the 44.1kHz and 48kHz templates are ported
+	for sample_rate in [8000, 11025, 16000, 22050, 32000, 39999, 50001, 96000] {
+		for channels in [1, 2] {
+			assert_eq!(VorbisInfo::new_vbr(channels, sample_rate, 0.4).unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+			assert_eq!(VorbisInfo::new_managed(channels, sample_rate, None, 64000 * channels, None).unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+		}
+	}
+	assert!(VorbisInfo::new_vbr(1, 40000, 0.4).is_ok());
+	assert!(VorbisInfo::new_vbr(1, 50000, 0.4).is_ok());
+	let wav = pcm_to_wav_i16(1, 22050, &[vec![0.0; 4096]]).unwrap();
+	assert_eq!(encode_wav_to_ogg_vorbis(&wav, 0.4).unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+
+	let vi = VorbisInfo::new_vbr(2, 44100, 0.4).unwrap();
+	let ci = &vi.codec_setup;
+	assert_eq!(ci.block_size, [256, 2048]);
+	assert_eq!((ci.modes.len(), ci.maps.len(), ci.floors.len(), ci.residues.len()), (2, 2, 2, 2));
+	assert_eq!(ci.maps[1].coupling_steps, 1);
+	assert_eq!(ci.residues[1].residue_type, 2);
+	assert_eq!(vi.bitrate_nominal, 128000);
+	assert!(ci.psys.iter().all(|psy|psy.normal_p == 1));
+	assert_eq!(ci.psys[3].block_flag, 1);
+
+	// The setup packs and loads back
+	let (ident, _, setup) = vi.build_headers(&VorbisCommentHeader::new()).unwrap();
+	let ident = VorbisIdentificationHeader::load_from_slice(&ident).unwrap();
+	let setup = VorbisSetupHeader::load(&mut BitReader::new(&setup), &ident).unwrap();
+	assert_eq!(setup.static_codebooks, ci.static_codebooks);
+	for (map, expected) in setup.maps.iter().zip(ci.maps.iter()) {
+		assert_eq!((map.submaps, map.floorsubmap, map.residuesubmap), (expected.submaps, expected.floorsubmap, expected.residuesubmap));
+		assert_eq!((map.coupling_steps, map.coupling_mag, map.coupling_ang), (expected.coupling_steps, expected.coupling_mag, expected.coupling_ang));
+	}
+	assert_eq!(setup.modes, ci.modes);
+	for (residue, expected) in setup.residues.iter().zip(ci.residues.iter()) {
+		assert_eq!((residue.end, residue.grouping, residue.partitions), (expected.end, expected.grouping, expected.partitions));
+		assert_eq!(residue.booklist, expected.booklist);
+	}
+	VorbisDspState::new(VorbisInfo::new(&ident, &setup).unwrap(), false).unwrap();
+	VorbisDspState::new(vi, true).unwrap();
+
+	// The other channel counts go to the uncoupled template
+	let vi = VorbisInfo::new_vbr(1, 48000, 0.0).unwrap();
+	assert_eq!(vi.codec_setup.maps[0].coupling_steps, 0);
+	assert_eq!(vi.codec_setup.residues[0].residue_type, 1);
+	vi.build_headers(&VorbisCommentHeader::new()).unwrap();
+	VorbisDspState::new(vi, true).unwrap();
+}
+
+#[test]
+fn test_new_managed() {
+	assert_eq!(VorbisInfo::new_managed(2, 44100, None, 0, None).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+	assert_eq!(VorbisInfo::new_managed(2, 44100, Some(96000), 128000, Some(160000)).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+	let vi = VorbisInfo::new_managed(2, 44100, Some(160000), 128000, Some(96000)).unwrap();
+	assert_eq!(vi.codec_setup.block_size, [256, 2048]);
+	assert_eq!((vi.bitrate_upper, vi.bitrate_nominal, vi.bitrate_lower, vi.bitrate_window), (160000, 128000, 96000, 2));
+	let bi = &vi.codec_setup.bitrate_manager_info;
+	assert_eq!((bi.max_rate, bi.avg_rate, bi.min_rate), (160000, 128000, 96000));
+	assert_eq!(bi.reservoir_bits, 256000);
+	let bm = bitrate::VorbisBitrateManagerState::new(&vi);
+	assert!(bm.managed);
+	assert_eq!(bm.short_per_long, 8);
+	assert_eq!((bm.max_bitsper, bm.avg_bitsper, bm.min_bitsper), (464, 372, 279));
+	assert_eq!((bm.avg_reservoir, bm.minmax_reservoir, bm.desired_fill), (25600, 25600, 25600));
+	let vd = VorbisDspState::new(vi, true).unwrap();
+	assert!(vd.backend_state.borrow().bitrate_manager_state.as_ref().unwrap().managed);
+
+	// The nominal bitrate is guessed from the max bitrate, the average isn't managed then
+	let vi = VorbisInfo::new_managed(1, 48000, Some(128000), 0, None).unwrap();
+	assert_eq!((vi.bitrate_upper, vi.bitrate_nominal, vi.bitrate_lower, vi.bitrate_window), (128000, 112000, -1, 0));
+	let bm = bitrate::VorbisBitrateManagerState::new(&vi);
+	assert!(bm.managed);
+	assert_eq!((bm.max_bitsper, bm.avg_bitsper), (341, 0));
+
+	let mut vi = VorbisInfo::default();
+	assert_eq!(vi.setup_bitrate_management(Some(160000), 0, Some(96000)).unwrap(), 128000);
+	assert_eq!((vi.bitrate_upper, vi.bitrate_nominal, vi.bitrate_lower), (160000, 128000, 96000));
+	let bi = &vi.codec_setup.bitrate_manager_info;
+	assert_eq!((bi.max_rate, bi.avg_rate, bi.min_rate), (160000, 0, 96000));
+	assert_eq!(bi.reservoir_bits, 256000);
+
+	let mut vi = VorbisInfo::default();
+	assert_eq!(vi.setup_bitrate_management(None, 128000, None).unwrap(), 128000);
+	assert_eq!((vi.bitrate_upper, vi.bitrate_nominal, vi.bitrate_lower), (-1, 128000, -1));
+	assert_eq!(vi.codec_setup.bitrate_manager_info.avg_rate, 128000);
+	assert_eq!(vi.bitrate_window, 2);
+}
+
+#[test]
+fn test_bitrate_flush() {
+	use std::{rc::Rc, cell::RefCell};
+	use ogg::OggPacketType;
+	use blocks::VorbisBlock;
+	let mut vi = load_test_info_for_encode();
+	vi.setup_bitrate_management(None, 128000, None).unwrap();
+	let mut vd = VorbisDspState::new(vi, true).unwrap();
+	let mut vb = VorbisBlock::new(&vd, 0);
+	vb.granulepos = 106886;
+	vb.sequence = 7;
+	let desired_fill = {
+		let mut backend_state = vd.backend_state.borrow_mut();
+		let bm = backend_state.bitrate_manager_state.as_mut().unwrap();
+		assert!(bm.managed);
+		let choice = bm.choice as usize;
+		vb.internal.as_mut().unwrap().packetblob[choice].borrow_mut().write(0x5a5, 12).unwrap();
+		bm.vorbis_block = Some(Rc::new(RefCell::new(vb)));
+		bm.avg_reservoir += 1000;
+		bm.avgfloat = 1.0;
+		bm.desired_fill
+	};
+
+	let packet = vd.flush_packet().unwrap().unwrap();
+	assert!(matches!(packet.packet_type, OggPacketType::EndOfStream));
+	assert_eq!(packet.granule_position, 106886);
+	assert_eq!(packet.packet_index, 7);
+	assert_eq!(packet.data, [0xa5, 0x05]);
+	{
+		let backend_state = vd.backend_state.borrow();
+		let bm = backend_state.bitrate_manager_state.as_ref().unwrap();
+		assert!(bm.vorbis_block.is_none());
+		assert_eq!((bm.avg_reservoir, bm.minmax_reservoir), (desired_fill, desired_fill));
+		assert_eq!(bm.avgfloat, (PACKETBLOBS / 2) as f64);
+	}
+	assert!(vd.flush_packet().unwrap().is_none());
+	assert!(vd.packet_out().is_none());
+}
+
+#[test]
+fn test_bitrate_add_block() {
+	use std::{rc::Rc, cell::RefCell};
+	use blocks::VorbisBlock;
+	let mut vi = load_test_info_for_encode();
+	vi.setup_bitrate_management(None, 128000, None).unwrap();
+	let mut vd = VorbisDspState::new(vi, true).unwrap();
+	let bm = vd.backend_state.borrow().bitrate_manager_state.clone().unwrap();
+	let target_bytes = (bm.avg_bitsper * bm.short_per_long) as usize / 8;
+
+	// Each blob is 40 bytes bigger than the previous one, the target is somewhere in the middle
+	let blocks = 400;
+	let mut total_bytes = 0;
+	let mut choices = Vec::new();
+	for _ in 0..blocks {
+		let mut vb = VorbisBlock::new(&vd, 0);
+		vb.W = 1;
+		for (i, blob) in vb.internal.as_ref().unwrap().packetblob.iter().enumerate() {
+			let mut blob = blob.borrow_mut();
+			for _ in 0..(i + 1) * 40 {
+				blob.write(0x55, 8).unwrap();
+			}
+		}
+		{
+			let mut backend_state = vd.backend_state.borrow_mut();
+			let bm = backend_state.bitrate_manager_state.as_mut().unwrap();
+			bm.add_block(Rc::new(RefCell::new(vb))).unwrap();
+			choices.push(bm.choice);
+		}
+		total_bytes += vd.packet_out().unwrap().data.len();
+	}
+	assert!(choices.iter().all(|&c|(0..PACKETBLOBS as i32).contains(&c)));
+	assert!(choices.iter().skip(blocks / 2).all(|&c|c > 0 && c < PACKETBLOBS as i32 - 1), "{choices:?}");
+	let average = total_bytes / blocks;
+	assert!(average.abs_diff(target_bytes) < target_bytes / 10, "average {average}, target {target_bytes}");
+}
+
+#[test]
+fn test_build_headers() {
+	use savagestr::prelude::*;
+	let packets = load_test_packets();
+	let (ident, setup) = load_test_headers();
+	let vi = load_test_info();
+	let mut comments = VorbisCommentHeader::new();
+	comments.comments.push("TITLE=test".to_string());
+	let (ident_packet, comment_packet, setup_packet) = vi.build_headers(&comments).unwrap();
+	assert_eq!(ident_packet, packets[0].data);
+	assert_eq!(VorbisIdentificationHeader::load_from_slice(&ident_packet).unwrap(), ident);
+	assert_eq!(VorbisCommentHeader::load(&mut BitReader::new(&comment_packet), &StringCodecMaps::new()).unwrap(), comments);
+	assert_eq!(VorbisSetupHeader::load(&mut BitReader::new(&setup_packet), &ident).unwrap(), setup);
+}
+
+#[test]
+fn test_safe_defaults() {
+	use residue::VorbisResidue;
+	use floor::VorbisFloor1;
+	use mapping::VorbisMapping;
+	use psy::VorbisInfoPsy;
+	use bitrate::VorbisBitrateManagerState;
+	let residue = VorbisResidue::default();
+	assert_eq!(residue, residue.clone());
+	assert!(residue.secondstages.is_empty() && residue.booklist.is_empty());
+	assert!(residue.classmetric1.iter().chain(residue.classmetric2.iter()).all(|&m|m == 0));
+	let floor1 = VorbisFloor1::default();
+	assert!(floor1.postlist.is_empty() && floor1.partitions == 0);
+	let mapping = VorbisMapping::default();
+	assert!(mapping.chmuxlist.is_empty() && mapping.coupling_steps == 0);
+	let psy = VorbisInfoPsy::default();
+	assert!(psy.noiseoff.iter().flatten().chain(psy.noisecompand.iter()).all(|&v|v == 0.0));
+	let bm = VorbisBitrateManagerState::default();
+	assert!(!bm.managed && bm.vorbis_block.is_none() && bm.avg_reservoir == 0);
+}
+
+#[test]
+fn test_dsp_state_default() {
+	fn make_default<T: Default>() -> T {
+		T::default()
+	}
+	let vd: VorbisDspState = make_default();
+	assert!(!vd.for_encode);
+	assert!(vd.pcm.is_empty() && vd.pcm_ret.is_empty());
+	assert!(vd.vorbis_info.codec_setup.fullbooks.borrow().is_empty());
+	assert!(vd.backend_state.borrow().bitrate_manager_state.is_none());
+	let cloned = vd.clone();
+	drop(vd);
+	assert_eq!(cloned.pcm_current, 0);
+}
+
+#[test]
+fn test_read_aligned_bytes() {
+	use std::borrow::Cow;
+	let data = [0x01u8, b'v', b'o', b'r', b'b', b'i', b's', 0xff];
+	let mut bitreader = BitReader::new(&data);
+	let bytes = bitreader.read_aligned_bytes(7).unwrap();
+	assert!(matches!(bytes, Cow::Borrowed(_)));
+	assert_eq!(&bytes[..], b"\x01vorbis");
+	assert_eq!((bitreader.cursor, bitreader.total_bits), (7, 56));
+	assert_eq!(bitreader.read_aligned_bytes(2).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+	assert_eq!(bitreader.cursor, 7);
+
+	let mut bitreader = BitReader::new(&data);
+	bitreader.read(4).unwrap();
+	let bytes = bitreader.read_aligned_bytes(3).unwrap();
+	assert!(matches!(bytes, Cow::Owned(_)));
+	assert_eq!(&bytes[..], [0x60, 0xf7, 0x26]);
+	assert_eq!(bitreader.total_bits, 28);
+	assert_eq!(bitreader.read_aligned_bytes(5).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+	assert_eq!(bitreader.read_aligned_bytes(4).unwrap().len(), 4);
+}
+
+#[test]
+fn test_write_aligned_bytes() {
+	use io_utils::CursorVecU8;
+	let bytes: Vec<u8> = (0..3000u32).map(|i|(i * 7 + 3) as u8).collect();
+	for lead_bits in [0, 3, 8] {
+		let mut fast = BitWriter::new(CursorVecU8::default());
+		let mut slow = BitWriter::new(CursorVecU8::default());
+		fast.write(0x5, lead_bits).unwrap();
+		slow.write(0x5, lead_bits).unwrap();
+		fast.write_aligned_bytes(&bytes).unwrap();
+		for &byte in bytes.iter() {
+			slow.write(byte as u32, 8).unwrap();
+		}
+		fast.write(1, 1).unwrap();
+		slow.write(1, 1).unwrap();
+		assert_eq!(fast.total_bits, slow.total_bits);
+		let fast = fast.into_bytes();
+		assert_eq!(fast, slow.into_bytes());
+		assert_eq!(fast.len(), (lead_bits as usize + bytes.len() * 8 + 1).div_ceil(8));
+	}
+
+	// Byte-aligned output doesn't get an extra byte, even after the cache was flushed
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	for i in 0..2000u32 {
+		bitwriter.write(i & 0xff, 8).unwrap();
+	}
+	let data = bitwriter.into_bytes();
+	assert_eq!(data.len(), 2000);
+	assert!(data.iter().enumerate().all(|(i, &b)|b == i as u8));
+
+	// `flush()` keeps its behavior: at a byte boundary the empty byte for the next bits is written too,
+	// in the middle of a byte the unfinished byte stays in the cache. `force_flush()` doesn't write the empty byte.
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	bitwriter.write(0xab, 8).unwrap();
+	bitwriter.flush().unwrap();
+	assert_eq!(bitwriter.writer[..], [0xab, 0x00]);
+	bitwriter.write(0x5, 3).unwrap();
+	bitwriter.flush().unwrap();
+	assert_eq!(bitwriter.writer[..], [0xab, 0x00]);
+	bitwriter.write(0xcd, 8).unwrap();
+	bitwriter.flush().unwrap();
+	assert_eq!(bitwriter.writer[..], [0xab, 0x00, 0x6d]);
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	bitwriter.write(0xab, 8).unwrap();
+	bitwriter.force_flush().unwrap();
+	assert_eq!(bitwriter.writer[..], [0xab]);
+}
+
+#[test]
+fn test_shift_data_64bit() {
+	// The reference is the plain bit-by-bit shift that the 8-bit work unit used to do
+	fn shift_bit_by_bit(data: &[u8], bits: usize, total_bits: usize) -> Vec<u8> {
+		let shifted_total_bits = total_bits.saturating_sub(bits);
+		let mut ret = vec![0u8; shifted_total_bits.div_ceil(8)];
+		for i in 0..shifted_total_bits {
+			let src = i + bits;
+			if src / 8 < data.len() && data[src / 8] & (1 << (src % 8)) != 0 {
+				ret[i / 8] |= 1 << (i % 8);
+			}
+		}
+		ret
+	}
+	let data: Vec<u8> = (0..61u32).map(|i|(i * 37 + 11) as u8).collect();
+	let total_bits = data.len() * 8;
+	for bits in (1..total_bits).filter(|b|b % 8 != 0) {
+		assert_eq!(shift_data_to_front(&data, bits, total_bits), shift_bit_by_bit(&data, bits, total_bits), "shift by {bits}");
+	}
+	let bitwise = BitwiseData::new(&data, 419);
+	let (front, back) = bitwise.split(123);
+	let mut joined = front.clone();
+	joined.concat(&back);
+	assert_eq!(joined, bitwise);
+}
+
+#[test]
+fn test_try_transmute_vector() {
+	let v: Vec<u32> = vec![0x01020304, 0x05060708];
+	let ret: Vec<i32> = try_transmute_vector(v).unwrap();
+	assert_eq!(ret, [0x01020304, 0x05060708]);
+	let v: Vec<u8> = vec![1, 2, 3];
+	let v = try_transmute_vector::<u8, [u8; 2]>(v).unwrap_err();
+	assert_eq!(v, [1, 2, 3]);
+	let mut v: Vec<[u8; 2]> = Vec::with_capacity(3);
+	v.push([1, 2]);
+	let ret: Vec<u8> = try_transmute_vector(v).unwrap();
+	assert_eq!(ret, [1, 2]);
+	assert_eq!(ret.capacity(), 6);
+	let v: Vec<u8> = vec![0; 8];
+	assert!(try_transmute_vector::<u8, u64>(v).is_err());
+}
+
+#[test]
+fn test_ilog_icount() {
+	const BITS: u32 = ilog(255);
+	assert_eq!(BITS, 8);
+	assert_eq!([0, 1, 2, 3, 4, 7, 8, u64::MAX].map(ilog), [0, 1, 2, 2, 3, 3, 4, 64]);
+	assert_eq!([0, 1, 3, 0x80000001, u64::MAX].map(icount), [0, 1, 2, 2, 64]);
+	let f: fn(u64) -> u32 = ilog;
+	assert_eq!(f(1024), 11);
+
+	// The macros evaluate the argument only once
+	let mut calls = 0;
+	let mut next = || {calls += 1; 5u32};
+	let bits: i32 = ilog!(next());
+	let ones = icount!(next());
+	assert_eq!((bits, ones, calls), (3, 2, 2));
+
+	// The macro gives the type the context wants
+	let wide: u8 = ilog!(u64::MAX);
+	let index: usize = ilog!(1000i64);
+	assert_eq!((wide, index), (64, 10));
+}
+
+#[test]
+fn test_book_bit_ranges() {
+	let packets = load_test_packets();
+	let setup = &packets[2].data;
+	let books = StaticCodeBooks::load_from_slice(&setup[7..]).unwrap();
+	let ranges = books.book_bit_ranges();
+	assert_eq!(ranges.len(), books.len());
+	assert_eq!(ranges[0].start, 8);
+	assert_eq!(ranges.last().unwrap().end, books.total_bits);
+	assert!(ranges.windows(2).all(|w|w[0].end == w[1].start));
+	assert_eq!(books.to_packed_codebooks().unwrap().book_bit_ranges(), ranges);
+	for (range, book) in ranges.iter().zip(books.books.iter()) {
+		let mut bitreader = BitReader::new(setup);
+		let mut skip = 56 + range.start;
+		while skip > 0 {
+			let bits = skip.min(32);
+			bitreader.read(bits as i32).unwrap();
+			skip -= bits;
+		}
+		assert_eq!(&StaticCodeBook::load(&mut bitreader).unwrap(), book);
+		assert_eq!(bitreader.total_bits, 56 + range.end);
+	}
+}
+
+#[test]
+fn test_bitwise_data_shift_in_place() {
+	let bytes: Vec<u8> = (0..37u32).map(|i|(i * 53 + 29) as u8).collect();
+	let data = BitwiseData::new(&bytes, 290);
+	for bits in [0, 1, 7, 8, 13, 64, 289, 290, 400] {
+		let mut shifted = data.clone();
+		shifted.shift_front(bits);
+		let expected = BitwiseData::new(&shift_data_to_front(&data.data, bits, data.total_bits), data.total_bits.saturating_sub(bits));
+		assert_eq!(shifted, expected, "shift_front({bits})");
+
+		let mut shifted = data.clone();
+		shifted.shift_back(bits);
+		let expected = BitwiseData::new(&shift_data_to_back(&data.data, bits, data.total_bits), data.total_bits + bits);
+		assert_eq!(shifted, expected, "shift_back({bits})");
+		shifted.shift_front(bits);
+		assert_eq!(shifted, data);
+	}
+}
+
+#[test]
+fn test_vorbis_error() {
+	use floor::VorbisFloor;
+	let err = VorbisIdentificationHeader::load_from_slice(b"\x03vorbis\0\0\0\0").unwrap_err();
+	assert!(matches!(err, VorbisError::BadMagic{expected: 1, ref got} if got == b"\x03vorbis"));
+	assert!(matches!(VorbisIdentificationHeader::load_from_slice(b"\x01vorbis\0\0").unwrap_err(), VorbisError::UnexpectedEof));
+
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(5, 16).unwrap();
+	let data = bitwriter.into_bytes();
+	let mut bitreader = BitReader::new(&data);
+	assert!(matches!(VorbisFloor::load(&mut bitreader, &VorbisSetupHeader::default()).unwrap_err(), VorbisError::InvalidFloorType(5)));
+
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(3, 16).unwrap();
+	let data = bitwriter.into_bytes();
+	let mut bitreader = BitReader::new(&data);
+	assert!(matches!(residue::VorbisResidue::load(&mut bitreader, &VorbisSetupHeader::default()).unwrap_err(), VorbisError::InvalidResidueType(3)));
+
+	let err = VorbisError::from(StaticCodeBook::from_lengths(1, vec![1, 1, 1]).unwrap_err());
+	assert!(matches!(err, VorbisError::OverpopulatedTree));
+	let err = VorbisError::from(StaticCodeBook::from_lengths(1, vec![1, 2]).unwrap_err());
+	assert!(matches!(err, VorbisError::UnderpopulatedTree));
+	let err: std::io::Error = err.into();
+	assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_identification_header_new() {
+	let ident = VorbisIdentificationHeader::new(2, 44100, 256, 2048).unwrap();
+	let mut bitwriter = BitWriterCursor::default();
+	ident.pack(&mut bitwriter).unwrap();
+	assert_eq!(VorbisIdentificationHeader::load_from_slice(&bitwriter.into_bytes()).unwrap(), ident);
+	assert!(VorbisIdentificationHeader::new(0, 44100, 256, 2048).is_err());
+	assert!(VorbisIdentificationHeader::new(2, 0, 256, 2048).is_err());
+	assert!(VorbisIdentificationHeader::new(2, 44100, 32, 2048).is_err());
+	assert!(VorbisIdentificationHeader::new(2, 44100, 2048, 256).is_err());
+	assert!(VorbisIdentificationHeader::new(2, 44100, 256, 16384).is_err());
+	assert!(VorbisIdentificationHeader::new(2, 44100, 256, 1000).is_err());
+	assert_eq!(VorbisIdentificationHeader::new(2, 44100, 300, 2048).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_identification_header_block_size_exponents() {
+	let mut bitwriter = BitWriterCursor::default();
+	VorbisIdentificationHeader::new(2, 44100, 256, 2048).unwrap().pack(&mut bitwriter).unwrap();
+	let data = bitwriter.into_bytes();
+	let with_exponents = |bs_1: u8, bs_2: u8| {
+		let mut data = data.clone();
+		data[28] = bs_1 | (bs_2 << 4);
+		VorbisIdentificationHeader::load_from_slice(&data)
+	};
+	assert_eq!(with_exponents(6, 6).unwrap().block_size, [64, 64]);
+	assert_eq!(with_exponents(6, 13).unwrap().block_size, [64, 8192]);
+	assert_eq!(with_exponents(13, 13).unwrap().block_size, [8192, 8192]);
+	assert!(with_exponents(5, 13).is_err());
+	assert!(with_exponents(6, 14).is_err());
+	assert!(with_exponents(0, 15).is_err());
+	assert!(with_exponents(11, 8).is_err());
+}
+
+#[test]
+fn test_send_sync() {
+	fn assert_send_sync<T: Send + Sync>() {}
+	assert_send_sync::<MdctLookup>();
+	assert_send_sync::<DrftLookup>();
+	assert_send_sync::<StaticCodeBook>();
+	assert_send_sync::<CodeBook>();
+	assert_send_sync::<VorbisIdentificationHeader>();
+	assert_send_sync::<VorbisCommentHeader>();
+	assert_send_sync::<VorbisError>();
+}
+
+#[test]
+fn test_codebook_sparsity() {
+	let src = StaticCodeBook::from_lengths(1, vec![1, 0, 2, 0, 3, 3, 0, 0]).unwrap();
+	let book = CodeBook::new(false, &src).unwrap();
+	assert!(book.is_sparse());
+	assert_eq!(book.sparsity(), 0.5);
+	assert_eq!(book.code_list.len(), 4);
+
+	let src = StaticCodeBook::from_lengths(1, vec![1, 2, 3, 3]).unwrap();
+	let book = CodeBook::new(false, &src).unwrap();
+	assert!(!book.is_sparse());
+	assert_eq!(book.sparsity(), 1.0);
+}
+
+#[test]
+fn test_static_codebook_value_vectors() {
+	let mut book = StaticCodeBook::from_lengths(2, vec![2; 4]).unwrap();
+	assert!(book.value_vectors().is_err());
+
+	// Maptype 1: each column counts through the two quantized values
+	book.maptype = 1;
+	book.q_min = -1.0;
+	book.q_delta = 2.0;
+	book.q_quant = 1;
+	book.quantlist = vec![0, 1];
+	let vectors: Vec<Vec<f32>> = book.value_vectors().unwrap().collect();
+	assert_eq!(vectors, vec![vec![-1.0, -1.0], vec![1.0, -1.0], vec![-1.0, 1.0], vec![1.0, 1.0]]);
+	assert_eq!(vectors.concat(), book.book_unquantize(4, None).unwrap().unwrap());
+
+	// The sequence flag accumulates along the vector
+	book.q_sequencep = true;
+	let vectors: Vec<Vec<f32>> = book.value_vectors().unwrap().collect();
+	assert_eq!(vectors, vec![vec![-1.0, -2.0], vec![1.0, 0.0], vec![-1.0, 0.0], vec![1.0, 2.0]]);
+
+	// Maptype 2: the values are listed explicitly
+	book.maptype = 2;
+	book.q_sequencep = false;
+	book.quantlist = vec![0, 1, 1, 1, 1, 0, 0, 0];
+	let vectors: Vec<Vec<f32>> = book.value_vectors().unwrap().collect();
+	assert_eq!(vectors, vec![vec![-1.0, 1.0], vec![1.0, 1.0], vec![1.0, -1.0], vec![-1.0, -1.0]]);
+}
+
+#[test]
+fn test_verify_setup_roundtrip() {
+	let a = BitwiseData::new(&[0b1010_1100, 0xFF], 12);
+	assert_eq!(a.first_difference(&a.clone()), None);
+	assert_eq!(a.first_difference(&BitwiseData::new(&[0b1010_1100, 0x0F], 12)), None);
+	assert_eq!(a.first_difference(&BitwiseData::new(&[0b1010_1000, 0xFF], 12)), Some(2));
+	assert_eq!(a.first_difference(&BitwiseData::new(&[0b1010_1100, 0xF7], 12)), Some(11));
+	assert_eq!(a.first_difference(&BitwiseData::new(&[0b1010_1100], 8)), Some(8));
+
+	let packets = load_test_packets();
+	let (ident_header, _) = load_test_headers();
+	assert!(verify_setup_roundtrip(&packets[2].data, &ident_header).unwrap());
+	let mut corrupted = packets[2].data.clone();
+	corrupted.push(0);
+	assert!(!verify_setup_roundtrip(&corrupted, &ident_header).unwrap());
+}
+
+#[test]
+fn test_remove_codebook_padding() {
+	let packets = load_test_packets();
+	let (ident_header, _) = load_test_headers();
+	let setup = &packets[2].data;
+	let codebooks = StaticCodeBooks::load_from_slice(&setup[7..]).unwrap();
+	let stripped = no_usage::remove_codebook_from_setup_header(setup).unwrap();
+
+	// The end-of-packet flag stays the last set bit, followed by the padding of the last byte only
+	let last = *stripped.last().unwrap();
+	assert_ne!(last, 0);
+	let original_bits = (setup.len() - 1) * 8 + 8 - setup.last().unwrap().leading_zeros() as usize;
+	let stripped_bits = (stripped.len() - 1) * 8 + 8 - last.leading_zeros() as usize;
+	assert_eq!(stripped_bits, original_bits - codebooks.total_bits + 8);
+
+	// Putting the codebooks back gives a header that parses to the original
+	let stripped = BitwiseData::new(&stripped, stripped_bits);
+	let (front, _) = stripped.split(56);
+	let (_, back) = stripped.split(64);
+	let mut restored = front;
+	restored.concat(&codebooks.to_packed_codebooks().unwrap().books);
+	restored.concat(&back);
+	let restored = restored.into_bytes();
+	assert_eq!(&restored, setup);
+	let original = VorbisSetupHeader::load(&mut BitReader::new(setup), &ident_header).unwrap();
+	assert_eq!(VorbisSetupHeader::load(&mut BitReader::new(&restored), &ident_header).unwrap(), original);
+}
+
+#[test]
+fn test_replace_codebooks_in_setup_header() {
+	let packets = load_test_packets();
+	let (ident_header, _) = load_test_headers();
+	let setup = &packets[2].data;
+	let original = VorbisSetupHeader::load(&mut BitReader::new(setup), &ident_header).unwrap();
+	let mut codebooks = StaticCodeBooks::load_from_slice(&setup[7..]).unwrap();
+	assert_eq!(&replace_codebooks_in_setup_header(setup, &codebooks).unwrap(), setup);
+
+	// Append an extra book, the rest of the header is untouched
+	let extra = StaticCodeBook::from_lengths(1, vec![1, 2, 3, 3]).unwrap();
+	let mut bitwriter = BitWriterCursor::default();
+	codebooks.bits_of_books.push(extra.pack(&mut bitwriter).unwrap());
+	codebooks.total_bits += *codebooks.bits_of_books.last().unwrap();
+	codebooks.books.push(extra.clone());
+	let replaced = replace_codebooks_in_setup_header(setup, &codebooks).unwrap();
+	let replaced = VorbisSetupHeader::load(&mut BitReader::new(&replaced), &ident_header).unwrap();
+	assert_eq!(replaced.static_codebooks.len(), original.static_codebooks.len() + 1);
+	assert_eq!(replaced.static_codebooks.last(), Some(&extra));
+	assert_eq!(replaced.floors, original.floors);
+	assert_eq!(replaced.residues, original.residues);
+	assert_eq!(replaced.maps, original.maps);
+	assert_eq!(replaced.modes, original.modes);
+
+	assert!(replace_codebooks_in_setup_header(&packets[0].data, &codebooks).is_err());
+}
+
+#[test]
+fn test_packet_blobs() {
+	use std::rc::Rc;
+	use blocks::{PacketBlob, PacketBlobs};
+	let center = PacketBlob::default();
+	let blobs = PacketBlobs::new(center.clone());
+	assert_eq!(PacketBlobs::center(), PACKETBLOBS / 2);
+	assert!(Rc::ptr_eq(&blobs[PacketBlobs::center()], &center));
+	assert_eq!(blobs.iter().filter(|blob|Rc::ptr_eq(blob, &center)).count(), 1);
+	assert!(blobs.get(PACKETBLOBS).is_none());
+	assert!(Rc::ptr_eq(blobs.get_clamped(-1), &blobs[0]));
+	assert!(Rc::ptr_eq(blobs.get_clamped(PACKETBLOBS as i32), &blobs[PACKETBLOBS - 1]));
+}
+
+#[test]
+fn test_get_vorbis_headers_checked() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let packets = ogg_stream_packets(&data, None).unwrap();
+	let mut stream_id = 0;
+	let (ident, comment, setup) = get_vorbis_headers_checked(&data, &mut stream_id).unwrap();
+	assert_eq!(stream_id, ogg_pages(&data).next().unwrap().unwrap().stream_id);
+	assert_eq!(ident, packets[0].data);
+	assert_eq!(comment, packets[1].data);
+	assert_eq!(setup, packets[2].data);
+
+	// Flip a bit in the payload of the second page
+	let page = ogg_pages(&data).nth(1).unwrap().unwrap();
+	let mut corrupted = data.clone();
+	corrupted[page.offset + page.len() - 1] ^= 0x10;
+	let err = get_vorbis_headers_checked(&corrupted, &mut stream_id).unwrap_err();
+	assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+	assert!(err.to_string().contains("checksum"), "{err}");
+
+	let first_page_len = ogg_pages(&data).next().unwrap().unwrap().len();
+	assert_eq!(get_vorbis_headers_checked(&data[..first_page_len], &mut stream_id).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_granule_advance() {
+	use blocks::VorbisBlock;
+	let packets = load_test_packets();
+	let (ident, setup) = load_test_headers();
+	let vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	let mut vb = VorbisBlock::new(&vd, 0);
+	assert_eq!(vb.granule_advance(), 128);
+	vb.W = 1;
+	assert_eq!(vb.granule_advance(), 64 + 512);
+	vb.lW = 1;
+	assert_eq!(vb.granule_advance(), 1024);
+
+	// The decoder finishes the same number of samples for each packet after the first one
+	let mut decoder = VorbisDspState::new(load_test_info(), false).unwrap();
+	let mut prev_w = None;
+	for packet in packets[3..20].iter() {
+		let w = (vorbis_packet_block_size(&packet.data, &ident, &setup).unwrap() == 2048) as usize;
+		let finished = decoder.decode_packet(&packet.data).unwrap()[0].len();
+		if let Some(prev_w) = prev_w {
+			vb.lW = prev_w;
+			vb.W = w;
+			assert_eq!(vb.granule_advance(), finished as u64);
+		} else {
+			assert_eq!(finished, 0);
+		}
+		prev_w = Some(w);
+	}
+}
+
+#[test]
+fn test_parse_wav() {
+	let frames: [[i16; 2]; 4] = [[0, 0], [16384, -16384], [-32768, 32767], [8192, 0]];
+	let mut wav = Vec::<u8>::new();
+	wav.extend(b"RIFF");
+	wav.extend((36 + 16u32).to_le_bytes());
+	wav.extend(b"WAVEfmt ");
+	wav.extend(16u32.to_le_bytes());
+	wav.extend(1u16.to_le_bytes());
+	wav.extend(2u16.to_le_bytes());
+	wav.extend(44100u32.to_le_bytes());
+	wav.extend((44100u32 * 4).to_le_bytes());
+	wav.extend(4u16.to_le_bytes());
+	wav.extend(16u16.to_le_bytes());
+	wav.extend(b"data");
+	wav.extend(16u32.to_le_bytes());
+	for frame in frames.iter() {
+		for sample in frame.iter() {
+			wav.extend(sample.to_le_bytes());
+		}
+	}
+	let pcm = parse_wav(&wav).unwrap();
+	assert_eq!((pcm.channels, pcm.sample_rate), (2, 44100));
+	assert_eq!(pcm.samples, vec![vec![0.0, 0.5, -1.0, 0.25], vec![0.0, -0.5, 32767.0 / 32768.0, 0.0]]);
+
+	assert_eq!(parse_wav(&wav[..8]).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+	assert_eq!(parse_wav(&wav[..36]).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+	// Even a few samples make a complete stream
+	let (ident, decoded) = decode_ogg_vorbis(&encode_wav_to_ogg_vorbis(&wav, 0.4).unwrap()).unwrap();
+	assert_eq!((ident.channels, ident.sample_rate), (2, 44100));
+	assert!(decoded.iter().all(|channel|channel.len() == frames.len()));
+}
+
+#[test]
+fn test_encode_wav_to_ogg_vorbis() {
+	// Two tones in the left channel, a sweep in the right channel, fading in and out
+	let rate = 44100;
+	let frames = 44100 + 1234;
+	let fade = |i: usize|(i.min(frames - 1 - i) as f32 / 2048.0).min(1.0);
+	let left: Vec<f32> = (0..frames).map(|i|{
+		let t = i as f32 / rate as f32;
+		fade(i) * (0.3 * (std::f32::consts::TAU * 440.0 * t).sin() + 0.1 * (std::f32::consts::TAU * 3000.0 * t).sin())
+	}).collect();
+	let right: Vec<f32> = (0..frames).map(|i|{
+		let t = i as f32 / rate as f32;
+		fade(i) * 0.3 * (std::f32::consts::TAU * (200.0 + 1000.0 * t) * t).sin()
+	}).collect();
+	let wav = pcm_to_wav_i16(2, rate, &[left, right]).unwrap();
+	let input = parse_wav(&wav).unwrap();
+
+	let ogg = encode_wav_to_ogg_vorbis(&wav, 0.4).unwrap();
+	assert_eq!(ogg, encode_wav_to_ogg_vorbis(&wav, 0.4).unwrap());
+	let report = validate_ogg_vorbis(&ogg);
+	assert!(report.is_ok(), "{:?}", report.issues);
+	let pages: Vec<OggPageView> = ogg_pages(&ogg).collect::<std::io::Result<_>>().unwrap();
+	assert!(pages.first().unwrap().is_bos());
+	assert!(pages.last().unwrap().is_eos());
+	assert!(ogg.len() < wav.len() / 4);
+
+	let (ident, decoded) = decode_ogg_vorbis(&ogg).unwrap();
+	assert_eq!((ident.channels, ident.sample_rate), (2, 44100));
+	for (decoded, input) in decoded.iter().zip(input.samples.iter()) {
+		assert_eq!(decoded.len(), frames);
+		let signal: f32 = input.iter().map(|s|s * s).sum();
+		let noise: f32 = input.iter().zip(decoded.iter()).map(|(s, d)|(s - d) * (s - d)).sum();
+		let snr = 10.0 * (signal / noise).log10();
+		assert!(snr > 30.0, "SNR {snr} dB");
+	}
+
+	// A mono stream at 48kHz takes the uncoupled template
+	let wav = pcm_to_wav_i16(1, 48000, &[vec![0.25; 5000]]).unwrap();
+	let (ident, decoded) = decode_ogg_vorbis(&encode_wav_to_ogg_vorbis(&wav, 0.0).unwrap()).unwrap();
+	assert_eq!((ident.channels, ident.sample_rate), (1, 48000));
+	assert_eq!(decoded[0].len(), 5000);
+}
+
+#[test]
+fn test_decode_ogg_vorbis_to_wav() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let (ident, pcm) = decode_ogg_vorbis(&data).unwrap();
+	let wav = decode_ogg_vorbis_to_wav(&data).unwrap();
+	assert_eq!(&wav[0..4], b"RIFF");
+	assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()) as usize, wav.len() - 8);
+	assert_eq!(wav.len(), 44 + pcm[0].len() * ident.channels as usize * 2);
+
+	let parsed = parse_wav(&wav).unwrap();
+	assert_eq!(parsed.channels as i32, ident.channels);
+	assert_eq!(parsed.sample_rate as i32, ident.sample_rate);
+	for (parsed, decoded) in parsed.samples.iter().zip(pcm.iter()) {
+		assert_eq!(parsed.len(), decoded.len());
+		assert!(parsed.iter().zip(decoded.iter()).all(|(p, d)|(p - d.clamp(-1.0, 32767.0 / 32768.0)).abs() <= 0.5 / 32768.0));
+	}
+	assert!(pcm_to_wav_i16(2, 44100, &[vec![0.0; 3], vec![0.0; 2]]).is_err());
+}
+
+#[test]
+fn test_channel_layout() {
+	use Channel::*;
+	let mut vi = VorbisInfo::default();
+	for channels in 1..=8 {
+		vi.channels = channels;
+		assert_eq!(vi.channel_layout().len(), channels as usize);
+	}
+	vi.channels = 2;
+	assert_eq!(vi.channel_layout(), [FrontLeft, FrontRight]);
+	vi.channels = 6;
+	assert_eq!(vi.channel_layout(), [FrontLeft, FrontCenter, FrontRight, RearLeft, RearRight, LowFrequency]);
+	vi.channels = 9;
+	assert!(vi.channel_layout().is_empty());
+	vi.channels = 0;
+	assert!(vi.channel_layout().is_empty());
+}
+
+#[test]
+fn test_psy_tables() {
+	let ath = ath_curve();
+	assert_eq!(ath.len(), MAX_ATH);
+	assert_eq!(ath[0], -51.0);
+	assert!(ath.iter().all(|&db|db < 0.0));
+	for band in 0..P_BANDS {
+		let curves = tone_mask(band).unwrap();
+		assert_eq!(curves.len(), 6);
+		assert!(curves.iter().all(|curve|curve.len() == EHMER_MAX));
+	}
+	assert!(tone_mask(P_BANDS).is_none());
+}
+
+#[test]
+fn test_psy_mask_curve() {
+	use std::rc::Rc;
+	use psy::{VorbisInfoPsy, VorbisInfoPsyGlobal, VorbisLookPsy};
+	let vi = Rc::new(VorbisInfoPsy {
+		ath_adjatt: -120.0,
+		ath_maxatt: -140.0,
+		tone_masteratt: [0.0; 3],
+		tone_abs_limit: 105.0,
+		noisemaxsupp: -10.0,
+		noisewindowlo: 0.5,
+		noisewindowhi: 0.5,
+		noisewindowlomin: 2,
+		noisewindowhimin: 2,
+		noisewindowfixed: 8,
+		noisecompand: std::array::from_fn(|i|i as f32),
+		max_curve_dB: 105.0,
+		..Default::default()
+	});
+	let gi = VorbisInfoPsyGlobal {
+		eighth_octave_lines: 8,
+		..Default::default()
+	};
+	let look = VorbisLookPsy::new(vi, &gi, 1024, 44100);
+
+	// Without any tone, the noise mask is capped by the noise suppression limit
+	let quiet = vec![1e-3f32; 1024];
+	let mut quiet_mask = vec![0.0f32; 1024];
+	look.mask_curve(&quiet, &mut quiet_mask);
+	assert!(quiet_mask.iter().all(|&m|m.is_finite() && m <= -10.0));
+
+	// A loud tone masks the bins above it
+	let mut magnitudes = vec![1e-3f32; 1024];
+	magnitudes[100] = 1000.0;
+	let mut mask = vec![0.0f32; 1024];
+	look.mask_curve(&magnitudes, &mut mask);
+	assert!(mask.iter().all(|m|m.is_finite()));
+	assert!(mask[120..200].iter().any(|&m|m > 0.0));
+	assert!(mask[120..200].iter().zip(quiet_mask[120..200].iter()).all(|(m, q)|m > q));
+}
+
+#[test]
+fn test_decode_packet_into() {
+	let packets = load_test_packets();
+	let (ident, _) = load_test_headers();
+	let vi = load_test_info();
+	let mut vd = VorbisDspState::new(vi.clone(), false).unwrap();
+	let mut vd_into = VorbisDspState::new(vi, false).unwrap();
+	let channels = ident.channels as usize;
+
+	// The buffers must hold the half of the long block
+	let mut short = vec![vec![0.0f32; ident.block_size[1] as usize / 2 - 1]; channels];
+	assert_eq!(vd_into.decode_packet_into(&packets[3].data, &mut short).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+	// The stale samples in the reused buffers never leak into the output
+	let mut out = vec![vec![f32::NAN; ident.block_size[1] as usize / 2]; channels];
+	for packet in packets[3..].iter().take(40) {
+		let pcm = vd.decode_packet(&packet.data).unwrap();
+		let samples = vd_into.decode_packet_into(&packet.data, &mut out).unwrap();
+		assert_eq!(samples, pcm[0].len());
+		for (channel, expected) in out.iter_mut().zip(pcm.iter()) {
+			assert_eq!(&channel[..samples], &expected[..]);
+			channel.fill(f32::NAN);
+		}
+	}
+	assert_eq!(vd.granulepos, vd_into.granulepos);
+
+	// Once the buffers are large enough for both of the block sizes, decoding again doesn't grow them
+	let capacities = |vd: &VorbisDspState| {
+		let backend_state = vd.backend_state.borrow();
+		let scratch = &backend_state.decode_scratch;
+		[scratch.do_not_decode.capacity(), scratch.submap_channels.capacity(), scratch.residue.partwords.capacity(), scratch.residue.values.capacity()]
+	};
+	let warmed = capacities(&vd_into);
+	assert!(warmed.iter().all(|&capacity|capacity > 0));
+	vd_into.synthesis_restart();
+	for packet in packets[3..].iter().take(40) {
+		vd_into.decode_packet_into(&packet.data, &mut out).unwrap();
+		assert_eq!(capacities(&vd_into), warmed);
+	}
+}
+
+#[test]
+fn test_read_bytes_into() {
+	let data = [0x01u8, b'v', b'o', b'r', b'b', b'i', b's', 0xff];
+	let mut scratch = [0u8; 7];
+	let mut bitreader = BitReader::new(&data);
+	bitreader.read_bytes_into(&mut scratch).unwrap();
+	assert_eq!(&scratch, b"\x01vorbis");
+	assert_eq!((bitreader.cursor, bitreader.total_bits), (7, 56));
+	assert_eq!(bitreader.read_bytes_into(&mut scratch[..2]).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+	assert_eq!(bitreader.cursor, 7);
+
+	let mut bitreader = BitReader::new(&data);
+	bitreader.read(4).unwrap();
+	bitreader.read_bytes_into(&mut scratch[..3]).unwrap();
+	assert_eq!(&scratch[..3], [0x60, 0xf7, 0x26]);
+	assert_eq!(bitreader.total_bits, 28);
+	assert_eq!(bitreader.read_bytes_into(&mut scratch[..5]).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+	assert_eq!(bitreader.total_bits, 28);
+}
+
+#[test]
+fn test_setup_header_packed_bit_size() {
+	use io_utils::CursorVecU8;
+	let (ident, setup) = load_test_headers();
+	for book in setup.static_codebooks.iter() {
+		let mut bitwriter = BitWriter::new(CursorVecU8::default());
+		assert_eq!(book.packed_bits(), book.pack(&mut bitwriter).unwrap());
+	}
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	let packed_bits = setup.pack(&mut bitwriter, &ident).unwrap();
+	assert_eq!(setup.packed_bit_size(&ident), packed_bits);
+	assert_eq!(setup.packed_bit_size(&ident).div_ceil(8), bitwriter.into_bytes().len());
+
+	// A floor without partitions only has the two implicit posts
+	let mut floor1 = floor::VorbisFloor1 {
+		mult: 2,
+		..Default::default()
+	};
+	floor1.postlist.push(0);
+	floor1.postlist.push(128);
+	let mut bitwriter = BitWriter::new(CursorVecU8::default());
+	assert_eq!(floor1.packed_bits(), floor1.pack(&mut bitwriter).unwrap());
+	let bytes = bitwriter.into_bytes();
+	let mut bitreader = BitReader::new(&bytes);
+	assert_eq!(bitreader.read(16).unwrap(), 1);
+	let floor::VorbisFloor::Floor1(loaded) = floor::VorbisFloor1::load(&mut bitreader, &setup).unwrap() else {
+		panic!("Expected a floor 1");
+	};
+	assert_eq!(loaded.partitions, 0);
+	assert_eq!(loaded.postlist, floor1.postlist);
+}
+
+#[test]
+fn test_bitwriter_append_to_vec() {
+	let mut cursor = BitWriterCursor::default();
+	cursor.write(0x5, 3).unwrap();
+	cursor.write(0x1234567, 27).unwrap();
+	cursor.write_aligned_bytes(b"vorbis").unwrap();
+	let expected = cursor.into_bytes();
+
+	let mut buffer = b"\x05".to_vec();
+	let mut bitwriter = BitWriterVec::append_to(&mut buffer);
+	bitwriter.write(0x5, 3).unwrap();
+	bitwriter.write(0x1234567, 27).unwrap();
+	bitwriter.write_aligned_bytes(b"vorbis").unwrap();
+	assert_eq!(bitwriter.get_total_bits(), 30 + 48);
+	bitwriter.finish().unwrap();
+	assert_eq!(buffer[0], 0x05);
+	assert_eq!(&buffer[1..], &expected[..]);
+
+	// Appending again continues at the byte boundary
+	let mut bitwriter = BitWriterVec::append_to(&mut buffer);
+	bitwriter.write(1, 1).unwrap();
+	bitwriter.finish().unwrap();
+	assert_eq!(buffer.len(), expected.len() + 2);
+	assert_eq!(*buffer.last().unwrap(), 1);
+}
+
+#[test]
+fn test_floor1_check_postlist() {
+	use copiablebuf::CopiableBuffer;
+	use floor::VorbisFloor1;
+	let mut floor1 = VorbisFloor1 {
+		partitions: 1,
+		mult: 1,
+		..Default::default()
+	};
+	floor1.partitions_class.push(0);
+	floor1.class_dim.push(2);
+	floor1.class_subs.push(0);
+	floor1.class_book.push(0);
+	floor1.class_subbook.push(CopiableBuffer::default());
+	for x in [0, 128, 64, 32] {
+		floor1.postlist.push(x);
+	}
+	assert!(floor1.check_postlist().is_ok());
+
+	let with_posts = |posts: &[i32]| {
+		let mut floor1 = floor1;
+		floor1.postlist.clear();
+		for &x in posts {
+			floor1.postlist.push(x);
+		}
+		floor1.check_postlist()
+	};
+	assert!(with_posts(&[0, 128, 64, 64]).is_err());
+	assert!(with_posts(&[0, 128, 64, 128]).is_err());
+	assert!(with_posts(&[0, 100, 64, 32]).is_err());
+	assert!(with_posts(&[1, 128, 64, 32]).is_err());
+	assert!(with_posts(&[0, 128, 64]).is_err());
+	assert!(with_posts(&[0, 128, -1, 32]).is_err());
+
+	// The loaded floors of a real stream pass the check
+	let (_, setup) = load_test_headers();
+	for floor in setup.floors.iter() {
+		if let floor::VorbisFloor::Floor1(floor1) = floor {
+			assert!(floor1.check_postlist().is_ok());
+		}
+	}
+}
+
+#[test]
+fn test_residue_sample_range() {
+	use residue::VorbisResidue;
+	let residue = VorbisResidue {
+		begin: 16,
+		end: 800,
+		..Default::default()
+	};
+	assert_eq!(residue.sample_range(1024), 16..800);
+	assert_eq!(residue.sample_range(128), 16..128);
+	assert!(residue.sample_range(8).is_empty());
+	assert_eq!(residue.sample_range(1024 * 2), 16..800);
+	assert!(VorbisResidue {begin: 900, ..residue}.sample_range(1024).is_empty());
+}
+
+#[test]
+fn test_mode_accessors() {
+	let (_, setup) = load_test_headers();
+	let vi = load_test_info();
+	for mode in setup.modes.iter() {
+		assert_eq!(mode.block_size(&vi), if mode.block_flag {2048} else {256});
+		assert!(std::ptr::eq(mode.mapping_ref(&setup).unwrap(), &setup.maps[mode.mapping as usize]));
+	}
+	let bad_mode = headers::VorbisMode {mapping: setup.maps.len() as i32, ..setup.modes[0]};
+	assert!(bad_mode.mapping_ref(&setup).is_err());
+	assert!(bad_mode.mapping_ref_in(&vi.codec_setup.maps).is_err());
+
+	// The decoder goes through the checked accessor rather than indexing the mappings
+	let mut vi = load_test_info();
+	let num_maps = vi.codec_setup.maps.len() as i32;
+	vi.codec_setup.modes.iter_mut().for_each(|mode|mode.mapping = num_maps);
+	let mut vd = VorbisDspState::new(vi, false).unwrap();
+	let packets = load_test_packets();
+	let err = vd.decode_packet(&packets[3].data).unwrap_err();
+	assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_ogg_vorbis_audio_packets() {
+	let data = std::fs::read("test.ogg").unwrap();
+	let packets = ogg_stream_packets(&data, None).unwrap();
+	let audio_packets = ogg_vorbis_audio_packets(&data).unwrap();
+	assert_eq!(audio_packets.len(), packets.len() - 3);
+	assert!(audio_packets.iter().all(|packet|packet.data.first().is_none_or(|&b|b & 1 == 0)));
+	assert_eq!(audio_packets.last().unwrap().granule_position, Some(106886));
+
+	let mut vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	for packet in audio_packets.iter().take(10) {
+		vd.decode_packet(&packet.data).unwrap();
+	}
+	assert!(ogg_vorbis_audio_packets(&data[..100]).is_err());
+}
+
+#[test]
+fn test_window_index() {
+	assert_eq!(window_index(64), 0);
+	assert_eq!(window_index(256), 2);
+	assert_eq!(window_index(2048), 5);
+	assert_eq!(window_index(8192), 7);
+	let (ident, _) = load_test_headers();
+	let vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	assert_eq!(vd.backend_state.borrow().window, ident.block_size.map(window_index));
+}
+
+#[test]
+fn test_codebook_decode_without_firsttable() {
+	use io_utils::CursorVecU8;
+	let (_, setup) = load_test_headers();
+	for book in setup.static_codebooks.iter() {
+		let table = CodeBook::new_for_decode(book).unwrap();
+		let treeless = CodeBook {dec_firsttable: Vec::new(), dec_firsttablen: 0, ..table.clone()};
+		let encoder = CodeBook::new_for_encode(book).unwrap();
+		let used: Vec<i32> = (0..book.entries).filter(|&i|book.lengthlist[i as usize] > 0).collect();
+		let mut bitwriter = BitWriter::new(CursorVecU8::default());
+		for &entry in used.iter() {
+			encoder.encode(entry, &mut bitwriter).unwrap();
+		}
+		let bytes = bitwriter.into_bytes();
+		let mut with_table = BitReader::new(&bytes);
+		let mut without_table = BitReader::new(&bytes);
+		for &entry in used.iter() {
+			assert_eq!(table.decode_scalar(&mut with_table).unwrap(), entry);
+			assert_eq!(treeless.decode_scalar(&mut without_table).unwrap(), entry);
+		}
+		assert_eq!(with_table.total_bits, without_table.total_bits);
+	}
+}
+
+#[test]
+fn test_vorbis_info_accessors() {
+	let (_, setup) = load_test_headers();
+	let vi = load_test_info();
+	for (i, floor) in setup.floors.iter().enumerate() {
+		assert_eq!(vi.floor(i), Some(floor));
+	}
+	for (i, residue) in setup.residues.iter().enumerate() {
+		assert_eq!(vi.residue(i), Some(residue));
+	}
+	for (i, mapping) in setup.maps.iter().enumerate() {
+		assert_eq!(vi.mapping(i), Some(mapping));
+	}
+	for (i, mode) in setup.modes.iter().enumerate() {
+		assert_eq!(vi.mode(i), Some(mode));
+	}
+	assert!(vi.floor(setup.floors.len()).is_none());
+	assert!(vi.mode(setup.modes.len()).is_none());
+	assert_eq!(std::rc::Rc::strong_count(&vi.codec_setup.maps[0]), 1);
+}
+
+#[test]
+fn test_unsupported_mapping_type() {
+	use mapping::VorbisMapping;
+	assert_eq!(VorbisMapping::supported_mapping_types(), &[0]);
+	let (ident, _) = load_test_headers();
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(1, 16).unwrap();
+	let data = bitwriter.into_bytes();
+	let err = VorbisMapping::load(&mut BitReader::new(&data), &VorbisSetupHeader::default(), &ident).unwrap_err();
+	assert!(matches!(err, VorbisError::UnsupportedMappingType(1)));
+	assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+	let err: std::io::Error = err.into();
+	assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+	assert!(err.to_string().contains("supported mapping types are [0]"));
+}
+
+#[test]
+fn test_bitwise_data_push_bits() {
+	let mut data = BitwiseData::default();
+	data.push_bit(true);
+	data.push_bit(false);
+	data.push_bits(0b101, 3).unwrap();
+	data.push_bits(0x1234_5678, 32).unwrap();
+	assert_eq!(data.push_bits(0, 33).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+	assert_eq!(data.total_bits, 37);
+
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(1, 1).unwrap();
+	bitwriter.write(0, 1).unwrap();
+	bitwriter.write(0b101, 3).unwrap();
+	bitwriter.write(0x1234_5678, 32).unwrap();
+	assert_eq!(data.clone().into_bytes(), bitwriter.into_bytes());
+
+	let mut concated = BitwiseData::new(&[0b0000_0101], 3);
+	concated.concat(&BitwiseData::new(&[0x78, 0x56, 0x34, 0x12], 32));
+	let mut pushed = BitwiseData::default();
+	pushed.push_bits(0b101, 3).unwrap();
+	pushed.push_bits(0x1234_5678, 32).unwrap();
+	assert_eq!(pushed, concated);
+
+	// The padding bytes receive the pushed bits, the data is trimmed once when turned to bytes
+	let mut padded = BitwiseData::new(&[0b0000_0101], 3);
+	padded.fit_to_aligned_size();
+	padded.push_bits(0x1234_5678, 32).unwrap();
+	assert_eq!(padded.data.len(), 8);
+	assert_eq!(padded, concated);
+	assert_eq!(padded.into_bytes(), concated.into_bytes());
+}
+
+#[test]
+fn test_codebook_is_single_entry() {
+	let single = StaticCodeBook::from_lengths(1, vec![1]).unwrap();
+	assert!(single.is_single_entry());
+	let sparse_single = StaticCodeBook {
+		dim: 1,
+		entries: 3,
+		lengthlist: vec![0, 1, 0],
+		..Default::default()
+	};
+	assert!(sparse_single.is_single_entry());
+	assert!(!StaticCodeBook::from_lengths(1, vec![1, 1]).unwrap().is_single_entry());
+	assert!(!StaticCodeBook::from_lengths(1, vec![1, 2, 2]).unwrap().is_single_entry());
+	assert!(!StaticCodeBook::default().is_single_entry());
+
+	let book = CodeBook::new_for_decode(&single).unwrap();
+	assert_eq!(book.dec_firsttablen, 1);
+	let data = [0u8; 2];
+	let mut bitreader = BitReader::new(&data);
+	assert_eq!(book.decode_scalar(&mut bitreader).unwrap(), 0);
+}
+
+#[test]
+fn test_dsp_state_initial_sequence() {
+	assert_eq!(VorbisDspState::new(load_test_info(), false).unwrap().sequence, 0);
+	let mut vd = VorbisDspState::new(load_test_info_for_encode(), true).unwrap();
+	assert_eq!(vd.sequence, HEADER_PACKETS);
+	for buffer in vd.analysis_buffer(4096) {
+		buffer.fill(0.0);
+	}
+	vd.analysis_wrote(4096).unwrap();
+	vd.analysis_wrote(0).unwrap();
+	let vb = vd.analysis_blockout().unwrap().unwrap();
+	assert_eq!(vb.sequence, HEADER_PACKETS);
+	assert_eq!(vd.sequence, HEADER_PACKETS + 1);
+}
+
+#[test]
+fn test_analysis_blockout_shared_state() {
+	use std::rc::Rc;
+	let mut vd = VorbisDspState::new(load_test_info_for_encode(), true).unwrap();
+	let rate = vd.vorbis_info.sample_rate;
+	for buffer in vd.analysis_buffer(8192) {
+		buffer.fill(0.0);
+	}
+	vd.analysis_wrote(8192).unwrap();
+	vd.analysis_wrote(0).unwrap();
+	let vb = vd.analysis_blockout().unwrap().unwrap();
+	assert!(Rc::ptr_eq(&vb.backend_state, &vd.backend_state));
+	assert!(Rc::ptr_eq(&vb.vorbis_info, &vd.vorbis_info));
+	assert_eq!(vb.internal.as_ref().unwrap().ampmax, -9999.0);
+
+	// The peak found while analyzing a block is tracked for the next blocks
+	vb.backend_state.borrow_mut().psy_g_look.ampmax_decay(-10.0, 0, rate);
+	let next = vd.analysis_blockout().unwrap().unwrap();
+	assert_eq!(next.internal.as_ref().unwrap().ampmax, -10.0);
+
+	// A clone of the state doesn't share the backend with the blocks
+	let cloned = vd.clone();
+	assert!(!Rc::ptr_eq(&cloned.backend_state, &next.backend_state));
+}
+
+#[test]
+fn test_residue_builder() {
+	use residue::VorbisResidue;
+	let (_, setup) = load_test_headers();
+	for residue in setup.residues.iter() {
+		let mut builder = VorbisResidue::builder(residue.residue_type, residue.begin, residue.end, residue.grouping, residue.partitions, residue.groupbook).unwrap();
+		let mut books = residue.booklist.iter().copied();
+		for (partition, &cascade) in residue.secondstages.iter().enumerate() {
+			let stages: Vec<Option<i32>> = (0..8).map(|stage|if cascade & (1 << stage) != 0 {books.next()} else {None}).collect();
+			builder = builder.stage_books(partition, &stages).unwrap();
+		}
+		let built = builder.build(&setup.static_codebooks).unwrap();
+		assert_eq!(&built, residue);
+		assert_eq!(built.packed_bits(), residue.packed_bits());
+	}
+
+	let residue = &setup.residues[0];
+	let groupbook = &setup.static_codebooks[residue.groupbook as usize];
+	assert_eq!(VorbisResidue::calc_partvals(residue.partitions, groupbook).unwrap(), residue.partvals);
+	let groupbook = StaticCodeBook {
+		dim: 2,
+		entries: 8,
+		..Default::default()
+	};
+	assert_eq!(VorbisResidue::calc_partvals(2, &groupbook).unwrap(), 4);
+	assert!(VorbisResidue::calc_partvals(3, &groupbook).is_err());
+	assert!(VorbisResidue::builder(3, 0, 100, 16, 4, 0).is_err());
+	assert!(VorbisResidue::builder(2, 100, 0, 16, 4, 0).is_err());
+	assert!(VorbisResidue::builder(2, 0, 100, 16, 65, 0).is_err());
+	let builder = VorbisResidue::builder(2, 0, 100, 16, 1, residue.groupbook).unwrap();
+	assert!(builder.clone().stage_books(1, &[None]).is_err());
+	let bad_book = setup.static_codebooks.len() as i32;
+	assert!(builder.stage_books(0, &[Some(bad_book)]).unwrap().build(&setup.static_codebooks).is_err());
+}
+
+#[test]
+fn test_copiable_buffer_ext() {
+	use copiablebuf::CopiableBuffer;
+	let mut buffer = CopiableBuffer::<i32, 2>::default();
+	assert_eq!(CopiableBuffer::<i32, 2>::CAPACITY, 2);
+	assert_eq!(buffer.try_push(1), Ok(()));
+	assert_eq!(buffer.try_push(2), Ok(()));
+	assert_eq!(buffer.try_push(3), Err(3));
+	assert_eq!(buffer.len(), 2);
+	assert_eq!(buffer.try_resize(3, 0).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+	assert_eq!(buffer.len(), 2);
+	buffer.try_resize(1, 0).unwrap();
+	assert_eq!(buffer.len(), 1);
+}
+
+#[test]
+fn test_floor1_load_fuzz() {
+	use floor::VorbisFloor1;
+	let (_, setup) = load_test_headers();
+	let mut seed = 0x2545_f491_4f6c_dd1du64;
+	let mut loaded = 0;
+	for i in 0..20000 {
+		let mut bytes = vec![0u8; 1 + i % 96];
+		for byte in bytes.iter_mut() {
+			seed ^= seed << 13;
+			seed ^= seed >> 7;
+			seed ^= seed << 17;
+			*byte = seed as u8;
+		}
+		if std::panic::catch_unwind(|| {
+			VorbisFloor1::load(&mut BitReader::new(&bytes), &setup).is_ok()
+		}).unwrap_or_else(|_|panic!("`VorbisFloor1::load()` panicked on {bytes:02x?}")) {
+			loaded += 1;
+		}
+	}
+	assert!(loaded < 20000);
+
+	// No partitions: only the two boundary posts
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(0, 5).unwrap();
+	bitwriter.write(0, 2).unwrap();
+	bitwriter.write(7, 4).unwrap();
+	let data = bitwriter.into_bytes();
+	let floor = VorbisFloor1::load(&mut BitReader::new(&data), &setup).unwrap();
+	let floor::VorbisFloor::Floor1(floor1) = floor else {
+		panic!("Expected a floor 1");
+	};
+	assert_eq!(&floor1.postlist[..], &[0, 128]);
+}
+
+#[test]
+fn test_codebook_load_oversized() {
+	// 2^23 - 1 entries of all used codewords, but there's no data for the lengths
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(0x564342, 24).unwrap();
+	bitwriter.write(1, 16).unwrap();
+	bitwriter.write(0x7FFFFF, 24).unwrap();
+	bitwriter.write(0, 1).unwrap();
+	bitwriter.write(0, 1).unwrap();
+	bitwriter.write(0, 32).unwrap();
+	let data = bitwriter.into_bytes();
+	let err = StaticCodeBook::load(&mut BitReader::new(&data)).unwrap_err();
+	assert!(matches!(err, VorbisError::UnexpectedEof));
+
+	// The dimensions and the entries are too big together
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(0x564342, 24).unwrap();
+	bitwriter.write(0xFF, 16).unwrap();
+	bitwriter.write(0xFFFFFF, 24).unwrap();
+	let data = bitwriter.into_bytes();
+	assert_eq!(StaticCodeBook::load(&mut BitReader::new(&data)).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+	// 2^16 quantized values of 16 bits each for maptype 2, but the data ends
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(0x564342, 24).unwrap();
+	bitwriter.write(256, 16).unwrap();
+	bitwriter.write(256, 24).unwrap();
+	bitwriter.write(1, 1).unwrap();
+	bitwriter.write(7, 5).unwrap();
+	bitwriter.write(256, 9).unwrap();
+	bitwriter.write(2, 4).unwrap();
+	bitwriter.write(0, 32).unwrap();
+	bitwriter.write(0, 32).unwrap();
+	bitwriter.write(15, 4).unwrap();
+	bitwriter.write(0, 1).unwrap();
+	let data = bitwriter.into_bytes();
+	let err = StaticCodeBook::load(&mut BitReader::new(&data)).unwrap_err();
+	assert!(matches!(err, VorbisError::UnexpectedEof));
+
+	// 2^23 - 1 length ordered entries, but the data ends after the first length
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(0x564342, 24).unwrap();
+	bitwriter.write(1, 16).unwrap();
+	bitwriter.write(0x7FFFFF, 24).unwrap();
+	bitwriter.write(1, 1).unwrap();
+	bitwriter.write(22, 5).unwrap();
+	let data = bitwriter.into_bytes();
+	let err = StaticCodeBook::load(&mut BitReader::new(&data)).unwrap_err();
+	assert!(matches!(err, VorbisError::UnexpectedEof));
+
+	// A reserved maptype is reported by both load and pack
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(0x564342, 24).unwrap();
+	bitwriter.write(1, 16).unwrap();
+	bitwriter.write(2, 24).unwrap();
+	bitwriter.write(1, 1).unwrap();
+	bitwriter.write(0, 5).unwrap();
+	bitwriter.write(2, 2).unwrap();
+	bitwriter.write(3, 4).unwrap();
+	let data = bitwriter.into_bytes();
+	assert_eq!(StaticCodeBook::load(&mut BitReader::new(&data)).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+	let mut book = StaticCodeBook::from_lengths(1, vec![1, 1]).unwrap();
+	book.maptype = 3;
+	assert_eq!(book.pack(&mut BitWriterCursor::default()).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+	// The quantized values of a mapped book are missing
+	book.maptype = 2;
+	book.q_min = -1.0;
+	book.q_delta = 1.0;
+	book.q_quant = 1;
+	assert_eq!(book.pack(&mut BitWriterCursor::default()).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+	book.quantlist = vec![0];
+	assert_eq!(book.pack(&mut BitWriterCursor::default()).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+	book.quantlist = vec![0, 1];
+	assert!(book.pack(&mut BitWriterCursor::default()).is_ok());
+}
+
+#[test]
+fn test_bit_count_mismatch() {
+	let packets = load_test_packets();
+	let mut books = StaticCodeBooks::load_from_slice(&packets[2].data[7..]).unwrap();
+	let packed = books.to_packed_codebooks().unwrap();
+	assert_eq!(StaticCodeBooks::try_from(packed.clone()).unwrap(), books);
+
+	let mut bad_packed = packed.clone();
+	bad_packed.bits_of_books[1] += 1;
+	let err = StaticCodeBooks::try_from(bad_packed).unwrap_err();
+	assert!(matches!(err, VorbisError::BitCountMismatch{expected, actual} if expected == packed.bits_of_books[1] + 1 && actual == packed.bits_of_books[1]));
+
+	books.total_bits += 3;
+	let mut bitwriter = BitWriterCursor::default();
+	let err = books.pack(&mut bitwriter).unwrap_err();
+	assert!(matches!(err, VorbisError::BitCountMismatch{expected, actual} if expected == actual + 3));
+	let err: std::io::Error = err.into();
+	assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_mdct_lookup_shared() {
+	let lookup = MdctLookup::shared(256).unwrap();
+	assert_eq!(*lookup, MdctLookup::new(256));
+	assert!(matches!(MdctLookup::shared(100), Err(MdctError::NotPowerOfTwo(100))));
+
+	let vi = load_test_info();
+	let vd1 = VorbisDspState::new(vi.clone(), false).unwrap();
+	let vd2 = VorbisDspState::new(vi, false).unwrap();
+	let (t1, t2) = (vd1.backend_state.borrow().transform[0].clone(), vd2.backend_state.borrow().transform[0].clone());
+	assert_eq!(t1, t2);
+	assert_eq!(std::sync::Arc::ptr_eq(&t1[1], &t2[1]), cfg!(feature = "mdct-cache"));
+}
+
+#[test]
+fn test_drft_lookup_trivial_sizes() {
+	let mut empty = DrftLookup::new(0);
+	assert!(empty.is_empty());
+	empty.forward(&mut []);
+	empty.backward(&mut []);
+	assert!(empty.forward_checked(&mut [1.0]).is_err());
+
+	let mut one = DrftLookup::new(1);
+	let mut data = [3.0f32];
+	one.forward(&mut data);
+	one.backward(&mut data);
+	assert_eq!(data, [3.0]);
+}
+
+#[test]
+fn test_bitrate_mode() {
+	let mut vi = load_test_info();
+	let mode_of = |vi: &mut VorbisInfo, upper, nominal, lower| {
+		vi.bitrate_upper = upper;
+		vi.bitrate_nominal = nominal;
+		vi.bitrate_lower = lower;
+		vi.bitrate_mode()
+	};
+	assert_eq!(mode_of(&mut vi, 128000, 128000, 128000), BitrateMode::Cbr);
+	assert_eq!(mode_of(&mut vi, -1, 128000, -1), BitrateMode::Vbr);
+	assert_eq!(mode_of(&mut vi, 160000, 128000, -1), BitrateMode::Abr);
+	assert_eq!(mode_of(&mut vi, 0, 0, 96000), BitrateMode::Abr);
+	assert_eq!(mode_of(&mut vi, -1, -1, -1), BitrateMode::Unspecified);
+	assert_eq!(mode_of(&mut vi, 0, 0, 0), BitrateMode::Unspecified);
+
+	let vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	assert!(!vd.is_bitrate_managed());
+}
+
+#[test]
+fn test_max_bits_per_call() {
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write(5, 3).unwrap();
+	bitwriter.write(0xDEAD_BEEF, MAX_BITS_PER_CALL).unwrap();
+	let data = bitwriter.into_bytes();
+	let mut bitreader = BitReader::new(&data);
+	assert_eq!(bitreader.read(3).unwrap(), 5);
+	assert_eq!(bitreader.read(MAX_BITS_PER_CALL).unwrap() as u32, 0xDEAD_BEEF);
+}
+
+#[test]
+fn test_comment_size_limit() {
+	use savagestr::prelude::*;
+	let text_codecs = StringCodecMaps::new();
+	let mut comments = VorbisCommentHeader::new();
+	comments.comments.push(format!("COMMENT={}", "x".repeat(1000)));
+	let mut bitwriter = BitWriterCursor::default();
+	comments.pack(&mut bitwriter, &text_codecs).unwrap();
+	let packed = bitwriter.into_bytes();
+	assert_eq!(VorbisCommentHeader::load(&mut BitReader::new(&packed), &text_codecs).unwrap(), comments);
+	assert_eq!(VorbisCommentHeader::load_with_limit(&mut BitReader::new(&packed), &text_codecs, 1008).unwrap(), comments);
+	let err = VorbisCommentHeader::load_with_limit(&mut BitReader::new(&packed), &text_codecs, 1000).unwrap_err();
+	assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+	// A huge length and a huge number of comments in a tiny packet
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write_aligned_bytes(b"\x03vorbis").unwrap();
+	bitwriter.write(0x7FFF_FFFF, 32).unwrap();
+	let packed = bitwriter.into_bytes();
+	let err = VorbisCommentHeader::load(&mut BitReader::new(&packed), &text_codecs).unwrap_err();
+	assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+	let mut bitwriter = BitWriterCursor::default();
+	bitwriter.write_aligned_bytes(b"\x03vorbis").unwrap();
+	bitwriter.write(0, 32).unwrap();
+	bitwriter.write(0x7FFF_FFFF, 32).unwrap();
+	let packed = bitwriter.into_bytes();
+	let err = VorbisCommentHeader::load(&mut BitReader::new(&packed), &text_codecs).unwrap_err();
+	assert!(matches!(err, VorbisError::UnexpectedEof));
+}
+
+#[test]
+fn test_header_display() {
+	let (ident, setup) = load_test_headers();
+	let text = ident.to_string();
+	assert!(text.starts_with("Vorbis 0: 44100 Hz, 2 channels, block sizes 256/2048, bitrate upper/nominal/lower "));
+	assert!(!text.contains('\n'));
+	assert_eq!(setup.to_string(), format!("{} codebooks, {} floors, {} residues, {} mappings, {} modes",
+		setup.static_codebooks.len(), setup.floors.len(), setup.residues.len(), setup.maps.len(), setup.modes.len()));
+
+	let mut comments = VorbisCommentHeader::new();
+	comments.set_vendor("test vendor");
+	comments.comments.push("TITLE=A = B".to_string());
+	comments.comments.push("no separator".to_string());
+	assert_eq!(comments.to_string(), "vendor: test vendor\nTITLE: A = B\nno separator");
+}
+
+#[test]
+fn test_codebook_eq_defining() {
+	let (_, setup) = load_test_headers();
+	for (i, static_book) in setup.static_codebooks.iter().enumerate() {
+		let encode = CodeBook::new_for_encode(static_book).unwrap();
+		let decode = CodeBook::new_for_decode(static_book).unwrap();
+		assert!(encode.eq_defining(&decode), "book {i}");
+		assert!(decode.eq_defining(&encode), "book {i}");
+		assert!(decode.eq_defining(&CodeBook::new_for_decode(static_book).unwrap()));
+		if static_book.lengthlist.iter().any(|&length|length > 0) {
+			assert_ne!(encode, decode);
+		}
+	}
+
+	let a = StaticCodeBook::from_lengths(1, vec![1, 2, 3, 3]).unwrap();
+	let b = StaticCodeBook::from_lengths(1, vec![2, 2, 2, 2]).unwrap();
+	assert!(!CodeBook::new_for_decode(&a).unwrap().eq_defining(&CodeBook::new_for_decode(&b).unwrap()));
+	assert!(!CodeBook::new_for_encode(&a).unwrap().eq_defining(&CodeBook::new_for_decode(&b).unwrap()));
+	assert!(!CodeBook::new_for_encode(&a).unwrap().eq_defining(&CodeBook::new_for_encode(&b).unwrap()));
+}
+
+#[test]
+fn test_strip_comments_from_ogg_stream() {
+	use savagestr::prelude::*;
+	let data = std::fs::read("test.ogg").unwrap();
+	let tagged = retag_ogg_vorbis(&data, |comment_header| {
+		comment_header.comments.push("ARTIST=Someone".to_string());
+		comment_header.comments.push(format!("METADATA_BLOCK_PICTURE={}", "A".repeat(VorbisCommentHeader::DEFAULT_MAX_COMMENT_SIZE)));
+	}).unwrap();
+	let stripped = strip_comments_from_ogg_stream(&tagged).unwrap();
+	assert!(ogg_pages(&stripped).all(|page|page.unwrap().verify_checksum()));
+	let original_packets = ogg_stream_packets(&data, None).unwrap();
+	let stripped_packets = ogg_stream_packets(&stripped, None).unwrap();
+	let text_codecs = StringCodecMaps::new();
+	let original_comments = VorbisCommentHeader::load(&mut BitReader::new(&original_packets[1].data), &text_codecs).unwrap();
+	let comment_header = VorbisCommentHeader::load(&mut BitReader::new(&stripped_packets[1].data), &text_codecs).unwrap();
+	assert!(comment_header.comments.is_empty());
+	assert_eq!(comment_header.vendor, original_comments.vendor);
+	assert_eq!(original_packets.len(), stripped_packets.len());
+	assert_eq!(original_packets[0].data, stripped_packets[0].data);
+	for (a, b) in original_packets[2..].iter().zip(stripped_packets[2..].iter()) {
+		assert_eq!(a.data, b.data);
+		assert_eq!(a.granule_position, b.granule_position);
+	}
+}
+
+#[test]
+fn test_bitrate_reservoir_snapshot() {
+	use bitrate::VorbisBitrateManagerState;
+	let mut vi = load_test_info_for_encode();
+	vi.setup_bitrate_management(None, 128000, None).unwrap();
+	let vd = VorbisDspState::new(vi, true).unwrap();
+	assert!(vd.is_bitrate_managed());
+	let mut backend_state = vd.backend_state.borrow_mut();
+	let bm = backend_state.bitrate_manager_state.as_mut().unwrap();
+	bm.avg_reservoir -= 500;
+	bm.choice = 3;
+	let snapshot = bm.snapshot();
+	assert_eq!(snapshot, ReservoirSnapshot {
+		managed: true,
+		avg_reservoir: bm.desired_fill - 500,
+		minmax_reservoir: bm.desired_fill,
+		desired_fill: bm.desired_fill,
+		avgfloat: bm.avgfloat,
+		choice: 3,
+	});
+	assert_eq!((bm.avg_reservoir(), bm.minmax_reservoir(), bm.current_choice()), (snapshot.avg_reservoir, snapshot.minmax_reservoir, 3));
+
+	let unmanaged = VorbisBitrateManagerState::default();
+	assert!(!unmanaged.snapshot().managed);
+	assert_eq!(unmanaged.current_choice(), (PACKETBLOBS / 2) as i32);
+}
+
+#[test]
+fn test_decode_corrupted_packet() {
+	let packets = load_test_packets();
+	let (ident, _) = load_test_headers();
+	let mut vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	let channels = ident.channels as usize;
+
+	// Keep the packet type and the mode bits, scramble the floors and the residues
+	let mut seed = 0x1234_5678u32;
+	for packet in packets[3..].iter().take(60) {
+		let mut corrupted = packet.data.clone();
+		for byte in corrupted.iter_mut().skip(1) {
+			seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+			*byte ^= (seed >> 24) as u8;
+		}
+		let pcm = vd.decode_packet(&corrupted).unwrap();
+		assert_eq!(pcm.len(), channels);
+		assert!(pcm.iter().all(|c|c.len() == pcm[0].len()));
+	}
+
+	// Cut the packets short, a codeword cut off by the end of the packet ends the floor or the residue
+	let mut vd = VorbisDspState::new(load_test_info(), false).unwrap();
+	for (i, packet) in packets[3..].iter().enumerate() {
+		let truncated = &packet.data[..1 + (packet.data.len() - 1) * (i % 7 + 1) / 8];
+		let pcm = vd.decode_packet(truncated).unwrap();
+		assert_eq!(pcm.len(), channels);
+		assert!(pcm.iter().all(|c|c.len() == pcm[0].len()));
+	}
+}
@@ -0,0 +1,112 @@
+/* Preserved from libvorbis lpc.c:
+
+   Some of these routines (autocorrelator, LPC coefficient estimator)
+   are derived from code written by Jutta Degener and Carsten Bormann;
+   thus we include their copyright below.  The entirety of this file
+   is freely redistributable on the condition that both of these
+   copyright notices are preserved without modification.  */
+
+/* Preserved Copyright: *********************************************/
+
+/* Copyright 1992, 1993, 1994 by Jutta Degener and Carsten Bormann,
+Technische Universita"t Berlin
+
+Any use of this software is permitted provided that this notice is not
+confused with any other implementation, or taken to be a
+functional implementation of GSM. */
+
+/// * Compute the `lpc.len()` LPC coefficients of `data` by the autocorrelation method, returns the prediction error.
+/// * The filter is slightly damped, the coefficients are for `vorbis_lpc_predict()`.
+pub fn vorbis_lpc_from_data(data: &[f32], lpc: &mut [f32]) -> f32 {
+    let n = data.len();
+    let m = lpc.len();
+    let mut aut = vec![0.0f64; m + 1];
+    let mut lpc_d = vec![0.0f64; m];
+
+    // autocorrelation, p+1 lag coefficients
+    for (j, aut) in aut.iter_mut().enumerate() {
+        let mut d = 0.0f64; // double needed for accumulator depth
+        for i in j..n {
+            d += data[i] as f64 * data[i - j] as f64;
+        }
+        *aut = d;
+    }
+
+    // Generate lpc coefficients from autocorr values
+
+    // set our noise floor to about -100dB
+    let mut error = aut[0] * (1.0 + 1e-10);
+    let epsilon = 1e-9 * aut[0] + 1e-10;
+
+    for i in 0..m {
+        let mut r = -aut[i + 1];
+
+        if error < epsilon {
+            lpc_d[i..].fill(0.0);
+            break;
+        }
+
+        /* Sum up this iteration's reflection coefficient; note that in
+           Vorbis we don't save it.  If anyone wants to recycle this code
+           and needs reflection coefficients, save the results of 'r' from
+           each iteration. */
+        for j in 0..i {
+            r -= lpc_d[j] * aut[i - j];
+        }
+        r /= error;
+
+        // Update LPC coefficients and total error
+        lpc_d[i] = r;
+        let mut j = 0;
+        while j < i / 2 {
+            let tmp = lpc_d[j];
+            lpc_d[j] += r * lpc_d[i - 1 - j];
+            lpc_d[i - 1 - j] += r * tmp;
+            j += 1;
+        }
+        if i & 1 != 0 {
+            lpc_d[j] += lpc_d[j] * r;
+        }
+
+        error *= 1.0 - r * r;
+    }
+
+    // slightly damp the filter
+    let g = 0.99;
+    let mut damp = g;
+    for v in lpc_d.iter_mut() {
+        *v *= damp;
+        damp *= g;
+    }
+
+    for (lpci, &v) in lpc.iter_mut().zip(lpc_d.iter()) {
+        *lpci = v as f32;
+    }
+
+    // we need the error value to know how much big errors in the predicted signal will turn out
+    error as f32
+}
+
+/// * Run the predictor filter of the `coeff.len()` coefficients to generate `data.len()` samples.
+/// * `prime` is the samples right before `data`, it should have `coeff.len()` samples, or the filter begins from silence if it's `None`.
+pub fn vorbis_lpc_predict(coeff: &[f32], prime: Option<&[f32]>, data: &mut [f32]) {
+    let m = coeff.len();
+    let mut work = vec![0.0f32; m + data.len()];
+
+    if let Some(prime) = prime {
+        work[..m].copy_from_slice(&prime[..m]);
+    }
+
+    for (i, sample) in data.iter_mut().enumerate() {
+        let mut y = 0.0f32;
+        let mut o = i;
+        let mut p = m;
+        for _ in 0..m {
+            p -= 1;
+            y -= work[o] * coeff[p];
+            o += 1;
+        }
+        *sample = y;
+        work[o] = y;
+    }
+}
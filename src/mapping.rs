@@ -7,6 +7,9 @@ use crate::*;
 use bitwise::{BitReader, BitWriter};
 use headers::{VorbisSetupHeader, VorbisIdentificationHeader};
 use copiablebuf::CopiableBuffer;
+use blocks::{VorbisBlock, PacketBlobs};
+use floor::{VorbisFloor, VorbisFloor1, VorbisLookFloor, VorbisLookFloor1};
+use scales::todB;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct VorbisMapping {
@@ -31,11 +34,16 @@ pub struct VorbisMapping {
 }
 
 impl VorbisMapping {
-    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader, ident_header: &VorbisIdentificationHeader) -> io::Result<Self> {
+    /// * The mapping types that can be loaded, the other types are reserved by the specification rather than malformed.
+    pub fn supported_mapping_types() -> &'static [i32] {
+        &[0]
+    }
+
+    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader, ident_header: &VorbisIdentificationHeader) -> Result<Self, VorbisError> {
         let mapping_type = read_bits!(bitreader, 16);
 
-        if mapping_type != 0 {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid mapping type {mapping_type}")))
+        if !Self::supported_mapping_types().contains(&mapping_type) {
+            return Err(VorbisError::UnsupportedMappingType(mapping_type));
         }
 
         let channels = ident_header.channels as i32;
@@ -44,7 +52,7 @@ impl VorbisMapping {
         let submaps = if read_bits!(bitreader, 1) != 0 {
             let submaps = read_bits!(bitreader, 4).wrapping_add(1);
             if submaps == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No submaps.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No submaps.".to_string()).into());
             }
             submaps
         } else {
@@ -53,7 +61,7 @@ impl VorbisMapping {
         let coupling_steps = if read_bits!(bitreader, 1) != 0 {
             let coupling_steps = read_bits!(bitreader, 8).wrapping_add(1);
             if coupling_steps == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, "No coupling steps.".to_string()));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "No coupling steps.".to_string()).into());
             }
             coupling_steps
         } else {
@@ -69,8 +77,8 @@ impl VorbisMapping {
         let channels = channels as usize;
         let coupling_steps = coupling_steps as usize;
 
-        ret.coupling_mag.resize(coupling_steps, 0);
-        ret.coupling_ang.resize(coupling_steps, 0);
+        ret.coupling_mag.try_resize(coupling_steps, 0)?;
+        ret.coupling_ang.try_resize(coupling_steps, 0)?;
         for i in 0..coupling_steps {
             let test_m = read_bits!(bitreader, ilog!(channels - 1));
             let test_a = read_bits!(bitreader, ilog!(channels - 1));
@@ -79,37 +87,37 @@ impl VorbisMapping {
             if test_m == test_a
             || test_m >= channels as i32
             || test_a >= channels as i32 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Bad values for test_m = {test_m}, test_a = {test_a}, channels = {channels}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad values for test_m = {test_m}, test_a = {test_a}, channels = {channels}")).into());
             }
         }
 
         let reserved = read_bits!(bitreader, 2);
         if reserved != 0 {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Reserved value is {reserved}")));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Reserved value is {reserved}")).into());
         }
 
         if submaps > 1 {
-            ret.chmuxlist.resize(channels, 0);
+            ret.chmuxlist.try_resize(channels, 0)?;
             for i in 0..channels {
                 let chmux = read_bits!(bitreader, 4);
                 if chmux >= submaps as i32 {
-                    return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Chmux {chmux} >= submaps {submaps}")));
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Chmux {chmux} >= submaps {submaps}")).into());
                 }
                 ret.chmuxlist[i] = chmux;
             }
         }
-        ret.floorsubmap.resize(submaps, 0);
-        ret.residuesubmap.resize(submaps, 0);
+        ret.floorsubmap.try_resize(submaps, 0)?;
+        ret.residuesubmap.try_resize(submaps, 0)?;
         for i in 0..submaps {
             let _unused_time_submap = read_bits!(bitreader, 8);
             let floorsubmap = read_bits!(bitreader, 8);
             if floorsubmap >= floors {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("floorsubmap {floorsubmap} >= floors {floors}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("floorsubmap {floorsubmap} >= floors {floors}")).into());
             }
             ret.floorsubmap[i] = floorsubmap;
             let residuesubmap = read_bits!(bitreader, 8);
             if residuesubmap >= residues {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("residuesubmap {residuesubmap} >= residues {residues}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("residuesubmap {residuesubmap} >= residues {residues}")).into());
             }
             ret.residuesubmap[i] = residuesubmap;
         }
@@ -117,7 +125,7 @@ impl VorbisMapping {
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>, channels: i32) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>, channels: i32) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
@@ -156,6 +164,231 @@ impl VorbisMapping {
 
         Ok(bitwriter.total_bits - begin_bits)
     }
+
+    /// * The number of bits `pack()` writes for the channels, counted without packing
+    pub fn packed_bits(&self, channels: i32) -> usize {
+        let mut bits = 16 + 1;
+        if self.submaps > 1 {
+            bits += 4 + channels as usize * 4;
+        }
+        bits += 1;
+        if self.coupling_steps > 0 {
+            bits += 8 + self.coupling_steps as usize * ilog!(channels - 1) as usize * 2;
+        }
+        bits + 2 + self.submaps as usize * (8 + 8 + 8)
+    }
+
+    /// * Undo the square polar coupling of the channels, from the last coupling step to the first.
+    /// * `channels` are the residue vectors of all of the channels, before the floor curves are applied to them.
+    pub fn inverse_coupling(&self, channels: &mut [Vec<f32>]) {
+        for i in (0..self.coupling_steps as usize).rev() {
+            let m = self.coupling_mag[i] as usize;
+            let a = self.coupling_ang[i] as usize;
+            let (pcm_m, pcm_a) = if m < a {
+                let (lo, hi) = channels.split_at_mut(a);
+                (&mut lo[m], &mut hi[0])
+            } else {
+                let (lo, hi) = channels.split_at_mut(m);
+                (&mut hi[0], &mut lo[a])
+            };
+            for (pm, pa) in pcm_m.iter_mut().zip(pcm_a.iter_mut()) {
+                let mag = *pm;
+                let ang = *pa;
+                (*pm, *pa) = if mag > 0.0 {
+                    if ang > 0.0 {
+                        (mag, mag - ang)
+                    } else {
+                        (mag + ang, mag)
+                    }
+                } else if ang > 0.0 {
+                    (mag, mag + ang)
+                } else {
+                    (mag - ang, mag)
+                };
+            }
+        }
+    }
+
+    /// * Couple the channels into the square polar form, from the first coupling step to the last, the reverse of `inverse_coupling()`.
+    /// * `channels` are the quantized residue vectors of all of the channels, the coupling is lossless for the integer values.
+    pub fn forward_coupling(&self, channels: &mut [Vec<f32>]) {
+        for i in 0..self.coupling_steps as usize {
+            let m = self.coupling_mag[i] as usize;
+            let a = self.coupling_ang[i] as usize;
+            let (pcm_m, pcm_a) = if m < a {
+                let (lo, hi) = channels.split_at_mut(a);
+                (&mut lo[m], &mut hi[0])
+            } else {
+                let (lo, hi) = channels.split_at_mut(m);
+                (&mut hi[0], &mut lo[a])
+            };
+            for (pm, pa) in pcm_m.iter_mut().zip(pcm_a.iter_mut()) {
+                let a = *pm;
+                let b = *pa;
+                let (mut mag, mut ang) = if a.abs() >= b.abs() {
+                    (a, if a > 0.0 {a - b} else {b - a})
+                } else {
+                    (b, if b > 0.0 {a - b} else {b - a})
+                };
+                if ang > mag.abs() * 1.9999 {
+                    ang = -mag.abs() * 2.0;
+                    mag = -mag;
+                }
+                (*pm, *pa) = (mag, ang);
+            }
+        }
+    }
+
+    /// * Analyze the block and encode it into the packet blobs of the block, this is `mapping0_forward()` of `libvorbis`.
+    /// * The masking curve from the psychoacoustics is fitted as the floor of each channel, then the spectrum divided by the floor curve is quantized as the residue.
+    /// * Only the middle blob is encoded unless the bitrate is managed. The noise normalization and the point stereo aren't ported, the channels are coupled losslessly.
+    #[allow(non_snake_case)]
+    pub fn forward(&self, vb: &mut VorbisBlock) -> io::Result<()> {
+        let vi = vb.vorbis_info.clone();
+        let ci = &vi.codec_setup;
+        let channels = vi.channels as usize;
+        let (lW, W, nW) = (vb.lW, vb.W, vb.nW);
+        let n = ci.block_size[W] as usize;
+        let n2 = n / 2;
+        let submap_of = |ch: usize|self.chmuxlist.iter().nth(ch).copied().unwrap_or(0) as usize;
+        let books = ci.fullbooks.borrow();
+        let backend_state = vb.backend_state.clone();
+        let backend_state = &mut *backend_state.borrow_mut();
+        let managed = backend_state.bitrate_manager_state.as_ref().is_some_and(|bm|bm.managed);
+        let Some(vbi) = vb.internal.as_mut() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The block isn't in encoding mode".to_string()));
+        };
+        if vb.pcm.len() < channels || vb.pcm.iter().any(|pcm|pcm.len() < n) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The block should have {channels} channels of {n} samples")));
+        }
+
+        // Window the samples, then the MDCT for the floor and the residue, and the FFT for the tonal masking
+        let window = try_vorbis_window(ci.block_size[lW] as usize, n, ci.block_size[nW] as usize)?;
+        let scale_db = todB(4.0 / n as f32) + 0.345;
+        let mut mdct = vec![vec![0.0f32; n2]; channels];
+        let mut logfft = vec![vec![0.0f32; n2]; channels];
+        let mut local_ampmax = vec![0.0f32; channels];
+        let mut global_ampmax = vbi.ampmax;
+        for ch in 0..channels {
+            let mut pcm: Vec<f32> = vb.pcm[ch][..n].iter().zip(window.iter()).map(|(s, w)|s * w).collect();
+            backend_state.transform[0][W].forward_with_scratch(&pcm, &mut mdct[ch], &mut backend_state.mdct_scratch);
+            backend_state.fft_look[W].forward_checked(&mut pcm)?;
+
+            let logfft = &mut logfft[ch];
+            logfft[0] = scale_db + todB(pcm[0]) + 0.345;
+            let mut ampmax = logfft[0];
+            for j in (1..n - 1).step_by(2) {
+                let temp = pcm[j] * pcm[j] + pcm[j + 1] * pcm[j + 1];
+                let temp = scale_db + 0.5 * todB(temp) + 0.345;
+                logfft[(j + 1) >> 1] = temp;
+                ampmax = ampmax.max(temp);
+            }
+            local_ampmax[ch] = ampmax.min(0.0);
+            global_ampmax = global_ampmax.max(local_ampmax[ch]);
+        }
+        vbi.ampmax = global_ampmax;
+        backend_state.psy_g_look.track_ampmax(global_ampmax);
+
+        let backend_state = &*backend_state;
+        let floor1_of = |ch: usize| -> io::Result<(&VorbisFloor1, &VorbisLookFloor1)> {
+            let floor_index = self.floorsubmap[submap_of(ch)] as usize;
+            match (ci.floors[floor_index].as_ref(), &backend_state.flr_look[floor_index]) {
+                (VorbisFloor::Floor1(floor), VorbisLookFloor::Floor1(look)) => Ok((floor, look)),
+                _ => Err(io::Error::new(io::ErrorKind::Unsupported, "Only the floor 1 could be encoded".to_string())),
+            }
+        };
+
+        // Fit the floors to the masking curves, the managed streams get the fits for every packet blob
+        let psy_look = &backend_state.psy_look[vbi.blocktype as usize + if W != 0 {2} else {0}];
+        let center = PacketBlobs::center();
+        let mut floor_posts = vec![vec![None::<Vec<i32>>; PACKETBLOBS]; channels];
+        let mut logmdct = vec![0.0f32; n2];
+        let mut noise = vec![0.0f32; n2];
+        let mut tone = vec![0.0f32; n2];
+        let mut logmask = vec![0.0f32; n2];
+        for (ch, posts) in floor_posts.iter_mut().enumerate() {
+            let (floor, look) = floor1_of(ch)?;
+            for (logmdct, &mdct) in logmdct.iter_mut().zip(mdct[ch].iter()) {
+                *logmdct = todB(mdct) + 0.345;
+            }
+            psy_look.noise_mask(&logmdct, &mut noise);
+            psy_look.tone_mask(&logfft[ch], &mut tone, global_ampmax, local_ampmax[ch]);
+            psy_look.offset_and_mix(&noise, &tone, 1, &mut logmask);
+            posts[center] = floor.fit(look, &logmdct, &logmask);
+
+            // The high and the low fits for the rate tweaking, the blobs between them are interpolated
+            if managed && posts[center].is_some() {
+                psy_look.offset_and_mix(&noise, &tone, 2, &mut logmask);
+                posts[PACKETBLOBS - 1] = floor.fit(look, &logmdct, &logmask);
+                psy_look.offset_and_mix(&noise, &tone, 0, &mut logmask);
+                posts[0] = floor.fit(look, &logmdct, &logmask);
+
+                let interpolate = |a: &Option<Vec<i32>>, b: &Option<Vec<i32>>, del: usize| -> Option<Vec<i32>> {
+                    match (a, b) {
+                        (Some(a), Some(b)) => Some(VorbisFloor1::interpolate_fit(a, b, (del * 65536 / center) as i32)),
+                        _ => None,
+                    }
+                };
+                for k in 1..center {
+                    posts[k] = interpolate(&posts[0], &posts[center], k);
+                }
+                for k in center + 1..PACKETBLOBS - 1 {
+                    posts[k] = interpolate(&posts[center], &posts[PACKETBLOBS - 1], k - center);
+                }
+            }
+        }
+
+        // Encode the packet blobs
+        let blobs = if managed {0..PACKETBLOBS} else {center..center + 1};
+        let mut curve = vec![0.0f32; n2];
+        for k in blobs {
+            let blob = vbi.packetblob[k].clone();
+            let mut writer = blob.borrow_mut();
+
+            // the packet type and the mode
+            writer.write(0, 1)?;
+            writer.write(vb.mode as u32, backend_state.modebits)?;
+            if W != 0 {
+                writer.write(lW as u32, 1)?;
+                writer.write(nW as u32, 1)?;
+            }
+
+            // The floors, then the spectrum divided by the floor curve below the lowpass is the residue
+            let lowpass = (ci.psy_g.sliding_lowpass[W][k].max(0) as usize).min(n2);
+            let mut residues = vec![vec![0.0f32; n2]; channels];
+            let mut nonzero = vec![false; channels];
+            for ch in 0..channels {
+                let (floor, look) = floor1_of(ch)?;
+                let Some(posts) = floor.encode(look, &mut *writer, &books, floor_posts[ch][k].as_deref())? else {
+                    continue;
+                };
+                nonzero[ch] = true;
+                floor.inverse(look, &posts, &mut curve);
+                for ((residue, &mdct), &curve) in residues[ch].iter_mut().zip(mdct[ch].iter()).zip(curve.iter()).take(lowpass) {
+                    *residue = (mdct / curve).round_ties_even();
+                }
+            }
+
+            // The coupled channels are encoded if any of them is not silent
+            for i in 0..self.coupling_steps as usize {
+                let (m, a) = (self.coupling_mag[i] as usize, self.coupling_ang[i] as usize);
+                if nonzero[m] || nonzero[a] {
+                    nonzero[m] = true;
+                    nonzero[a] = true;
+                }
+            }
+            self.forward_coupling(&mut residues);
+
+            // Residues of each submap
+            for submap in 0..self.submaps as usize {
+                let submap_channels: Vec<usize> = (0..channels).filter(|&ch|submap_of(ch) == submap).collect();
+                let vectors: Vec<Vec<f32>> = submap_channels.iter().map(|&ch|residues[ch].clone()).collect();
+                let do_not_encode: Vec<bool> = submap_channels.iter().map(|&ch|!nonzero[ch]).collect();
+                backend_state.residue_look[self.residuesubmap[submap] as usize].encode(&mut *writer, &vectors, &do_not_encode)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Debug for VorbisMapping {
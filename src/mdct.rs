@@ -1,12 +1,15 @@
 #![allow(dead_code)]
 use std::{
-    fmt::{self, Debug, Formatter},
-    slice::{from_raw_parts, from_raw_parts_mut}
+    fmt::{self, Debug, Display, Formatter},
+    io,
+    slice::{from_raw_parts, from_raw_parts_mut},
+    sync::Arc,
 };
 
 use crate::*;
 
 /// * This is for the modified DCT transform forward and backward
+/// * It's read-only while transforming, so it's `Send` and `Sync` to share between threads.
 #[derive(Default, Clone, PartialEq)]
 pub struct MdctLookup {
     pub n: usize,
@@ -28,18 +31,85 @@ impl Debug for MdctLookup {
     }
 }
 
+/// * The error of creating a `MdctLookup`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MdctError {
+    /// * The size of the transform must be a power of two
+    NotPowerOfTwo(usize),
+
+    /// * The size of the transform must be at least `MdctLookup::MIN_SIZE`
+    TooSmall(usize),
+}
+
+impl Display for MdctError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::NotPowerOfTwo(n) => write!(f, "The MDCT size {n} is not a power of two"),
+            Self::TooSmall(n) => write!(f, "The MDCT size {n} is smaller than {}", MdctLookup::MIN_SIZE),
+        }
+    }
+}
+
+impl std::error::Error for MdctError {}
+
+impl From<MdctError> for io::Error {
+    fn from(e: MdctError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
 const COS_PI3_8: f32 = 0.3826834323650897717284599840304; // (std::f32::consts::PI * 3.0 / 8.0).cos();
 const COS_PI2_8: f32 = 0.70710678118654752440084436210485; // (std::f32::consts::PI * 2.0 / 8.0).cos();
 const COS_PI1_8: f32 = 0.92387953251128675612818318939679; // (std::f32::consts::PI * 1.0 / 8.0).cos();
 
 impl MdctLookup {
+    /// * The smallest size the butterflies can handle, it's also the smallest block size of Vorbis.
+    pub const MIN_SIZE: usize = 64;
+
     /// * build lookups for trig functions; also pre-figure scaling and some window function algebra.
+    /// * Panics if `n` is not a power of two or is smaller than `MIN_SIZE`, use `try_new()` for untrusted sizes.
     pub fn new(n: usize) -> Self {
+        match Self::try_new(n) {
+            Ok(lookup) => lookup,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// * Get a shared lookup of the size for the read-only use of the transforms.
+    /// * With the `mdct-cache` feature, the lookups are cached by the size for the whole process, so the streams of the same block sizes share the tables.
+    ///   Without it, a new lookup is built every time.
+    pub fn shared(n: usize) -> Result<Arc<Self>, MdctError> {
+        #[cfg(feature = "mdct-cache")]
+        {
+            use std::{collections::HashMap, sync::{Mutex, OnceLock}};
+            static CACHE: OnceLock<Mutex<HashMap<usize, Arc<MdctLookup>>>> = OnceLock::new();
+            let mut cache = CACHE.get_or_init(Default::default).lock().unwrap_or_else(|e|e.into_inner());
+            if let Some(lookup) = cache.get(&n) {
+                return Ok(lookup.clone());
+            }
+            let lookup = Arc::new(Self::try_new(n)?);
+            cache.insert(n, lookup.clone());
+            Ok(lookup)
+        }
+        #[cfg(not(feature = "mdct-cache"))]
+        {
+            Ok(Arc::new(Self::try_new(n)?))
+        }
+    }
+
+    /// * build lookups for trig functions, the size is checked before building.
+    pub fn try_new(n: usize) -> Result<Self, MdctError> {
+        if !n.is_power_of_two() {
+            return Err(MdctError::NotPowerOfTwo(n));
+        }
+        if n < Self::MIN_SIZE {
+            return Err(MdctError::TooSmall(n));
+        }
         let pi = std::f32::consts::PI;
         let n2 = n >> 1;
         let n4 = n >> 2;
         let n8 = n >> 3;
-        let log2n = rint!((n as f32).ln() / 2.0_f32.ln());
+        let log2n = n.trailing_zeros() as i32;
         let mut bitrev = vec![0; n4];
         let mut trig = vec![0.0f32; n + n4];
 
@@ -50,15 +120,15 @@ impl MdctLookup {
             let i_f = i as f32;
             let i2 = i_f * 2.0;
             let i4 = i_f * 4.0;
-            trig[i * 2 + 0] =  (pi_div_n * i4).cos();
+            trig[i * 2] =  (pi_div_n * i4).cos();
             trig[i * 2 + 1] = -(pi_div_n * i4).sin();
-            trig[n2 + i * 2 + 0] = (pi_div_n2 * (i2 + 1.0)).cos();
+            trig[n2 + i * 2] = (pi_div_n2 * (i2 + 1.0)).cos();
             trig[n2 + i * 2 + 1] = (pi_div_n2 * (i2 + 1.0)).sin();
         }
         for i in 0..n8 {
             let i_f = i as f32;
             let i4 = i_f * 4.0;
-            trig[n + i * 2 + 0] =  (pi_div_n * (i4 + 2.0)).cos() * 0.5;
+            trig[n + i * 2] =  (pi_div_n * (i4 + 2.0)).cos() * 0.5;
             trig[n + i * 2 + 1] = -(pi_div_n * (i4 + 2.0)).sin() * 0.5;
         }
 
@@ -67,25 +137,23 @@ impl MdctLookup {
         for i in 0..n8 {
             let mut acc = 0;
             let mut j = 0;
-            let mut msb_rsh_j = msb >> j;
-            while j < msb_rsh_j {
-                if msb_rsh_j & i != 0 {
+            while msb >> j != 0 {
+                if (msb >> j) & i != 0 {
                     acc |= 1 << j;
                 }
                 j += 1;
-                msb_rsh_j = msb >> j;
             }
-            bitrev[i * 2 + 0] = ((!acc) & mask) - 1;
+            bitrev[i * 2] = ((!acc) & mask) - 1;
             bitrev[i * 2 + 1] = acc;
         }
 
-        Self {
+        Ok(Self {
             n,
             log2n,
             trig,
             bitrev,
             scale: 4.0 / n as f32,
-        }
+        })
     }
 
     /// * 8 point butterfly (in place, 4 register)
@@ -212,7 +280,7 @@ impl MdctLookup {
     /// * N point first stage butterfly (in place, 2 register)
     pub fn butterfly_first(mut t: &[f32], x: &mut [f32], points: usize) {
         let x = x.as_mut_ptr();
-        let mut x1 = unsafe {x.add((points >> 0) - 8)};
+        let mut x1 = unsafe {x.add(points - 8)};
         let mut x2 = unsafe {x.add((points >> 1) - 8)};
         loop {
             unsafe {
@@ -260,7 +328,7 @@ impl MdctLookup {
     /// * N/stage point generic N stage butterfly (in place, 2 register)
     pub fn butterfly_generic(mut t: &[f32], x: &mut [f32], points: usize, trigint: usize) {
         let x = x.as_mut_ptr();
-        let mut x1 = unsafe {x.add((points >> 0) - 8)};
+        let mut x1 = unsafe {x.add(points - 8)};
         let mut x2 = unsafe {x.add((points >> 1) - 8)};
         loop {
             unsafe {
@@ -355,7 +423,7 @@ impl MdctLookup {
             unsafe {
                 w1 = w1.sub(4);
                 let w0 = from_raw_parts_mut(w0, 4);
-                let w1 = from_raw_parts_mut(w1.add(4), 4);
+                let w1 = from_raw_parts_mut(w1, 4);
 
                 let x0 = from_raw_parts(x.add(bit[0] as usize), 2);
                 let x1 = from_raw_parts(x.add(bit[1] as usize), 2);
@@ -475,13 +543,13 @@ impl MdctLookup {
                 let ox1 = from_raw_parts_mut(ox1, 4);
 
                 ox1[3] =  ix[0] * t[1] - ix[1] * t[0];
-                ox2[0] = -ix[0] * t[0] + ix[1] * t[1];
+                ox2[0] = -(ix[0] * t[0] + ix[1] * t[1]);
                 ox1[2] =  ix[2] * t[3] - ix[3] * t[2];
-                ox2[1] = -ix[2] * t[2] + ix[3] * t[3];
+                ox2[1] = -(ix[2] * t[2] + ix[3] * t[3]);
                 ox1[1] =  ix[4] * t[5] - ix[5] * t[4];
-                ox2[2] = -ix[4] * t[4] + ix[5] * t[5];
+                ox2[2] = -(ix[4] * t[4] + ix[5] * t[5]);
                 ox1[0] =  ix[6] * t[7] - ix[7] * t[6];
-                ox2[3] = -ix[6] * t[6] + ix[7] * t[7];
+                ox2[3] = -(ix[6] * t[6] + ix[7] * t[7]);
 
             }
             ox2 = &mut ox2[4..];
@@ -538,11 +606,17 @@ impl MdctLookup {
     }
 
     pub fn forward(&self, in_: &[f32], out: &mut [f32]) {
+        self.forward_with_scratch(in_, out, &mut Vec::new())
+    }
+
+    /// * The same as `forward()`, but the working space is `scratch`, it's resized to `n` and reused between calls.
+    pub fn forward_with_scratch(&self, in_: &[f32], out: &mut [f32], scratch: &mut Vec<f32>) {
         let n = self.n;
         let n2 = n >> 1;
         let n4 = n >> 2;
         let n8 = n >> 3;
-        let mut w = vec![0.0_f32; n]; // forward needs working space
+        scratch.resize(n, 0.0); // forward needs working space
+        let w = &mut scratch[..];
         let w2 = &mut w[n2..];
         let in_ = in_.as_ptr();
 
@@ -564,7 +638,7 @@ impl MdctLookup {
                 let x1 = from_raw_parts(x1, 4);
                 let r0 = x0[2] + x1[0];
                 let r1 = x0[0] + x1[2];
-                w2[i + 0] = r1 * t[1] + r0 * t[0];
+                w2[i] = r1 * t[1] + r0 * t[0];
                 w2[i + 1] = r1 * t[0] - r0 * t[1];
             }
             x1 = unsafe {x1.add(4)};
@@ -582,7 +656,7 @@ impl MdctLookup {
                 let x1 = from_raw_parts(x1, 4);
                 let r0 = x0[2] - x1[0];
                 let r1 = x0[0] - x1[2];
-                w2[i + 0] = r1 * t[1] + r0 * t[0];
+                w2[i] = r1 * t[1] + r0 * t[0];
                 w2[i + 1] = r1 * t[0] - r0 * t[1];
             }
             x1 = unsafe {x1.add(4)};
@@ -600,7 +674,7 @@ impl MdctLookup {
                 let x1 = from_raw_parts(x1, 4);
                 let r0 = -x0[2] - x1[0];
                 let r1 = -x0[0] - x1[2];
-                w2[i + 0] = r1 * t[1] + r0 * t[0];
+                w2[i] = r1 * t[1] + r0 * t[0];
                 w2[i + 1] = r1 * t[0] - r0 * t[1];
             }
             x1 = unsafe {x1.add(4)};
@@ -608,20 +682,51 @@ impl MdctLookup {
         }
 
         self.butterflies(&mut w[n2..], n2);
-        self.bitreverse(&mut w);
+        self.bitreverse(w);
 
         // roatate + window
 
         let mut t = &self.trig[n2..];
-        let mut x0 = out[n2..].as_mut_ptr();
+        let (head, tail) = out[..n2].split_at_mut(n4);
         let mut w = &w[..];
 
-        for i in 0..n4 {
-            x0 = unsafe {x0.sub(1)};
-            out[i] = (w[0] * t[0] + w[1] * t[1]) * self.scale;
-            unsafe{*x0 = (w[0] * t[1] - w[1] * t[0]) * self.scale};
+        for (x, x0) in head.iter_mut().zip(tail.iter_mut().rev()) {
+            *x = (w[0] * t[0] + w[1] * t[1]) * self.scale;
+            *x0 = (w[0] * t[1] - w[1] * t[0]) * self.scale;
             w = &w[2..];
             t = &t[2..];
         }
     }
+
+    /// * Check how numerically sound the lookup is: `input` is cut into blocks of `n` samples that overlap by half,
+    ///   each block is windowed, transformed forward and backward, windowed again, and overlapped-added.
+    /// * The time-domain aliasing cancels out, so the result should equal `input`, returns the max absolute deviation.
+    pub fn roundtrip_error(&self, input: &[f32]) -> f32 {
+        let n = self.n;
+        let n2 = n >> 1;
+        let window = window::vorbis_window(n, n, n);
+
+        // Pad half a block of zeros before and after the input so every sample is covered by two blocks
+        let num_blocks = input.len().div_ceil(n2) + 1;
+        let mut padded = vec![0.0f32; (num_blocks + 1) * n2];
+        padded[n2..n2 + input.len()].copy_from_slice(input);
+        let mut output = vec![0.0f32; padded.len()];
+
+        let mut block = vec![0.0f32; n];
+        let mut spectrum = vec![0.0f32; n2];
+        let mut scratch = Vec::new();
+        for b in 0..num_blocks {
+            let offset = b * n2;
+            for i in 0..n {
+                block[i] = padded[offset + i] * window[i];
+            }
+            self.forward_with_scratch(&block, &mut spectrum, &mut scratch);
+            self.backward(&spectrum, &mut block);
+            for i in 0..n {
+                output[offset + i] += block[i] * window[i];
+            }
+        }
+
+        input.iter().zip(output[n2..].iter()).map(|(a, b)|(a - b).abs()).fold(0.0, f32::max)
+    }
 }
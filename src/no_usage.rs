@@ -1,13 +1,23 @@
 #![allow(dead_code)]
 use std::{
     fmt::{self, Debug, Formatter},
-    io::{self, Write}
+    io::{self, Write},
 };
 use crate::*;
 
 use io_utils::CursorVecU8;
 use codebook::StaticCodeBook;
 
+/// * Turn the sizes of the books into the bit ranges of them, the first book begins after the 8-bit count of the books.
+fn bit_ranges_of_books(bits_of_books: &[usize]) -> Vec<Range<usize>> {
+    let mut begin = 8usize;
+    bits_of_books.iter().map(|&bits| {
+        let range = begin..begin + bits;
+        begin += bits;
+        range
+    }).collect()
+}
+
 /// * This is the encoding information for the `VorbisSetupHeader` static codebooks section.
 /// * The struct stores packed codebooks together, and stores each books size in bits.
 /// * This is for the **test** usage.
@@ -22,7 +32,7 @@ pub struct StaticCodeBooksPacked {
 
 impl StaticCodeBooksPacked {
     pub fn unpack(&self) -> io::Result<StaticCodeBooks> {
-        StaticCodeBooks::load_from_slice(&self.books.data)
+        Ok(StaticCodeBooks::load_from_slice(&self.books.data)?)
     }
 
     /// * Get the number of total bits in the `data` field
@@ -70,6 +80,12 @@ impl StaticCodeBooksPacked {
         ret
     }
 
+    /// * The bit range of each book from the beginning of the codebooks section, the 8-bit count of the books comes first.
+    /// * In a setup header packet, the codebooks section begins at bit 56, after the packet type and `vorbis`.
+    pub fn book_bit_ranges(&self) -> Vec<Range<usize>> {
+        bit_ranges_of_books(&self.bits_of_books)
+    }
+
     /// * Concat a packed book without a gap
     pub fn concat(&mut self, book: &BitwiseData) {
         self.books.concat(book);
@@ -86,6 +102,7 @@ impl StaticCodeBooksPacked {
 /// * The struct stores each decoded books and its size in bits, and the total bits of the books.
 /// * This is for the **test** usage.
 #[derive(Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StaticCodeBooks {
     /// * The unpacked codebooks
     pub books: Vec<StaticCodeBook>,
@@ -99,7 +116,7 @@ pub struct StaticCodeBooks {
 
 impl StaticCodeBooks {
     /// * Unpack the codebooks from the bitstream
-    pub fn load(bitreader: &mut BitReader) -> io::Result<Self> {
+    pub fn load(bitreader: &mut BitReader) -> Result<Self, VorbisError> {
         let begin_bits = bitreader.total_bits;
         let num_books = (read_bits!(bitreader, 8).wrapping_add(1)) as usize;
         let mut books = Vec::<StaticCodeBook>::with_capacity(num_books);
@@ -117,7 +134,7 @@ impl StaticCodeBooks {
     }
 
     /// * Unpack from a slice
-    pub fn load_from_slice(data: &[u8]) -> io::Result<Self> {
+    pub fn load_from_slice(data: &[u8]) -> Result<Self, VorbisError> {
         let mut bitreader = BitReader::new(data);
         Self::load(&mut bitreader)
     }
@@ -132,6 +149,12 @@ impl StaticCodeBooks {
         BitwiseData::calc_total_bytes(self.total_bits)
     }
 
+    /// * The bit range of each book from the beginning of the codebooks section, the 8-bit count of the books comes first.
+    /// * In a setup header packet, the codebooks section begins at bit 56, after the packet type and `vorbis`.
+    pub fn book_bit_ranges(&self) -> Vec<Range<usize>> {
+        bit_ranges_of_books(&self.bits_of_books)
+    }
+
     /// * Get how many books
     pub fn len(&self) -> usize {
         self.books.len()
@@ -161,7 +184,7 @@ impl StaticCodeBooks {
     }
 
     /// * Pack to bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
@@ -170,17 +193,30 @@ impl StaticCodeBooks {
             book.pack(bitwriter)?;
         }
         let total_bits = bitwriter.total_bits - begin_bits;
-        assert_eq!(total_bits, self.total_bits);
+        if total_bits != self.total_bits {
+            return Err(VorbisError::BitCountMismatch{expected: self.total_bits, actual: total_bits});
+        }
         Ok(total_bits)
     }
 }
 
-impl From<StaticCodeBooksPacked> for StaticCodeBooks {
-    fn from(packed: StaticCodeBooksPacked) -> Self {
-        let ret = Self::load_from_slice(&packed.books.data).unwrap();
-        assert_eq!(ret.bits_of_books, packed.bits_of_books, "StaticCodeBooks::from(&StaticCodeBooksPacked), bits_of_books");
-        assert_eq!(ret.total_bits, packed.books.total_bits, "StaticCodeBooks::from(&StaticCodeBooksPacked), total_bits");
-        ret
+impl TryFrom<StaticCodeBooksPacked> for StaticCodeBooks {
+    type Error = VorbisError;
+
+    /// * Unpack the books, the sizes of the unpacked books must be the same as the packed ones.
+    fn try_from(packed: StaticCodeBooksPacked) -> Result<Self, VorbisError> {
+        let ret = Self::load_from_slice(&packed.books.data)?;
+        if ret.bits_of_books != packed.bits_of_books {
+            let i = ret.bits_of_books.iter().zip(packed.bits_of_books.iter()).position(|(a, b)|a != b).unwrap_or(ret.bits_of_books.len().min(packed.bits_of_books.len()));
+            return Err(VorbisError::BitCountMismatch{
+                expected: packed.bits_of_books.get(i).copied().unwrap_or(0),
+                actual: ret.bits_of_books.get(i).copied().unwrap_or(0),
+            });
+        }
+        if ret.total_bits != packed.books.total_bits {
+            return Err(VorbisError::BitCountMismatch{expected: packed.books.total_bits, actual: ret.total_bits});
+        }
+        Ok(ret)
     }
 }
 
@@ -196,30 +232,57 @@ impl Debug for StaticCodeBooks {
 
 derive_index!(StaticCodeBooks, StaticCodeBook, books);
 
+/// * The number of meaningful bits of a header packet, the zero padding after the end-of-packet flag is excluded.
+/// * The end-of-packet flag is the last bit that is set, so splicing the packet must keep it as the last set bit.
+fn packet_bits(packet: &[u8]) -> usize {
+    match packet.iter().rposition(|&b|b != 0) {
+        Some(i) => i * 8 + 8 - packet[i].leading_zeros() as usize,
+        None => 0,
+    }
+}
+
 /// * This function removes the codebooks from the Vorbis setup header. The setup header was extracted from the Ogg stream.
 /// * Since Vorbis stores data in bitwise form, all of the data are not aligned in bytes, we have to parse it bit by bit.
 /// * After parsing the codebooks, we can sum up the total bits of the codebooks, and then we can replace it with an empty codebook.
 /// * At last, use our `BitwiseData` to concatenate these bit-strings without any gaps.
 pub fn remove_codebook_from_setup_header(setup_header: &[u8]) -> io::Result<Vec<u8>> {
-    // Try to verify if this is the right way to read the codebook
-    assert_eq!(&setup_header[0..7], b"\x05vorbis", "Checking the vorbis header that is a `setup_header` or not");
+    replace_codebooks_in_setup_header(setup_header, &StaticCodeBooks::default())
+}
 
-    // Let's find the book, and kill it.
-    let codebooks = StaticCodeBooks::load_from_slice(&setup_header[7..]).unwrap();
+/// * Replace the codebooks section of the Vorbis setup header with `new_books`, the floors, residues, maps and modes are kept bit by bit.
+/// * The floors and residues refer to the books by index, so the new books should be compatible with them to get a decodable header.
+pub fn replace_codebooks_in_setup_header(setup_header: &[u8], new_books: &StaticCodeBooks) -> io::Result<Vec<u8>> {
+    if setup_header.len() < 7 || &setup_header[0..7] != b"\x05vorbis" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a Vorbis setup header.".to_string()));
+    }
+
+    // Find the span of the original codebooks
+    let codebooks = StaticCodeBooks::load_from_slice(&setup_header[7..])?;
     let bytes_before_codebook = BitwiseData::from_bytes(&setup_header[0..7]);
-    let (_codebook_bits, bits_after_codebook) = BitwiseData::new(&setup_header[7..], (setup_header.len() - 7) * 8).split(codebooks.total_bits);
+    let (_codebook_bits, bits_after_codebook) = BitwiseData::new(&setup_header[7..], packet_bits(setup_header) - 56).split(codebooks.total_bits);
 
-    // Let's generate the empty codebook.
-    let _empty_codebooks = StaticCodeBooks::default().to_packed_codebooks().unwrap().books;
+    let new_books = new_books.to_packed_codebooks()?.books;
 
     let mut setup_header = BitwiseData::default();
     setup_header.concat(&bytes_before_codebook);
-    setup_header.concat(&_empty_codebooks);
+    setup_header.concat(&new_books);
     setup_header.concat(&bits_after_codebook);
 
     Ok(setup_header.into_bytes())
 }
 
+/// * Load the setup header and pack it back, check if the repacked bytes are identical to the original bit by bit.
+/// * The setup header can't be parsed without the number of channels, so the identification header is needed too.
+/// * `remove_codebook_from_setup_header()` relies on this, the packer must write back exactly what the loader reads.
+pub fn verify_setup_roundtrip(setup_header: &[u8], ident_header: &VorbisIdentificationHeader) -> io::Result<bool> {
+    let mut bitreader = BitReader::new(setup_header);
+    let loaded = VorbisSetupHeader::load(&mut bitreader, ident_header)?;
+    let mut bitwriter = BitWriterCursor::default();
+    loaded.pack(&mut bitwriter, ident_header)?;
+    let repacked = bitwriter.into_bytes();
+    Ok(BitwiseData::from_bytes(setup_header).first_difference(&BitwiseData::from_bytes(&repacked)).is_none())
+}
+
 /// * This function removes all codebooks from the Vorbis Setup Header.
 /// * To think normally, when the codebooks in the Vorbis audio data were removed, the Vorbis audio was unable to decode.
 /// * This function exists because the author of `Vorbis ACM` registered `FORMAT_TAG_OGG_VORBIS3` and `FORMAT_TAG_OGG_VORBIS3P`, and its comment says "Have no codebook header".
@@ -238,7 +301,7 @@ pub fn _remove_codebook_from_ogg_stream(data: &[u8]) -> io::Result<Vec<u8>> {
     // If this packet doesn't have any `setup_header`
     // We return.
     if setup_header.is_empty() {
-        return_Err!(io::Error::new(io::ErrorKind::InvalidData, "There's no setup header in the given Ogg packets.".to_string()));
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "There's no setup header in the given Ogg packets.".to_string()));
     }
 
     let setup_header = remove_codebook_from_setup_header(&setup_header)?;
@@ -0,0 +1,475 @@
+#![allow(dead_code)]
+use std::io;
+
+use crate::*;
+use codec::{VorbisInfo, VorbisDspState};
+use bitwise::{BitReader, BitWriterCursor};
+use headers::{VorbisIdentificationHeader, VorbisCommentHeader, VorbisSetupHeader};
+use pages::{OggPageView, OggStreamPacket, ogg_pages, ogg_stream_packets, packets_to_ogg_pages};
+use io_utils::CursorVecU8;
+use savagestr::prelude::*;
+
+/// * Split the packets of an Ogg Vorbis stream into the identification header and the audio packets.
+/// * The comment header and the setup header are skipped.
+fn split_ogg_vorbis_packets(ogg_bytes: &[u8]) -> io::Result<(VorbisIdentificationHeader, Vec<OggStreamPacket>)> {
+    let mut packets = ogg_stream_packets(ogg_bytes, None)?;
+    if packets.len() < 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("An Ogg Vorbis stream needs at least 3 header packets, got {} packets", packets.len())));
+    }
+    let identification_header = VorbisIdentificationHeader::load_from_slice(&packets[0].data)?;
+    let audio_packets = packets.split_off(3);
+    Ok((identification_header, audio_packets))
+}
+
+/// * Get the audio packets of the first logical stream of an Ogg Vorbis byte buffer, the three header packets are skipped.
+/// * Each packet comes with the granule position of the page it finishes on, the packets could be fed to `VorbisDspState::decode_packet()` in order.
+pub fn ogg_vorbis_audio_packets(ogg_bytes: &[u8]) -> io::Result<Vec<OggStreamPacket>> {
+    let (_, audio_packets) = split_ogg_vorbis_packets(ogg_bytes)?;
+    Ok(audio_packets)
+}
+
+/// * Calculate the bitrate of each `window_ms` long window of the audio in bits per second.
+/// * Only the Vorbis audio packets are counted, the Ogg page overhead and the header packets are not.
+/// * The granule positions only mark where the pages end, so the bytes of the packets that finished on a page
+///   are spread evenly across the samples between the granule position of the previous page and this page.
+pub fn ogg_vorbis_bitrate_windows(ogg_bytes: &[u8], window_ms: u32) -> io::Result<Vec<u32>> {
+    let (identification_header, audio_packets) = split_ogg_vorbis_packets(ogg_bytes)?;
+    let sample_rate = identification_header.sample_rate as u64;
+    let window_size = (sample_rate * window_ms as u64 / 1000).max(1);
+
+    // Every span is (first sample, end sample, bytes)
+    let mut spans = Vec::<(u64, u64, usize)>::new();
+    let mut span_begin = 0u64;
+    let mut span_bytes = 0usize;
+    for packet in audio_packets.iter() {
+        span_bytes += packet.data.len();
+        if let Some(granule_position) = packet.granule_position {
+            let span_end = granule_position.max(span_begin);
+            spans.push((span_begin, span_end, span_bytes));
+            span_begin = span_end;
+            span_bytes = 0;
+        }
+    }
+    let total_samples = span_begin;
+    if total_samples == 0 {
+        return Ok(Vec::new());
+    }
+
+    let num_windows = total_samples.div_ceil(window_size) as usize;
+    let mut window_bits = vec![0.0f64; num_windows];
+    for &(begin, end, bytes) in spans.iter() {
+        let bits = (bytes * 8) as f64;
+        if end == begin {
+            window_bits[((begin / window_size) as usize).min(num_windows - 1)] += bits;
+            continue;
+        }
+        let mut pos = begin;
+        while pos < end {
+            let window = pos / window_size;
+            let next = ((window + 1) * window_size).min(end);
+            window_bits[window as usize] += bits * (next - pos) as f64 / (end - begin) as f64;
+            pos = next;
+        }
+    }
+
+    Ok(window_bits.into_iter().enumerate().map(|(i, bits)| {
+        let window_begin = i as u64 * window_size;
+        let window_samples = window_size.min(total_samples - window_begin);
+        (bits * sample_rate as f64 / window_samples as f64).round() as u32
+    }).collect())
+}
+
+/// * Get the maximum bitrate of all of the `window_ms` long windows of the audio in bits per second.
+/// * Useful to check if a constrained VBR stream stays under its declared `bitrate_upper`.
+pub fn ogg_vorbis_peak_bitrate(ogg_bytes: &[u8], window_ms: u32) -> io::Result<u32> {
+    match ogg_vorbis_bitrate_windows(ogg_bytes, window_ms)?.into_iter().max() {
+        Some(peak) => Ok(peak),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "There's no audio in the Ogg Vorbis stream.".to_string())),
+    }
+}
+
+/// * Read the block size of an audio packet from its mode number, without decoding the packet.
+pub fn vorbis_packet_block_size(packet: &[u8], identification_header: &VorbisIdentificationHeader, setup_header: &VorbisSetupHeader) -> io::Result<i32> {
+    let mut bitreader = BitReader::new(packet);
+    if bitreader.read(1)? != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a Vorbis audio packet.".to_string()));
+    }
+    let mode_number = bitreader.read(ilog!(setup_header.modes.len().wrapping_sub(1)))? as usize;
+    match setup_header.modes.get(mode_number) {
+        Some(mode) => Ok(identification_header.block_size[mode.block_flag as usize]),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Mode number {mode_number} exceeded boundary: {}", setup_header.modes.len()))),
+    }
+}
+
+/// * Get the total samples and the duration in seconds of the first logical stream, nothing is decoded.
+/// * The granule position of the last page is the number of the samples, it already excludes the first audio packet
+///   which only primes the overlap-add, and the trimmed samples at the end.
+pub fn ogg_vorbis_duration(ogg_bytes: &[u8]) -> io::Result<(u64, f64)> {
+    let mut pages = ogg_pages(ogg_bytes);
+    let Some(first_page) = pages.next() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "There's no Ogg page in the data.".to_string()));
+    };
+    // The identification header is alone on the first page
+    let first_page = first_page?;
+    let identification_header = VorbisIdentificationHeader::load_from_slice(first_page.data)?;
+    if identification_header.sample_rate <= 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid sample rate {}", identification_header.sample_rate)));
+    }
+    let mut total_samples = 0u64;
+    for page in pages {
+        let page = page?;
+        if page.stream_id == first_page.stream_id && page.has_granule() {
+            total_samples = page.granule_position;
+        }
+    }
+    Ok((total_samples, total_samples as f64 / identification_header.sample_rate as f64))
+}
+
+/// * Decode the first logical stream of an Ogg Vorbis byte buffer to planar PCM samples.
+/// * The samples are trimmed by the granule positions: the samples before the first granule position are dropped if the stream
+///   begins with a negative offset, and the samples after the granule position of the last page are dropped.
+pub fn decode_ogg_vorbis(ogg_bytes: &[u8]) -> io::Result<(VorbisIdentificationHeader, Vec<Vec<f32>>)> {
+    let packets = ogg_stream_packets(ogg_bytes, None)?;
+    if packets.len() < 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("An Ogg Vorbis stream needs at least 3 header packets, got {} packets", packets.len())));
+    }
+    let identification_header = VorbisIdentificationHeader::load_from_slice(&packets[0].data)?;
+    let setup_header = VorbisSetupHeader::load(&mut BitReader::new(&packets[2].data), &identification_header)?;
+    let vi = VorbisInfo::new(&identification_header, &setup_header)?;
+    let mut vd = VorbisDspState::new(vi, false)?;
+
+    let mut pcm = vec![Vec::<f32>::new(); identification_header.channels as usize];
+    let mut granule_offset = None;
+    let mut end_granule = None;
+    for packet in packets[3..].iter() {
+        if packet.data.is_empty() {
+            continue;
+        }
+        for (channel, samples) in pcm.iter_mut().zip(vd.decode_packet(&packet.data)?) {
+            channel.extend(samples);
+        }
+        if let Some(granule_position) = packet.granule_position {
+            let decoded = pcm[0].len() as i64;
+            if granule_offset.is_none() && !packet.is_last_page {
+                granule_offset = Some(granule_position as i64 - decoded);
+            }
+            if packet.is_last_page {
+                end_granule = Some(granule_position as i64);
+            }
+        }
+    }
+
+    // The position of the first sample in the granule
+    let granule_offset = granule_offset.unwrap_or(0);
+    let skip = (-granule_offset).max(0) as usize;
+    let begin_granule = granule_offset.max(0);
+    for channel in pcm.iter_mut() {
+        channel.drain(..skip.min(channel.len()));
+        if let Some(end_granule) = end_granule {
+            channel.truncate((end_granule - begin_granule).max(0) as usize);
+        }
+    }
+    Ok((identification_header, pcm))
+}
+
+/// * An audio packet in the `OggVorbisIndex`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OggVorbisIndexEntry {
+    /// * The offset of the Ogg page where the packet begins
+    pub page_offset: usize,
+
+    /// * The granule position of the first sample the packet outputs
+    pub start_granule: u64,
+
+    /// * How many samples the packet outputs, the first audio packet outputs nothing.
+    pub num_samples: u64,
+
+    /// * The block size of the packet
+    pub block_size: i32,
+}
+
+/// * The index of all of the audio packets of an Ogg Vorbis stream, for random access without decoding everything.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OggVorbisIndex {
+    /// * The audio packets in the order of the stream
+    pub entries: Vec<OggVorbisIndexEntry>,
+
+    /// * The total samples of the stream
+    pub total_samples: u64,
+}
+
+impl OggVorbisIndex {
+    /// * Walk through the packets of the first logical stream and record where each audio packet is.
+    /// * Only the block sizes are read from the audio packets, nothing is decoded.
+    pub fn build(ogg_bytes: &[u8]) -> io::Result<Self> {
+        let packets = ogg_stream_packets(ogg_bytes, None)?;
+        if packets.len() < 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("An Ogg Vorbis stream needs at least 3 header packets, got {} packets", packets.len())));
+        }
+        let identification_header = VorbisIdentificationHeader::load_from_slice(&packets[0].data)?;
+        let setup_header = VorbisSetupHeader::load(&mut BitReader::new(&packets[2].data), &identification_header)?;
+
+        let mut entries = Vec::<OggVorbisIndexEntry>::with_capacity(packets.len() - 3);
+        let mut granule = 0u64;
+        let mut prev_block_size = None;
+        let mut granule_offset = None;
+        let mut last_granule = None;
+        for packet in packets[3..].iter() {
+            if packet.data.is_empty() {
+                continue;
+            }
+            let block_size = vorbis_packet_block_size(&packet.data, &identification_header, &setup_header)?;
+
+            // The overlapping part of the previous block and this block is what's output
+            let num_samples = match prev_block_size {
+                Some(prev_block_size) => (prev_block_size / 4 + block_size / 4) as u64,
+                None => 0,
+            };
+            prev_block_size = Some(block_size);
+            entries.push(OggVorbisIndexEntry {
+                page_offset: packet.page_offset,
+                start_granule: granule,
+                num_samples,
+                block_size,
+            });
+            granule += num_samples;
+
+            if let Some(granule_position) = packet.granule_position {
+                // The stream may not begin at granule 0, the first granule position on a non-last page tells the beginning.
+                if granule_offset.is_none() && !packet.is_last_page {
+                    granule_offset = Some(granule_position as i64 - granule as i64);
+                }
+                last_granule = Some(granule_position);
+            }
+        }
+
+        let granule_offset = granule_offset.unwrap_or(0);
+        for entry in entries.iter_mut() {
+            entry.start_granule = (entry.start_granule as i64 + granule_offset).max(0) as u64;
+        }
+        let total_samples = match last_granule {
+            Some(last_granule) => last_granule,
+            None => (granule as i64 + granule_offset).max(0) as u64,
+        };
+
+        Ok(Self {
+            entries,
+            total_samples,
+        })
+    }
+
+    /// * Find the audio packet that outputs the sample, returns the index of the entry.
+    /// * To decode the packet, the previous packet must be decoded first for the overlapping.
+    pub fn packet_for_sample(&self, sample: u64) -> Option<usize> {
+        if sample >= self.total_samples {
+            return None;
+        }
+        let index = self.entries.partition_point(|entry|entry.start_granule <= sample);
+        if index == 0 {
+            None
+        } else {
+            Some(index - 1)
+        }
+    }
+}
+
+/// * Modify the comment header of the first logical stream, the pages of the headers are rebuilt.
+/// * The pages of the audio are copied as is except their page indices, pages of other logical streams are untouched.
+pub fn retag_ogg_vorbis<F>(ogg_bytes: &[u8], edit: F) -> io::Result<Vec<u8>>
+where
+    F: FnOnce(&mut VorbisCommentHeader) {
+    let pages = ogg_pages(ogg_bytes).collect::<io::Result<Vec<OggPageView>>>()?;
+    let Some(stream_id) = pages.first().map(|page|page.stream_id) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No Ogg pages in the data.".to_string()));
+    };
+
+    // Find which pages the 3 header packets are on, the setup header must finish its page.
+    let mut num_header_pages = 0usize;
+    let mut num_packets = 0usize;
+    for page in pages.iter().filter(|page|page.stream_id == stream_id) {
+        num_header_pages += 1;
+        num_packets += page.segment_table.iter().filter(|&&lacing|lacing < 255).count();
+        if num_packets >= 3 {
+            break;
+        }
+    }
+    match num_packets {
+        3 => (),
+        0..3 => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("An Ogg Vorbis stream needs at least 3 header packets, got {num_packets} packets"))),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "The audio data begins on the page of the setup header.".to_string())),
+    }
+
+    let packets = ogg_stream_packets(ogg_bytes, Some(stream_id))?;
+    let text_codecs = StringCodecMaps::new();
+    // The comments can't be larger than the input, big pictures are allowed for being edited or removed
+    let mut comment_header = VorbisCommentHeader::load_with_limit(&mut BitReader::new(&packets[1].data), &text_codecs, ogg_bytes.len())?;
+    edit(&mut comment_header);
+    let mut bitwriter = BitWriterCursor::new(CursorVecU8::default());
+    comment_header.pack(&mut bitwriter, &text_codecs)?;
+    let comment_packet = bitwriter.into_bytes();
+
+    // The identification header has its own page, the comment header and the setup header share pages.
+    let (mut header_pages, next_page_index) = packets_to_ogg_pages(&[&packets[0].data], stream_id, 0, true, 0)?;
+    let (comment_setup_pages, next_page_index) = packets_to_ogg_pages(&[&comment_packet, &packets[2].data], stream_id, next_page_index, false, 0)?;
+    header_pages.extend(comment_setup_pages);
+    let page_index_shift = next_page_index as i64 - num_header_pages as i64;
+
+    let mut ret = Vec::<u8>::with_capacity(ogg_bytes.len());
+    let mut stream_page_count = 0usize;
+    for page in pages.iter() {
+        if page.stream_id != stream_id {
+            ret.extend(page.raw);
+            continue;
+        }
+        stream_page_count += 1;
+        if stream_page_count == 1 {
+            for header_page in header_pages.iter() {
+                ret.extend(header_page);
+            }
+        }
+        if stream_page_count > num_header_pages {
+            ret.extend(page.with_page_index((page.page_index as i64 + page_index_shift) as u32)?);
+        }
+    }
+    Ok(ret)
+}
+
+/// * Remove all of the `METADATA_BLOCK_PICTURE` comments from the comment header, the audio is untouched.
+pub fn strip_pictures_from_ogg(ogg_bytes: &[u8]) -> io::Result<Vec<u8>> {
+    retag_ogg_vorbis(ogg_bytes, |comment_header| {
+        comment_header.remove_tag("METADATA_BLOCK_PICTURE");
+    })
+}
+
+/// * Remove all of the comments from the comment header, the vendor string is kept. The audio is untouched.
+/// * Use `retag_ogg_vorbis()` to blank the vendor string too.
+pub fn strip_comments_from_ogg_stream(ogg_bytes: &[u8]) -> io::Result<Vec<u8>> {
+    retag_ogg_vorbis(ogg_bytes, |comment_header| {
+        comment_header.comments.clear();
+    })
+}
+
+/// * A problem found by `validate_ogg_vorbis()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    /// * The checksum of the page doesn't match its content
+    CrcMismatch {
+        page_offset: usize,
+    },
+
+    /// * The Ogg pages can't be parsed
+    BadPage(String),
+
+    /// * A header packet is missing or can't be parsed
+    BadHeader(String),
+
+    /// * The lengths of a codebook don't describe a valid codeword tree
+    BadCodebook {
+        index: usize,
+        reason: String,
+    },
+
+    /// * A mapping or a mode references a floor, residue, submap or mapping that doesn't exist
+    BadReference(String),
+
+    /// * Packing the parsed setup header doesn't give back the same bytes
+    SetupNotLossless,
+
+    /// * The granule position of the last page doesn't fit the samples the audio packets decode to
+    GranuleMismatch {
+        final_granule: u64,
+        decoded_samples: u64,
+    },
+}
+
+/// * The result of `validate_ogg_vorbis()`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// * How many pages were checked
+    pub num_pages: usize,
+
+    /// * How many audio packets the first logical stream has
+    pub num_audio_packets: usize,
+
+    /// * Everything found wrong
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /// * Nothing was found wrong
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// * Check an Ogg Vorbis file in one call: the page checksums, the headers, the codebooks, the references in the setup header,
+///   whether the setup header packs back to the same bytes, and whether the final granule position fits the decoded length.
+pub fn validate_ogg_vorbis(ogg_bytes: &[u8]) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    for page in ogg_pages(ogg_bytes) {
+        match page {
+            Ok(page) => {
+                report.num_pages += 1;
+                if !page.verify_checksum() {
+                    report.issues.push(ValidationIssue::CrcMismatch {page_offset: page.offset});
+                }
+            }
+            Err(e) => report.issues.push(ValidationIssue::BadPage(e.to_string())),
+        }
+    }
+
+    let packets = match ogg_stream_packets(ogg_bytes, None) {
+        Ok(packets) => packets,
+        Err(e) => {
+            report.issues.push(ValidationIssue::BadPage(e.to_string()));
+            return report;
+        }
+    };
+    if packets.len() < 3 {
+        report.issues.push(ValidationIssue::BadHeader(format!("An Ogg Vorbis stream needs at least 3 header packets, got {} packets", packets.len())));
+        return report;
+    }
+    report.num_audio_packets = packets.len() - 3;
+
+    let identification_header = match VorbisIdentificationHeader::load_from_slice(&packets[0].data) {
+        Ok(identification_header) => identification_header,
+        Err(e) => {
+            report.issues.push(ValidationIssue::BadHeader(format!("Identification header: {e}")));
+            return report;
+        }
+    };
+    if let Err(e) = VorbisCommentHeader::load(&mut BitReader::new(&packets[1].data), &StringCodecMaps::new()) {
+        report.issues.push(ValidationIssue::BadHeader(format!("Comment header: {e}")));
+    }
+    let setup_header = match VorbisSetupHeader::load(&mut BitReader::new(&packets[2].data), &identification_header) {
+        Ok(setup_header) => setup_header,
+        Err(e) => {
+            report.issues.push(ValidationIssue::BadHeader(format!("Setup header: {e}")));
+            return report;
+        }
+    };
+
+    for (index, static_codebook) in setup_header.static_codebooks.iter().enumerate() {
+        if let Err(e) = static_codebook.validate() {
+            report.issues.push(ValidationIssue::BadCodebook {index, reason: e.to_string()});
+        }
+    }
+    if let Err(e) = VorbisInfo::check_setup_references(&identification_header, &setup_header) {
+        report.issues.push(ValidationIssue::BadReference(e.to_string()));
+    }
+
+    let mut bitwriter = BitWriterCursor::new(CursorVecU8::default());
+    if setup_header.pack(&mut bitwriter, &identification_header).is_err() || bitwriter.into_bytes() != packets[2].data {
+        report.issues.push(ValidationIssue::SetupNotLossless);
+    }
+
+    // The last packet may be trimmed by the final granule position, but only within the samples it outputs.
+    if let Ok(index) = OggVorbisIndex::build(ogg_bytes) && let Some(last) = index.entries.last() {
+        let decoded_samples = last.start_granule + last.num_samples;
+        if index.total_samples > decoded_samples || index.total_samples < last.start_granule {
+            report.issues.push(ValidationIssue::GranuleMismatch {final_granule: index.total_samples, decoded_samples});
+        }
+    }
+
+    report
+}
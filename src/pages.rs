@@ -0,0 +1,331 @@
+#![allow(dead_code)]
+use std::{
+    fmt::{self, Debug, Formatter},
+    io,
+};
+
+use crate::*;
+use ogg::OggPacket;
+
+/// * The page continues a packet from the previous page
+pub const PAGE_FLAG_CONTINUED: u8 = 1;
+
+/// * The first page of a logical stream
+pub const PAGE_FLAG_BOS: u8 = 2;
+
+/// * The last page of a logical stream
+pub const PAGE_FLAG_EOS: u8 = 4;
+
+/// * The granule position of a page that has no packet finished on it
+pub const NO_GRANULE: u64 = u64::MAX;
+
+/// * A borrowed view of an Ogg page inside a byte buffer.
+/// * Unlike `ogg::OggPacket`, this one accepts pages that continue a packet from the previous page,
+///   and it doesn't copy anything.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct OggPageView<'a> {
+    /// * Where the page begins in the buffer
+    pub offset: usize,
+
+    /// * The combination of `PAGE_FLAG_CONTINUED`, `PAGE_FLAG_BOS` and `PAGE_FLAG_EOS`
+    pub header_type: u8,
+
+    /// * The granule position when the last packet finished on this page was decoded, `NO_GRANULE` if none finished.
+    pub granule_position: u64,
+
+    /// * The serial number of the logical stream
+    pub stream_id: u32,
+
+    /// * The page sequence number
+    pub page_index: u32,
+
+    /// * The checksum stored in the page
+    pub checksum: u32,
+
+    /// * The lacing values
+    pub segment_table: &'a [u8],
+
+    /// * The payload of the page
+    pub data: &'a [u8],
+
+    /// * The whole page including the page header
+    pub raw: &'a [u8],
+}
+
+impl<'a> OggPageView<'a> {
+    /// * Parse a page at the beginning of `data`, `offset` is only recorded for the caller.
+    pub fn parse(data: &'a [u8], offset: usize) -> io::Result<Self> {
+        if data.len() < 27 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("The Ogg page at 0x{offset:x} is too small: {} < 27", data.len())));
+        }
+        if data[0..4] != *b"OggS" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected `OggS` at 0x{offset:x}, got `{}`", String::from_utf8_lossy(&data[0..4]))));
+        }
+        if data[4] != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid Ogg version {} at 0x{offset:x}", data[4])));
+        }
+        let num_segments = data[26] as usize;
+        let data_start = 27 + num_segments;
+        if data.len() < data_start {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("The segment table of the Ogg page at 0x{offset:x} is truncated")));
+        }
+        let segment_table = &data[27..data_start];
+        let data_end = data_start + segment_table.iter().map(|&s|s as usize).sum::<usize>();
+        if data.len() < data_end {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("The Ogg page at 0x{offset:x} is truncated: {} < {data_end}", data.len())));
+        }
+        Ok(Self {
+            offset,
+            header_type: data[5],
+            granule_position: u64::from_le_bytes(data[6..14].try_into().unwrap()),
+            stream_id: u32::from_le_bytes(data[14..18].try_into().unwrap()),
+            page_index: u32::from_le_bytes(data[18..22].try_into().unwrap()),
+            checksum: u32::from_le_bytes(data[22..26].try_into().unwrap()),
+            segment_table,
+            data: &data[data_start..data_end],
+            raw: &data[..data_end],
+        })
+    }
+
+    /// * The size of the whole page in bytes
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    /// * A page always has the header, it's never empty
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    /// * Is the first packet on this page continued from the previous page
+    pub fn is_continued(&self) -> bool {
+        self.header_type & PAGE_FLAG_CONTINUED != 0
+    }
+
+    /// * Is this the first page of the logical stream
+    pub fn is_bos(&self) -> bool {
+        self.header_type & PAGE_FLAG_BOS != 0
+    }
+
+    /// * Is this the last page of the logical stream
+    pub fn is_eos(&self) -> bool {
+        self.header_type & PAGE_FLAG_EOS != 0
+    }
+
+    /// * Does any packet finish on this page
+    pub fn has_granule(&self) -> bool {
+        self.granule_position != NO_GRANULE
+    }
+
+    /// * Calculate the checksum of the page and compare it with the stored one
+    pub fn verify_checksum(&self) -> bool {
+        OggPacket::get_checksum(self.raw).map(|checksum|checksum == self.checksum).unwrap_or(false)
+    }
+}
+
+impl Debug for OggPageView<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("OggPageView")
+        .field("offset", &self.offset)
+        .field("header_type", &self.header_type)
+        .field("granule_position", &self.granule_position)
+        .field("stream_id", &format_args!("0x{:08x}", self.stream_id))
+        .field("page_index", &self.page_index)
+        .field("checksum", &format_args!("0x{:08x}", self.checksum))
+        .field("segment_table", &format_args!("[{}]", format_array!(self.segment_table)))
+        .field("data", &format_args!("[u8; {}]", self.data.len()))
+        .finish()
+    }
+}
+
+/// * Iterate through the Ogg pages in a byte buffer
+#[derive(Debug, Clone, Copy)]
+pub struct OggPageIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+    failed: bool,
+}
+
+impl<'a> OggPageIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            failed: false,
+        }
+    }
+}
+
+impl<'a> Iterator for OggPageIter<'a> {
+    type Item = io::Result<OggPageView<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.data.len() {
+            return None;
+        }
+        match OggPageView::parse(&self.data[self.offset..], self.offset) {
+            Ok(page) => {
+                self.offset += page.len();
+                Some(Ok(page))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// * Iterate through the Ogg pages in a byte buffer
+pub fn ogg_pages(data: &[u8]) -> OggPageIter<'_> {
+    OggPageIter::new(data)
+}
+
+/// * A packet reassembled from the lacing values of the Ogg pages
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct OggStreamPacket {
+    /// * The packet data
+    pub data: Vec<u8>,
+
+    /// * The offset of the page where the packet begins
+    pub page_offset: usize,
+
+    /// * The granule position of the page where the packet ends, only set if this is the last packet finished on that page.
+    pub granule_position: Option<u64>,
+
+    /// * Does the packet end on the last page of the stream
+    pub is_last_page: bool,
+}
+
+impl Debug for OggStreamPacket {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("OggStreamPacket")
+        .field("data", &format_args!("[u8; {}]", self.data.len()))
+        .field("page_offset", &self.page_offset)
+        .field("granule_position", &self.granule_position)
+        .field("is_last_page", &self.is_last_page)
+        .finish()
+    }
+}
+
+/// * Reassemble all of the packets of a logical stream from a byte buffer.
+/// * If `stream_id` is `None`, the stream of the first page is used. Pages of other streams are skipped.
+pub fn ogg_stream_packets(data: &[u8], stream_id: Option<u32>) -> io::Result<Vec<OggStreamPacket>> {
+    let mut stream_id = stream_id;
+    let mut ret = Vec::<OggStreamPacket>::new();
+    let mut cur_packet: Option<OggStreamPacket> = None;
+    for page in ogg_pages(data) {
+        let page = page?;
+        match stream_id {
+            None => stream_id = Some(page.stream_id),
+            Some(id) => if id != page.stream_id {
+                continue;
+            }
+        }
+        if !page.is_continued() {
+            // An unfinished packet was followed by a page that doesn't continue it, the packet is lost.
+            cur_packet = None;
+        }
+        let mut pos = 0usize;
+        let mut last_finished = None;
+        for &lacing in page.segment_table.iter() {
+            let segment = &page.data[pos..pos + lacing as usize];
+            pos += lacing as usize;
+            let packet = cur_packet.get_or_insert_with(|| OggStreamPacket {
+                page_offset: page.offset,
+                ..Default::default()
+            });
+            packet.data.extend(segment);
+            if lacing < 255 {
+                let mut packet = cur_packet.take().unwrap();
+                packet.is_last_page = page.is_eos();
+                ret.push(packet);
+                last_finished = Some(ret.len() - 1);
+            }
+        }
+        if let Some(last_finished) = last_finished && page.has_granule() {
+            ret[last_finished].granule_position = Some(page.granule_position);
+        }
+    }
+    Ok(ret)
+}
+
+/// * Build an Ogg page from the segment table and the payload, the checksum is calculated.
+pub fn build_ogg_page(header_type: u8, granule_position: u64, stream_id: u32, page_index: u32, segment_table: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    if segment_table.len() > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Too many segments for an Ogg page: {}", segment_table.len())));
+    }
+    let mut page = Vec::<u8>::with_capacity(27 + segment_table.len() + data.len());
+    page.extend(b"OggS");
+    page.push(0);
+    page.push(header_type);
+    page.extend(granule_position.to_le_bytes());
+    page.extend(stream_id.to_le_bytes());
+    page.extend(page_index.to_le_bytes());
+    page.extend(0u32.to_le_bytes());
+    page.push(segment_table.len() as u8);
+    page.extend(segment_table);
+    page.extend(data);
+    OggPacket::fill_checksum_field(&mut page)?;
+    Ok(page)
+}
+
+/// * Lay the packets out into pages, the packets are put one after another and a new page begins when the segment table is full.
+/// * The last packet finishes the last page. All of the pages get `granule_position` if any packet finishes on it, otherwise `NO_GRANULE`.
+/// * Returns the pages and the next page index.
+pub fn packets_to_ogg_pages(packets: &[&[u8]], stream_id: u32, first_page_index: u32, first_is_bos: bool, granule_position: u64) -> io::Result<(Vec<Vec<u8>>, u32)> {
+    let mut ret = Vec::<Vec<u8>>::new();
+    let mut page_index = first_page_index;
+    let mut segment_table = Vec::<u8>::with_capacity(255);
+    let mut data = Vec::<u8>::new();
+    let mut page_continued = false;
+    let mut finished_any = false;
+
+    let mut seal = |segment_table: &mut Vec<u8>, data: &mut Vec<u8>, page_continued: bool, finished_any: bool| -> io::Result<()> {
+        let mut header_type = if page_continued {PAGE_FLAG_CONTINUED} else {0};
+        if ret.is_empty() && first_is_bos {
+            header_type |= PAGE_FLAG_BOS;
+        }
+        ret.push(build_ogg_page(header_type, if finished_any {granule_position} else {NO_GRANULE}, stream_id, page_index, segment_table, data)?);
+        page_index += 1;
+        segment_table.clear();
+        data.clear();
+        Ok(())
+    };
+
+    for packet in packets.iter() {
+        // A packet whose size is a multiple of 255 ends with a zero lacing value
+        let mut remaining = *packet;
+        let mut first_segment = true;
+        loop {
+            if segment_table.len() == 255 {
+                seal(&mut segment_table, &mut data, page_continued, finished_any)?;
+                page_continued = !first_segment;
+                finished_any = false;
+            }
+            let lacing = remaining.len().min(255);
+            segment_table.push(lacing as u8);
+            data.extend(&remaining[..lacing]);
+            remaining = &remaining[lacing..];
+            first_segment = false;
+            if lacing < 255 {
+                break;
+            }
+        }
+        finished_any = true;
+    }
+    if !segment_table.is_empty() {
+        seal(&mut segment_table, &mut data, page_continued, finished_any)?;
+    }
+    Ok((ret, page_index))
+}
+
+impl OggPageView<'_> {
+    /// * Copy the page with a new page index, the checksum is recalculated.
+    pub fn with_page_index(&self, page_index: u32) -> io::Result<Vec<u8>> {
+        let mut page = self.raw.to_vec();
+        page[18..22].copy_from_slice(&page_index.to_le_bytes());
+        OggPacket::fill_checksum_field(&mut page)?;
+        Ok(page)
+    }
+}
@@ -1,7 +1,6 @@
 #![allow(dead_code)]
 use std::{
     cmp::{min, max},
-    mem,
     fmt::{self, Debug, Formatter},
     rc::Rc,
 };
@@ -73,6 +72,22 @@ impl VorbisLookPsyGlobal {
             ..Default::default()
         }
     }
+
+    /// * Track the strongest peak: take the peak of the block if it's stronger, then decay it by the duration of the block.
+    /// * `n` is the half of the block size, returns the tracked peak.
+    pub fn ampmax_decay(&mut self, block_ampmax: f32, n: usize, rate: i32) -> f32 {
+        if block_ampmax > self.ampmax {
+            self.ampmax = block_ampmax;
+        }
+        let secs = n as f32 / rate as f32;
+        self.ampmax = (self.ampmax + secs * self.info_psy_global.ampmax_att_per_sec).max(-9999.0);
+        self.ampmax
+    }
+
+    /// * Take the peak of the analyzed block if it's stronger, the next `ampmax_decay()` carries it to the next block.
+    pub fn track_ampmax(&mut self, block_ampmax: f32) {
+        self.ampmax = self.ampmax.max(block_ampmax);
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -137,7 +152,57 @@ impl Debug for VorbisInfoPsy {
 
 impl Default for VorbisInfoPsy {
     fn default() -> Self {
-        unsafe {mem::MaybeUninit::<Self>::zeroed().assume_init()}
+        Self {
+            block_flag: 0,
+            ath_adjatt: 0.0,
+            ath_maxatt: 0.0,
+            tone_masteratt: [0.0; P_NOISECURVES],
+            tone_centerboost: 0.0,
+            tone_decay: 0.0,
+            tone_abs_limit: 0.0,
+            toneatt: [0.0; P_BANDS],
+            noisemaskp: 0,
+            noisemaxsupp: 0.0,
+            noisewindowlo: 0.0,
+            noisewindowhi: 0.0,
+            noisewindowlomin: 0,
+            noisewindowhimin: 0,
+            noisewindowfixed: 0,
+            noiseoff: [[0.0; P_BANDS]; P_NOISECURVES],
+            noisecompand: [0.0; NOISE_COMPAND_LEVELS],
+            max_curve_dB: 0.0,
+            normal_p: 0,
+            normal_start: 0,
+            normal_partition: 0,
+            normal_thresh: 0.0,
+        }
+    }
+}
+
+impl VorbisInfoPsy {
+    /// * The starting point of the psychoacoustic settings of each block type before the encoder setup fills them, `_psy_info_template` of `libvorbis`
+    pub fn info_template() -> Self {
+        let mut noiseoff = [[0.0; P_BANDS]; P_NOISECURVES];
+        for curve in noiseoff.iter_mut() {
+            curve[0] = -1.0;
+        }
+        let mut noisecompand = [0.0; NOISE_COMPAND_LEVELS];
+        noisecompand[0] = -1.0;
+        Self {
+            block_flag: -1,
+            ath_adjatt: -140.0,
+            ath_maxatt: -140.0,
+            tone_abs_limit: -40.0,
+            noisemaskp: 1,
+            noisewindowlo: 0.5,
+            noisewindowhi: 0.5,
+            noiseoff,
+            noisecompand,
+            max_curve_dB: 105.0,
+            normal_start: -1,
+            normal_partition: -1,
+            ..Default::default()
+        }
     }
 }
 
@@ -155,7 +220,7 @@ fn max_curve(c: &mut [f32], c2: &[f32]) {
 
 fn attenuate_curve(c: &mut [f32], att: f32) {
     for i in 0..EHMER_MAX {
-        c[i] *= att;
+        c[i] += att;
     }
 }
 
@@ -252,8 +317,8 @@ fn setup_tone_curves(
 
         /* which octave curves will we be compositing? */
         let bin = (fromOC!(i as f32 * 0.5) / binHz).floor();
-        let lo_curve = ((toOC!(bin * binHz + 1.0) * 2.0).ceil() as usize).clamp(0, i);
-        let hi_curve = min((toOC!((bin + 1.0) * binHz) * 2.0).floor() as usize, P_BANDS);
+        let lo_curve = ((toOC!(bin * binHz + 1.0) * 2.0).ceil() as i32).clamp(0, i as i32);
+        let hi_curve = min((toOC!((bin + 1.0) * binHz) * 2.0).floor() as i32, P_BANDS as i32 - 1);
 
         for m in 0..P_LEVELS {
             let ret_i_m = &mut ret_i[m];
@@ -283,8 +348,8 @@ fn setup_tone_curves(
                     l += 1;
                 }
             };
-            for k in lo_curve..hi_curve {
-                process_curve(k, &mut brute_buffer);
+            for k in lo_curve..=hi_curve {
+                process_curve(k as usize, &mut brute_buffer);
             }
 
             /* be equally paranoid about being valid up to next half ocatve */
@@ -376,9 +441,22 @@ pub struct VorbisLookPsy {
 }
 
 impl Default for VorbisLookPsy {
-    #[allow(invalid_value)]
     fn default() -> Self {
-        unsafe {mem::MaybeUninit::zeroed().assume_init()}
+        Self {
+            n: 0,
+            vorbis_info_phy: Rc::default(),
+            tonecurves: Vec::new(),
+            noiseoffset: Vec::new(),
+            ath: Vec::new(),
+            octave: Vec::new(),
+            bark: Vec::new(),
+            firstoc: 0,
+            shiftoc: 0,
+            eighth_octave_lines: 0,
+            total_octave_lines: 0,
+            rate: 0,
+            m_val: 0.0,
+        }
     }
 }
 
@@ -390,8 +468,8 @@ impl VorbisLookPsy {
         rate: u32,
     ) -> Self {
         let eighth_octave_lines = vorbis_info_psy_global.eighth_octave_lines;
-        let shiftoc = toOC!(rint!((vorbis_info_psy_global.eighth_octave_lines as f32 * 8.0).log2())) as i32 - 1;
-        let firstoc = (toOC!(0.25 * rate as f32 * 0.5 / n as f32) as i32 * (1 << (shiftoc + 1))) as i32 - eighth_octave_lines;
+        let shiftoc = rint!((eighth_octave_lines as f32 * 8.0).log2()) - 1;
+        let firstoc = (toOC!(0.25 * rate as f32 * 0.5 / n as f32) * (1 << (shiftoc + 1)) as f32) as i32 - eighth_octave_lines;
         let maxoc = (toOC!((n as f32 + 0.25) * rate as f32 * 0.5 / n as f32) * (1 << (shiftoc + 1)) as f32 + 0.5) as i32;
         let total_octave_lines = maxoc - firstoc + 1;
         let mut ath = vec![0.0; n];
@@ -424,7 +502,7 @@ impl VorbisLookPsy {
             }
         }
 
-        while j < n {
+        while j > 0 && j < n {
             ath[j] = ath[j - 1];
             j += 1;
         }
@@ -483,6 +561,291 @@ impl VorbisLookPsy {
     }
 }
 
+/// * The floor of the masking seeds, nothing is masked here
+const NEGINF: f32 = -9999.0;
+
+/// * Seed the tone masking curve of a peak into the eighth octave lines
+fn seed_curve(seed: &mut [f32], curves: &[Vec<f32>], amp: f32, oc: i32, n: usize, linesper: i32, db_offset: f32) {
+    let choice = (((amp + db_offset - P_LEVEL_0) * 0.1) as i32).clamp(0, P_LEVELS as i32 - 1) as usize;
+    let posts = &curves[choice];
+    let curve = &posts[2..];
+    let post0 = posts[0] as i32;
+    let post1 = posts[1] as i32;
+    let mut seedptr = oc + (post0 - EHMER_OFFSET as i32) * linesper - (linesper >> 1);
+
+    for i in post0..post1 {
+        if seedptr > 0 && (seedptr as usize) < n {
+            let lin = amp + curve[i as usize];
+            if seed[seedptr as usize] < lin {
+                seed[seedptr as usize] = lin;
+            }
+        }
+        seedptr += linesper;
+        if seedptr >= n as i32 {
+            break;
+        }
+    }
+}
+
+/// * Keep only the seeds that matter, then spread each of them over the lines it covers. Linear time.
+fn seed_chase(seeds: &mut [f32], linesper: i32, n: usize) {
+    let mut posstack = Vec::<i32>::with_capacity(n);
+    let mut ampstack = Vec::<f32>::with_capacity(n);
+
+    for i in 0..n as i32 {
+        let seed = seeds[i as usize];
+        if posstack.len() >= 2 {
+            while seed >= *ampstack.last().unwrap() {
+                let stack = posstack.len();
+                if i < posstack[stack - 1] + linesper &&
+                   stack > 1 && ampstack[stack - 1] <= ampstack[stack - 2] &&
+                   i < posstack[stack - 2] + linesper {
+                    // we completely overlap, making stack-1 irrelevant. pop it
+                    posstack.pop();
+                    ampstack.pop();
+                    continue;
+                }
+                break;
+            }
+        }
+        posstack.push(i);
+        ampstack.push(seed);
+    }
+
+    // the stack now contains only the positions that are relevant. Scan 'em straight through
+    let stack = posstack.len();
+    let mut pos = 0usize;
+    for i in 0..stack {
+        let endpos = if i + 1 < stack && ampstack[i + 1] > ampstack[i] {
+            posstack[i + 1]
+        } else {
+            // +1 is important, else bin 0 is discarded in short frames
+            posstack[i] + linesper + 1
+        };
+        let endpos = (endpos.max(0) as usize).min(n);
+        while pos < endpos {
+            seeds[pos] = ampstack[i];
+            pos += 1;
+        }
+    }
+}
+
+/// * Linear regression of the noise energy in the bark window of each bin, the windows are in `b` as `(lo << 16) + hi`.
+/// * If `fixed` is positive, a window of `fixed` bins is also tried and the lower of them is used.
+fn bark_noise_hybridmp(n: usize, b: &[i32], f: &[f32], noise: &mut [f32], offset: f32, fixed: i32) {
+    let mut tn = vec![0.0f32; n];
+    let mut tx = vec![0.0f32; n];
+    let mut txx = vec![0.0f32; n];
+    let mut ty = vec![0.0f32; n];
+    let mut txy = vec![0.0f32; n];
+
+    let (mut sum_n, mut sum_x, mut sum_xx, mut sum_y, mut sum_xy) = (0.0f32, 0.0f32, 0.0f32, 0.0f32, 0.0f32);
+    for i in 0..n {
+        let x = i as f32;
+        let y = (f[i] + offset).max(1.0);
+        let w = if i == 0 {y * y * 0.5} else {y * y};
+        sum_n += w;
+        sum_x += if i == 0 {w} else {w * x};
+        sum_xx += w * x * x;
+        sum_y += w * y;
+        sum_xy += w * x * y;
+        tn[i] = sum_n;
+        tx[i] = sum_x;
+        txx[i] = sum_xx;
+        ty[i] = sum_y;
+        txy[i] = sum_xy;
+    }
+
+    let mut a = 0.0f32;
+    let mut bb = 0.0f32;
+    let mut d = 1.0f32;
+
+    // The window sums, a negative `lo` means the window is mirrored at bin 0
+    let window = |lo: i32, hi: usize| -> (f32, f32, f32, f32, f32) {
+        if lo < 0 {
+            let lo = (-lo) as usize;
+            (tn[hi] + tn[lo], tx[hi] - tx[lo], txx[hi] + txx[lo], ty[hi] + ty[lo], txy[hi] - txy[lo])
+        } else {
+            let lo = lo as usize;
+            (tn[hi] - tn[lo], tx[hi] - tx[lo], txx[hi] - txx[lo], ty[hi] - ty[lo], txy[hi] - txy[lo])
+        }
+    };
+    let solve = |(sn, sx, sxx, sy, sxy): (f32, f32, f32, f32, f32)| -> (f32, f32, f32) {
+        (sy * sxx - sx * sxy, sn * sxy - sx * sy, sn * sxx - sx * sx)
+    };
+
+    let mut i = 0usize;
+    while i < n {
+        let lo = b[i] >> 16;
+        let hi = (b[i] & 0xffff) as usize;
+        if lo >= 0 || -lo >= n as i32 || hi >= n {
+            break;
+        }
+        (a, bb, d) = solve(window(lo, hi));
+        noise[i] = ((a + i as f32 * bb) / d).max(0.0) - offset;
+        i += 1;
+    }
+    while i < n {
+        let lo = b[i] >> 16;
+        let hi = (b[i] & 0xffff) as usize;
+        if lo < 0 || lo >= n as i32 || hi >= n {
+            break;
+        }
+        (a, bb, d) = solve(window(lo, hi));
+        noise[i] = ((a + i as f32 * bb) / d).max(0.0) - offset;
+        i += 1;
+    }
+    while i < n {
+        noise[i] = ((a + i as f32 * bb) / d).max(0.0) - offset;
+        i += 1;
+    }
+
+    if fixed <= 0 {
+        return;
+    }
+
+    i = 0;
+    while i < n {
+        let hi = i as i32 + fixed / 2;
+        let lo = hi - fixed;
+        if hi >= n as i32 || lo >= 0 {
+            break;
+        }
+        (a, bb, d) = solve(window(lo, hi as usize));
+        noise[i] = noise[i].min((a + i as f32 * bb) / d - offset);
+        i += 1;
+    }
+    while i < n {
+        let hi = i as i32 + fixed / 2;
+        let lo = hi - fixed;
+        if hi >= n as i32 || lo < 0 {
+            break;
+        }
+        (a, bb, d) = solve(window(lo, hi as usize));
+        noise[i] = noise[i].min((a + i as f32 * bb) / d - offset);
+        i += 1;
+    }
+    while i < n {
+        noise[i] = noise[i].min((a + i as f32 * bb) / d - offset);
+        i += 1;
+    }
+}
+
+impl VorbisLookPsy {
+    /// * Prime the seeds of the eighth octave lines with the tone masking curves of the peaks, the port of `seed_loop()`
+    fn seed_loop(&self, f: &[f32], flr: &[f32], seed: &mut [f32], specmax: f32) {
+        let n = self.n;
+        let db_offset = self.vorbis_info_phy.max_curve_dB - specmax;
+
+        // prime the working vector with peak values
+        let mut i = 0usize;
+        while i < n {
+            let mut max = f[i];
+            let oc = self.octave[i];
+            while i + 1 < n && self.octave[i + 1] == oc {
+                i += 1;
+                max = max.max(f[i]);
+            }
+
+            if max + 6.0 > flr[i] {
+                let band = (oc >> self.shiftoc).clamp(0, P_BANDS as i32 - 1) as usize;
+                seed_curve(seed, &self.tonecurves[band], max, self.octave[i] - self.firstoc, self.total_octave_lines as usize, self.eighth_octave_lines, db_offset);
+            }
+            i += 1;
+        }
+    }
+
+    /// * Pull the seeds back from the eighth octave lines into the bins, the port of `max_seeds()`
+    fn max_seeds(&self, seed: &mut [f32], flr: &mut [f32]) {
+        let n = self.total_octave_lines as usize;
+        let linesper = self.eighth_octave_lines;
+        let tone_abs_limit = self.vorbis_info_phy.tone_abs_limit;
+        seed_chase(seed, linesper, n);
+
+        let mut linpos = 0usize;
+        let mut pos = (self.octave[0] - self.firstoc - (linesper >> 1)).max(0) as usize;
+        while linpos + 1 < self.n {
+            let mut min_v = seed[pos].min(tone_abs_limit);
+            let end = ((self.octave[linpos] + self.octave[linpos + 1]) >> 1) - self.firstoc;
+            while ((pos + 1) as i32) <= end && pos + 1 < n {
+                pos += 1;
+                if (seed[pos] > NEGINF && seed[pos] < min_v) || min_v == NEGINF {
+                    min_v = seed[pos];
+                }
+            }
+
+            let end = pos as i32 + self.firstoc;
+            while linpos < self.n && self.octave[linpos] <= end {
+                flr[linpos] = flr[linpos].max(min_v);
+                linpos += 1;
+            }
+        }
+
+        let min_v = seed[n - 1];
+        for v in flr[linpos..self.n].iter_mut() {
+            *v = v.max(min_v);
+        }
+    }
+
+    /// * The noise masking curve from the log MDCT spectrum in dB, the port of `_vp_noisemask()`
+    pub fn noise_mask(&self, logmdct: &[f32], logmask: &mut [f32]) {
+        let n = self.n;
+        let vi = &self.vorbis_info_phy;
+        bark_noise_hybridmp(n, &self.bark, logmdct, logmask, 140.0, -1);
+
+        let mut work: Vec<f32> = logmdct.iter().zip(logmask.iter()).map(|(m, l)|m - l).collect();
+        bark_noise_hybridmp(n, &self.bark, &work, logmask, 0.0, vi.noisewindowfixed);
+        for (w, m) in work.iter_mut().zip(logmdct.iter()) {
+            *w = m - *w;
+        }
+
+        for (l, w) in logmask[..n].iter_mut().zip(work.iter()) {
+            let db = ((*l + 0.5) as i32).clamp(0, NOISE_COMPAND_LEVELS as i32 - 1) as usize;
+            *l = w + vi.noisecompand[db];
+        }
+    }
+
+    /// * The tone masking curve from the log FFT spectrum in dB, the ATH floats below the local peak. The port of `_vp_tonemask()`
+    pub fn tone_mask(&self, logfft: &[f32], logmask: &mut [f32], global_specmax: f32, local_specmax: f32) {
+        let vi = &self.vorbis_info_phy;
+        let mut seed = vec![NEGINF; self.total_octave_lines as usize];
+
+        // set the ATH (floating below localmax, not global max by a specified att)
+        let att = (local_specmax + vi.ath_adjatt).max(vi.ath_maxatt);
+        for (l, a) in logmask[..self.n].iter_mut().zip(self.ath.iter()) {
+            *l = a + att;
+        }
+
+        // tone masking
+        self.seed_loop(logfft, logmask, &mut seed, global_specmax);
+        self.max_seeds(&mut seed, logmask);
+    }
+
+    /// * Mix the noise mask with the offset of `offset_select` and the tone mask, the louder wins. The port of `_vp_offset_and_mix()` without the AoTuV MDCT tweaks.
+    pub fn offset_and_mix(&self, noise: &[f32], tone: &[f32], offset_select: usize, logmask: &mut [f32]) {
+        let vi = &self.vorbis_info_phy;
+        let toneatt = vi.tone_masteratt[offset_select];
+        let noiseoffset = &self.noiseoffset[offset_select];
+        for i in 0..self.n {
+            let val = (noise[i] + noiseoffset[i]).min(vi.noisemaxsupp);
+            logmask[i] = val.max(tone[i] + toneatt);
+        }
+    }
+
+    /// * Compute the masking threshold in dB of the `n` bins from the FFT magnitudes, the mask is what the floor 1 fit consumes.
+    /// * The magnitudes are used for both the tone mask and the noise mask, and the peak of them is both the global and the local peak.
+    pub fn mask_curve(&self, fft_magnitudes: &[f32], out: &mut [f32]) {
+        let n = self.n;
+        let logfft: Vec<f32> = fft_magnitudes[..n].iter().map(|&m|todB(m)).collect();
+        let specmax = logfft.iter().copied().fold(NEGINF, f32::max);
+        let mut noise = vec![0.0; n];
+        let mut tone = vec![0.0; n];
+        self.noise_mask(&logfft, &mut noise);
+        self.tone_mask(&logfft, &mut tone, specmax, specmax);
+        self.offset_and_mix(&noise, &tone, 1, out);
+    }
+}
+
 impl Debug for VorbisLookPsy {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("VorbisLookPsy")
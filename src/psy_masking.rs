@@ -910,3 +910,14 @@ pub const TONEMASKS: [[[f32; EHMER_MAX]; 6]; P_BANDS] = [
         ]
     ]
 ];
+
+/// * The absolute threshold of hearing in dB, in 1/8 octave steps from about 15 Hz to 16 kHz and beyond
+pub fn ath_curve() -> &'static [f32] {
+    &ATH
+}
+
+/// * The tone masking curves of a half-octave band from 62.5 Hz, one curve for each of the 6 levels from 40 dB to 90 dB.
+/// * Each curve is in 1/8 octave steps, the tone is at `EHMER_OFFSET`. `None` if the band is not less than `P_BANDS`.
+pub fn tone_mask(band: usize) -> Option<&'static [[f32; EHMER_MAX]]> {
+    TONEMASKS.get(band).map(|curves|&curves[..])
+}
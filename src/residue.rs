@@ -1,8 +1,8 @@
 #![allow(dead_code)]
 use std::{
     fmt::{self, Debug, Formatter},
-    mem,
     io::{self, Write},
+    ops::Range,
     rc::Rc,
     cell::RefCell,
 };
@@ -12,7 +12,7 @@ use utils::NestVecFormatter;
 use codec::VorbisDspState;
 use bitwise::{BitReader, BitWriter};
 use headers::VorbisSetupHeader;
-use codebook::CodeBook;
+use codebook::{CodeBook, StaticCodeBook};
 use copiablebuf::CopiableBuffer;
 
 /// * block-partitioned VQ coded straight residue
@@ -62,12 +62,12 @@ pub struct VorbisLookResidue {
 }
 
 impl VorbisResidue {
-    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> io::Result<Self> {
+    pub fn load(bitreader: &mut BitReader, vorbis_info: &VorbisSetupHeader) -> Result<Self, VorbisError> {
         let static_codebooks = &vorbis_info.static_codebooks;
         let residue_type = read_bits!(bitreader, 16);
 
         if !(0..3).contains(&residue_type) {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid residue type {residue_type}")))
+            return Err(VorbisError::InvalidResidueType(residue_type));
         }
 
         let mut ret = Self {
@@ -83,11 +83,11 @@ impl VorbisResidue {
         };
 
         if !(0..static_codebooks.len()).contains(&(ret.groupbook as usize)) {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid groupbook index {}", ret.groupbook)));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid groupbook index {}", ret.groupbook)).into());
         }
 
         let partitions = ret.partitions as usize;
-        ret.secondstages.resize(partitions, 0);
+        ret.secondstages.try_resize(partitions, 0)?;
 
         let mut acc = 0usize;
         for i in 0..partitions {
@@ -100,39 +100,76 @@ impl VorbisResidue {
             acc += icount!(cascade);
         }
 
-        ret.booklist.resize(acc, 0);
+        ret.booklist.try_resize(acc, 0)?;
         for i in 0..acc {
             let book = read_bits!(bitreader, 8);
             if !(0..static_codebooks.len()).contains(&(book as usize)) {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book index {book}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book index {book}")).into());
             }
             ret.booklist[i] = book;
             let book_maptype = static_codebooks[book as usize].maptype;
             if book_maptype == 0 {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book maptype {book_maptype}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book maptype {book_maptype}")).into());
             }
         }
 
-        let groupbook = &static_codebooks[ret.groupbook as usize];
+        ret.partvals = Self::calc_partvals(ret.partitions, &static_codebooks[ret.groupbook as usize])?;
+        Ok(ret)
+    }
+
+    /// * `partitions` ^ the dimension of the groupbook, the number of the partition class combinations a groupbook entry codes.
+    /// * The groupbook must have enough entries for all of the combinations.
+    pub fn calc_partvals(partitions: i32, groupbook: &StaticCodeBook) -> io::Result<i32> {
         let entries = groupbook.entries;
         let mut dim = groupbook.dim;
         let mut partvals = 1i32;
         if dim < 1 {
-            return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid groupbook dimension {dim}")));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid groupbook dimension {dim}")));
         }
         while dim > 0 {
-            partvals *= ret.partitions;
+            partvals *= partitions;
             if partvals > entries {
-                return_Err!(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid partvals {partvals}")));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid partvals {partvals}")));
             }
             dim -= 1;
         }
-        ret.partvals = partvals;
-        Ok(ret)
+        Ok(partvals)
+    }
+
+    /// * Start building a residue for the encoder setup, set the second stage books with `VorbisResidueBuilder::stage_books()`,
+    ///   then `VorbisResidueBuilder::build()` computes `partvals` and checks the books against the codebooks.
+    pub fn builder(residue_type: i32, begin: i32, end: i32, grouping: i32, partitions: i32, groupbook: i32) -> io::Result<VorbisResidueBuilder> {
+        if !(0..3).contains(&residue_type) {
+            return Err(VorbisError::InvalidResidueType(residue_type).into());
+        }
+        if begin < 0 || end < begin || end >= 1 << 24 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Bad residue range {begin}..{end}")));
+        }
+        if !(1..=1 << 24).contains(&grouping) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Bad grouping {grouping}")));
+        }
+        if !(1..=64).contains(&partitions) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Bad number of partitions {partitions}, should be 1 to 64")));
+        }
+        if !(0..256).contains(&groupbook) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid groupbook index {groupbook}")));
+        }
+        Ok(VorbisResidueBuilder {
+            residue: Self {
+                residue_type,
+                begin,
+                end,
+                grouping,
+                partitions,
+                groupbook,
+                ..Default::default()
+            },
+            stage_books: vec![[None; 8]; partitions as usize],
+        })
     }
 
     /// * Pack to the bitstream
-    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> io::Result<usize>
+    pub fn pack<W>(&self, bitwriter: &mut BitWriter<W>) -> Result<usize, VorbisError>
     where
         W: Write {
         let begin_bits = bitwriter.total_bits;
@@ -161,6 +198,84 @@ impl VorbisResidue {
 
         Ok(bitwriter.total_bits - begin_bits)
     }
+
+    /// * The range of the bins that the residue codes, `begin` and `end` are clamped to `block_n`, the half of the block size.
+    /// * For residue type 2, the channels are interleaved into one vector, so `block_n` is the half of the block size times the number of the channels.
+    /// * The range is empty if `begin` isn't less than `end`.
+    pub fn sample_range(&self, block_n: usize) -> Range<usize> {
+        let end = (self.end.max(0) as usize).min(block_n);
+        let begin = (self.begin.max(0) as usize).min(end);
+        begin..end
+    }
+
+    /// * The number of bits `pack()` writes, counted without packing
+    pub fn packed_bits(&self) -> usize {
+        let stages: usize = self.secondstages.iter().map(|&secondstage| {
+            (if ilog!(secondstage) > 3 {3 + 1 + 5} else {4}) + icount!(secondstage) * 8
+        }).sum();
+        16 + 24 + 24 + 24 + 6 + 8 + stages
+    }
+}
+
+/// * The builder of a `VorbisResidue`, created by `VorbisResidue::builder()`
+#[derive(Debug, Clone, PartialEq)]
+pub struct VorbisResidueBuilder {
+    residue: VorbisResidue,
+
+    /// [partition][stage] the second stage books
+    stage_books: Vec<[Option<i32>; 8]>,
+}
+
+impl VorbisResidueBuilder {
+    /// * Set the second stage books of the partition class, one for each stage, `None` for the stages that the class skips.
+    pub fn stage_books(mut self, partition: usize, books: &[Option<i32>]) -> io::Result<Self> {
+        if partition >= self.stage_books.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Partition {partition} is out of {} partitions", self.stage_books.len())));
+        }
+        if books.len() > 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Too many stages {}, should be at most 8", books.len())));
+        }
+        self.stage_books[partition] = [None; 8];
+        self.stage_books[partition][..books.len()].copy_from_slice(books);
+        Ok(self)
+    }
+
+    /// * Set the metrics the encoder uses to classify the partitions of the class.
+    pub fn class_metrics(mut self, partition: usize, classmetric1: i32, classmetric2: i32) -> io::Result<Self> {
+        if partition >= self.stage_books.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Partition {partition} is out of {} partitions", self.stage_books.len())));
+        }
+        self.residue.classmetric1[partition] = classmetric1;
+        self.residue.classmetric2[partition] = classmetric2;
+        Ok(self)
+    }
+
+    /// * Fill `secondstages`, `booklist` and `partvals`, the books are checked the same way as `VorbisResidue::load()` does.
+    pub fn build(self, static_codebooks: &[StaticCodeBook]) -> io::Result<VorbisResidue> {
+        let mut ret = self.residue;
+        if ret.groupbook as usize >= static_codebooks.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid groupbook index {}", ret.groupbook)));
+        }
+        for books in self.stage_books.iter() {
+            let mut cascade = 0;
+            for (stage, book) in books.iter().enumerate() {
+                let Some(book) = *book else {
+                    continue;
+                };
+                let Some(codebook) = static_codebooks.get(book as usize).filter(|_|book >= 0) else {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book index {book}")));
+                };
+                if codebook.maptype == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid book maptype {}", codebook.maptype)));
+                }
+                cascade |= 1 << stage;
+                ret.booklist.push(book);
+            }
+            ret.secondstages.push(cascade);
+        }
+        ret.partvals = VorbisResidue::calc_partvals(ret.partitions, &static_codebooks[ret.groupbook as usize])?;
+        Ok(ret)
+    }
 }
 
 impl VorbisLookResidue {
@@ -228,6 +343,368 @@ impl VorbisLookResidue {
     }
 }
 
+/// * The buffers that `VorbisLookResidue::decode_into()` reuses across the packets.
+#[derive(Default, Debug, Clone)]
+pub struct VorbisResidueScratch {
+    /// * The partition classes words of each channel, as the indices to the decode map
+    pub partwords: Vec<usize>,
+
+    /// * The values decoded from a codeword
+    pub values: Vec<f32>,
+}
+
+impl VorbisLookResidue {
+    /// * Decode the residue vectors of `ch` channels from an audio packet, `n` is the half of the block size.
+    /// * The channels with `do_not_decode` set aren't read from the packet and come out as zeros,
+    ///   for residue type 2, all of the channels are decoded if any of them should be.
+    /// * A truncated packet just means to stop decoding, the vectors decoded so far are returned.
+    pub fn decode(&self, reader: &mut BitReader, do_not_decode: &[bool], ch: usize, n: usize) -> io::Result<Vec<Vec<f32>>> {
+        let mut ret = vec![vec![0.0f32; n]; ch];
+        let channels: Vec<usize> = (0..ch).collect();
+        self.decode_into(reader, do_not_decode, &mut ret, &channels, n, &mut VorbisResidueScratch::default())?;
+        Ok(ret)
+    }
+
+    /// * Decode the residue vectors of the `channels` into `vectors[channel][..n]`, `n` is the half of the block size.
+    /// * `do_not_decode` is indexed by the channel too. The vectors are cleared first, then it works the same as `decode()`.
+    /// * The buffers in `scratch` are reused, nothing is allocated once they are large enough.
+    pub fn decode_into(&self, reader: &mut BitReader, do_not_decode: &[bool], vectors: &mut [Vec<f32>], channels: &[usize], n: usize, scratch: &mut VorbisResidueScratch) -> io::Result<()> {
+        for &c in channels.iter() {
+            vectors[c][..n].fill(0.0);
+        }
+        match self.info.residue_type {
+            0 | 1 => {
+                if channels.iter().any(|&c|!do_not_decode[c]) {
+                    self.decode_01(reader, do_not_decode, vectors, channels, n, scratch)?;
+                }
+            }
+            2 => {
+                if channels.iter().any(|&c|!do_not_decode[c]) {
+                    self.decode_2(reader, vectors, channels, n, scratch)?;
+                }
+            }
+            o => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid residue type {o}"))),
+        }
+        Ok(())
+    }
+
+    /// * Read the partition classes word as the index to the decode map, `None` if the packet ends here or the word is invalid.
+    fn decode_partword(&self, reader: &mut BitReader) -> io::Result<Option<usize>> {
+        match self.phrasebook.decode_scalar(reader) {
+            Ok(temp) if temp >= 0 && temp < self.info.partvals => Ok(Some(temp as usize)),
+            Ok(_) => Ok(None),
+            // Like `eopbreak` in libvorbis, a corrupted codeword ends the packet the same way running out of data does
+            Err(e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// * Decode `out.len()` values with the book, `false` if the packet ends or a codeword is invalid before that.
+    fn decode_values(book: &CodeBook, reader: &mut BitReader, out: &mut [f32]) -> io::Result<bool> {
+        match book.decode_vector(reader, out) {
+            Ok(()) => Ok(true),
+            Err(e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// * Residue type 0 interleaves the values of a codeword across the partition, type 1 puts them in order.
+    /// * Only the `channels` without `do_not_decode` are read.
+    fn decode_01(&self, reader: &mut BitReader, do_not_decode: &[bool], vectors: &mut [Vec<f32>], channels: &[usize], n: usize, scratch: &mut VorbisResidueScratch) -> io::Result<()> {
+        let info = &self.info;
+        let samples_per_partition = info.grouping as usize;
+        let partitions_per_word = self.phrasebook.dim as usize;
+        let range = info.sample_range(n);
+        let begin = range.start;
+        if range.is_empty() || samples_per_partition == 0 || partitions_per_word == 0 {
+            return Ok(());
+        }
+        let partvals = range.len() / samples_per_partition;
+        let partwords = partvals.div_ceil(partitions_per_word);
+        let used = || channels.iter().copied().filter(|&c|!do_not_decode[c]);
+        let ch = used().count();
+        let partword = &mut scratch.partwords;
+        partword.clear();
+        partword.resize(ch * partwords, 0);
+        scratch.values.resize(samples_per_partition, 0.0);
+        let buf = &mut scratch.values[..];
+
+        for s in 0..self.stages as usize {
+            // each loop decodes on partition codeword containing partitions_per_word partitions
+            let mut i = 0;
+            let mut l = 0;
+            while i < partvals {
+                if s == 0 {
+                    // fetch the partition word for each channel
+                    for j in 0..ch {
+                        match self.decode_partword(reader)? {
+                            Some(word) => partword[j * partwords + l] = word,
+                            None => return Ok(()),
+                        }
+                    }
+                }
+
+                // now we decode residual values for the partitions
+                let mut k = 0;
+                while k < partitions_per_word && i < partvals {
+                    let offset = begin + i * samples_per_partition;
+                    for (j, c) in used().enumerate() {
+                        let class = self.decodemap[partword[j * partwords + l]][k] as usize;
+                        if info.secondstages[class] & (1 << s) == 0 {
+                            continue;
+                        }
+                        let Some(stagebook) = &self.partbooks[class][s] else {
+                            continue;
+                        };
+                        if stagebook.used_entries == 0 {
+                            continue;
+                        }
+                        let target = &mut vectors[c][offset..offset + samples_per_partition];
+                        if info.residue_type == 0 {
+                            let dim = stagebook.dim as usize;
+                            let step = samples_per_partition / dim;
+                            let values = &mut buf[..step * dim];
+                            if !Self::decode_values(stagebook, reader, values)? {
+                                return Ok(());
+                            }
+                            for d in 0..dim {
+                                for t in 0..step {
+                                    target[d * step + t] += values[t * dim + d];
+                                }
+                            }
+                        } else {
+                            if !Self::decode_values(stagebook, reader, buf)? {
+                                return Ok(());
+                            }
+                            for (t, v) in target.iter_mut().zip(buf.iter()) {
+                                *t += v;
+                            }
+                        }
+                    }
+                    k += 1;
+                    i += 1;
+                }
+                l += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// * Residue type 2 interleaves all of the `channels` into one vector and decode it as type 1.
+    fn decode_2(&self, reader: &mut BitReader, vectors: &mut [Vec<f32>], channels: &[usize], n: usize, scratch: &mut VorbisResidueScratch) -> io::Result<()> {
+        let info = &self.info;
+        let ch = channels.len();
+        let samples_per_partition = info.grouping as usize;
+        let partitions_per_word = self.phrasebook.dim as usize;
+        let range = info.sample_range(n * ch);
+        let begin = range.start;
+        if range.is_empty() || samples_per_partition == 0 || partitions_per_word == 0 {
+            return Ok(());
+        }
+        let partvals = range.len() / samples_per_partition;
+        let partwords = partvals.div_ceil(partitions_per_word);
+        let partword = &mut scratch.partwords;
+        partword.clear();
+        partword.resize(partwords, 0);
+        scratch.values.resize(samples_per_partition, 0.0);
+        let buf = &mut scratch.values[..];
+
+        for s in 0..self.stages as usize {
+            let mut i = 0;
+            let mut l = 0;
+            while i < partvals {
+                if s == 0 {
+                    // fetch the partition word
+                    match self.decode_partword(reader)? {
+                        Some(word) => partword[l] = word,
+                        None => return Ok(()),
+                    }
+                }
+
+                // now we decode residual values for the partitions
+                let mut k = 0;
+                while k < partitions_per_word && i < partvals {
+                    let class = self.decodemap[partword[l]][k] as usize;
+                    if info.secondstages[class] & (1 << s) != 0 && let Some(stagebook) = &self.partbooks[class][s] && stagebook.used_entries > 0 {
+                        if !Self::decode_values(stagebook, reader, buf)? {
+                            return Ok(());
+                        }
+                        let offset = i * samples_per_partition + begin;
+                        let first = offset / ch;
+                        let last = (offset + samples_per_partition) / ch;
+                        for (v, value) in buf.iter().enumerate() {
+                            let index = first + v / ch;
+                            if index >= last {
+                                break;
+                            }
+                            vectors[channels[v % ch]][index] += value;
+                        }
+                    }
+                    k += 1;
+                    i += 1;
+                }
+                l += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VorbisLookResidue {
+    /// * Encode the residue vectors of the channels into an audio packet, each vector is the half of the block size.
+    /// * The channels with `do_not_encode` set aren't written, the decoder gets zeros for them,
+    ///   for residue type 2, all of the channels are encoded if any of them should be.
+    /// * The partitions are classified by `classmetric1` and `classmetric2` first, then each stage writes the class words and the values of the partitions.
+    pub fn encode<W>(&self, writer: &mut BitWriter<W>, residuals: &[Vec<f32>], do_not_encode: &[bool]) -> io::Result<()>
+    where
+        W: Write {
+        match self.info.residue_type {
+            0 | 1 => {
+                let mut used: Vec<Vec<f32>> = residuals.iter().zip(do_not_encode.iter()).filter(|(_, skip)|!**skip).map(|(v, _)|v.clone()).collect();
+                if !used.is_empty() {
+                    let partword = self.classify_01(&used);
+                    self.forward(writer, &mut used, &partword)?;
+                }
+            }
+            2 => {
+                if do_not_encode.iter().take(residuals.len()).any(|&skip|!skip) {
+                    let ch = residuals.len();
+                    let n = residuals.iter().map(|v|v.len()).min().unwrap_or(0);
+                    let mut work = vec![0.0f32; n * ch];
+                    for (i, frame) in work.chunks_mut(ch).enumerate() {
+                        for (j, v) in frame.iter_mut().enumerate() {
+                            *v = residuals[j][i];
+                        }
+                    }
+                    let partword = self.classify_2(residuals, n);
+                    self.forward(writer, &mut [work], &[partword])?;
+                }
+            }
+            o => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid residue type {o}"))),
+        }
+        Ok(())
+    }
+
+    /// * Pick the class of each partition of each channel by its peak and its average magnitude.
+    fn classify_01(&self, vectors: &[Vec<f32>]) -> Vec<Vec<usize>> {
+        let info = &self.info;
+        let samples_per_partition = info.grouping as usize;
+        let possible_partitions = info.partitions as usize;
+        let n = vectors.iter().map(|v|v.len()).min().unwrap_or(0);
+        let range = info.sample_range(n);
+        let begin = range.start;
+        let partvals = range.len().checked_div(samples_per_partition).unwrap_or(0);
+        let scale = 100.0 / samples_per_partition as f32;
+
+        vectors.iter().map(|vector| {
+            (0..partvals).map(|i| {
+                let offset = i * samples_per_partition + begin;
+                let partition = &vector[offset..offset + samples_per_partition];
+                let max = partition.iter().fold(0.0f32, |max, v|max.max(v.abs()));
+                let ent = (partition.iter().map(|v|v.abs()).sum::<f32>() * scale).trunc();
+                (0..possible_partitions - 1).find(|&k| {
+                    max <= info.classmetric1[k] as f32 && (info.classmetric2[k] < 0 || ent < info.classmetric2[k] as f32)
+                }).unwrap_or(possible_partitions - 1)
+            }).collect()
+        }).collect()
+    }
+
+    /// * Pick the class of each partition of the interleaved vector, by the peak of the first channel and the peak of the others.
+    fn classify_2(&self, vectors: &[Vec<f32>], n: usize) -> Vec<usize> {
+        let info = &self.info;
+        let ch = vectors.len();
+        let samples_per_partition = info.grouping as usize;
+        let possible_partitions = info.partitions as usize;
+        let range = info.sample_range(n * ch);
+        let begin = range.start;
+        let partvals = range.len().checked_div(samples_per_partition).unwrap_or(0);
+
+        let mut l = begin / ch;
+        (0..partvals).map(|_| {
+            let mut magmax = 0.0f32;
+            let mut angmax = 0.0f32;
+            for _ in (0..samples_per_partition).step_by(ch) {
+                magmax = magmax.max(vectors[0][l].abs());
+                for vector in vectors.iter().skip(1) {
+                    angmax = angmax.max(vector[l].abs());
+                }
+                l += 1;
+            }
+            (0..possible_partitions - 1).find(|&j| {
+                magmax <= info.classmetric1[j] as f32 && angmax <= info.classmetric2[j] as f32
+            }).unwrap_or(possible_partitions - 1)
+        }).collect()
+    }
+
+    /// * Write the class words and the partitions of each stage, the same order `decode_01()` reads them.
+    fn forward<W>(&self, writer: &mut BitWriter<W>, vectors: &mut [Vec<f32>], partword: &[Vec<usize>]) -> io::Result<()>
+    where
+        W: Write {
+        let info = &self.info;
+        let samples_per_partition = info.grouping as usize;
+        let possible_partitions = info.partitions as usize;
+        let partitions_per_word = self.phrasebook.dim as usize;
+        let begin = info.begin as usize;
+        let partvals = partword.iter().map(|p|p.len()).min().unwrap_or(0);
+
+        for s in 0..self.stages as usize {
+            let mut i = 0;
+            while i < partvals {
+                if s == 0 {
+                    // first we encode a partition codeword for each channel
+                    for partword in partword.iter() {
+                        let mut val = partword[i];
+                        for k in 1..partitions_per_word {
+                            val *= possible_partitions;
+                            if i + k < partvals {
+                                val += partword[i + k];
+                            }
+                        }
+                        self.phrasebook.encode(val as i32, writer)?;
+                    }
+                }
+
+                // now we encode interleaved residual values for the partitions
+                let mut k = 0;
+                while k < partitions_per_word && i < partvals {
+                    let offset = i * samples_per_partition + begin;
+                    for (vector, partword) in vectors.iter_mut().zip(partword.iter()) {
+                        let class = partword[i];
+                        if info.secondstages[class] & (1 << s) == 0 {
+                            continue;
+                        }
+                        let Some(stagebook) = &self.partbooks[class][s] else {
+                            continue;
+                        };
+                        let target = &mut vector[offset..offset + samples_per_partition];
+                        if info.residue_type == 0 {
+                            let dim = stagebook.dim as usize;
+                            let step = samples_per_partition / dim;
+                            let mut values = vec![0.0f32; dim];
+                            for t in 0..step {
+                                for d in 0..dim {
+                                    values[d] = target[d * step + t];
+                                }
+                                stagebook.encode_vector(&mut values, writer)?;
+                                for d in 0..dim {
+                                    target[d * step + t] = values[d];
+                                }
+                            }
+                        } else {
+                            for chunk in target.chunks_mut(stagebook.dim as usize) {
+                                stagebook.encode_vector(chunk, writer)?;
+                            }
+                        }
+                    }
+                    k += 1;
+                    i += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Debug for VorbisResidue {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_struct("VorbisResidue")
@@ -248,7 +725,19 @@ impl Debug for VorbisResidue {
 
 impl Default for VorbisResidue {
     fn default() -> Self {
-        unsafe {mem::MaybeUninit::<Self>::zeroed().assume_init()}
+        Self {
+            residue_type: 0,
+            begin: 0,
+            end: 0,
+            grouping: 0,
+            partitions: 0,
+            partvals: 0,
+            groupbook: 0,
+            secondstages: CopiableBuffer::default(),
+            booklist: CopiableBuffer::default(),
+            classmetric1: [0; 64],
+            classmetric2: [0; 64],
+        }
     }
 }
 
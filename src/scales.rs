@@ -8,7 +8,7 @@ pub fn unitnorm(x: f32) -> f32 {
 	unsafe {transmute(i)}
 }
 
-/// * Convert dB to gain
+/// * Convert gain to dB
 #[inline(always)]
 #[allow(non_snake_case)]
 pub fn todB(x: f32) -> f32 {
@@ -17,7 +17,7 @@ pub fn todB(x: f32) -> f32 {
 	i as f32 * 7.17711438e-7 - 764.6161886
 }
 
-/// * Convert gain to dB
+/// * Convert dB to gain
 #[inline(always)]
 #[allow(non_snake_case)]
 pub fn fromdB(x: f32) -> f32 {
@@ -72,6 +72,27 @@ pub fn fromOC(o: f32) -> f32 {
 	((o + 5.965784) * 0.693147).exp()
 }
 
+/// * Convert a frequency in Hz to octaves, octave 0.0 is about 62.5 Hz
+pub fn to_oc(f: f32) -> f32 {
+	toOC(f)
+}
+
+/// * Convert octaves back to a frequency in Hz, octave 0.0 is about 62.5 Hz
+pub fn from_oc(oc: f32) -> f32 {
+	fromOC(oc)
+}
+
+/// * Convert a linear amplitude to dB, the sign is ignored
+/// * This is the fast approximation `libvorbis` uses, it reads the float bits as a log2, so it can be off by up to about 0.5 dB between powers of two
+pub fn to_db(x: f32) -> f32 {
+	todB(x)
+}
+
+/// * Convert dB back to a linear amplitude
+pub fn from_db(db: f32) -> f32 {
+	fromdB(db)
+}
+
 #[macro_export]
 macro_rules! unitnorm {
 	($x:expr) => {
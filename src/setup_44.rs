@@ -1,4 +1,479 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+};
+
 use crate::*;
+use codebook::StaticCodeBook;
+use floor::VorbisFloor1;
+use residue::VorbisResidue;
+use mapping::VorbisMapping;
+use psy::VorbisInfoPsyGlobal;
+use psy_masking::{P_BANDS, P_NOISECURVES};
+use envelope::VE_BANDS;
+use highlevel::*;
+use vorbisenc::{MAP_NOMINAL, MAP_NOMINAL_U};
+use copiablebuf::CopiableBuffer;
+
+/// * The number of the settings of the templates, from quality -0.1 to 1.0
+const SETTINGS: usize = 12;
+
+const QUALITY_MAPPING_44: [f64; SETTINGS] = [-0.1, 0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+
+/// * The bitrate of each setting for each channel
+const RATE_MAPPING_44_STEREO: [f64; SETTINGS] = [22500.0, 32000.0, 40000.0, 48000.0, 56000.0, 64000.0, 80000.0, 96000.0, 112000.0, 128000.0, 160000.0, 250001.0];
+const RATE_MAPPING_44_UNCOUPLED: [f64; SETTINGS] = [32000.0, 48000.0, 60000.0, 70000.0, 80000.0, 86000.0, 96000.0, 110000.0, 120000.0, 140000.0, 160000.0, 240001.0];
+
+const PSY_LOWPASS_44: [f64; SETTINGS] = [13.9, 15.1, 15.8, 16.5, 17.2, 18.9, 20.1, 48.0, 999.0, 999.0, 999.0, 999.0];
+const PSY_ATH_FLOATER: [i32; SETTINGS] = [-100, -100, -100, -100, -100, -100, -105, -105, -105, -105, -110, -120];
+const PSY_ATH_ABS: [i32; SETTINGS] = [-130, -130, -130, -140, -140, -140, -140, -140, -140, -140, -140, -150];
+
+const PSY_TONE_0DB: [i32; SETTINGS] = [90, 90, 95, 95, 95, 95, 105, 105, 105, 105, 105, 105];
+const PSY_TONE_SUPPRESS: [i32; SETTINGS] = [-20, -20, -20, -20, -20, -24, -30, -40, -40, -45, -45, -45];
+const PSY_NOISE_SUPPRESS: [i32; SETTINGS] = [-20, -20, -24, -24, -24, -24, -30, -40, -40, -45, -45, -45];
+
+const fn att3(att: [i32; P_NOISECURVES], boost: f32, decay: f32) -> Att3 {
+    Att3 {att, boost, decay}
+}
+
+const PSY_TONE_MASTERATT_44: [Att3; SETTINGS] = [
+    att3([35, 21, 9], 0.0, 0.0),
+    att3([30, 20, 8], -2.0, 1.25),
+    att3([25, 12, 2], 0.0, 0.0),
+    att3([20, 9, -3], 0.0, 0.0),
+    att3([20, 9, -4], 0.0, 0.0),
+    att3([20, 9, -4], 0.0, 0.0),
+    att3([20, 6, -6], 0.0, 0.0),
+    att3([20, 3, -10], 0.0, 0.0),
+    att3([18, 1, -14], 0.0, 0.0),
+    att3([18, 0, -16], 0.0, 0.0),
+    att3([18, -2, -16], 0.0, 0.0),
+    att3([12, -2, -20], 0.0, 0.0),
+];
+
+/// * The tone mask adjustments of the lowest and the highest setting, the settings between are interpolated
+const TONEMASK_ADJ_LONG: [[i32; P_BANDS]; 2] = [
+    [-3, -8, -13, -15, -10, -10, -10, -10, -10, -10, -10, 0, 0, 0, 0, 0, 0],
+    [-16, -16, -16, -16, -16, -16, -16, -15, -14, -14, -14, -12, -9, -4, -2, -2, 0],
+];
+const TONEMASK_ADJ_OTHER: [[i32; P_BANDS]; 2] = [
+    [-3, -8, -13, -15, -10, -10, -9, -9, -9, -9, -9, 1, 1, 1, 1, 1, 1],
+    [-16, -16, -16, -16, -16, -16, -16, -15, -14, -14, -14, -12, -9, -4, -2, -2, 0],
+];
+
+/// * The noise bias curves of the lowest and the highest setting, the settings between are interpolated
+const NOISEBIAS_LONG: [[[i32; P_BANDS]; P_NOISECURVES]; 2] = [
+    [
+        [-10, -10, -10, -10, -10, -4, 0, 0, 0, 6, 10, 10, 12, 12, 12, 18, 20],
+        [-20, -20, -20, -20, -20, -20, -10, -2, 0, 0, 0, 0, 0, 2, 4, 6, 15],
+        [-30, -30, -30, -30, -30, -30, -20, -14, -10, -6, -6, -6, -6, -6, -6, -6, 0],
+    ],
+    [
+        [-24, -24, -24, -24, -24, -24, -24, -24, -24, -24, -24, -24, -24, -20, -20, -20, -10],
+        [-30, -30, -30, -30, -30, -30, -30, -30, -30, -30, -30, -30, -30, -30, -30, -30, -24],
+        [-34, -34, -34, -34, -34, -34, -34, -34, -34, -34, -34, -34, -34, -34, -34, -34, -24],
+    ],
+];
+const NOISEBIAS_SHORT: [[[i32; P_BANDS]; P_NOISECURVES]; 2] = [
+    [
+        [-10, -10, -10, -10, -10, -4, 0, 0, 4, 4, 8, 8, 8, 10, 10, 12, 20],
+        [-20, -20, -20, -20, -20, -20, -20, -10, -6, -6, -4, -4, -4, -2, 2, 4, 14],
+        [-30, -30, -30, -30, -30, -30, -30, -20, -16, -16, -14, -14, -14, -12, -8, -6, 0],
+    ],
+    [
+        [-22, -22, -22, -22, -22, -22, -22, -22, -22, -22, -22, -22, -22, -18, -18, -18, -8],
+        [-28, -28, -28, -28, -28, -28, -28, -28, -28, -28, -28, -28, -28, -28, -28, -28, -22],
+        [-32, -32, -32, -32, -32, -32, -32, -32, -32, -32, -32, -32, -32, -32, -32, -32, -22],
+    ],
+];
+
+const PSY_NOISEGUARDS_44: [NoiseGuard; 4] = [
+    NoiseGuard {lo: 3, hi: 3, fixed: 15},
+    NoiseGuard {lo: 3, hi: 3, fixed: 15},
+    NoiseGuard {lo: 10, hi: 10, fixed: 100},
+    NoiseGuard {lo: 10, hi: 10, fixed: 100},
+];
+
+const PSY_COMPAND_44: [CompandBlock; 6] = [
+    // sub-mode Z short
+    CompandBlock {data: [
+        0, 1, 2, 3, 4, 5, 6, 7,
+        8, 9, 10, 11, 12, 13, 14, 15,
+        16, 17, 18, 19, 20, 21, 22, 23,
+        24, 25, 26, 27, 28, 29, 30, 31,
+        32, 33, 34, 35, 36, 37, 38, 39,
+    ]},
+    // mode Z nominal short
+    CompandBlock {data: [
+        0, 1, 2, 3, 4, 5, 6, 6,
+        7, 7, 7, 7, 6, 6, 6, 7,
+        7, 8, 9, 10, 11, 12, 13, 14,
+        15, 16, 17, 17, 17, 18, 18, 19,
+        19, 19, 20, 21, 22, 23, 24, 25,
+    ]},
+    // mode A short
+    CompandBlock {data: [
+        0, 1, 2, 3, 4, 5, 5, 5,
+        6, 6, 6, 5, 4, 4, 4, 4,
+        4, 4, 5, 5, 5, 6, 6, 6,
+        7, 7, 7, 8, 8, 8, 9, 10,
+        11, 12, 13, 14, 15, 16, 17, 18,
+    ]},
+    // sub-mode Z long
+    CompandBlock {data: [
+        0, 1, 2, 3, 4, 5, 6, 7,
+        8, 9, 10, 11, 12, 13, 14, 15,
+        16, 17, 18, 19, 20, 21, 22, 23,
+        24, 25, 26, 27, 28, 29, 30, 31,
+        32, 33, 34, 35, 36, 37, 38, 39,
+    ]},
+    // mode Z nominal long
+    CompandBlock {data: [
+        0, 1, 2, 3, 4, 5, 6, 7,
+        8, 9, 10, 11, 12, 12, 13, 13,
+        13, 14, 14, 14, 15, 15, 15, 15,
+        16, 16, 17, 17, 17, 18, 18, 19,
+        19, 19, 20, 21, 22, 23, 24, 25,
+    ]},
+    // mode A long
+    CompandBlock {data: [
+        0, 1, 2, 3, 4, 5, 6, 7,
+        8, 8, 7, 6, 5, 4, 4, 4,
+        4, 4, 5, 5, 5, 6, 6, 6,
+        7, 7, 7, 8, 8, 8, 9, 10,
+        11, 12, 13, 14, 15, 16, 17, 18,
+    ]},
+];
+
+const PSY_COMPAND_SHORT_MAPPING: [f64; SETTINGS] = [0.5, 1.0, 1.0, 1.3, 1.6, 2.0, 2.0, 2.0, 2.0, 2.0, 2.0, 2.0];
+const PSY_COMPAND_LONG_MAPPING: [f64; SETTINGS] = [3.5, 4.0, 4.0, 4.3, 4.6, 5.0, 5.0, 5.0, 5.0, 5.0, 5.0, 5.0];
+
+/// * The position of each setting in the global parameters
+const GLOBAL_MAPPING_44: [f64; SETTINGS] = [0.0, 1.0, 1.0, 1.5, 2.0, 2.0, 2.5, 2.7, 3.0, 3.7, 4.0, 4.0];
+
+const PREECHO_THRESH_44: [[f32; VE_BANDS]; 5] = [
+    [20.0, 14.0, 12.0, 12.0, 14.0, 14.0, 14.0],
+    [14.0, 10.0, 10.0, 10.0, 10.0, 10.0, 10.0],
+    [12.0, 10.0, 10.0, 10.0, 10.0, 10.0, 10.0],
+    [10.0, 8.0, 8.0, 8.0, 8.0, 8.0, 8.0],
+    [10.0, 6.0, 6.0, 6.0, 6.0, 6.0, 6.0],
+];
+const POSTECHO_THRESH_44: [[f32; VE_BANDS]; 5] = [
+    [-60.0, -30.0, -40.0, -40.0, -40.0, -40.0, -40.0],
+    [-40.0, -30.0, -25.0, -25.0, -25.0, -25.0, -25.0],
+    [-20.0, -20.0, -15.0, -15.0, -15.0, -15.0, -15.0],
+    [-20.0, -15.0, -12.0, -12.0, -12.0, -12.0, -12.0],
+    [-15.0, -15.0, -12.0, -12.0, -12.0, -12.0, -12.0],
+];
+const STRETCH_PENALTY_44: [f32; 5] = [2.0, 2.0, 0.0, 0.0, 0.0];
+const PREECHO_MINENERGY_44: [f32; 5] = [-75.0, -80.0, -80.0, -80.0, -85.0];
+
+/// * The point stereo amplitudes of the packet blobs
+const STEREO_PRE: [i32; PACKETBLOBS] = [4, 4, 4, 4, 4, 4, 4, 3, 2, 2, 1, 0, 0, 0, 0];
+const STEREO_POST: [i32; PACKETBLOBS] = [8, 8, 8, 8, 6, 6, 5, 5, 4, 4, 3, 2, 1, 1, 0];
+
+/// * Where the point stereo begins in kHz for each setting, the highest settings are lossless
+const STEREO_POINT_KHZ: [f32; SETTINGS] = [3.0, 4.0, 4.0, 5.0, 6.0, 7.0, 8.0, 10.0, 99.0, 99.0, 99.0, 99.0];
+
+/// * The posts of the short and the long floors, in the order the floor 1 fitting splits the lines
+const POSTLIST_SHORT: [i32; 11] = [0, 128, 24, 6, 64, 3, 12, 40, 92, 17, 31];
+const POSTLIST_LONG: [i32; 29] = [0, 1024, 93, 23, 372, 6, 46, 186, 750, 14, 33, 65, 130, 260, 556, 3, 10, 18, 28, 39, 55, 79, 111, 158, 220, 312, 464, 650, 850];
+
+/// * A lattice book of the residue stages as `(dim, quantvals, delta)`
+type LatticeParams = Option<(i32, i32, f32)>;
+
+/// * The residue classes: the largest value the class codes and the books of its stages.
+/// * The last class is the catch-all of the louder partitions, its cascade refines the values by the stages.
+///   It reaches beyond 16000, the floor of the highest settings could be that far below a pure tone. The coarse stages are
+///   one dimensional to keep their books small.
+const RESIDUE_CLASSES: [(i32, [LatticeParams; 4]); 7] = [
+    (0, [None, None, None, None]),
+    (1, [None, None, Some((4, 3, 1.0)), None]),
+    (2, [None, None, Some((2, 5, 1.0)), None]),
+    (4, [None, None, Some((2, 9, 1.0)), None]),
+    (8, [None, None, Some((2, 17, 1.0)), None]),
+    (16, [Some((2, 9, 4.0)), Some((2, 5, 1.0)), None, None]),
+    (999, [Some((1, 33, 1024.0)), Some((1, 33, 32.0)), Some((2, 17, 2.0)), Some((2, 3, 1.0))]),
+];
+
+/// * How often each residue class is expected, for the codeword lengths of the class book
+const RESIDUE_CLASS_WEIGHTS: [f64; 7] = [0.3, 0.2, 0.15, 0.12, 0.1, 0.08, 0.05];
+
+/// * How often each floor 1 sub book is expected, for the codeword lengths of the class book
+const FLOOR_SUB_WEIGHTS: [f64; 4] = [0.25, 0.35, 0.3, 0.1];
+
+/// * The codeword lengths of the Huffman code of the weights.
+/// * The weights are floored to 1/65536 of the total, so the rare entries don't get the codewords longer than 32 bits.
+fn huffman_lengths(weights: &[f64]) -> Vec<i8> {
+    let total: f64 = weights.iter().sum();
+    let floor = total / 65536.0;
+    let mut heap = BinaryHeap::new();
+    let mut parent = vec![usize::MAX; weights.len()];
+    for (i, &weight) in weights.iter().enumerate() {
+        heap.push(Reverse(((weight.max(floor) / floor * 1024.0) as u64, i)));
+    }
+    while heap.len() > 1 {
+        let (Some(Reverse((weight1, node1))), Some(Reverse((weight2, node2)))) = (heap.pop(), heap.pop()) else {
+            break;
+        };
+        let node = parent.len();
+        parent.push(usize::MAX);
+        parent[node1] = node;
+        parent[node2] = node;
+        heap.push(Reverse((weight1 + weight2, node)));
+    }
+    (0..weights.len()).map(|mut node| {
+        let mut length = 0;
+        while parent[node] != usize::MAX {
+            node = parent[node];
+            length += 1;
+        }
+        length
+    }).collect()
+}
+
+/// * A codebook without value mapping, its codeword lengths are from the weights of the entries
+fn scalar_book(dim: i32, weights: &[f64]) -> StaticCodeBook {
+    StaticCodeBook {
+        dim,
+        entries: weights.len() as i32,
+        lengthlist: huffman_lengths(weights),
+        maptype: 0,
+        ..Default::default()
+    }
+}
+
+/// * A lattice codebook of `quantvals` values in each dimension, centered on zero with the step `delta`.
+/// * The weights of the entries fall off exponentially by the distance from zero.
+fn lattice_book(dim: i32, quantvals: i32, delta: f32) -> StaticCodeBook {
+    let entries = quantvals.pow(dim as u32);
+    let center = (quantvals - 1) / 2;
+    let scale = (center as f64 * delta as f64 * 0.4).max(0.5);
+    let weights: Vec<f64> = (0..entries).map(|mut entry| {
+        let mut weight = 1.0;
+        for _ in 0..dim {
+            let value = ((entry % quantvals - center) as f64 * delta as f64).abs();
+            weight *= (-value / scale).exp();
+            entry /= quantvals;
+        }
+        weight
+    }).collect();
+    StaticCodeBook {
+        dim,
+        entries,
+        lengthlist: huffman_lengths(&weights),
+        maptype: 1,
+        q_min: -(center as f32) * delta,
+        q_delta: delta,
+        q_quant: ilog!(quantvals - 1),
+        q_sequencep: false,
+        quantlist: (0..quantvals).collect(),
+    }
+}
+
+/// * The books of the floor 1 of `floor_params()`: the class book of the 3 posts, then the sub books of 8, 32 and 128 values.
+/// * A value that fits in a smaller sub book is never coded by the bigger one, it gets the lowest weight.
+fn floor_books() -> Vec<StaticCodeBook> {
+    let class_weights: Vec<f64> = (0..64usize).map(|cval| {
+        (0..3).map(|j|FLOOR_SUB_WEIGHTS[(cval >> (j * 2)) & 3]).product()
+    }).collect();
+    let mut books = vec![scalar_book(1, &class_weights)];
+    let mut lower = 1;
+    for entries in [8, 32, 128] {
+        let weights: Vec<f64> = (0..entries).map(|v| {
+            if v < lower {
+                0.0
+            } else {
+                1.0 / ((v + 1) as f64).powf(1.3)
+            }
+        }).collect();
+        books.push(scalar_book(1, &weights));
+        lower = entries;
+    }
+    books
+}
+
+/// * A floor 1 of the posts, each partition has 3 posts coded by one of the sub books of `floor_books()`
+fn floor_params(postlist: &[i32]) -> VorbisFloor1 {
+    let mut floor1 = VorbisFloor1 {
+        partitions: ((postlist.len() - 2) / 3) as i32,
+        mult: 2,
+        maxover: 60.0,
+        maxunder: 30.0,
+        maxerr: 500.0,
+        twofitweight: 1.0,
+        twofitatten: 18.0,
+        ..Default::default()
+    };
+    for _ in 0..floor1.partitions {
+        floor1.partitions_class.push(0);
+    }
+    floor1.class_dim.push(3);
+    floor1.class_subs.push(2);
+    floor1.class_book.push(0);
+    let mut subbooks = CopiableBuffer::default();
+    for subbook in [-1, 1, 2, 3] {
+        subbooks.push(subbook);
+    }
+    floor1.class_subbook.push(subbooks);
+    for &x in postlist {
+        floor1.postlist.push(x);
+    }
+    floor1
+}
+
+/// * The residue of `RESIDUE_CLASSES`, residue 2 classifies by the magnitude and the angle, residue 0 and 1 by the peak only
+fn residue_template(res_type: i32, grouping: i32) -> VorbisResidueTemplate {
+    let partitions = RESIDUE_CLASSES.len();
+    let mut res = VorbisResidue {
+        residue_type: res_type,
+        grouping,
+        partitions: partitions as i32,
+        ..Default::default()
+    };
+    let mut books_base = StaticBookBlock::default();
+    for (i, (metric, stages)) in RESIDUE_CLASSES.iter().enumerate() {
+        res.classmetric1[i] = *metric;
+        res.classmetric2[i] = if res_type == 2 {*metric} else {-1};
+        for (k, stage) in stages.iter().enumerate() {
+            books_base.books[i][k] = stage.map(|(dim, quantvals, delta)|lattice_book(dim, quantvals, delta));
+        }
+    }
+    let class_weights: Vec<f64> = (0..partitions * partitions).map(|v| {
+        RESIDUE_CLASS_WEIGHTS[v / partitions] * RESIDUE_CLASS_WEIGHTS[v % partitions]
+    }).collect();
+    let book_aux = scalar_book(2, &class_weights);
+    VorbisResidueTemplate {
+        res_type,
+        limit_type: 0,
+        grouping,
+        res,
+        book_aux_managed: book_aux.clone(),
+        book_aux,
+        books_base_managed: books_base.clone(),
+        books_base,
+    }
+}
+
+/// * Interpolate the rows of each setting between the lowest and the highest setting
+fn interpolate_rows<const N: usize>(first: &[i32; N], last: &[i32; N]) -> Vec<[i32; N]> {
+    (0..SETTINGS).map(|s| {
+        let t = s as f64 / (SETTINGS - 1) as f64;
+        let mut row = [0; N];
+        for (i, v) in row.iter_mut().enumerate() {
+            *v = (first[i] as f64 * (1.0 - t) + last[i] as f64 * t).round() as i32;
+        }
+        row
+    }).collect()
+}
+
+fn tonemask_adj(rows: &[[i32; P_BANDS]; 2]) -> Vec<VpAdjBlock> {
+    interpolate_rows(&rows[0], &rows[1]).into_iter().map(|block|VpAdjBlock {block}).collect()
+}
+
+fn noise_bias(curves: &[[[i32; P_BANDS]; P_NOISECURVES]; 2]) -> Vec<Noise3> {
+    let mut ret = vec![Noise3::default(); SETTINGS];
+    for (j, (first, last)) in curves[0].iter().zip(curves[1].iter()).enumerate() {
+        for (noise, row) in ret.iter_mut().zip(interpolate_rows(first, last)) {
+            noise.data[j] = row;
+        }
+    }
+    ret
+}
+
+fn psy_global_44() -> Vec<VorbisInfoPsyGlobal> {
+    (0..PREECHO_THRESH_44.len()).map(|i| VorbisInfoPsyGlobal {
+        eighth_octave_lines: 8,
+        preecho_thresh: PREECHO_THRESH_44[i],
+        postecho_thresh: POSTECHO_THRESH_44[i],
+        stretch_penalty: STRETCH_PENALTY_44[i],
+        preecho_minenergy: PREECHO_MINENERGY_44[i],
+        ampmax_att_per_sec: -6.0,
+        ..Default::default()
+    }).collect()
+}
+
+fn stereo_modes_44() -> Vec<AdjStereo> {
+    STEREO_POINT_KHZ.iter().map(|&point_khz| {
+        let mut mode = AdjStereo {
+            pre: STEREO_PRE,
+            post: STEREO_POST,
+            lowpasskHz: [99.0; PACKETBLOBS],
+            ..Default::default()
+        };
+        for (i, khz) in mode.kHz.iter_mut().enumerate() {
+            *khz = if point_khz >= 99.0 {99.0} else {point_khz + i as f32 * 0.5};
+        }
+        mode
+    }).collect()
+}
+
+fn setup_44(coupling_restriction: i32, rate_mapping: &[f64], map: [VorbisMapping; 2], res_type: i32) -> VorbisEncodeSetupDataTemplate {
+    let floor_books = floor_books();
+    VorbisEncodeSetupDataTemplate {
+        mapping: SETTINGS as i32 - 1,
+        rate_mapping: rate_mapping.to_vec(),
+        quality_mapping: QUALITY_MAPPING_44.to_vec(),
+        coupling_restriction,
+        samplerate_min_restriction: 40000,
+        samplerate_max_restriction: 50000,
+
+        blocksize_short: vec![256; SETTINGS],
+        blocksize_long: vec![2048; SETTINGS],
+
+        psy_tone_masteratt: PSY_TONE_MASTERATT_44.to_vec(),
+        psy_tone_0dB: PSY_TONE_0DB.to_vec(),
+        psy_tone_dBsuppress: PSY_TONE_SUPPRESS.to_vec(),
+
+        psy_tone_adj_impulse: tonemask_adj(&TONEMASK_ADJ_OTHER),
+        psy_tone_adj_long: tonemask_adj(&TONEMASK_ADJ_LONG),
+        psy_tone_adj_other: tonemask_adj(&TONEMASK_ADJ_OTHER),
+
+        psy_noiseguards: PSY_NOISEGUARDS_44.to_vec(),
+        psy_noise_bias_impulse: noise_bias(&NOISEBIAS_SHORT),
+        psy_noise_bias_padding: noise_bias(&NOISEBIAS_SHORT),
+        psy_noise_bias_trans: noise_bias(&NOISEBIAS_SHORT),
+        psy_noise_bias_long: noise_bias(&NOISEBIAS_LONG),
+        psy_noise_dBsuppress: PSY_NOISE_SUPPRESS.to_vec(),
+
+        psy_noise_compand: PSY_COMPAND_44.to_vec(),
+        psy_noise_compand_short_mapping: PSY_COMPAND_SHORT_MAPPING.to_vec(),
+        psy_noise_compand_long_mapping: PSY_COMPAND_LONG_MAPPING.to_vec(),
+
+        psy_noise_normal_start: [vec![32; SETTINGS], vec![256; SETTINGS]],
+        psy_noise_normal_partition: [vec![8; SETTINGS], vec![32; SETTINGS]],
+        psy_noise_normal_thresh: vec![0.2; SETTINGS],
+
+        psy_ath_float: PSY_ATH_FLOATER.to_vec(),
+        psy_ath_abs: PSY_ATH_ABS.to_vec(),
+
+        psy_lowpass: PSY_LOWPASS_44.to_vec(),
+
+        global_params: psy_global_44(),
+        global_mapping: GLOBAL_MAPPING_44.to_vec(),
+        stereo_modes: if coupling_restriction == 2 {stereo_modes_44()} else {Vec::new()},
+
+        floor_books: vec![floor_books.clone(), floor_books],
+        floor_params: vec![floor_params(&POSTLIST_SHORT), floor_params(&POSTLIST_LONG)],
+        floor_mappings: 2,
+        floor_mapping_list: vec![vec![0; SETTINGS], vec![1; SETTINGS]],
 
+        maps: vec![VorbisMappingTemplate {
+            map,
+            res: vec![residue_template(res_type, 16), residue_template(res_type, 32)],
+        }; SETTINGS],
+    }
+}
 
+/// * The stereo template of 44.1kHz and 48kHz, the channels are coupled and coded by residue 2.
+/// * It's laid out as `setup_44.h` of `libvorbis` and the psychoacoustic tables are shaped after it, but the codebooks are
+///   generated from model distributions instead of the trained ones, so the files are bigger than `libvorbis` makes.
+pub fn setup_44_stereo() -> VorbisEncodeSetupDataTemplate {
+    setup_44(2, &RATE_MAPPING_44_STEREO, MAP_NOMINAL, 2)
+}
 
+/// * The uncoupled template of 44.1kHz and 48kHz for any number of channels, each channel is coded by residue 1.
+/// * Like `setup_44_stereo()`, it follows `setup_44u.h` of `libvorbis` with the generated codebooks.
+pub fn setup_44_uncoupled() -> VorbisEncodeSetupDataTemplate {
+    setup_44(-1, &RATE_MAPPING_44_UNCOUPLED, MAP_NOMINAL_U, 1)
+}
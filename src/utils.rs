@@ -1,8 +1,10 @@
 use std::{
     fmt::{self, Debug, Display, Formatter},
+    io,
 };
 
 use crate::*;
+use copiablebuf::{CopiableBuffer, CopiableItem};
 
 /// * Format array in a specific patterns
 #[macro_export]
@@ -85,6 +87,43 @@ where
     }
 }
 
+/// * The checked operations of `CopiableBuffer`, its own `push()` and `resize()` panic when the capacity is exceeded.
+/// * The header loaders use them to reject the oversized fields as bad data.
+pub trait CopiableBufferExt<T> {
+    /// * The capacity of the buffer type, the same as `capacity()` but without an instance
+    const CAPACITY: usize;
+
+    /// * Push the item, gives it back if the buffer is full.
+    fn try_push(&mut self, v: T) -> Result<(), T>;
+
+    /// * Resize the buffer, `InvalidData` if the new size exceeds the capacity.
+    fn try_resize(&mut self, new_len: usize, val: T) -> io::Result<()>;
+}
+
+impl<T, const N: usize> CopiableBufferExt<T> for CopiableBuffer<T, N>
+where
+    T: CopiableItem {
+    const CAPACITY: usize = N;
+
+    fn try_push(&mut self, v: T) -> Result<(), T> {
+        if self.is_full() {
+            Err(v)
+        } else {
+            self.push(v);
+            Ok(())
+        }
+    }
+
+    fn try_resize(&mut self, new_len: usize, val: T) -> io::Result<()> {
+        if new_len > N {
+            Err(io::Error::new(io::ErrorKind::InvalidData, format!("The size {new_len} exceeded the capacity {N}")))
+        } else {
+            self.resize(new_len, val);
+            Ok(())
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! debugln {
     () => {
@@ -105,7 +144,7 @@ macro_rules! return_Err {
         if PANIC_ON_ERROR {
             panic!("{:?}", $error)
         } else {
-            return Err($error)
+            return Err($error.into())
         }
     }
 }
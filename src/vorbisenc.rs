@@ -1,7 +1,19 @@
 #![allow(dead_code)]
+use std::{
+	io,
+	rc::Rc,
+};
+
 use crate::*;
 use headers::VorbisMode;
 use mapping::VorbisMapping;
+use codec::{VorbisInfo, VorbisCodecSetup};
+use codebook::StaticCodeBook;
+use floor::{VorbisFloor, VorbisFloor1};
+use residue::VorbisResidue;
+use psy::VorbisInfoPsy;
+use psy_masking::{P_BANDS, P_NOISECURVES, NOISE_COMPAND_LEVELS};
+use highlevel::{VorbisEncodeSetupDataTemplate, VorbisResidueTemplate, Att3, VpAdjBlock, CompandBlock, Noise3, NoiseGuard};
 use copiablebuf::CopiableBuffer;
 
 pub const MODE_TEMPLATE: [VorbisMode; 2] = [
@@ -41,3 +53,528 @@ pub static MAP_NOMINAL: [VorbisMapping; 2] = [
 		coupling_ang: CopiableBuffer::from_fixed_array([1]),
 	},
 ];
+
+/// * The mappings of the uncoupled templates, every channel goes to the only submap
+pub static MAP_NOMINAL_U: [VorbisMapping; 2] = [
+	VorbisMapping {
+		mapping_type: 0,
+		submaps: 1,
+		chmuxlist: CopiableBuffer::from_fixed_array([0, 0]),
+		floorsubmap: CopiableBuffer::from_fixed_array([0]),
+		residuesubmap: CopiableBuffer::from_fixed_array([0]),
+		coupling_steps: 0,
+		coupling_mag: CopiableBuffer::from_fixed_array([0]),
+		coupling_ang: CopiableBuffer::from_fixed_array([0]),
+	},
+	VorbisMapping{
+		mapping_type: 0,
+		submaps: 1,
+		chmuxlist: CopiableBuffer::from_fixed_array([0, 0]),
+		floorsubmap: CopiableBuffer::from_fixed_array([1]),
+		residuesubmap: CopiableBuffer::from_fixed_array([1]),
+		coupling_steps: 0,
+		coupling_mag: CopiableBuffer::from_fixed_array([0]),
+		coupling_ang: CopiableBuffer::from_fixed_array([0]),
+	},
+];
+
+/// * The encoder setup templates, searched in order by `get_setup_template()`.
+/// * Only the 44.1kHz and 48kHz family of `libvorbis` is ported, the stereo one comes first so it's preferred for two channels.
+pub fn setup_list() -> Vec<VorbisEncodeSetupDataTemplate> {
+	vec![
+		setup_44::setup_44_stereo(),
+		setup_44::setup_44_uncoupled(),
+	]
+}
+
+/// * Find the template for the channels and the sample rate, `req` is the quality if `q_or_bitrate` is false, or the bitrate if true.
+/// * Returns the template and the base setting, which is the position of `req` in the quality (or bitrate) mapping of the template.
+pub fn get_setup_template(channels: i32, sample_rate: i32, req: f64, q_or_bitrate: bool) -> Option<(VorbisEncodeSetupDataTemplate, f64)> {
+	let req = if q_or_bitrate {req / channels as f64} else {req};
+	for setup in setup_list() {
+		if setup.coupling_restriction != -1 && setup.coupling_restriction != channels {
+			continue;
+		}
+		if sample_rate < setup.samplerate_min_restriction || sample_rate > setup.samplerate_max_restriction {
+			continue;
+		}
+		let mappings = setup.mapping as usize;
+		let map = if q_or_bitrate {&setup.rate_mapping} else {&setup.quality_mapping};
+
+		// The template matches. Does the requested quality mode fall within this template's modes?
+		if req < map[0] || req > map[mappings] {
+			continue;
+		}
+		let base_setting = match (0..mappings).find(|&j|req >= map[j] && req < map[j + 1]) {
+			Some(j) => j as f64 + (req - map[j]) / (map[j + 1] - map[j]),
+			// An all-points match
+			None => mappings as f64 - 0.001,
+		};
+		return Some((setup, base_setting));
+	}
+	None
+}
+
+/// * Interpolate the table at the setting, the integer part is the index and the fraction part is the weight of the next one.
+fn interpolate_setting<T>(table: &[T], setting: f64) -> f64
+where
+	T: Copy + Into<f64> {
+	let is = setting as usize;
+	let ds = setting - is as f64;
+	table[is].into() * (1.0 - ds) + table[(is + 1).min(table.len() - 1)].into() * ds
+}
+
+/// * Map the setting through the table to the setting of another table, returns the index and the weight of the next one.
+/// * A setting that lands right on an entry is taken as the end of the previous entry, so the next one always exists.
+fn remap_setting(table: &[f64], setting: f64) -> (usize, f64) {
+	let setting = interpolate_setting(table, setting);
+	let mut is = setting as usize;
+	let mut ds = setting - is as f64;
+	if ds == 0.0 && is > 0 {
+		is -= 1;
+		ds = 1.0;
+	}
+	(is, ds)
+}
+
+/// * Find the codebook in the setup or add it, returns the index of it.
+fn book_dup_or_new(static_codebooks: &mut Vec<StaticCodeBook>, book: &StaticCodeBook) -> io::Result<i32> {
+	if let Some(i) = static_codebooks.iter().position(|b|b == book) {
+		return Ok(i as i32);
+	}
+	book.validate()?;
+	static_codebooks.push(book.clone());
+	Ok(static_codebooks.len() as i32 - 1)
+}
+
+fn tonemask_setup(p: &mut VorbisInfoPsy, setting: f64, att: &[Att3], max: &[i32], adj: &[VpAdjBlock]) {
+	let is = setting as usize;
+	let ds = setting - is as f64;
+	let lerp = |a: f64, b: f64|(a * (1.0 - ds) + b * ds) as f32;
+
+	// 0 and 2 are only used by bitmanagement, but there's no harm to always filling the values in here
+	for i in 0..P_NOISECURVES {
+		p.tone_masteratt[i] = lerp(att[is].att[i] as f64, att[is + 1].att[i] as f64);
+	}
+	p.tone_centerboost = lerp(att[is].boost as f64, att[is + 1].boost as f64);
+	p.tone_decay = lerp(att[is].decay as f64, att[is + 1].decay as f64);
+	p.max_curve_dB = lerp(max[is] as f64, max[is + 1] as f64);
+	for i in 0..P_BANDS {
+		p.toneatt[i] = lerp(adj[is].block[i] as f64, adj[is + 1].block[i] as f64);
+	}
+}
+
+fn compand_setup(p: &mut VorbisInfoPsy, setting: f64, compand: &[CompandBlock], mapping: &[f64]) {
+	let (is, ds) = remap_setting(mapping, setting);
+
+	// interpolate the compander settings
+	for i in 0..NOISE_COMPAND_LEVELS {
+		p.noisecompand[i] = (compand[is].data[i] as f64 * (1.0 - ds) + compand[is + 1].data[i] as f64 * ds) as f32;
+	}
+}
+
+fn noisebias_setup(p: &mut VorbisInfoPsy, setting: f64, block: usize, suppress: &[i32], bias: &[Noise3], guard: &[NoiseGuard], userbias: f64) {
+	let is = setting as usize;
+	let ds = setting - is as f64;
+
+	p.noisemaxsupp = interpolate_setting(suppress, setting) as f32;
+	p.noisewindowlomin = guard[block].lo;
+	p.noisewindowhimin = guard[block].hi;
+	p.noisewindowfixed = guard[block].fixed;
+
+	for j in 0..P_NOISECURVES {
+		for i in 0..P_BANDS {
+			p.noiseoff[j][i] = (bias[is].data[j][i] as f64 * (1.0 - ds) + bias[is + 1].data[j][i] as f64 * ds) as f32;
+		}
+	}
+
+	// impulse blocks may take a user specified bias to boost the nominal/high noise encoding depth
+	for noiseoff in p.noiseoff.iter_mut() {
+		// the lowest it can go
+		let min = noiseoff[0] + 6.0;
+		for v in noiseoff.iter_mut() {
+			*v = (*v + userbias as f32).max(min);
+		}
+	}
+}
+
+/// * Set up the residue of the mapping template, its books are added to the codec setup.
+/// * The lowpass is set in the floor of the block too, the floor only fits the samples below it.
+#[allow(clippy::too_many_arguments)]
+fn residue_setup(ci: &mut VorbisCodecSetup, floors: &mut [VorbisFloor1], maps: &[VorbisMapping], channels: usize, rate: i32, number: i32, block: usize, res: &VorbisResidueTemplate) -> io::Result<VorbisResidue> {
+	let managed = ci.highlevel_encode_setup.managed != 0;
+	let lowpass_khz = ci.highlevel_encode_setup.lowpass_kHz;
+	let (book_aux, books_base) = if managed {
+		(&res.book_aux_managed, &res.books_base_managed)
+	} else {
+		(&res.book_aux, &res.books_base)
+	};
+	let groupbook = book_dup_or_new(&mut ci.static_codebooks, book_aux)?;
+
+	// lowpass setup/pointlimit
+	let nyq = rate as f64 / 2.0;
+	let blocksize = ci.block_size[block] >> 1;
+	let mut freq = (lowpass_khz * 1000.0).min(nyq);
+
+	/* lowpass needs to be set in the floor and the residue. in the floor, the granularity can be very fine;
+	   it doesn't alter the encoding structure, only the samples used to fit the floor approximation */
+	if let Some(floor) = floors.get_mut(block) {
+		floor.n = (freq / nyq * blocksize as f64) as i32;
+	}
+
+	// this res may by limited by the maximum pointlimit of the mode, not the lowpass. the floor is always lowpass limited.
+	match res.limit_type {
+		// point stereo limited
+		1 => {
+			let pkhz = ci.psy_g.coupling_pkHz[if managed {PACKETBLOBS - 1} else {PACKETBLOBS / 2}];
+			freq = (pkhz as f64 * 1000.0).min(nyq);
+		}
+		// LFE channel; lowpass at ~ 250Hz
+		2 => freq = 250.0,
+		// already set
+		_ => (),
+	}
+
+	/* in the residue, we're constrained, physically, by partition boundaries. We still lowpass 'wherever',
+	   but we have to round up here to next boundary, or the vorbis spec will round it *down* to previous boundary in encode/decode */
+	let ch = if res.res_type == 2 {
+		/* residue 2 bundles together multiple channels; used by stereo and surround. Count the channels in use.
+		   Multiple maps/submaps can point to the same residue. In the case of residue 2, they all better have the same number of channels/samples. */
+		maps.iter().find_map(|map| {
+			(0..map.submaps as usize).find(|&j|map.residuesubmap[j] == number).map(|j| {
+				(0..channels).filter(|&k|map.chmuxlist.iter().nth(k).copied().unwrap_or(0) == j as i32).count() as i32
+			})
+		}).unwrap_or(0)
+	} else {
+		1
+	};
+	let grouping = res.grouping;
+	// round up only if we're well past
+	let mut end = ((freq / nyq * blocksize as f64 * ch as f64) / grouping as f64 + 0.9) as i32 * grouping;
+	// the blocksize and grouping may disagree at the end
+	if end > blocksize * ch {
+		end = blocksize * ch / grouping * grouping;
+	}
+	// LFE channel
+	if end == 0 {
+		end = grouping;
+	}
+
+	// fill in all the books
+	let mut builder = VorbisResidue::builder(res.res_type, res.res.begin, end, grouping, res.res.partitions, groupbook)?;
+	for i in 0..res.res.partitions as usize {
+		let mut stage_books = [None; 4];
+		for (k, book) in books_base.books[i].iter().enumerate() {
+			if let Some(book) = book {
+				stage_books[k] = Some(book_dup_or_new(&mut ci.static_codebooks, book)?);
+			}
+		}
+		builder = builder
+			.stage_books(i, &stage_books)?
+			.class_metrics(i, res.res.classmetric1[i], res.res.classmetric2[i])?;
+	}
+	builder.build(&ci.static_codebooks)
+}
+
+impl VorbisInfo {
+	/// * Create an encoder setup for the quality, the quality is from -0.1 (lowest) to 1.0 (highest), like `vorbis_encode_init_vbr()`.
+	/// * The setup template is chosen by the channels, the sample rate and the quality.
+	/// * Only the 44.1kHz and 48kHz templates are ported, so the sample rate must be from 40000 to 50000 Hz.
+	///   The other rates such as 8, 11.025, 16, 22.05 and 32 kHz get an `Unsupported` error, resample them first.
+	pub fn new_vbr(channels: i32, sample_rate: i32, quality: f32) -> io::Result<Self> {
+		if channels <= 0 || channels > 255 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid channels: {channels}")));
+		}
+		if sample_rate <= 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid sample rate: {sample_rate}")));
+		}
+		if !(-0.1..=1.0).contains(&quality) {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The quality should be from -0.1 to 1.0, got {quality}")));
+		}
+		let mut ret = Self {
+			version: 0,
+			channels,
+			sample_rate,
+			..Default::default()
+		};
+
+		let quality = (quality as f64 + 0.0000001).min(0.9999);
+		let Some((setup, base_setting)) = get_setup_template(channels, sample_rate, quality, false) else {
+			return Err(io::Error::new(io::ErrorKind::Unsupported, format!("No encoder setup template for {channels} channels at {sample_rate} Hz with quality {quality:.2}")));
+		};
+		let hi = &mut ret.codec_setup.highlevel_encode_setup;
+		hi.req = quality as f32;
+		hi.setup = setup;
+		hi.base_setting = base_setting;
+		ret.encode_setup_setting();
+		let hi = &mut ret.codec_setup.highlevel_encode_setup;
+		hi.managed = 0;
+		hi.coupling_p = 1;
+
+		ret.encode_setup_init()?;
+		Ok(ret)
+	}
+
+	/// * Create an encoder setup for the bitrates in bits per second, the bitrate management keeps the bitrate between
+	///   `min_bitrate` and `max_bitrate` and averages it to `nominal`, like `vorbis_encode_init()`.
+	/// * If `nominal` is not positive, it's guessed from the other two bitrates, one of them should be given then.
+	/// * The sample rate must be from 40000 to 50000 Hz, the same as `new_vbr()`.
+	pub fn new_managed(channels: i32, sample_rate: i32, max_bitrate: Option<i32>, nominal: i32, min_bitrate: Option<i32>) -> io::Result<Self> {
+		if channels <= 0 || channels > 255 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid channels: {channels}")));
+		}
+		if sample_rate <= 0 {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid sample rate: {sample_rate}")));
+		}
+		let mut ret = Self {
+			version: 0,
+			channels,
+			sample_rate,
+			..Default::default()
+		};
+		let nominal_bitrate = ret.setup_bitrate_management(max_bitrate, nominal, min_bitrate)?;
+
+		let Some((setup, base_setting)) = get_setup_template(channels, sample_rate, nominal_bitrate as f64, true) else {
+			return Err(io::Error::new(io::ErrorKind::Unsupported, format!("No encoder setup template for {channels} channels at {sample_rate} Hz with bitrate {nominal_bitrate}")));
+		};
+		let hi = &mut ret.codec_setup.highlevel_encode_setup;
+		hi.setup = setup;
+		hi.base_setting = base_setting;
+		ret.encode_setup_setting();
+
+		ret.encode_setup_init()?;
+		Ok(ret)
+	}
+
+	/// * Set up the bitrate management with sane defaults, the bitrates in the identification header are set too.
+	/// * If `nominal` is not positive, it's guessed from the other two bitrates. Returns the nominal bitrate used for choosing the template.
+	pub fn setup_bitrate_management(&mut self, max_bitrate: Option<i32>, nominal: i32, min_bitrate: Option<i32>) -> io::Result<i32> {
+		let max_bitrate = max_bitrate.filter(|&b|b > 0);
+		let min_bitrate = min_bitrate.filter(|&b|b > 0);
+		let nominal_bitrate = if nominal > 0 {
+			nominal
+		} else {
+			match (max_bitrate, min_bitrate) {
+				(Some(max_bitrate), Some(min_bitrate)) => ((max_bitrate as f64 + min_bitrate as f64) * 0.5) as i32,
+				(Some(max_bitrate), None) => (max_bitrate as f64 * 0.875) as i32,
+				(None, Some(min_bitrate)) => min_bitrate,
+				(None, None) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "At least one of the bitrates should be given".to_string())),
+			}
+		};
+		if let (Some(max_bitrate), Some(min_bitrate)) = (max_bitrate, min_bitrate) && max_bitrate < min_bitrate {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("The max bitrate {max_bitrate} is lower than the min bitrate {min_bitrate}")));
+		}
+
+		let hi = &mut self.codec_setup.highlevel_encode_setup;
+		hi.req = nominal_bitrate as f32;
+		hi.coupling_p = 1;
+		hi.managed = 1;
+		hi.bitrate_min = min_bitrate.unwrap_or(-1);
+		hi.bitrate_max = max_bitrate.unwrap_or(-1);
+		hi.bitrate_av = nominal.max(0);
+		// Full range in no less than 1.5 second
+		hi.bitrate_av_damp = 1.5;
+		hi.bitrate_reservoir = nominal_bitrate * 2;
+		// Bias toward hoarding bits
+		hi.bitrate_reservoir_bias = 0.1;
+
+		// The readonlies of the identification header
+		self.bitrate_nominal = nominal_bitrate;
+		self.bitrate_lower = hi.bitrate_min;
+		self.bitrate_upper = hi.bitrate_max;
+		self.bitrate_window = if hi.bitrate_av > 0 {hi.bitrate_reservoir / hi.bitrate_av} else {0};
+
+		let bi = &mut self.codec_setup.bitrate_manager_info;
+		bi.avg_rate = hi.bitrate_av;
+		bi.min_rate = hi.bitrate_min;
+		bi.max_rate = hi.bitrate_max;
+		bi.reservoir_bits = hi.bitrate_reservoir as usize;
+		bi.reservoir_bias = hi.bitrate_reservoir_bias;
+		bi.slew_damp = hi.bitrate_av_damp;
+		Ok(nominal_bitrate)
+	}
+
+	/// * Fill the high-level settings from the base setting of the template
+	fn encode_setup_setting(&mut self) {
+		let hi = &mut self.codec_setup.highlevel_encode_setup;
+		let setting = hi.base_setting;
+		hi.impulse_block_p = 1;
+		hi.noise_normalize_p = 1;
+		hi.stereo_point_setting = setting;
+		hi.lowpass_kHz = interpolate_setting(&hi.setup.psy_lowpass, setting);
+		hi.ath_floating_dB = interpolate_setting(&hi.setup.psy_ath_float, setting);
+		hi.ath_absolute_dB = interpolate_setting(&hi.setup.psy_ath_abs, setting);
+		hi.amplitude_track_dBpersec = -6.0;
+		hi.trigger_setting = setting;
+		for block in hi.block.iter_mut() {
+			block.tone_mask_setting = setting;
+			block.tone_peaklimit_setting = setting;
+			block.noise_bias_setting = setting;
+			block.noise_compand_setting = setting;
+		}
+	}
+
+	/// * Build the modes, mappings, floors, residues, codebooks and the psychoacoustic settings from the template, like `vorbis_encode_setup_init()`
+	fn encode_setup_init(&mut self) -> io::Result<()> {
+		let hi = &mut self.codec_setup.highlevel_encode_setup;
+
+		// too low/high an ATH floater is nonsensical, but doesn't break anything
+		hi.ath_floating_dB = hi.ath_floating_dB.clamp(-200.0, -80.0);
+
+		// again, bound this to avoid the app shooting itself in the foot too badly
+		hi.amplitude_track_dBpersec = hi.amplitude_track_dBpersec.clamp(-99999.0, 0.0);
+		hi.set_in_stone = 1;
+
+		let setup = std::mem::take(&mut hi.setup);
+		let ret = self.encode_setup_from_template(&setup);
+		self.codec_setup.highlevel_encode_setup.setup = setup;
+		ret
+	}
+
+	fn encode_setup_from_template(&mut self, setup: &VorbisEncodeSetupDataTemplate) -> io::Result<()> {
+		let channels = self.channels as usize;
+		let rate = self.sample_rate;
+		let ci = &mut self.codec_setup;
+		let hi = ci.highlevel_encode_setup.clone();
+		let i0 = if hi.impulse_block_p == 0 {1} else {0};
+		let is = hi.base_setting as usize;
+		let ds = hi.base_setting - is as f64;
+
+		// block size setup
+		ci.block_size = [setup.blocksize_short[is], setup.blocksize_long[is]];
+		let singleblock = ci.block_size[0] == ci.block_size[1];
+
+		// floor setup; choose proper floor params. Allocated on the floor stack in order; if we alloc only a single long floor, it's 0
+		let mut floors = Vec::<VorbisFloor1>::new();
+		for floor_mapping in setup.floor_mapping_list.iter().take(setup.floor_mappings as usize) {
+			let x = floor_mapping[is] as usize;
+			let mut floor1 = setup.floor_params[x];
+			let books = ci.static_codebooks.len() as i32;
+			let maxclass = floor1.partitions_class.iter().map(|&class|class as usize + 1).max().unwrap_or(0);
+			for i in 0..maxclass {
+				floor1.class_book[i] += books;
+				for k in 0..floor1.class_subbook[i].len() {
+					if floor1.class_subbook[i][k] >= 0 {
+						floor1.class_subbook[i][k] += books;
+					}
+				}
+			}
+			for book in setup.floor_books[x].iter() {
+				book.validate()?;
+				ci.static_codebooks.push(book.clone());
+			}
+			floors.push(floor1);
+		}
+
+		// setup of [mostly] short block detection
+		let mut g = setup.global_params[interpolate_setting(&setup.global_mapping, hi.trigger_setting) as usize];
+		let (gis, gds) = remap_setting(&setup.global_mapping, hi.trigger_setting);
+		let in_g = &setup.global_params;
+		// interpolate the trigger threshholds
+		for i in 0..4 {
+			g.preecho_thresh[i] = in_g[gis].preecho_thresh[i] * (1.0 - gds as f32) + in_g[gis + 1].preecho_thresh[i] * gds as f32;
+			g.postecho_thresh[i] = in_g[gis].postecho_thresh[i] * (1.0 - gds as f32) + in_g[gis + 1].postecho_thresh[i] * gds as f32;
+		}
+		g.ampmax_att_per_sec = hi.amplitude_track_dBpersec as f32;
+
+		// stereo
+		let block_size = [ci.block_size[0] as f64, ci.block_size[1] as f64];
+		if setup.stereo_modes.is_empty() {
+			for i in 0..PACKETBLOBS {
+				g.sliding_lowpass[0][i] = ci.block_size[0];
+				g.sliding_lowpass[1][i] = ci.block_size[1];
+			}
+		} else {
+			let p = &setup.stereo_modes;
+			let sis = hi.stereo_point_setting as usize;
+			let sds = hi.stereo_point_setting - sis as f64;
+			let lerp = |a: f32, b: f32|a as f64 * (1.0 - sds) + b as f64 * sds;
+			g.coupling_prepointamp = p[sis].pre;
+			g.coupling_postpointamp = p[sis].post;
+			for i in 0..PACKETBLOBS {
+				// the unmanaged mode uses the middle one for all of the packet blobs
+				let k = if hi.managed != 0 {i} else {PACKETBLOBS / 2};
+				let khz = lerp(p[sis].kHz[k], p[sis + 1].kHz[k]);
+				g.coupling_pointlimit[0][i] = (khz * 1000.0 / rate as f64 * block_size[0]) as i32;
+				g.coupling_pointlimit[1][i] = (khz * 1000.0 / rate as f64 * block_size[1]) as i32;
+				g.coupling_pkHz[i] = khz as i32;
+
+				let khz = lerp(p[sis].lowpasskHz[k], p[sis + 1].lowpasskHz[k]);
+				g.sliding_lowpass[0][i] = (khz * 1000.0 / rate as f64 * block_size[0]) as i32;
+				g.sliding_lowpass[1][i] = (khz * 1000.0 / rate as f64 * block_size[1]) as i32;
+			}
+		}
+		ci.psy_g = Rc::new(g);
+
+		// basic psych setup and noise normalization, then the tone masking, the noise companding, the peak guarding, the noise bias and the ATH of each block type
+		let blocks = if singleblock {2} else {4};
+		for n in 0..blocks {
+			ci.psyset_setup(n, hi.base_setting, &setup.psy_noise_normal_start[n >> 1], &setup.psy_noise_normal_partition[n >> 1], &setup.psy_noise_normal_thresh);
+			let block = &hi.block[if n == 0 {i0} else {n}];
+			let p = Rc::make_mut(&mut ci.psys[n]);
+			let (tone_adj, noise_bias, compand_mapping) = match n {
+				0 => (&setup.psy_tone_adj_impulse, &setup.psy_noise_bias_impulse, &setup.psy_noise_compand_short_mapping),
+				1 => (&setup.psy_tone_adj_other, &setup.psy_noise_bias_padding, &setup.psy_noise_compand_short_mapping),
+				2 => (&setup.psy_tone_adj_other, &setup.psy_noise_bias_trans, &setup.psy_noise_compand_long_mapping),
+				_ => (&setup.psy_tone_adj_long, &setup.psy_noise_bias_long, &setup.psy_noise_compand_long_mapping),
+			};
+			tonemask_setup(p, block.tone_mask_setting, &setup.psy_tone_masteratt, &setup.psy_tone_0dB, tone_adj);
+			compand_setup(p, block.noise_compand_setting, &setup.psy_noise_compand, compand_mapping);
+			p.tone_abs_limit = interpolate_setting(&setup.psy_tone_dBsuppress, block.tone_peaklimit_setting) as f32;
+			let userbias = if n == 0 && i0 == 0 {hi.impulse_noisetune} else {0.0};
+			noisebias_setup(p, block.noise_bias_setting, n, &setup.psy_noise_dBsuppress, noise_bias, &setup.psy_noiseguards, userbias);
+			p.ath_adjatt = hi.ath_floating_dB as f32;
+			p.ath_maxatt = hi.ath_absolute_dB as f32;
+		}
+
+		// the modes, the mappings and the residues they refer to, we assume two maps in this encoder
+		let map_template = &setup.maps[is];
+		let modes = if singleblock {1} else {2};
+		let mut maps = Vec::<VorbisMapping>::with_capacity(modes);
+		let mut residues = Vec::<Option<VorbisResidue>>::new();
+		ci.modes = MODE_TEMPLATE[..modes].to_vec();
+		for i in 0..modes {
+			let map = map_template.map[i];
+			maps.push(map);
+			for j in 0..map.submaps as usize {
+				let number = map.residuesubmap[j];
+				let residue = residue_setup(ci, &mut floors, &maps, channels, rate, number, i, &map_template.res[number as usize])?;
+				let number = number as usize;
+				if residues.len() <= number {
+					residues.resize(number + 1, None);
+				}
+				residues[number] = Some(residue);
+			}
+		}
+		if ci.static_codebooks.len() > 256 {
+			return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The template needs {} codebooks, at most 256 could be in the setup header", ci.static_codebooks.len())));
+		}
+		ci.floors = floors.into_iter().map(|floor1|Rc::new(VorbisFloor::Floor1(Rc::new(floor1)))).collect();
+		ci.residues = residues.into_iter().enumerate().map(|(i, residue)| {
+			residue.map(Rc::new).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Residue {i} isn't used by any of the mappings")))
+		}).collect::<io::Result<_>>()?;
+		ci.maps = maps.into_iter().map(Rc::new).collect();
+
+		// set bitrate readonlies and management
+		self.bitrate_nominal = if hi.bitrate_av > 0 {
+			hi.bitrate_av
+		} else {
+			let r = &setup.rate_mapping;
+			((r[is] * (1.0 - ds) + r[is + 1] * ds) * channels as f64) as i32
+		};
+		self.bitrate_lower = hi.bitrate_min;
+		self.bitrate_upper = hi.bitrate_max;
+		self.bitrate_window = if hi.bitrate_av != 0 {hi.bitrate_reservoir / hi.bitrate_av} else {0};
+
+		if hi.managed != 0 {
+			let bi = &mut self.codec_setup.bitrate_manager_info;
+			bi.avg_rate = hi.bitrate_av;
+			bi.min_rate = hi.bitrate_min;
+			bi.max_rate = hi.bitrate_max;
+			bi.reservoir_bits = hi.bitrate_reservoir as usize;
+			bi.reservoir_bias = hi.bitrate_reservoir_bias;
+			bi.slew_damp = hi.bitrate_av_damp;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,250 @@
+#![allow(dead_code)]
+use std::{
+    fmt::{self, Debug, Formatter},
+    io,
+};
+
+use crate::*;
+use ogg::OggPacket;
+use pages::PAGE_FLAG_EOS;
+
+/// * `WAVE_FORMAT_PCM`
+const FORMAT_TAG_PCM: u16 = 1;
+
+/// * `WAVE_FORMAT_IEEE_FLOAT`
+const FORMAT_TAG_FLOAT: u16 = 3;
+
+/// * `WAVE_FORMAT_EXTENSIBLE`, the real format tag is the first two bytes of the sub format GUID
+const FORMAT_TAG_EXTENSIBLE: u16 = 0xFFFE;
+
+/// * The planar PCM samples read from a WAV file, the samples are from -1.0 to 1.0
+#[derive(Default, Clone, PartialEq)]
+pub struct WavPcm {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub samples: Vec<Vec<f32>>,
+}
+
+impl Debug for WavPcm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("WavPcm")
+        .field("channels", &self.channels)
+        .field("sample_rate", &self.sample_rate)
+        .field("samples", &NestVecFormatter::new_level1(&self.samples))
+        .finish()
+    }
+}
+
+/// * Iterate through the RIFF chunks after the `WAVE` form type, yields the chunk ID and the chunk data.
+fn riff_chunks(wav: &[u8]) -> io::Result<Vec<([u8; 4], &[u8])>> {
+    if wav.len() < 12 || wav[0..4] != *b"RIFF" || wav[8..12] != *b"WAVE" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a RIFF WAVE file.".to_string()));
+    }
+    let mut ret = Vec::new();
+    let mut pos = 12usize;
+    while pos + 8 <= wav.len() {
+        let id: [u8; 4] = wav[pos..pos + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(wav[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let begin = pos + 8;
+        // Some writers leave the size of the last chunk unset, take what we have
+        let end = begin.saturating_add(size).min(wav.len());
+        ret.push((id, &wav[begin..end]));
+        // The chunks are padded to even sizes
+        pos = end + (size & 1);
+    }
+    Ok(ret)
+}
+
+/// * Read a PCM WAV file, 8/16/24/32-bit integer and 32-bit float samples are supported.
+pub fn parse_wav(wav: &[u8]) -> io::Result<WavPcm> {
+    let chunks = riff_chunks(wav)?;
+    let Some((_, fmt)) = chunks.iter().find(|(id, _)|id == b"fmt ") else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The WAV file has no `fmt ` chunk.".to_string()));
+    };
+    let Some((_, data)) = chunks.iter().find(|(id, _)|id == b"data") else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The WAV file has no `data` chunk.".to_string()));
+    };
+    if fmt.len() < 16 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The `fmt ` chunk is too small: {} < 16", fmt.len())));
+    }
+    let mut format_tag = u16::from_le_bytes(fmt[0..2].try_into().unwrap());
+    let channels = u16::from_le_bytes(fmt[2..4].try_into().unwrap());
+    let sample_rate = u32::from_le_bytes(fmt[4..8].try_into().unwrap());
+    let block_align = u16::from_le_bytes(fmt[12..14].try_into().unwrap()) as usize;
+    let bits_per_sample = u16::from_le_bytes(fmt[14..16].try_into().unwrap());
+    if format_tag == FORMAT_TAG_EXTENSIBLE {
+        if fmt.len() < 26 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The extensible `fmt ` chunk is too small: {} < 26", fmt.len())));
+        }
+        format_tag = u16::from_le_bytes(fmt[24..26].try_into().unwrap());
+    }
+    if channels == 0 || sample_rate == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad WAV format: channels = {channels}, sample rate = {sample_rate}")));
+    }
+    let bytes_per_sample = bits_per_sample.div_ceil(8) as usize;
+    if block_align < bytes_per_sample * channels as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad block align {block_align} for {channels} channels of {bits_per_sample}-bit samples")));
+    }
+    let convert: fn(&[u8]) -> f32 = match (format_tag, bytes_per_sample) {
+        (FORMAT_TAG_PCM, 1) => |s|(s[0] as f32 - 128.0) / 128.0,
+        (FORMAT_TAG_PCM, 2) => |s|i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0,
+        (FORMAT_TAG_PCM, 3) => |s|(i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) as f32 / 8388608.0,
+        (FORMAT_TAG_PCM, 4) => |s|i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2147483648.0,
+        (FORMAT_TAG_FLOAT, 4) => |s|f32::from_le_bytes([s[0], s[1], s[2], s[3]]),
+        (t, b) => return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unsupported WAV format: format tag = 0x{t:04x}, bits per sample = {}", b * 8))),
+    };
+    let mut samples = vec![Vec::<f32>::with_capacity(data.len() / block_align); channels as usize];
+    for frame in data.chunks_exact(block_align) {
+        for (ch, channel) in samples.iter_mut().enumerate() {
+            channel.push(convert(&frame[ch * bytes_per_sample..(ch + 1) * bytes_per_sample]));
+        }
+    }
+    Ok(WavPcm {
+        channels,
+        sample_rate,
+        samples,
+    })
+}
+
+/// * How many samples of each channel are submitted to the encoder at once
+const ENCODE_CHUNK_SAMPLES: usize = 1024;
+
+/// * The audio page is sealed when its payload reaches this size, the same as `ogg_stream_pageout()` of `libogg`
+const AUDIO_PAGE_FILL: usize = 4096;
+
+/// * Encode a PCM WAV file to an Ogg Vorbis stream of the quality, the quality is from -0.1 (lowest) to 1.0 (highest).
+/// * The encoder is set up by `VorbisInfo::new_vbr()`, the headers get their own pages and the audio packets are packed into pages of about 4 KiB.
+/// * The serial number of the stream is the CRC of the WAV file, so the same input always gets the same output.
+/// * Only 44.1kHz and 48kHz WAV files can be encoded, other sample rates get an `Unsupported` error from `VorbisInfo::new_vbr()`.
+/// * The vendor string of the comment header is `VENDOR_STRING`, use `encode_wav_to_ogg_vorbis_with_vendor()` for another one.
+pub fn encode_wav_to_ogg_vorbis(wav: &[u8], quality: f32) -> io::Result<Vec<u8>> {
+    encode_wav_to_ogg_vorbis_with_vendor(wav, quality, VENDOR_STRING)
+}
+
+/// * Encode a PCM WAV file to an Ogg Vorbis stream the same as `encode_wav_to_ogg_vorbis()`, the comment header identifies the encoder by `vendor`.
+pub fn encode_wav_to_ogg_vorbis_with_vendor(wav: &[u8], quality: f32, vendor: &str) -> io::Result<Vec<u8>> {
+    let pcm = parse_wav(wav)?;
+    let vi = VorbisInfo::new_vbr(pcm.channels as i32, pcm.sample_rate as i32, quality)?;
+    let mut vd = VorbisDspState::new(vi, true)?;
+    vd.set_vendor(vendor);
+    let (ident, comment, setup) = vd.headerout(&VorbisCommentHeader::new())?;
+    let stream_id = OggPacket::crc(0, wav);
+
+    let (header_pages, next_page_index) = packets_to_ogg_pages(&[&ident], stream_id, 0, true, 0)?;
+    let (comment_setup_pages, next_page_index) = packets_to_ogg_pages(&[&comment, &setup], stream_id, next_page_index, false, 0)?;
+    let mut muxer = AudioPageMuxer {
+        stream_id,
+        page_index: next_page_index,
+        output: header_pages.into_iter().chain(comment_setup_pages).flatten().collect(),
+        ..Default::default()
+    };
+
+    let frames = pcm.samples.first().map_or(0, |channel|channel.len());
+    for begin in (0..frames).step_by(ENCODE_CHUNK_SAMPLES) {
+        let end = (begin + ENCODE_CHUNK_SAMPLES).min(frames);
+        for (buffer, channel) in vd.analysis_buffer(end - begin).into_iter().zip(pcm.samples.iter()) {
+            buffer.copy_from_slice(&channel[begin..end]);
+        }
+        vd.analysis_wrote(end - begin)?;
+        encode_blocks(&mut vd, &mut muxer)?;
+    }
+    vd.analysis_wrote(0)?;
+    encode_blocks(&mut vd, &mut muxer)?;
+    muxer.seal(true)?;
+    Ok(muxer.output)
+}
+
+/// * Encode the blocks that are ready in the encoder and pass the packets to the muxer
+fn encode_blocks(vd: &mut VorbisDspState, muxer: &mut AudioPageMuxer) -> io::Result<()> {
+    while let Some(mut vb) = vd.analysis_blockout()? {
+        vb.analysis()?;
+        vd.bitrate_addblock(vb)?;
+        while let Some(packet) = vd.packet_out() {
+            muxer.push(packet.get_inner_data(), packet.granule_position)?;
+        }
+    }
+    Ok(())
+}
+
+/// * Packs the audio packets into Ogg pages, a page holds whole packets only and takes the granule position of its last packet.
+#[derive(Default)]
+struct AudioPageMuxer {
+    stream_id: u32,
+    page_index: u32,
+    segment_table: Vec<u8>,
+    data: Vec<u8>,
+    granule_position: u64,
+    output: Vec<u8>,
+}
+
+impl AudioPageMuxer {
+    /// * Add a packet to the current page, the page is sealed first if it's full enough or the packet doesn't fit in its segment table.
+    /// * A full page waits for the next packet to be sealed, so the last page of the stream always has the last packet on it.
+    fn push(&mut self, packet: Vec<u8>, granule_position: u64) -> io::Result<()> {
+        // A packet whose size is a multiple of 255 ends with a zero lacing value
+        let segments = packet.len() / 255 + 1;
+        if segments > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The audio packet of {} bytes is too big for an Ogg page", packet.len())));
+        }
+        if self.data.len() >= AUDIO_PAGE_FILL || self.segment_table.len() + segments > 255 {
+            self.seal(false)?;
+        }
+        self.segment_table.extend(std::iter::repeat_n(255u8, segments - 1));
+        self.segment_table.push((packet.len() % 255) as u8);
+        self.data.extend(packet);
+        self.granule_position = granule_position;
+        Ok(())
+    }
+
+    /// * Write out the current page, `eos` marks it as the last page of the stream.
+    fn seal(&mut self, eos: bool) -> io::Result<()> {
+        let header_type = if eos {PAGE_FLAG_EOS} else {0};
+        self.output.extend(build_ogg_page(header_type, self.granule_position, self.stream_id, self.page_index, &self.segment_table, &self.data)?);
+        self.page_index += 1;
+        self.segment_table.clear();
+        self.data.clear();
+        Ok(())
+    }
+}
+
+/// * Serialize planar samples to a canonical 16-bit PCM WAV file, the samples are converted the same way as `VorbisDspState::decode_packet_i16()`.
+pub fn pcm_to_wav_i16(channels: u16, sample_rate: u32, samples: &[Vec<f32>]) -> io::Result<Vec<u8>> {
+    if channels == 0 || samples.len() != channels as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Expected {channels} channels of samples, got {}", samples.len())));
+    }
+    let frames = samples[0].len();
+    if samples.iter().any(|channel|channel.len() != frames) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The channels have different numbers of samples.".to_string()));
+    }
+    let block_align = channels as usize * 2;
+    let data_size = frames * block_align;
+    if data_size > u32::MAX as usize - 36 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Too many samples for a WAV file: {frames} frames")));
+    }
+    let mut ret = Vec::<u8>::with_capacity(44 + data_size);
+    ret.extend(b"RIFF");
+    ret.extend((36 + data_size as u32).to_le_bytes());
+    ret.extend(b"WAVEfmt ");
+    ret.extend(16u32.to_le_bytes());
+    ret.extend(FORMAT_TAG_PCM.to_le_bytes());
+    ret.extend(channels.to_le_bytes());
+    ret.extend(sample_rate.to_le_bytes());
+    ret.extend((sample_rate * block_align as u32).to_le_bytes());
+    ret.extend((block_align as u16).to_le_bytes());
+    ret.extend(16u16.to_le_bytes());
+    ret.extend(b"data");
+    ret.extend((data_size as u32).to_le_bytes());
+    for i in 0..frames {
+        for channel in samples.iter() {
+            ret.extend(((channel[i] * 32768.0).round_ties_even().clamp(-32768.0, 32767.0) as i16).to_le_bytes());
+        }
+    }
+    Ok(ret)
+}
+
+/// * Decode the first logical stream of an Ogg Vorbis byte buffer to a 16-bit PCM WAV file.
+/// * The `fmt ` chunk is from the identification header, the samples are trimmed by the granule positions as `decode_ogg_vorbis()` does.
+pub fn decode_ogg_vorbis_to_wav(data: &[u8]) -> io::Result<Vec<u8>> {
+    let (identification_header, pcm) = decode_ogg_vorbis(data)?;
+    pcm_to_wav_i16(identification_header.channels as u16, identification_header.sample_rate as u32, &pcm)
+}
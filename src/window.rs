@@ -0,0 +1,58 @@
+#![allow(dead_code)]
+use std::{
+    f64::consts::FRAC_PI_2,
+    io,
+};
+
+use crate::*;
+
+/// * The index of the window of a block size, as `VorbisDspStatePrivate` keys its windows: 0 for 64 samples, 1 for 128 samples, up to 7 for 8192 samples.
+pub fn window_index(block_size: i32) -> i32 {
+    ilog!(block_size) - 7
+}
+
+/// * The rising slope of the Vorbis window over `size` samples: `sin(π/2 * sin²((i + 0.5) / size * π/2))`
+/// * The falling slope is the same slope reversed. `slope[i]² + slope[size - 1 - i]² == 1`, so the overlapped windows sum to unity power.
+pub fn vorbis_window_slope(size: usize) -> Vec<f32> {
+    (0..size).map(|i| {
+        let x = ((i as f64 + 0.5) / size as f64 * FRAC_PI_2).sin();
+        (FRAC_PI_2 * x * x).sin() as f32
+    }).collect()
+}
+
+/// * Generate the window of a block of `n` samples, `left_n` and `right_n` are the sizes of the previous and the next blocks.
+/// * When a neighbour block is smaller, the slope on that side only spans the overlapping part around the quarter point
+///   and the window is 0 outside of it and 1 inside of it, as described in the Vorbis I specification.
+/// * When the neighbour block is the same size or larger, the slope spans the whole half of the block.
+/// * The sizes must be powers of two and `n` must be at least 4, as the block sizes of Vorbis are. Panics otherwise, use `try_vorbis_window()` for unchecked sizes.
+pub fn vorbis_window(left_n: usize, n: usize, right_n: usize) -> Vec<f32> {
+    match try_vorbis_window(left_n, n, right_n) {
+        Ok(window) => window,
+        Err(e) => panic!("{e}"),
+    }
+}
+
+/// * Generate the window the same as `vorbis_window()`, the sizes are checked before generating.
+pub fn try_vorbis_window(left_n: usize, n: usize, right_n: usize) -> io::Result<Vec<f32>> {
+    if n < 4 || ![left_n, n, right_n].iter().all(|size|size.is_power_of_two()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Bad window sizes: {left_n}, {n}, {right_n}, they should be powers of two and the block size should be at least 4")));
+    }
+    let mut window = vec![0.0f32; n];
+    let n4 = n / 4;
+
+    let left_size = left_n.min(n) / 2;
+    let left_begin = n4 - left_size / 2;
+    let left_end = left_begin + left_size;
+    let right_size = right_n.min(n) / 2;
+    let right_begin = n4 * 3 - right_size / 2;
+    let right_end = right_begin + right_size;
+
+    let left_slope = vorbis_window_slope(left_size);
+    window[left_begin..left_end].copy_from_slice(&left_slope);
+    window[left_end..right_begin].fill(1.0);
+    let right_slope = vorbis_window_slope(right_size);
+    for (i, w) in window[right_begin..right_end].iter_mut().enumerate() {
+        *w = right_slope[right_size - 1 - i];
+    }
+    Ok(window)
+}